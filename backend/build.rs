@@ -1,4 +1,7 @@
 fn main(){
+	println!("cargo:rerun-if-changed=migrations");
+	println!("cargo:rerun-if-changed=../proto_library");
+
 	tonic_prost_build::compile_protos(
 		"../proto_library/users.proto"
 	).unwrap();
@@ -0,0 +1,8 @@
+CREATE TABLE IF NOT EXISTS users (
+    id           TEXT PRIMARY KEY NOT NULL,
+    email        TEXT UNIQUE,
+    phone        TEXT UNIQUE,
+    display_name TEXT NOT NULL DEFAULT '',
+    created_at   INTEGER NOT NULL,
+    status       TEXT NOT NULL DEFAULT 'active'
+);
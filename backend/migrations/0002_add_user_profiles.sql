@@ -0,0 +1,3 @@
+ALTER TABLE users ADD COLUMN about TEXT NOT NULL DEFAULT '';
+ALTER TABLE users ADD COLUMN avatar BLOB;
+ALTER TABLE users ADD COLUMN avatar_mime TEXT NOT NULL DEFAULT '';
@@ -0,0 +1,6 @@
+-- sha256 of the normalized identifier, for contact discovery without sending raw addresses.
+-- Existing rows are filled in by SqliteStore::connect since SQLite has no sha256().
+ALTER TABLE users ADD COLUMN email_hash TEXT;
+ALTER TABLE users ADD COLUMN phone_hash TEXT;
+CREATE INDEX IF NOT EXISTS users_email_hash ON users (email_hash);
+CREATE INDEX IF NOT EXISTS users_phone_hash ON users (phone_hash);
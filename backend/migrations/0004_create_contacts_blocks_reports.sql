@@ -0,0 +1,25 @@
+CREATE TABLE IF NOT EXISTS contacts (
+    owner_id   TEXT NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    contact_id TEXT NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    added_at   INTEGER NOT NULL,
+    PRIMARY KEY (owner_id, contact_id)
+);
+
+CREATE TABLE IF NOT EXISTS blocks (
+    blocker_id TEXT NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    blocked_id TEXT NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    blocked_at INTEGER NOT NULL,
+    PRIMARY KEY (blocker_id, blocked_id)
+);
+
+-- Reports outlive both accounts so moderators keep the history.
+CREATE TABLE IF NOT EXISTS reports (
+    id          INTEGER PRIMARY KEY AUTOINCREMENT,
+    reporter_id TEXT NOT NULL,
+    reported_id TEXT NOT NULL,
+    reason      TEXT NOT NULL,
+    details     TEXT NOT NULL DEFAULT '',
+    created_at  INTEGER NOT NULL,
+    status      TEXT NOT NULL DEFAULT 'open'
+);
+CREATE INDEX IF NOT EXISTS reports_reported ON reports (reported_id);
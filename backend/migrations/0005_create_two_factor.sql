@@ -0,0 +1,16 @@
+-- TOTP secrets are stored encrypted (nonce || AES-256-GCM ciphertext).
+-- A row with confirmed = 0 is an enrollment the user has not finished yet.
+CREATE TABLE IF NOT EXISTS user_totp (
+    user_id          TEXT PRIMARY KEY REFERENCES users (id) ON DELETE CASCADE,
+    secret_encrypted BLOB NOT NULL,
+    confirmed        INTEGER NOT NULL DEFAULT 0,
+    last_used_step   INTEGER NOT NULL DEFAULT 0,
+    created_at       INTEGER NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS recovery_codes (
+    user_id   TEXT NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    code_hash TEXT NOT NULL,
+    used_at   INTEGER,
+    PRIMARY KEY (user_id, code_hash)
+);
@@ -0,0 +1,21 @@
+-- Every message that went through SendMessage. The id is what clients use to
+-- edit or delete it. Deleting for everyone wipes the body but keeps the row so
+-- both sides can still show where the message was.
+CREATE TABLE IF NOT EXISTS messages (
+    id           INTEGER PRIMARY KEY AUTOINCREMENT,
+    sender_id    TEXT NOT NULL,
+    recipient_id TEXT NOT NULL,
+    body         BLOB NOT NULL,
+    sent_at      INTEGER NOT NULL,
+    edited_at    INTEGER,
+    deleted      INTEGER NOT NULL DEFAULT 0
+);
+CREATE INDEX IF NOT EXISTS messages_sender ON messages (sender_id, recipient_id);
+CREATE INDEX IF NOT EXISTS messages_recipient ON messages (recipient_id, sender_id);
+
+-- "Delete for me": the message stays for the other side.
+CREATE TABLE IF NOT EXISTS message_hidden (
+    user_id    TEXT NOT NULL,
+    message_id INTEGER NOT NULL REFERENCES messages (id) ON DELETE CASCADE,
+    PRIMARY KEY (user_id, message_id)
+);
@@ -0,0 +1,11 @@
+-- The message this one replies to, if any. Kept as a plain id so a reply
+-- survives the quoted message being deleted.
+ALTER TABLE messages ADD COLUMN reply_to INTEGER;
+
+CREATE TABLE IF NOT EXISTS message_reactions (
+    message_id INTEGER NOT NULL REFERENCES messages (id) ON DELETE CASCADE,
+    user_id    TEXT NOT NULL,
+    emoji      TEXT NOT NULL,
+    reacted_at INTEGER NOT NULL,
+    PRIMARY KEY (message_id, user_id, emoji)
+);
@@ -0,0 +1,23 @@
+-- Full-text index over message bodies, keyed by message id. Triggers keep it
+-- in step with `messages`; messages deleted for everyone drop out of it.
+CREATE VIRTUAL TABLE IF NOT EXISTS message_search USING fts5 (
+    body,
+    tokenize = 'unicode61 remove_diacritics 2'
+);
+
+INSERT INTO message_search (rowid, body)
+    SELECT id, CAST(body AS TEXT) FROM messages WHERE deleted = 0;
+
+CREATE TRIGGER IF NOT EXISTS messages_search_insert AFTER INSERT ON messages BEGIN
+    INSERT INTO message_search (rowid, body) VALUES (new.id, CAST(new.body AS TEXT));
+END;
+
+CREATE TRIGGER IF NOT EXISTS messages_search_update AFTER UPDATE OF body, deleted ON messages BEGIN
+    DELETE FROM message_search WHERE rowid = old.id;
+    INSERT INTO message_search (rowid, body)
+        SELECT new.id, CAST(new.body AS TEXT) WHERE new.deleted = 0;
+END;
+
+CREATE TRIGGER IF NOT EXISTS messages_search_delete AFTER DELETE ON messages BEGIN
+    DELETE FROM message_search WHERE rowid = old.id;
+END;
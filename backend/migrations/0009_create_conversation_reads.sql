@@ -0,0 +1,9 @@
+-- How far each user has read in each of their conversations. Only ever moves
+-- forward, so devices reporting out of order can't mark messages unread again.
+CREATE TABLE IF NOT EXISTS conversation_reads (
+    user_id      TEXT NOT NULL,
+    peer_id      TEXT NOT NULL,
+    last_read_id INTEGER NOT NULL,
+    updated_at   INTEGER NOT NULL,
+    PRIMARY KEY (user_id, peer_id)
+);
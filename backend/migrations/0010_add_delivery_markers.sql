@@ -0,0 +1,4 @@
+-- How far each user's devices have received each conversation, so senders can
+-- tell delivered from merely sent. Reading implies delivery, so this is never
+-- behind last_read_id.
+ALTER TABLE conversation_reads ADD COLUMN last_delivered_id INTEGER NOT NULL DEFAULT 0;
@@ -3,17 +3,20 @@ use tokio::sync::mpsc;
 
 pub struct ChannelLayer<T>{
 	receiver: tokio::sync::mpsc::Receiver<Command<T>>,
-	subscribers: HashMap<String, tokio::sync::mpsc::Sender<T>>
+	// addr -> session id -> that device's stream
+	subscribers: HashMap<String, HashMap<String, tokio::sync::mpsc::Sender<T>>>
 }
 
 pub enum Command<T>{
-	Subscribe((String, tokio::sync::mpsc::Sender<T>)),
-	Message((String, T))
+	Subscribe((String, String, tokio::sync::mpsc::Sender<T>)),
+	Message((String, T)),
+	// deliver a last item to one session's stream, then close it
+	Kick((String, String, T))
 }
 
-impl <T>ChannelLayer<T>{
+impl <T: Clone>ChannelLayer<T>{
 	pub fn new()->(Self, tokio::sync::mpsc::Sender<Command<T>>){
-		let subscribers = HashMap::<String, tokio::sync::mpsc::Sender<T>>::new();
+		let subscribers = HashMap::<String, HashMap<String, tokio::sync::mpsc::Sender<T>>>::new();
 		let (sender, receiver) = tokio::sync::mpsc::channel::<Command<T>>(128);
 		(
 			Self{
@@ -27,14 +30,38 @@ impl <T>ChannelLayer<T>{
 	pub async fn handover_to_runtime(mut self){
 		loop {
 			match self.receiver.recv().await{
-				Some(Command::Subscribe((addr, sender))) => {
-					println!("subscribing {} ", addr);
-					self.subscribers.insert(addr, sender);
+				Some(Command::Subscribe((addr, session_id, sender))) => {
+					println!("subscribing {} ({})", addr, session_id);
+					self.subscribers.entry(addr).or_default().insert(session_id, sender);
 				},
 				Some(Command::Message((addr, message))) => {
-					match self.subscribers.get(&addr){
-						Some(msg_receiver) => {msg_receiver.send(message).await;}
-						None => {dbg!(format!("receiver {} is not active:", addr));}
+					let Some(devices) = self.subscribers.get_mut(&addr) else {
+						dbg!(format!("receiver {} is not active:", addr));
+						continue;
+					};
+
+					let mut gone = Vec::new();
+					for (session_id, msg_receiver) in devices.iter(){
+						if msg_receiver.send(message.clone()).await.is_err(){
+							gone.push(session_id.clone());
+						}
+					}
+					for session_id in gone{
+						devices.remove(&session_id);
+					}
+					if devices.is_empty(){
+						self.subscribers.remove(&addr);
+					}
+				},
+				Some(Command::Kick((addr, session_id, last_message))) => {
+					if let Some(devices) = self.subscribers.get_mut(&addr){
+						if let Some(msg_receiver) = devices.remove(&session_id){
+							// dropping the sender afterwards ends the stream
+							let _ = msg_receiver.send(last_message).await;
+						}
+						if devices.is_empty(){
+							self.subscribers.remove(&addr);
+						}
 					}
 				},
 				_ => {
@@ -48,4 +75,4 @@ impl <T>ChannelLayer<T>{
 		dbg!("chat service unstable without channel");
 		panic!("shutting down chat service");
 	}
-}
\ No newline at end of file
+}
@@ -12,26 +12,68 @@ pub use chat::{
     IncomingMessage,
     ReceiveMessageRequest,
     Empty,
+    ChatEvent,
+    EditMessageRequest,
+    DeleteMessageRequest,
+    MessageDeleted,
+    QuotedMessage,
+    Reaction,
+    ReactRequest,
+    ReactionChanged,
+    SearchMessagesRequest,
+    SearchResults,
+    SearchHit,
+    TextSpan,
+    MarkReadRequest,
+    ReadMarker,
+    ListConversationsRequest,
+    ConversationList,
+    Conversation,
+    PeerProfile,
+    GetHistoryRequest,
+    History,
+    chat_event,
     chat_server::{
         ChatServer, Chat
     },
 };
 
+use std::sync::Arc;
+
 use crate::channel_layers::{Command, ChannelLayer};
+use crate::sessions::SessionManager;
+use crate::stores::{ContactStore, MessageQuery, MessageStore, Store, StoredMessage, StoredReaction, UserStatus, UserStore};
+use crate::utils;
 
 pub mod chat{
     tonic::include_proto!("chat");
 }
 
+/// How much of a replied-to message travels with the reply.
+const QUOTE_SNIPPET_CHARS: usize = 120;
+/// Generous enough for ZWJ sequences like family or flag emoji.
+const MAX_EMOJI_BYTES: usize = 32;
+/// Search page sizes, and how many neighbours each hit comes with.
+const DEFAULT_SEARCH_RESULTS: u32 = 20;
+const MAX_SEARCH_RESULTS: u32 = 50;
+const SEARCH_CONTEXT_MESSAGES: i64 = 2;
+/// History page sizes.
+const DEFAULT_HISTORY_PAGE: u32 = 50;
+const MAX_HISTORY_PAGE: u32 = 200;
+
+pub type ChannelHandle = tokio::sync::mpsc::Sender<Command<Result<ChatEvent, Status>>>;
+
 pub struct ChatService{
-    channel_handler: tokio::sync::mpsc::Sender<Command<Result<IncomingMessage, Status>>>
+    channel_handler: ChannelHandle,
+    store: Arc<dyn Store>,
+    sessions: Arc<SessionManager>
 }
 
 
 #[tonic::async_trait]
 impl Chat for ChatService{
     type ReceiveIncomingMessagesStream =
-    ReceiverStream<Result<IncomingMessage, Status>>;
+    ReceiverStream<Result<ChatEvent, Status>>;
 
     async fn receive_incoming_messages(
         &self,
@@ -40,9 +82,10 @@ impl Chat for ChatService{
     ->
     Result<Response<Self::ReceiveIncomingMessagesStream>, Status>
     {
-        let user_id = request.into_inner().id;
+        // the stream is tied to the session so revoking it can close the stream
+        let claims = self.sessions.authorize(&request, &request.get_ref().id).await?;
         let (sender, receiver) = tokio::sync::mpsc::channel(5);
-        self.channel_handler.send(Command::Subscribe((user_id, sender))).await;
+        self.channel_handler.send(Command::Subscribe((claims.user_id, claims.session_id, sender))).await;
 
         Ok(Response::new(ReceiverStream::new(receiver)))
     }
@@ -53,21 +96,500 @@ impl Chat for ChatService{
         request: Request<IncomingMessage>
     )
     ->
-    Result<Response<Empty>, Status>
+    Result<Response<IncomingMessage>, Status>
     {   
-        let message = request.into_inner();
-        let cmd = Command::Message((message.to_addr.clone(), Ok(message)));
-        self.channel_handler.send(cmd).await;
+        self.sessions.authorize(&request, &request.get_ref().from_addr).await?;
+        let sent = self.deliver(request.into_inner()).await?;
+        Ok(Response::new(sent))
+    }
+
+
+    async fn edit_message(
+        &self,
+        request: Request<EditMessageRequest>
+    )
+    ->
+    Result<Response<IncomingMessage>, Status>
+    {
+        self.sessions.authorize(&request, &request.get_ref().user_id).await?;
+        let edit = request.into_inner();
+
+        let message = self.own_message(&edit.user_id, edit.message_id).await?;
+        if message.sender_id != edit.user_id{
+            return Err(Status::permission_denied("you can only edit your own messages"));
+        }
+        let edited = self.store.edit_message(message.id, &edit.msg, utils::now_secs())
+            .await
+            .map_err(Status::internal)?
+            .ok_or_else(|| Status::failed_precondition("this message was deleted"))?;
+
+        let updated = self.present(&edited).await?;
+        self.publish_to_participants(&edited, chat_event::Event::Edited(updated.clone())).await?;
+        Ok(Response::new(updated))
+    }
+
+
+    async fn delete_message(
+        &self,
+        request: Request<DeleteMessageRequest>
+    )
+    ->
+    Result<Response<Empty>, Status>
+    {
+        self.sessions.authorize(&request, &request.get_ref().user_id).await?;
+        let delete = request.into_inner();
+
+        let message = self.own_message(&delete.user_id, delete.message_id).await?;
+        let event = chat_event::Event::Deleted(MessageDeleted{
+            id: delete.message_id,
+            from_addr: message.sender_id.clone(),
+            to_addr: message.recipient_id.clone(),
+            for_everyone: delete.for_everyone,
+        });
+
+        if delete.for_everyone{
+            if message.sender_id != delete.user_id{
+                return Err(Status::permission_denied("only the sender can delete a message for everyone"));
+            }
+            self.store.delete_message(message.id).await.map_err(Status::internal)?;
+            self.publish_to_participants(&message, event).await?;
+        }else{
+            self.store.hide_message(&delete.user_id, message.id).await.map_err(Status::internal)?;
+            self.publish(&delete.user_id, event).await;
+        }
+        Ok(Response::new(Empty{}))
+    }
+
+
+    async fn react_to_message(
+        &self,
+        request: Request<ReactRequest>
+    )
+    ->
+    Result<Response<Empty>, Status>
+    {
+        self.sessions.authorize(&request, &request.get_ref().user_id).await?;
+        let react = request.into_inner();
+
+        let emoji = react.emoji.trim();
+        if emoji.is_empty() || emoji.len() > MAX_EMOJI_BYTES || emoji.chars().any(|c| c.is_whitespace() || c.is_control()){
+            return Err(Status::invalid_argument("a reaction is a single emoji"));
+        }
+        let message = self.own_message(&react.user_id, react.message_id).await?;
+        if message.deleted{
+            return Err(Status::failed_precondition("this message was deleted"));
+        }
+
+        let changed = if react.remove{
+            self.store.remove_reaction(message.id, &react.user_id, emoji).await
+        }else{
+            self.store.add_reaction(message.id, &react.user_id, emoji).await
+        }.map_err(Status::internal)?;
 
+        if changed{
+            let event = chat_event::Event::Reaction(ReactionChanged{
+                message_id: react.message_id,
+                user_id: react.user_id.clone(),
+                emoji: emoji.to_owned(),
+                removed: react.remove,
+            });
+            self.publish_to_participants(&message, event).await?;
+        }
         Ok(Response::new(Empty{}))
     }
+
+
+    async fn search_messages(
+        &self,
+        request: Request<SearchMessagesRequest>
+    )
+    ->
+    Result<Response<SearchResults>, Status>
+    {
+        self.sessions.authorize(&request, &request.get_ref().user_id).await?;
+        let search = request.into_inner();
+
+        if search.query.trim().chars().count() < 2{
+            return Err(Status::invalid_argument("type at least two characters to search"));
+        }
+        let query = MessageQuery{
+            text: search.query,
+            peer_id: Some(search.peer_id).filter(|peer_id| !peer_id.is_empty()),
+            before_id: Some(search.before_id as i64).filter(|id| *id > 0),
+            limit: match search.limit{
+                0 => DEFAULT_SEARCH_RESULTS,
+                limit => limit.min(MAX_SEARCH_RESULTS)
+            } as i64,
+        };
+
+        let matches = self.store.search_messages(&search.user_id, &query).await.map_err(Status::internal)?;
+        let mut hits = Vec::with_capacity(matches.len());
+        for found in matches{
+            let (before, after) = self.store.message_context(&search.user_id, &found.message, SEARCH_CONTEXT_MESSAGES)
+                .await
+                .map_err(Status::internal)?;
+            hits.push(SearchHit{
+                message: Some(self.present(&found.message).await?),
+                spans: found.spans.into_iter().map(|(text, highlighted)| TextSpan{text, highlighted}).collect(),
+                before: before.iter().map(to_incoming).collect(),
+                after: after.iter().map(to_incoming).collect(),
+            });
+        }
+        Ok(Response::new(SearchResults{hits}))
+    }
+
+
+    async fn mark_read(
+        &self,
+        request: Request<MarkReadRequest>
+    )
+    ->
+    Result<Response<Empty>, Status>
+    {
+        self.sessions.authorize(&request, &request.get_ref().user_id).await?;
+        let read = request.into_inner();
+
+        let message = self.own_message(&read.user_id, read.message_id).await?;
+        if message.sender_id != read.peer_id && message.recipient_id != read.peer_id{
+            return Err(Status::invalid_argument("that message is from another conversation"));
+        }
+        let last_read_id = self.store.mark_read(&read.user_id, &read.peer_id, message.id)
+            .await
+            .map_err(Status::internal)?;
+
+        let marker = ReadMarker{peer_id: read.peer_id.clone(), last_read_id: last_read_id as u64};
+        self.publish(&read.user_id, chat_event::Event::Read(marker)).await;
+        // the receipt for the other side names us as the reader
+        let receipt = ReadMarker{peer_id: read.user_id, last_read_id: last_read_id as u64};
+        self.publish(&read.peer_id, chat_event::Event::PeerRead(receipt)).await;
+        Ok(Response::new(Empty{}))
+    }
+
+
+    async fn mark_delivered(
+        &self,
+        request: Request<MarkReadRequest>
+    )
+    ->
+    Result<Response<Empty>, Status>
+    {
+        self.sessions.authorize(&request, &request.get_ref().user_id).await?;
+        let delivered = request.into_inner();
+
+        let message = self.own_message(&delivered.user_id, delivered.message_id).await?;
+        if message.sender_id != delivered.peer_id && message.recipient_id != delivered.peer_id{
+            return Err(Status::invalid_argument("that message is from another conversation"));
+        }
+        let last_delivered_id = self.store.mark_delivered(&delivered.user_id, &delivered.peer_id, message.id)
+            .await
+            .map_err(Status::internal)?;
+
+        let receipt = ReadMarker{peer_id: delivered.user_id, last_read_id: last_delivered_id as u64};
+        self.publish(&delivered.peer_id, chat_event::Event::PeerDelivered(receipt)).await;
+        Ok(Response::new(Empty{}))
+    }
+
+
+    async fn list_conversations(
+        &self,
+        request: Request<ListConversationsRequest>
+    )
+    ->
+    Result<Response<ConversationList>, Status>
+    {
+        self.sessions.authorize(&request, &request.get_ref().user_id).await?;
+        let user_id = request.into_inner().user_id;
+
+        let summaries = self.store.list_conversations(&user_id).await.map_err(Status::internal)?;
+        let peer_ids: Vec<String> = summaries.iter().map(|c| c.peer_id.clone()).collect();
+        let profiles = self.store.get_profiles(&peer_ids).await.map_err(Status::internal)?;
+
+        let mut conversations = Vec::with_capacity(summaries.len());
+        for summary in summaries{
+            let peer = profiles.iter()
+                .find(|p| p.user_id == summary.peer_id)
+                .map(|p| PeerProfile{
+                    display_name: p.display_name.clone(),
+                    about: p.about.clone(),
+                    avatar: p.avatar.clone(),
+                    avatar_mime: p.avatar_mime.clone(),
+                });
+            conversations.push(Conversation{
+                peer_id: summary.peer_id,
+                last_read_id: summary.last_read_id as u64,
+                unread_count: summary.unread_count as u64,
+                peer,
+                last_message: Some(self.present(&summary.last_message).await?),
+                peer_delivered_id: summary.peer_delivered_id as u64,
+                peer_read_id: summary.peer_read_id as u64,
+            });
+        }
+        Ok(Response::new(ConversationList{conversations}))
+    }
+
+
+    async fn get_history(
+        &self,
+        request: Request<GetHistoryRequest>
+    )
+    ->
+    Result<Response<History>, Status>
+    {
+        self.sessions.authorize(&request, &request.get_ref().user_id).await?;
+        let page = request.into_inner();
+
+        let limit = match page.limit{
+            0 => DEFAULT_HISTORY_PAGE,
+            limit => limit.min(MAX_HISTORY_PAGE)
+        } as i64;
+        // one extra row tells whether there is another page
+        let mut messages = self.store.conversation_history(
+            &page.user_id,
+            &page.peer_id,
+            Some(page.before_id as i64).filter(|id| *id > 0),
+            Some(page.after_id as i64).filter(|id| *id > 0),
+            limit + 1
+        ).await.map_err(Status::internal)?;
+
+        let has_more = messages.len() as i64 > limit;
+        if has_more{
+            // drop the extra from the far end of the direction we paged in
+            if page.after_id > 0 { messages.pop(); } else { messages.remove(0); }
+        }
+        Ok(Response::new(History{messages: self.present_all(&messages).await?, has_more}))
+    }
 }
 
 
+fn to_incoming(message: &StoredMessage)->IncomingMessage{
+    IncomingMessage{
+        from_addr: message.sender_id.clone(),
+        to_addr: message.recipient_id.clone(),
+        msg: message.body.clone(),
+        id: message.id as u64,
+        sent_at: message.sent_at,
+        edited_at: message.edited_at.unwrap_or_default(),
+        reply_to: message.reply_to.unwrap_or_default() as u64,
+        deleted: message.deleted,
+        ..Default::default()
+    }
+}
+
+fn quote_of(message: &StoredMessage)->QuotedMessage{
+    let text = String::from_utf8_lossy(&message.body);
+    let mut snippet: String = text.chars().take(QUOTE_SNIPPET_CHARS).collect();
+    if text.chars().nth(QUOTE_SNIPPET_CHARS).is_some(){
+        snippet.push('…');
+    }
+    QuotedMessage{
+        id: message.id as u64,
+        from_addr: message.sender_id.clone(),
+        snippet,
+        deleted: message.deleted,
+    }
+}
+
+/// One entry per emoji, in the order each was first used.
+fn group_reactions<'a>(reactions: impl Iterator<Item = &'a StoredReaction>)->Vec<Reaction>{
+    let mut grouped: Vec<Reaction> = Vec::new();
+    for reaction in reactions{
+        match grouped.iter_mut().find(|g| g.emoji == reaction.emoji){
+            Some(group) => group.user_ids.push(reaction.user_id.clone()),
+            None => grouped.push(Reaction{emoji: reaction.emoji.clone(), user_ids: vec![reaction.user_id.clone()]})
+        }
+    }
+    grouped
+}
+
 impl ChatService{
-    pub fn new()->Self{
+    /// Starts the channel layer; the returned handle is shared with `SessionManager`.
+    pub fn start_channel_layer()->ChannelHandle{
         let (mut message_channel, channel_handler) = ChannelLayer::new();
         tokio::spawn(message_channel.handover_to_runtime());
-        return ChatService{channel_handler};
+        channel_handler
+    }
+
+    pub fn new(store: Arc<dyn Store>, channel_handler: ChannelHandle, sessions: Arc<SessionManager>)->Self{
+        return ChatService{channel_handler, store, sessions};
     }
-}
\ No newline at end of file
+
+    /// Stores an authorized `SendMessage` and hands it to both sides, unless
+    /// a block stands in the way.
+    async fn deliver(&self, message: IncomingMessage)->Result<IncomingMessage, Status>{
+        // refuse up front instead of letting the channel layer drop it
+        match self.store.get_user(&message.to_addr).await{
+            Ok(Some(user)) if user.status == UserStatus::Active => {},
+            Ok(_) => return Err(Status::not_found(format!("no such user: {}", message.to_addr))),
+            Err(err) => return Err(Status::internal(err))
+        }
+
+        if self.store.is_blocked(&message.from_addr, &message.to_addr).await.map_err(Status::internal)?{
+            return Err(Status::failed_precondition("you have blocked this user, unblock them first"));
+        }
+
+        // replies must stay within the conversation
+        let reply_to = match message.reply_to{
+            0 => None,
+            id => {
+                let quoted = self.own_message(&message.from_addr, id).await?;
+                if quoted.sender_id != message.to_addr && quoted.recipient_id != message.to_addr{
+                    return Err(Status::invalid_argument("you can only reply to a message from this conversation"));
+                }
+                Some(quoted.id)
+            }
+        };
+
+        let stored = self.store.insert_message(&message.from_addr, &message.to_addr, &message.msg, utils::now_secs(), reply_to)
+            .await
+            .map_err(Status::internal)?;
+        let mut sent = self.present(&stored).await?;
+        sent.client_id = message.client_id;
+
+        // the sender is not told they are blocked; the message just never shows up on their side
+        if self.store.is_blocked(&message.to_addr, &message.from_addr).await.map_err(Status::internal)?{
+            self.store.hide_message(&message.to_addr, stored.id).await.map_err(Status::internal)?;
+        }else{
+            self.publish(&message.to_addr, chat_event::Event::Message(sent.clone())).await;
+        }
+        // the sender's other devices
+        self.publish(&message.from_addr, chat_event::Event::Message(sent.clone())).await;
+
+        Ok(sent)
+    }
+
+    /// What clients see of a stored message: its quote and reactions filled in.
+    async fn present(&self, message: &StoredMessage)->Result<IncomingMessage, Status>{
+        let mut presented = self.present_all(std::slice::from_ref(message)).await?;
+        Ok(presented.pop().unwrap_or_default())
+    }
+
+    /// `present` for a whole page, with one lookup for all the reactions.
+    async fn present_all(&self, messages: &[StoredMessage])->Result<Vec<IncomingMessage>, Status>{
+        let ids: Vec<i64> = messages.iter().map(|m| m.id).collect();
+        let reactions = self.store.list_reactions(&ids).await.map_err(Status::internal)?;
+
+        let mut presented = Vec::with_capacity(messages.len());
+        for message in messages{
+            let mut incoming = to_incoming(message);
+            if let Some(reply_to) = message.reply_to{
+                // usually the quoted message is on the same page
+                let quoted = match messages.iter().find(|m| m.id == reply_to){
+                    Some(quoted) => Some(quoted.clone()),
+                    None => self.store.get_message(reply_to).await.map_err(Status::internal)?
+                };
+                incoming.quote = quoted.map(|quoted| quote_of(&quoted));
+            }
+            incoming.reactions = group_reactions(reactions.iter().filter(|r| r.message_id == message.id));
+            presented.push(incoming);
+        }
+        Ok(presented)
+    }
+
+    /// Sends `event` to every open stream of `user_id`.
+    async fn publish(&self, user_id: &str, event: chat_event::Event){
+        let cmd = Command::Message((user_id.to_owned(), Ok(ChatEvent{event: Some(event)})));
+        self.channel_handler.send(cmd).await;
+    }
+
+    /// Both sides of the conversation, minus anyone who hid the message.
+    async fn publish_to_participants(&self, message: &StoredMessage, event: chat_event::Event)->Result<(), Status>{
+        for user_id in [&message.sender_id, &message.recipient_id]{
+            if !self.store.is_hidden(user_id, message.id).await.map_err(Status::internal)?{
+                self.publish(user_id, event.clone()).await;
+            }
+        }
+        Ok(())
+    }
+
+    /// Looks up a message `user_id` took part in and can still see. Anything
+    /// else is reported as missing so ids of other people's messages leak nothing.
+    async fn own_message(&self, user_id: &str, message_id: u64)->Result<StoredMessage, Status>{
+        let message = self.store.get_message(message_id as i64).await.map_err(Status::internal)?
+            .filter(|m| m.sender_id == user_id || m.recipient_id == user_id);
+        match message{
+            Some(message) if !self.store.is_hidden(user_id, message.id).await.map_err(Status::internal)? => Ok(message),
+            _ => Err(Status::not_found("no such message"))
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    fn stored(id: i64, body: &str)->StoredMessage{
+        StoredMessage{
+            id,
+            sender_id: "alice".to_owned(),
+            recipient_id: "bob".to_owned(),
+            body: body.as_bytes().to_vec(),
+            sent_at: 0,
+            edited_at: None,
+            deleted: false,
+            reply_to: None,
+        }
+    }
+
+    async fn service()->(ChatService, Arc<crate::stores::sqlite::SqliteStore>){
+        let store = crate::stores::sqlite::test_store().await;
+        let channel_handler = ChatService::start_channel_layer();
+        // `deliver` runs after the caller has been authorized
+        let sessions = Arc::new(crate::sessions::fakes::sessions(channel_handler.clone()));
+        (ChatService::new(store.clone(), channel_handler, sessions), store)
+    }
+
+    fn message(from: &str, to: &str, body: &str)->IncomingMessage{
+        IncomingMessage{from_addr: from.to_owned(), to_addr: to.to_owned(), msg: body.as_bytes().to_vec(), ..Default::default()}
+    }
+
+    #[tokio::test]
+    async fn blocks_stop_sends_one_way_and_hide_them_the_other(){
+        use crate::stores::Identifier;
+
+        let (chat, store) = service().await;
+        let alice = store.find_or_create(&Identifier::parse("alice@example.com")).await.unwrap().id;
+        let bob = store.find_or_create(&Identifier::parse("bob@example.com")).await.unwrap().id;
+
+        let sent = chat.deliver(message(&alice, &bob, "hi")).await.unwrap();
+        assert!(!store.is_hidden(&bob, sent.id as i64).await.unwrap());
+
+        // alice blocked bob: her own sends are refused
+        store.block_user(&alice, &bob).await.unwrap();
+        let status = chat.deliver(message(&alice, &bob, "hello?")).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::FailedPrecondition);
+        store.unblock_user(&alice, &bob).await.unwrap();
+
+        // bob blocked alice: she can still send, but he never sees it
+        store.block_user(&bob, &alice).await.unwrap();
+        let hidden = chat.deliver(message(&alice, &bob, "are you there")).await.unwrap();
+        assert!(store.is_hidden(&bob, hidden.id as i64).await.unwrap());
+        assert!(!store.is_hidden(&alice, hidden.id as i64).await.unwrap());
+
+        let status = chat.deliver(message(&alice, "nobody", "hi")).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::NotFound);
+    }
+
+    #[test]
+    fn quotes_are_trimmed_snippets(){
+        let long = "é".repeat(QUOTE_SNIPPET_CHARS + 5);
+        let quote = quote_of(&stored(3, &long));
+        assert_eq!(quote.id, 3);
+        assert_eq!(quote.snippet.chars().count(), QUOTE_SNIPPET_CHARS + 1);
+        assert!(quote.snippet.ends_with('…'));
+
+        assert_eq!(quote_of(&stored(4, "short")).snippet, "short");
+    }
+
+    #[test]
+    fn reactions_group_by_emoji_in_first_use_order(){
+        let reaction = |user_id: &str, emoji: &str| StoredReaction{message_id: 1, user_id: user_id.to_owned(), emoji: emoji.to_owned()};
+        let reactions = [reaction("bob", "👍"), reaction("alice", "❤️"), reaction("alice", "👍")];
+
+        let grouped = group_reactions(reactions.iter());
+        assert_eq!(grouped.len(), 2);
+        assert_eq!(grouped[0].emoji, "👍");
+        assert_eq!(grouped[0].user_ids, vec!["bob".to_owned(), "alice".to_owned()]);
+        assert_eq!(grouped[1].user_ids, vec!["alice".to_owned()]);
+    }
+}
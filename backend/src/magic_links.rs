@@ -0,0 +1,113 @@
+use deadpool_redis::redis::cmd;
+use tonic::Status;
+
+use crate::utils;
+
+/// How long an emailed sign-in link stays usable.
+pub const MAGIC_LINK_TTL_SECS: i64 = 15 * 60;
+
+
+/// Signed, single-use sign-in links.
+///
+/// A link carries `hex(identifier).nonce.expiry` signed with `utils::sign_token`,
+/// so nothing but the nonce has to be stored: `magic_link:<nonce>` lives in
+/// Redis until the link is used or expires, and redeeming deletes it.
+pub struct MagicLinks{
+    redis_pool: deadpool_redis::Pool,
+    signing_key: String,
+    // e.g. dioxuschat://login, the token is appended as ?token=
+    base_url: String
+}
+
+impl MagicLinks{
+    pub fn new(redis_pool: deadpool_redis::Pool, signing_key: String, base_url: String)->Self{
+        Self{redis_pool, signing_key, base_url}
+    }
+
+    async fn redis(&self)->Result<deadpool_redis::Connection, Status>{
+        self.redis_pool.get().await.map_err(|e| Status::unavailable(format!("redis unavailable: {}", e)))
+    }
+
+    /// Creates a link that signs in as `identifier` (an email or phone).
+    pub async fn issue(&self, identifier: &str)->Result<String, Status>{
+        let nonce = utils::random_hex(16);
+        let mut conn = self.redis().await?;
+        cmd("SET").arg(format!("magic_link:{}", nonce))
+            .arg(1)
+            .arg("EX")
+            .arg(MAGIC_LINK_TTL_SECS)
+            .query_async::<()>(&mut conn)
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?;
+
+        let token = sign_link_token(identifier, &nonce, utils::now_secs() + MAGIC_LINK_TTL_SECS, &self.signing_key);
+        Ok(format!("{}?token={}", self.base_url, token))
+    }
+
+    /// Checks a link (or a bare token) and burns it, returning the identifier it was issued for.
+    pub async fn redeem(&self, link: &str)->Result<String, Status>{
+        let (identifier, nonce) = open_link_token(token_from_link(link), &self.signing_key, utils::now_secs())?;
+
+        let mut conn = self.redis().await?;
+        let removed: i64 = cmd("DEL").arg(format!("magic_link:{}", nonce))
+            .query_async(&mut conn)
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?;
+        if removed == 0{
+            return Err(Status::unauthenticated("this sign-in link was already used"));
+        }
+        Ok(identifier)
+    }
+}
+
+
+fn sign_link_token(identifier: &str, nonce: &str, expires_at: i64, key: &str)->String{
+    utils::sign_token(&format!("{}.{}.{}", hex::encode(identifier), nonce, expires_at), key)
+}
+
+fn open_link_token(token: &str, key: &str, now: i64)->Result<(String, String), Status>{
+    let payload = utils::open_token(token, key)
+        .ok_or_else(|| Status::unauthenticated("invalid sign-in link"))?;
+
+    let parts: Vec<&str> = payload.split('.').collect();
+    let &[identifier, nonce, expires_at] = parts.as_slice() else {
+        return Err(Status::unauthenticated("invalid sign-in link"));
+    };
+    if expires_at.parse::<i64>().unwrap_or(0) < now{
+        return Err(Status::unauthenticated("this sign-in link has expired"));
+    }
+    let identifier = hex::decode(identifier).ok()
+        .and_then(|bytes| String::from_utf8(bytes).ok())
+        .ok_or_else(|| Status::unauthenticated("invalid sign-in link"))?;
+
+    Ok((identifier, nonce.to_owned()))
+}
+
+/// Accepts the whole pasted link as well as just its token.
+fn token_from_link(link: &str)->&str{
+    let link = link.trim();
+    match link.split_once("token="){
+        Some((_, rest)) => rest.split('&').next().unwrap_or_default(),
+        None => link
+    }
+}
+
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    #[test]
+    fn link_round_trips_and_rejects_tampering(){
+        let token = sign_link_token("alice@example.com", "abc123", 2_000, "key");
+        let link = format!("dioxuschat://login?token={}&utm=mail", token);
+
+        let (identifier, nonce) = open_link_token(token_from_link(&link), "key", 1_000).unwrap();
+        assert_eq!(identifier, "alice@example.com");
+        assert_eq!(nonce, "abc123");
+
+        assert!(open_link_token(&token, "other key", 1_000).is_err());
+        assert!(open_link_token(&token.replacen("abc123", "abc124", 1), "key", 1_000).is_err());
+        assert!(open_link_token(&token, "key", 2_001).is_err());
+    }
+}
@@ -0,0 +1,267 @@
+use std::sync::Arc;
+
+use lettre::message::{Mailbox, MultiPart};
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::transport::smtp::PoolConfig;
+use lettre::{AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+
+pub mod queue;
+pub mod templates;
+
+pub use queue::{MailQueue, RedisDeadLetters, RetryPolicy};
+pub use templates::{Locale, MailTemplate};
+
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum DeliveryError{
+    /// Worth trying again later: timeouts, dropped connections, 4xx replies.
+    Transient(String),
+    /// Will never go through as is: bad recipient, rejected message, 5xx replies.
+    Permanent(String),
+}
+
+impl std::fmt::Display for DeliveryError{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>)->std::fmt::Result{
+        match self{
+            DeliveryError::Transient(err) | DeliveryError::Permanent(err) => f.write_str(err)
+        }
+    }
+}
+
+
+/// Anything that can deliver a finished message. Production uses SMTP; the
+/// tests swap in a transport that just records what it was given.
+#[tonic::async_trait]
+pub trait MailTransport: Send + Sync{
+    async fn send(&self, message: Message)->Result<(), DeliveryError>;
+}
+
+
+/// One long-lived, pooled SMTP connection set shared by every send.
+pub struct SmtpMailTransport{
+    transport: AsyncSmtpTransport<Tokio1Executor>
+}
+
+impl SmtpMailTransport{
+    /// `url` is lettre's connection url, e.g. `smtps://smtp.gmail.com` or
+    /// `smtp://localhost:1025` for a local sink such as mailpit.
+    pub fn connect(url: &str, credentials: Option<Credentials>, pool_size: u32)->Result<Self, String>{
+        let mut builder = AsyncSmtpTransport::<Tokio1Executor>::from_url(url)
+            .map_err(|e| format!("bad smtp url {}: {}", url, e))?
+            .pool_config(PoolConfig::new().max_size(pool_size));
+        if let Some(credentials) = credentials{
+            builder = builder.credentials(credentials);
+        }
+        Ok(Self{transport: builder.build()})
+    }
+}
+
+#[tonic::async_trait]
+impl MailTransport for SmtpMailTransport{
+    async fn send(&self, message: Message)->Result<(), DeliveryError>{
+        match self.transport.send(message).await{
+            Ok(_) => Ok(()),
+            Err(err) if err.is_permanent() => Err(DeliveryError::Permanent(err.to_string())),
+            Err(err) => Err(DeliveryError::Transient(err.to_string())),
+        }
+    }
+}
+
+
+/// Who transactional mail comes from, and the product name used in it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SenderIdentity{
+    pub name: String,
+    pub address: String,
+    pub product: String,
+}
+
+impl SenderIdentity{
+    /// Reads `MAIL_FROM_NAME`, `MAIL_FROM_ADDRESS` and `MAIL_PRODUCT_NAME`.
+    pub fn from_env()->Self{
+        let var = |key: &str, default: &str| std::env::var(key).unwrap_or_else(|_| default.to_owned());
+        Self{
+            name: var("MAIL_FROM_NAME", "DioxusChat"),
+            address: var("MAIL_FROM_ADDRESS", "nobody@domain.tld"),
+            product: var("MAIL_PRODUCT_NAME", "DioxusChat"),
+        }
+    }
+}
+
+
+pub struct Mailer{
+    queue: MailQueue,
+    sender: SenderIdentity
+}
+
+impl Mailer{
+    pub fn new(queue: MailQueue, sender: SenderIdentity)->Self{
+        Self{queue, sender}
+    }
+
+    /// Renders `template` into a multipart/alternative (plain text + HTML) message to `to`.
+    pub fn compose(&self, to: &str, template: &MailTemplate, locale: Locale)->Result<Message, String>{
+        let rendered = templates::render(template, locale, &self.sender.product);
+
+        let from = Mailbox::new(
+            Some(self.sender.name.clone()),
+            self.sender.address.parse().map_err(|e| format!("bad sender address: {:?}", e))?
+        );
+        let to = Mailbox::new(
+            None,
+            to.parse().map_err(|e| format!("error parsing email: {:?}", e))?
+        );
+
+        Message::builder()
+            .from(from)
+            .to(to)
+            .subject(rendered.subject)
+            .multipart(MultiPart::alternative_plain_html(rendered.text, rendered.html))
+            .map_err(|e| format!("could not build email: {}", e))
+    }
+
+    /// Renders and queues the mail; delivery happens in the background.
+    pub fn enqueue(&self, to: &str, template: &MailTemplate, locale: Locale)->Result<(), String>{
+        let message = self.compose(to, template, locale)?;
+        self.queue.enqueue(message)
+    }
+}
+
+
+#[cfg(test)]
+pub(crate) mod fakes{
+    use super::*;
+    use std::sync::Mutex;
+    use std::time::Duration;
+    use queue::{DeadLetter, DeadLetterSink};
+
+    /// Polls `read` until it yields at least `n` items, or fails the test.
+    async fn wait_until<T: Clone>(n: usize, read: impl Fn()->Vec<T>)->Vec<T>{
+        for _ in 0..200{
+            let items = read();
+            if items.len() >= n{
+                return items;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("timed out waiting for {} items", n);
+    }
+
+    #[derive(Default)]
+    pub struct CapturingTransport{
+        sent: Mutex<Vec<Message>>
+    }
+
+    impl CapturingTransport{
+        pub fn sent(&self)->Vec<Message>{
+            self.sent.lock().unwrap().clone()
+        }
+
+        pub async fn wait_for(&self, n: usize)->Vec<Message>{
+            wait_until(n, || self.sent()).await
+        }
+    }
+
+    #[tonic::async_trait]
+    impl MailTransport for CapturingTransport{
+        async fn send(&self, message: Message)->Result<(), DeliveryError>{
+            self.sent.lock().unwrap().push(message);
+            Ok(())
+        }
+    }
+
+    #[derive(Default)]
+    pub struct MemoryDeadLetters{
+        letters: Mutex<Vec<DeadLetter>>
+    }
+
+    impl MemoryDeadLetters{
+        pub fn letters(&self)->Vec<DeadLetter>{
+            self.letters.lock().unwrap().clone()
+        }
+
+        pub async fn wait_for(&self, n: usize)->Vec<DeadLetter>{
+            wait_until(n, || self.letters()).await
+        }
+    }
+
+    #[tonic::async_trait]
+    impl DeadLetterSink for MemoryDeadLetters{
+        async fn record(&self, letter: DeadLetter)->Result<(), String>{
+            self.letters.lock().unwrap().push(letter);
+            Ok(())
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use fakes::{CapturingTransport, MemoryDeadLetters};
+
+    fn mailer(transport: Arc<CapturingTransport>)->Mailer{
+        let queue = MailQueue::start(transport, Arc::new(MemoryDeadLetters::default()), RetryPolicy::default(), 8);
+        Mailer::new(queue, SenderIdentity{
+            name: "Chat Team".to_owned(),
+            address: "hello@chat.example".to_owned(),
+            product: "ChatApp".to_owned(),
+        })
+    }
+
+    #[tokio::test]
+    async fn otp_mail_is_multipart_from_configured_sender(){
+        let transport = Arc::new(CapturingTransport::default());
+        let otp = MailTemplate::Otp{code: "A1B2C3".to_owned(), valid_minutes: 2};
+
+        mailer(transport.clone()).enqueue("alice@example.com", &otp, Locale::En).unwrap();
+
+        let sent = transport.wait_for(1).await;
+        assert_eq!(sent.len(), 1);
+        let raw = String::from_utf8(sent[0].formatted()).unwrap();
+        assert!(raw.contains("From: \"Chat Team\" <hello@chat.example>"));
+        assert!(raw.contains("To: alice@example.com"));
+        assert!(raw.contains("Subject: A1B2C3 is your ChatApp verification code"));
+        assert!(raw.contains("multipart/alternative"));
+        assert!(raw.contains("text/plain"));
+        assert!(raw.contains("text/html"));
+    }
+
+    #[test]
+    fn subjects_are_localized_and_html_is_escaped(){
+        let login = MailTemplate::NewDeviceLogin{device_name: "<script>".to_owned()};
+
+        let en = templates::render(&login, Locale::parse("en-US"), "ChatApp");
+        let es = templates::render(&login, Locale::parse("es-MX"), "ChatApp");
+
+        assert_eq!(en.subject, "New sign-in to your ChatApp account");
+        assert_eq!(es.subject, "Nuevo inicio de sesión en tu cuenta de ChatApp");
+        assert!(en.text.contains("<script>"));
+        assert!(en.html.contains("&lt;script&gt;"));
+        assert!(!en.html.contains("<script>"));
+    }
+
+    #[tokio::test]
+    async fn bad_recipient_is_an_error(){
+        let transport = Arc::new(CapturingTransport::default());
+        let otp = MailTemplate::Otp{code: "A1B2C3".to_owned(), valid_minutes: 2};
+
+        assert!(mailer(transport).enqueue("not an address", &otp, Locale::En).is_err());
+    }
+
+    /// Run with a local sink (e.g. `mailpit`) listening:
+    /// `SMTP_SINK_URL=smtp://localhost:1025 cargo test -- --ignored`
+    #[tokio::test]
+    #[ignore]
+    async fn delivers_to_local_smtp_sink(){
+        let url = std::env::var("SMTP_SINK_URL").unwrap_or_else(|_| "smtp://localhost:1025".to_owned());
+        let transport = SmtpMailTransport::connect(&url, None, 2).unwrap();
+        let sender = SenderIdentity{name: "Chat Team".to_owned(), address: "hello@chat.example".to_owned(), product: "ChatApp".to_owned()};
+        let message = Mailer::new(
+            MailQueue::start(Arc::new(CapturingTransport::default()), Arc::new(MemoryDeadLetters::default()), RetryPolicy::default(), 1),
+            sender
+        ).compose("alice@example.com", &MailTemplate::Otp{code: "A1B2C3".to_owned(), valid_minutes: 2}, Locale::En).unwrap();
+
+        transport.send(message).await.unwrap();
+    }
+}
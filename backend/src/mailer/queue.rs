@@ -0,0 +1,269 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use deadpool_redis::redis::pipe;
+use lettre::Message;
+use tokio::sync::{mpsc, Semaphore};
+
+use super::{DeliveryError, MailTransport};
+use crate::utils;
+
+
+/// How hard the queue tries before giving a mail up.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetryPolicy{
+    pub max_attempts: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl Default for RetryPolicy{
+    fn default()->Self{
+        Self{
+            max_attempts: 5,
+            base_delay: Duration::from_secs(2),
+            max_delay: Duration::from_secs(5 * 60),
+        }
+    }
+}
+
+impl RetryPolicy{
+    /// Wait before attempt `attempt + 1`: doubles every time, capped, with up to
+    /// 25% jitter so a relay outage doesn't get hit by every mail at once.
+    pub fn delay_after(&self, attempt: u32)->Duration{
+        let exponential = self.base_delay.saturating_mul(1u32 << attempt.saturating_sub(1).min(16));
+        let capped = exponential.min(self.max_delay);
+        capped + capped.mul_f64(fastrand::f64() * 0.25)
+    }
+}
+
+
+/// A mail that ran out of attempts or was refused outright.
+#[derive(Debug, Clone)]
+pub struct DeadLetter{
+    pub recipients: String,
+    pub error: String,
+    pub attempts: u32,
+    pub failed_at: i64,
+    pub raw: Vec<u8>,
+}
+
+/// Where undeliverable mail ends up so it can be looked at (and resent) later.
+#[tonic::async_trait]
+pub trait DeadLetterSink: Send + Sync{
+    async fn record(&self, letter: DeadLetter)->Result<(), String>;
+}
+
+/// Keeps dead letters in Redis: `mail_dead_letter:<id>` hashes, newest first in
+/// the `mail_dead_letters` list.
+pub struct RedisDeadLetters{
+    redis_pool: deadpool_redis::Pool
+}
+
+impl RedisDeadLetters{
+    pub fn new(redis_pool: deadpool_redis::Pool)->Self{
+        Self{redis_pool}
+    }
+}
+
+#[tonic::async_trait]
+impl DeadLetterSink for RedisDeadLetters{
+    async fn record(&self, letter: DeadLetter)->Result<(), String>{
+        let mut conn = self.redis_pool.get().await.map_err(|e| e.to_string())?;
+        let id = utils::random_hex(8);
+
+        pipe().atomic()
+            .cmd("HSET").arg(format!("mail_dead_letter:{}", id))
+                .arg("recipients").arg(&letter.recipients)
+                .arg("error").arg(&letter.error)
+                .arg("attempts").arg(letter.attempts)
+                .arg("failed_at").arg(letter.failed_at)
+                .arg("raw").arg(&letter.raw)
+                .ignore()
+            .cmd("LPUSH").arg("mail_dead_letters").arg(&id).ignore()
+            .query_async::<()>(&mut conn)
+            .await
+            .map_err(|e| e.to_string())
+    }
+}
+
+
+struct Job{
+    message: Message,
+    attempts: u32,
+}
+
+/// Hands mail to a background worker so callers never wait on SMTP.
+///
+/// Transient failures are retried with backoff; permanent ones, and mail that
+/// exhausts `RetryPolicy::max_attempts`, go to the dead-letter sink.
+#[derive(Clone)]
+pub struct MailQueue{
+    sender: mpsc::Sender<Job>
+}
+
+impl MailQueue{
+    /// Spawns the worker. `capacity` bounds both the backlog and how many mails
+    /// are in flight (or waiting out a backoff) at once.
+    pub fn start(
+        transport: Arc<dyn MailTransport>,
+        dead_letters: Arc<dyn DeadLetterSink>,
+        policy: RetryPolicy,
+        capacity: usize
+    )->Self{
+        let (sender, mut receiver) = mpsc::channel::<Job>(capacity);
+        let in_flight = Arc::new(Semaphore::new(capacity));
+
+        tokio::spawn(async move {
+            while let Some(job) = receiver.recv().await{
+                let Ok(permit) = in_flight.clone().acquire_owned().await else { break };
+                let transport = transport.clone();
+                let dead_letters = dead_letters.clone();
+                let policy = policy.clone();
+                tokio::spawn(async move {
+                    deliver(job, transport.as_ref(), dead_letters.as_ref(), &policy).await;
+                    drop(permit);
+                });
+            }
+        });
+
+        Self{sender}
+    }
+
+    /// Queues `message`; fails only when the queue is full or the worker is gone.
+    pub fn enqueue(&self, message: Message)->Result<(), String>{
+        self.sender.try_send(Job{message, attempts: 0})
+            .map_err(|e| match e{
+                mpsc::error::TrySendError::Full(_) => "mail queue is full, try again shortly".to_owned(),
+                mpsc::error::TrySendError::Closed(_) => "mail queue is not running".to_owned(),
+            })
+    }
+}
+
+async fn deliver(mut job: Job, transport: &dyn MailTransport, dead_letters: &dyn DeadLetterSink, policy: &RetryPolicy){
+    let error = loop{
+        job.attempts += 1;
+        match transport.send(job.message.clone()).await{
+            Ok(()) => return,
+            Err(DeliveryError::Permanent(err)) => break err,
+            Err(DeliveryError::Transient(err)) if job.attempts >= policy.max_attempts => break err,
+            Err(DeliveryError::Transient(err)) => {
+                let wait = policy.delay_after(job.attempts);
+                println!("mail attempt {} failed ({}), retrying in {:?}", job.attempts, err, wait);
+                tokio::time::sleep(wait).await;
+            }
+        }
+    };
+
+    let recipients = job.message.envelope().to().iter()
+        .map(|address| address.to_string())
+        .collect::<Vec<_>>()
+        .join(", ");
+    println!("giving up on mail to {} after {} attempts: {}", recipients, job.attempts, error);
+
+    let letter = DeadLetter{
+        recipients,
+        error,
+        attempts: job.attempts,
+        failed_at: utils::now_secs(),
+        raw: job.message.formatted(),
+    };
+    if let Err(err) = dead_letters.record(letter).await{
+        println!("could not record dead letter: {}", err);
+    }
+}
+
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use crate::mailer::fakes::{CapturingTransport, MemoryDeadLetters};
+    use std::sync::Mutex;
+
+    fn fast_policy()->RetryPolicy{
+        RetryPolicy{max_attempts: 3, base_delay: Duration::from_millis(5), max_delay: Duration::from_millis(20)}
+    }
+
+    fn message()->Message{
+        Message::builder()
+            .from("sender@chat.example".parse().unwrap())
+            .to("alice@example.com".parse().unwrap())
+            .subject("hi")
+            .body("hello".to_owned())
+            .unwrap()
+    }
+
+    /// Fails with the queued errors first, then delivers.
+    struct ScriptedTransport{
+        failures: Mutex<Vec<DeliveryError>>,
+        inner: CapturingTransport,
+    }
+
+    #[tonic::async_trait]
+    impl MailTransport for ScriptedTransport{
+        async fn send(&self, message: Message)->Result<(), DeliveryError>{
+            if let Some(err) = self.failures.lock().unwrap().pop(){
+                return Err(err);
+            }
+            self.inner.send(message).await
+        }
+    }
+
+    fn scripted(failures: Vec<DeliveryError>)->Arc<ScriptedTransport>{
+        Arc::new(ScriptedTransport{failures: Mutex::new(failures), inner: CapturingTransport::default()})
+    }
+
+    #[test]
+    fn backoff_doubles_and_caps(){
+        let policy = RetryPolicy{max_attempts: 10, base_delay: Duration::from_secs(1), max_delay: Duration::from_secs(10)};
+
+        let first = policy.delay_after(1);
+        let third = policy.delay_after(3);
+        let ninth = policy.delay_after(9);
+        assert!(first >= Duration::from_secs(1) && first <= Duration::from_millis(1250));
+        assert!(third >= Duration::from_secs(4) && third <= Duration::from_secs(5));
+        assert!(ninth >= Duration::from_secs(10) && ninth <= Duration::from_millis(12500));
+    }
+
+    #[tokio::test]
+    async fn transient_failures_are_retried(){
+        let transport = scripted(vec![
+            DeliveryError::Transient("421 try later".to_owned()),
+            DeliveryError::Transient("connection reset".to_owned()),
+        ]);
+        let dead_letters = Arc::new(MemoryDeadLetters::default());
+        let queue = MailQueue::start(transport.clone(), dead_letters.clone(), fast_policy(), 8);
+
+        queue.enqueue(message()).unwrap();
+
+        assert_eq!(transport.inner.wait_for(1).await.len(), 1);
+        assert!(dead_letters.letters().is_empty());
+    }
+
+    #[tokio::test]
+    async fn exhausted_mail_is_dead_lettered(){
+        let transport = scripted(vec![DeliveryError::Transient("421 try later".to_owned()); 3]);
+        let dead_letters = Arc::new(MemoryDeadLetters::default());
+        let queue = MailQueue::start(transport.clone(), dead_letters.clone(), fast_policy(), 8);
+
+        queue.enqueue(message()).unwrap();
+
+        let letters = dead_letters.wait_for(1).await;
+        assert_eq!(letters[0].attempts, 3);
+        assert_eq!(letters[0].recipients, "alice@example.com");
+        assert!(transport.inner.sent().is_empty());
+    }
+
+    #[tokio::test]
+    async fn permanent_failures_are_not_retried(){
+        let transport = scripted(vec![DeliveryError::Permanent("550 no such mailbox".to_owned())]);
+        let dead_letters = Arc::new(MemoryDeadLetters::default());
+        let queue = MailQueue::start(transport.clone(), dead_letters.clone(), fast_policy(), 8);
+
+        queue.enqueue(message()).unwrap();
+
+        let letters = dead_letters.wait_for(1).await;
+        assert_eq!(letters[0].attempts, 1);
+        assert_eq!(letters[0].error, "550 no such mailbox");
+    }
+}
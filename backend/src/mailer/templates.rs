@@ -0,0 +1,229 @@
+/// Languages transactional mail can be written in.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Locale{
+    En,
+    Es,
+}
+
+impl Locale{
+    /// Accepts tags like "es" or "es-MX"; anything unknown falls back to English.
+    pub fn parse(tag: &str)->Self{
+        match tag.trim().to_lowercase().split(['-', '_']).next(){
+            Some("es") => Locale::Es,
+            _ => Locale::En,
+        }
+    }
+}
+
+
+/// Every kind of mail the server sends on its own.
+#[derive(Debug, Clone, PartialEq)]
+pub enum MailTemplate{
+    Otp{ code: String, valid_minutes: u32 },
+    MagicLink{ url: String, valid_minutes: u32 },
+    NewDeviceLogin{ device_name: String },
+    AccountDeletion{ code: String, valid_minutes: u32 },
+    VerifyNewAddress{ code: String, valid_minutes: u32 },
+    // sent to the old address once the account's email or phone changes
+    IdentifierChanged{ new_address: String },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RenderedMail{
+    pub subject: String,
+    pub text: String,
+    pub html: String,
+}
+
+
+/// The short blurbs that change per template and language. The surrounding
+/// layout lives in `wrap_html` / `wrap_text` so every mail looks the same.
+struct MailCopy{
+    subject: String,
+    heading: String,
+    lead: String,
+    highlight: Option<String>,
+    // button label and target
+    action: Option<(String, String)>,
+    footer: String,
+}
+
+fn copy_for(template: &MailTemplate, locale: Locale, product: &str)->MailCopy{
+    match (template, locale){
+        (MailTemplate::Otp{code, valid_minutes}, Locale::En) => MailCopy{
+            subject: format!("{} is your {} verification code", code, product),
+            heading: "Confirm it's you".to_owned(),
+            lead: format!("Enter this code in {} to sign in. It expires in {} minutes.", product, valid_minutes),
+            highlight: Some(code.clone()),
+            action: None,
+            footer: "If you didn't try to sign in, you can ignore this email.".to_owned(),
+        },
+        (MailTemplate::Otp{code, valid_minutes}, Locale::Es) => MailCopy{
+            subject: format!("{} es tu código de verificación de {}", code, product),
+            heading: "Confirma que eres tú".to_owned(),
+            lead: format!("Introduce este código en {} para iniciar sesión. Caduca en {} minutos.", product, valid_minutes),
+            highlight: Some(code.clone()),
+            action: None,
+            footer: "Si no intentaste iniciar sesión, puedes ignorar este correo.".to_owned(),
+        },
+        (MailTemplate::MagicLink{url, valid_minutes}, Locale::En) => MailCopy{
+            subject: format!("Your {} sign-in link", product),
+            heading: "Sign in with one click".to_owned(),
+            lead: format!("Open this link on the device you want to use {} on. It works once and expires in {} minutes.", product, valid_minutes),
+            highlight: None,
+            action: Some(("Sign in".to_owned(), url.clone())),
+            footer: "If the button doesn't open the app, copy the link and paste it into the sign-in screen. If you didn't ask for it, ignore this email.".to_owned(),
+        },
+        (MailTemplate::MagicLink{url, valid_minutes}, Locale::Es) => MailCopy{
+            subject: format!("Tu enlace de inicio de sesión de {}", product),
+            heading: "Inicia sesión con un clic".to_owned(),
+            lead: format!("Abre este enlace en el dispositivo donde quieras usar {}. Solo funciona una vez y caduca en {} minutos.", product, valid_minutes),
+            highlight: None,
+            action: Some(("Iniciar sesión".to_owned(), url.clone())),
+            footer: "Si el botón no abre la aplicación, copia el enlace y pégalo en la pantalla de inicio de sesión. Si no lo pediste, ignora este correo.".to_owned(),
+        },
+        (MailTemplate::NewDeviceLogin{device_name}, Locale::En) => MailCopy{
+            subject: format!("New sign-in to your {} account", product),
+            heading: "New sign-in".to_owned(),
+            lead: "Your account was just used to sign in on a new device:".to_owned(),
+            highlight: Some(device_name.clone()),
+            action: None,
+            footer: "If this wasn't you, sign that device out from your active sessions right away.".to_owned(),
+        },
+        (MailTemplate::NewDeviceLogin{device_name}, Locale::Es) => MailCopy{
+            subject: format!("Nuevo inicio de sesión en tu cuenta de {}", product),
+            heading: "Nuevo inicio de sesión".to_owned(),
+            lead: "Se acaba de iniciar sesión en tu cuenta desde un dispositivo nuevo:".to_owned(),
+            highlight: Some(device_name.clone()),
+            action: None,
+            footer: "Si no fuiste tú, cierra la sesión de ese dispositivo desde tus sesiones activas cuanto antes.".to_owned(),
+        },
+        (MailTemplate::AccountDeletion{code, valid_minutes}, Locale::En) => MailCopy{
+            subject: format!("Confirm deleting your {} account", product),
+            heading: "Delete your account?".to_owned(),
+            lead: format!("Enter this code to permanently delete your account and messages. It expires in {} minutes.", valid_minutes),
+            highlight: Some(code.clone()),
+            action: None,
+            footer: "If you didn't ask for this, ignore this email and your account stays as it is.".to_owned(),
+        },
+        (MailTemplate::AccountDeletion{code, valid_minutes}, Locale::Es) => MailCopy{
+            subject: format!("Confirma la eliminación de tu cuenta de {}", product),
+            heading: "¿Eliminar tu cuenta?".to_owned(),
+            lead: format!("Introduce este código para eliminar tu cuenta y tus mensajes para siempre. Caduca en {} minutos.", valid_minutes),
+            highlight: Some(code.clone()),
+            action: None,
+            footer: "Si no lo solicitaste, ignora este correo y tu cuenta seguirá igual.".to_owned(),
+        },
+        (MailTemplate::VerifyNewAddress{code, valid_minutes}, Locale::En) => MailCopy{
+            subject: format!("Confirm your new {} address", product),
+            heading: "Confirm this address".to_owned(),
+            lead: format!("Enter this code in {} to add this address to your account. It expires in {} minutes.", product, valid_minutes),
+            highlight: Some(code.clone()),
+            action: None,
+            footer: "If you didn't ask for this, you can ignore this email.".to_owned(),
+        },
+        (MailTemplate::VerifyNewAddress{code, valid_minutes}, Locale::Es) => MailCopy{
+            subject: format!("Confirma tu nueva dirección de {}", product),
+            heading: "Confirma esta dirección".to_owned(),
+            lead: format!("Introduce este código en {} para añadir esta dirección a tu cuenta. Caduca en {} minutos.", product, valid_minutes),
+            highlight: Some(code.clone()),
+            action: None,
+            footer: "Si no lo solicitaste, puedes ignorar este correo.".to_owned(),
+        },
+        (MailTemplate::IdentifierChanged{new_address}, Locale::En) => MailCopy{
+            subject: format!("Your {} sign-in details changed", product),
+            heading: "Sign-in details changed".to_owned(),
+            lead: "Your account can now be signed in to with:".to_owned(),
+            highlight: Some(new_address.clone()),
+            action: None,
+            footer: "If this wasn't you, sign in and check your account and active sessions right away.".to_owned(),
+        },
+        (MailTemplate::IdentifierChanged{new_address}, Locale::Es) => MailCopy{
+            subject: format!("Cambiaron tus datos de acceso a {}", product),
+            heading: "Datos de acceso actualizados".to_owned(),
+            lead: "Ahora se puede iniciar sesión en tu cuenta con:".to_owned(),
+            highlight: Some(new_address.clone()),
+            action: None,
+            footer: "Si no fuiste tú, inicia sesión y revisa tu cuenta y tus sesiones activas cuanto antes.".to_owned(),
+        },
+    }
+}
+
+
+pub fn render(template: &MailTemplate, locale: Locale, product: &str)->RenderedMail{
+    let copy = copy_for(template, locale, product);
+    RenderedMail{
+        html: wrap_html(&copy, product),
+        text: wrap_text(&copy, product),
+        subject: copy.subject,
+    }
+}
+
+fn wrap_text(copy: &MailCopy, product: &str)->String{
+    let mut text = format!("{}\n\n{}\n", copy.heading, copy.lead);
+    if let Some(highlight) = &copy.highlight{
+        text.push_str(&format!("\n    {}\n", highlight));
+    }
+    if let Some((_, url)) = &copy.action{
+        text.push_str(&format!("\n{}\n", url));
+    }
+    text.push_str(&format!("\n{}\n\n— {}\n", copy.footer, product));
+    text
+}
+
+fn wrap_html(copy: &MailCopy, product: &str)->String{
+    let highlight = copy.highlight.as_ref()
+        .map(|h| format!(
+            r#"<p style="font-size:28px;font-weight:700;letter-spacing:6px;color:#00a884;margin:24px 0">{}</p>"#,
+            escape(h)
+        ))
+        .unwrap_or_default();
+    let action = copy.action.as_ref()
+        .map(|(label, url)| format!(
+            concat!(
+                r#"<p style="margin:28px 0"><a href="{url}" style="background:#00a884;color:#111b21;text-decoration:none;font-weight:700;padding:12px 28px;border-radius:24px;display:inline-block">{label}</a></p>"#,
+                r#"<p style="font-size:12px;word-break:break-all;color:#8696a0;margin:0">{url}</p>"#
+            ),
+            url = escape(url),
+            label = escape(label)
+        ))
+        .unwrap_or_default();
+
+    format!(
+r#"<!DOCTYPE html>
+<html>
+<body style="margin:0;padding:32px 0;background:#0b141a;font-family:-apple-system,BlinkMacSystemFont,'Segoe UI',sans-serif">
+  <div style="max-width:480px;margin:0 auto;background:#202c33;border-radius:16px;padding:40px;color:#e9edef">
+    <p style="font-size:18px;font-weight:700;margin:0 0 24px">&#128172; {product}</p>
+    <h1 style="font-size:22px;margin:0 0 12px">{heading}</h1>
+    <p style="font-size:15px;line-height:1.5;color:#d1d7db;margin:0">{lead}</p>
+    {highlight}
+    {action}
+    <p style="font-size:13px;line-height:1.5;color:#8696a0;margin:24px 0 0">{footer}</p>
+  </div>
+</body>
+</html>
+"#,
+        product = escape(product),
+        heading = escape(&copy.heading),
+        lead = escape(&copy.lead),
+        highlight = highlight,
+        action = action,
+        footer = escape(&copy.footer),
+    )
+}
+
+fn escape(raw: &str)->String{
+    let mut escaped = String::with_capacity(raw.len());
+    for c in raw.chars(){
+        match c{
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
@@ -10,6 +10,20 @@ mod user_service;
 mod chat_service;
 mod channel_layers;
 mod utils;
+mod stores;
+mod sessions;
+mod mailer;
+mod magic_links;
+mod totp;
+mod two_factor;
+mod sms;
+
+use std::sync::Arc;
+use std::time::Duration;
+use deadpool_redis::{Config, Runtime};
+use http::{HeaderName, HeaderValue, Method};
+use http::header::{AUTHORIZATION, CONTENT_TYPE};
+use tower_http::cors::{AllowOrigin, CorsLayer};
 
 fn print_type_of<T>(obj: &T){
     println!("{:?}", std::any::type_name::<T>());
@@ -22,11 +36,115 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     println!("{:?}", addr);
 
 
-    let user_service_obj = user_service::UserService::new();
-    let chat_service_obj = chat_service::ChatService::new();
+    let database_url = std::env::var("DATABASE_URL")
+        .unwrap_or_else(|_| "sqlite://chat_app.db".to_owned());
+    let store = Arc::new(stores::sqlite::SqliteStore::connect(&database_url).await?);
+
+    let redis_url = std::env::var("REDIS_URL")
+        .unwrap_or_else(|_| "redis://127.0.0.1:6379".to_owned());
+    let redis_pool = Config::from_url(redis_url).create_pool(Some(Runtime::Tokio1))?;
+
+    // without a fixed key every restart invalidates outstanding access tokens;
+    // clients recover through RefreshToken since sessions live in redis
+    let signing_key = std::env::var("SESSION_SIGNING_KEY").unwrap_or_else(|_| {
+        println!("SESSION_SIGNING_KEY not set, using a random key for this run");
+        utils::random_hex(32)
+    });
+
+    let channel_handler = chat_service::ChatService::start_channel_layer();
+    let sessions = Arc::new(sessions::SessionManager::new(redis_pool.clone(), signing_key.clone(), channel_handler.clone()));
+
+    // e.g. smtp://localhost:1025 to point at a local sink while developing;
+    // sending without credentials is only allowed to an explicitly chosen server
+    let smtp_url = std::env::var("SMTP_URL").unwrap_or_else(|_| "smtps://smtp.gmail.com".to_owned());
+    let smtp_credentials = match (std::env::var("SMTP_USERNAME"), std::env::var("SMTP_PASSWORD")){
+        (Ok(username), Ok(password)) => Some(lettre::transport::smtp::authentication::Credentials::new(username, password)),
+        (Err(_), Err(_)) if std::env::var("SMTP_URL").is_ok() => None,
+        _ => return Err("set both SMTP_USERNAME and SMTP_PASSWORD, or point SMTP_URL at a server that needs no login".into()),
+    };
+    let mail_queue = mailer::MailQueue::start(
+        Arc::new(mailer::SmtpMailTransport::connect(&smtp_url, smtp_credentials, 4)?),
+        Arc::new(mailer::RedisDeadLetters::new(redis_pool.clone())),
+        mailer::RetryPolicy::default(),
+        256,
+    );
+    let mailer = Arc::new(mailer::Mailer::new(mail_queue, mailer::SenderIdentity::from_env()));
+
+    // the desktop client registers this scheme; a web page could forward to it instead
+    let magic_link_base = std::env::var("MAGIC_LINK_BASE_URL").unwrap_or_else(|_| "dioxuschat://login".to_owned());
+    // a separate key so a link token can never pass for an access token
+    let magic_link_key = utils::create_uuid(&"magic_link".to_owned(), &signing_key);
+    let magic_links = magic_links::MagicLinks::new(redis_pool.clone(), magic_link_key, magic_link_base);
+
+    // 64 hex chars; losing or changing it locks out everyone who has 2FA on,
+    // so it may only be derived from a signing key that outlives this run
+    let totp_key = match (std::env::var("TOTP_ENCRYPTION_KEY"), std::env::var("SESSION_SIGNING_KEY")){
+        (Ok(key), _) => hex::decode(key.trim())?,
+        (Err(_), Ok(_)) => {
+            println!("TOTP_ENCRYPTION_KEY not set, deriving it from SESSION_SIGNING_KEY; changing that key now locks out 2FA users");
+            hex::decode(utils::create_uuid(&"totp_encryption".to_owned(), &signing_key))?
+        }
+        (Err(_), Err(_)) => {
+            return Err("TOTP_ENCRYPTION_KEY is not set (64 hex chars); without it or a fixed SESSION_SIGNING_KEY, stored 2FA secrets would be unreadable after a restart".into());
+        }
+    };
+    let two_factor = two_factor::TwoFactor::new(
+        store.clone(),
+        redis_pool.clone(),
+        totp::SecretCipher::new(&totp_key)?,
+        mailer::SenderIdentity::from_env().product,
+    );
+
+    // there is no SMS gateway yet, so phone numbers can't be verified unless
+    // SMS_TO_CONSOLE=1 prints the texts for local testing
+    let sms_sender: Arc<dyn sms::SmsSender> = if std::env::var("SMS_TO_CONSOLE").is_ok_and(|v| v == "1") {
+        println!("SMS_TO_CONSOLE=1: texts, codes included, are printed here instead of sent");
+        Arc::new(sms::ConsoleSms)
+    } else {
+        Arc::new(sms::NoSms)
+    };
+    let texter = sms::Texter::new(sms_sender, mailer::SenderIdentity::from_env().product);
+
+    let user_service_obj = user_service::UserService::new(store.clone(), redis_pool, sessions.clone(), mailer, texter, magic_links, two_factor);
+    let chat_service_obj = chat_service::ChatService::new(store.clone(), channel_handler, sessions);
+
 
+    // browsers speak gRPC-Web over HTTP/1.1 and need CORS to call us from the
+    // page's origin. WEB_ORIGINS (comma separated) lists the pages that may;
+    // without it only a local `dx serve` can, and "*" opens it to every site
+    let web_origins = std::env::var("WEB_ORIGINS")
+        .unwrap_or_else(|_| "http://localhost:8080,http://127.0.0.1:8080".to_owned());
+    let cors = if web_origins.trim() == "*" {
+        println!("WEB_ORIGINS=*: any web page may call this server");
+        AllowOrigin::mirror_request()
+    } else {
+        let origins = web_origins.split(',')
+            .map(str::trim)
+            .filter(|o| !o.is_empty())
+            .map(str::parse)
+            .collect::<Result<Vec<HeaderValue>, _>>()?;
+        AllowOrigin::list(origins)
+    };
+    let cors = CorsLayer::new()
+        .allow_origin(cors)
+        .allow_methods([Method::POST, Method::OPTIONS])
+        .allow_headers([
+            AUTHORIZATION, CONTENT_TYPE,
+            HeaderName::from_static("x-grpc-web"),
+            HeaderName::from_static("x-user-agent"),
+            HeaderName::from_static("grpc-timeout"),
+        ])
+        .expose_headers([
+            HeaderName::from_static("grpc-status"),
+            HeaderName::from_static("grpc-message"),
+            HeaderName::from_static("grpc-status-details-bin"),
+        ])
+        .max_age(Duration::from_secs(24 * 60 * 60));
 
     Server::builder()
+        .accept_http1(true)
+        .layer(cors)
+        .layer(tonic_web::GrpcWebLayer::new())
         .add_service(user_service::UserServer::new(user_service_obj))
         .add_service(chat_service::ChatServer::new(chat_service_obj))
         .serve(addr).await?;
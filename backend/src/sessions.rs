@@ -0,0 +1,381 @@
+use std::collections::HashMap;
+
+use deadpool_redis::redis::{cmd, pipe};
+use tonic::{Request, Status};
+
+use crate::channel_layers::Command;
+use crate::chat_service::ChannelHandle;
+use crate::utils;
+
+/// Access tokens are checked on every call, so keep them short-lived.
+pub const ACCESS_TTL_SECS: i64 = 15 * 60;
+/// A session that never refreshes disappears from Redis after this long.
+pub const REFRESH_TTL_SECS: i64 = 30 * 24 * 60 * 60;
+
+
+/// Rotates a session's refresh hash only if the presented one is current, in
+/// one step, so two refreshes racing with the same token can't both win.
+/// KEYS[1] the session; ARGV: presented hash, new hash, now, ttl.
+/// Replies `{outcome, user id}` with outcome `ok`, `reused` or `missing`.
+const ROTATE_REFRESH_SCRIPT: &str = r#"
+local session = redis.call('HMGET', KEYS[1], 'user_id', 'refresh_hash')
+if not session[1] or not session[2] then
+    return {'missing', ''}
+end
+if session[2] ~= ARGV[1] then
+    return {'reused', session[1]}
+end
+redis.call('HSET', KEYS[1], 'refresh_hash', ARGV[2], 'last_seen', ARGV[3])
+redis.call('EXPIRE', KEYS[1], ARGV[4])
+return {'ok', session[1]}
+"#;
+
+
+/// Who is calling, taken from a verified access token.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Claims{
+    pub user_id: String,
+    pub session_id: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct IssuedTokens{
+    pub session_id: String,
+    pub access_token: String,
+    pub refresh_token: String,
+    /// Hex key the client encrypts its local message cache with. Derived from the
+    /// session id, so it stays the same across refreshes and dies with the session.
+    pub cache_key: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SessionInfo{
+    pub session_id: String,
+    pub device_name: String,
+    pub created_at: i64,
+    pub last_seen: i64,
+}
+
+
+/// Issues, renews and revokes login sessions.
+///
+/// A session lives in Redis as `session:<id>` (a hash holding the owner, the
+/// device name and the sha256 of the current refresh token) and is indexed by
+/// `user_sessions:<user id>`. Access tokens are HMAC-signed
+/// `user.session.expiry` triples; they are only honoured while their session
+/// key still exists, so revoking takes effect on the very next call.
+pub struct SessionManager{
+    redis_pool: deadpool_redis::Pool,
+    signing_key: String,
+    channel_handler: ChannelHandle
+}
+
+impl SessionManager{
+    pub fn new(redis_pool: deadpool_redis::Pool, signing_key: String, channel_handler: ChannelHandle)->Self{
+        Self{redis_pool, signing_key, channel_handler}
+    }
+
+    async fn redis(&self)->Result<deadpool_redis::Connection, Status>{
+        self.redis_pool.get().await.map_err(|e| Status::unavailable(format!("redis unavailable: {}", e)))
+    }
+
+    fn sign_access_token(&self, user_id: &str, session_id: &str)->String{
+        let payload = format!("{}.{}.{}", user_id, session_id, utils::now_secs() + ACCESS_TTL_SECS);
+        let signature = utils::create_uuid(&payload, &self.signing_key);
+        format!("{}.{}", payload, signature)
+    }
+
+    fn cache_key(&self, session_id: &str)->String{
+        utils::create_uuid(&format!("cache_key:{}", session_id), &self.signing_key)
+    }
+
+    /// Starts a new session for `user_id` after a successful login.
+    pub async fn create(&self, user_id: &str, device_name: &str)->Result<IssuedTokens, Status>{
+        let mut conn = self.redis().await?;
+        let session_id = utils::random_hex(16);
+        let secret = utils::random_hex(32);
+        let now = utils::now_secs();
+        let key = format!("session:{}", session_id);
+
+        pipe().atomic()
+            .cmd("HSET").arg(&key)
+                .arg("user_id").arg(user_id)
+                .arg("device_name").arg(device_name)
+                .arg("created_at").arg(now)
+                .arg("last_seen").arg(now)
+                .arg("refresh_hash").arg(utils::sha256_hex(&secret))
+                .ignore()
+            .cmd("EXPIRE").arg(&key).arg(REFRESH_TTL_SECS).ignore()
+            .cmd("SADD").arg(format!("user_sessions:{}", user_id)).arg(&session_id).ignore()
+            .query_async::<()>(&mut conn)
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?;
+
+        Ok(IssuedTokens{
+            access_token: self.sign_access_token(user_id, &session_id),
+            refresh_token: format!("{}.{}", session_id, secret),
+            cache_key: self.cache_key(&session_id),
+            session_id,
+        })
+    }
+
+    /// Swaps a refresh token for a fresh access/refresh pair. Refresh tokens are
+    /// single use: presenting an old one means it leaked, so the whole session goes.
+    pub async fn refresh(&self, refresh_token: &str)->Result<IssuedTokens, Status>{
+        let Some((session_id, secret)) = refresh_token.split_once('.') else {
+            return Err(Status::unauthenticated("malformed refresh token"));
+        };
+        let mut conn = self.redis().await?;
+        let key = format!("session:{}", session_id);
+
+        let new_secret = utils::random_hex(32);
+
+        let (outcome, user_id): (String, String) = cmd("EVAL")
+            .arg(ROTATE_REFRESH_SCRIPT)
+            .arg(1)
+            .arg(&key)
+            .arg(utils::sha256_hex(secret))
+            .arg(utils::sha256_hex(&new_secret))
+            .arg(utils::now_secs())
+            .arg(REFRESH_TTL_SECS)
+            .query_async(&mut conn)
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?;
+
+        match outcome.as_str(){
+            "ok" => {}
+            "reused" => {
+                drop(conn);
+                self.revoke(&user_id, session_id).await?;
+                return Err(Status::unauthenticated("refresh token already used; session revoked"));
+            }
+            _ => return Err(Status::unauthenticated("session expired or revoked")),
+        }
+
+        Ok(IssuedTokens{
+            access_token: self.sign_access_token(&user_id, session_id),
+            refresh_token: format!("{}.{}", session_id, new_secret),
+            cache_key: self.cache_key(session_id),
+            session_id: session_id.to_owned(),
+        })
+    }
+
+    /// Checks the `authorization: Bearer <access token>` header of `request`.
+    pub async fn authenticate<T>(&self, request: &Request<T>)->Result<Claims, Status>{
+        let token = request.metadata()
+            .get("authorization")
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .ok_or_else(|| Status::unauthenticated("missing access token"))?;
+
+        let Some((payload, signature)) = token.rsplit_once('.') else {
+            return Err(Status::unauthenticated("malformed access token"));
+        };
+        if !utils::verify_uuid(&payload.to_owned(), &signature.to_owned(), &self.signing_key){
+            return Err(Status::unauthenticated("invalid access token"));
+        }
+
+        let parts: Vec<&str> = payload.split('.').collect();
+        let &[user_id, session_id, expires_at] = parts.as_slice() else {
+            return Err(Status::unauthenticated("malformed access token"));
+        };
+        if expires_at.parse::<i64>().unwrap_or(0) < utils::now_secs(){
+            return Err(Status::unauthenticated("access token expired"));
+        }
+
+        let mut conn = self.redis().await?;
+        let alive: bool = cmd("EXISTS").arg(format!("session:{}", session_id))
+            .query_async(&mut conn)
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?;
+        if !alive{
+            return Err(Status::unauthenticated("session revoked"));
+        }
+
+        Ok(Claims{user_id: user_id.to_owned(), session_id: session_id.to_owned()})
+    }
+
+    /// Like `authenticate`, but the caller must also be `user_id`.
+    pub async fn authorize<T>(&self, request: &Request<T>, user_id: &str)->Result<Claims, Status>{
+        let claims = self.authenticate(request).await?;
+        if claims.user_id != user_id{
+            return Err(Status::permission_denied("token does not belong to that user"));
+        }
+        Ok(claims)
+    }
+
+    pub async fn list(&self, user_id: &str)->Result<Vec<SessionInfo>, Status>{
+        let mut conn = self.redis().await?;
+        let index = format!("user_sessions:{}", user_id);
+        let session_ids: Vec<String> = cmd("SMEMBERS").arg(&index)
+            .query_async(&mut conn)
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?;
+
+        let mut sessions = Vec::new();
+        for session_id in session_ids{
+            let session: HashMap<String, String> = cmd("HGETALL").arg(format!("session:{}", session_id))
+                .query_async(&mut conn)
+                .await
+                .map_err(|e| Status::internal(e.to_string()))?;
+
+            if session.is_empty(){
+                // expired on its own; tidy the index
+                let _ = cmd("SREM").arg(&index).arg(&session_id).query_async::<()>(&mut conn).await;
+                continue;
+            }
+            let number = |field: &str| session.get(field).and_then(|v| v.parse().ok()).unwrap_or(0);
+            sessions.push(SessionInfo{
+                device_name: session.get("device_name").cloned().unwrap_or_default(),
+                created_at: number("created_at"),
+                last_seen: number("last_seen"),
+                session_id,
+            });
+        }
+        sessions.sort_by(|a, b| b.last_seen.cmp(&a.last_seen));
+        Ok(sessions)
+    }
+
+    /// Ends one of `user_id`'s sessions and closes that device's chat stream.
+    pub async fn revoke(&self, user_id: &str, session_id: &str)->Result<(), Status>{
+        let mut conn = self.redis().await?;
+        let key = format!("session:{}", session_id);
+
+        let owner: Option<String> = cmd("HGET").arg(&key).arg("user_id")
+            .query_async(&mut conn)
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?;
+        if owner.as_deref() != Some(user_id){
+            return Err(Status::not_found("no such session"));
+        }
+
+        pipe().atomic()
+            .cmd("DEL").arg(&key).ignore()
+            .cmd("SREM").arg(format!("user_sessions:{}", user_id)).arg(session_id).ignore()
+            .query_async::<()>(&mut conn)
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?;
+
+        let _ = self.channel_handler.send(Command::Kick((
+            user_id.to_owned(),
+            session_id.to_owned(),
+            Err(Status::unauthenticated("session revoked"))
+        ))).await;
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+pub(crate) mod fakes{
+    use super::*;
+    use deadpool_redis::{Config, Runtime};
+
+    /// A pool pointed at a port nothing listens on, for tests whose code path
+    /// never gets as far as Redis. Anything that does reach it fails.
+    pub fn dead_redis_pool()->deadpool_redis::Pool{
+        Config::from_url("redis://127.0.0.1:1").create_pool(Some(Runtime::Tokio1)).unwrap()
+    }
+
+    pub fn sessions(channel_handler: ChannelHandle)->SessionManager{
+        SessionManager::new(dead_redis_pool(), "test-key".to_owned(), channel_handler)
+    }
+}
+
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use deadpool_redis::{Config, Runtime};
+
+    const KEY: &str = "test-signing-key";
+
+    fn manager(redis_url: &str)->SessionManager{
+        let pool = Config::from_url(redis_url).create_pool(Some(Runtime::Tokio1)).unwrap();
+        SessionManager::new(pool, KEY.to_owned(), crate::chat_service::ChatService::start_channel_layer())
+    }
+
+    fn bearer(token: &str)->Request<()>{
+        let mut request = Request::new(());
+        request.metadata_mut().insert("authorization", format!("Bearer {}", token).parse().unwrap());
+        request
+    }
+
+    // these are turned away before Redis is asked, so nothing needs to listen
+    #[tokio::test]
+    async fn forged_and_expired_access_tokens_are_rejected(){
+        let sessions = manager("redis://127.0.0.1:1");
+        let future = utils::now_secs() + 60;
+
+        let forged = format!("alice.s1.{}.{}", future, utils::create_uuid(&format!("alice.s1.{}", future), &"other-key".to_owned()));
+        let tampered = sessions.sign_access_token("alice", "s1").replacen("alice", "mallory", 1);
+        let past = format!("alice.s1.{}", utils::now_secs() - 1);
+        let expired = format!("{}.{}", past, utils::create_uuid(&past, &KEY.to_owned()));
+
+        for token in [forged.as_str(), tampered.as_str(), expired.as_str(), "garbage"]{
+            let status = sessions.authenticate(&bearer(token)).await.unwrap_err();
+            assert_eq!(status.code(), tonic::Code::Unauthenticated, "{token}");
+        }
+        let status = sessions.authenticate(&Request::new(())).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Unauthenticated);
+        assert_eq!(sessions.refresh("no-dot").await.unwrap_err().code(), tonic::Code::Unauthenticated);
+    }
+
+    fn redis_url()->String{
+        std::env::var("REDIS_URL").unwrap_or_else(|_| "redis://127.0.0.1:6379".to_owned())
+    }
+
+    /// Run with Redis listening:
+    /// `REDIS_URL=redis://localhost:6379 cargo test -- --ignored`
+    #[tokio::test]
+    #[ignore]
+    async fn sessions_issue_rotate_and_authorize(){
+        let sessions = manager(&redis_url());
+        let user = format!("user-{}", utils::random_hex(4));
+        let issued = sessions.create(&user, "laptop").await.unwrap();
+
+        let claims = sessions.authenticate(&bearer(&issued.access_token)).await.unwrap();
+        assert_eq!(claims, Claims{user_id: user.clone(), session_id: issued.session_id.clone()});
+        assert!(sessions.authorize(&bearer(&issued.access_token), &user).await.is_ok());
+        let status = sessions.authorize(&bearer(&issued.access_token), "someone-else").await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::PermissionDenied);
+
+        let renewed = sessions.refresh(&issued.refresh_token).await.unwrap();
+        assert_eq!(renewed.session_id, issued.session_id);
+        assert_eq!(renewed.cache_key, issued.cache_key);
+        assert_ne!(renewed.refresh_token, issued.refresh_token);
+        let listed = sessions.list(&user).await.unwrap();
+        assert_eq!(listed.len(), 1);
+        assert_eq!(listed[0].device_name, "laptop");
+
+        sessions.revoke(&user, &issued.session_id).await.unwrap();
+        assert!(sessions.authenticate(&bearer(&renewed.access_token)).await.is_err());
+        assert!(sessions.refresh(&renewed.refresh_token).await.is_err());
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn reusing_a_refresh_token_revokes_the_session(){
+        let sessions = manager(&redis_url());
+        let user = format!("user-{}", utils::random_hex(4));
+        let issued = sessions.create(&user, "phone").await.unwrap();
+        let renewed = sessions.refresh(&issued.refresh_token).await.unwrap();
+
+        let status = sessions.refresh(&issued.refresh_token).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Unauthenticated);
+        assert!(sessions.authenticate(&bearer(&renewed.access_token)).await.is_err());
+        assert!(sessions.refresh(&renewed.refresh_token).await.is_err());
+        assert!(sessions.list(&user).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn racing_refreshes_with_one_token_cannot_both_win(){
+        let sessions = manager(&redis_url());
+        let user = format!("user-{}", utils::random_hex(4));
+        let issued = sessions.create(&user, "tablet").await.unwrap();
+
+        let (a, b) = tokio::join!(sessions.refresh(&issued.refresh_token), sessions.refresh(&issued.refresh_token));
+        assert!(!(a.is_ok() && b.is_ok()));
+    }
+}
@@ -0,0 +1,124 @@
+use std::sync::Arc;
+
+use crate::mailer::Locale;
+
+
+/// Texts a phone number. There is no gateway wired in yet: `NoSms` refuses
+/// every send, and `ConsoleSms` prints the text for local development.
+#[tonic::async_trait]
+pub trait SmsSender: Send + Sync{
+    async fn send(&self, to: &str, text: &str)->Result<(), String>;
+}
+
+pub struct NoSms;
+
+#[tonic::async_trait]
+impl SmsSender for NoSms{
+    async fn send(&self, _to: &str, _text: &str)->Result<(), String>{
+        Err("phone numbers can't be verified: no SMS gateway is configured".to_owned())
+    }
+}
+
+/// Never use in production: anyone with the server log can read the codes.
+pub struct ConsoleSms;
+
+#[tonic::async_trait]
+impl SmsSender for ConsoleSms{
+    async fn send(&self, to: &str, text: &str)->Result<(), String>{
+        println!("SMS to {}: {}", to, text);
+        Ok(())
+    }
+}
+
+/// Every kind of text the server sends; the SMS side of `MailTemplate`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SmsTemplate{
+    Otp{ code: String, valid_minutes: u32 },
+    VerifyNewNumber{ code: String, valid_minutes: u32 },
+    // sent to the old number once the account's phone is replaced
+    IdentifierChanged{ new_address: String },
+}
+
+pub fn render(template: &SmsTemplate, locale: Locale, product: &str)->String{
+    match (template, locale){
+        (SmsTemplate::Otp{code, valid_minutes}, Locale::En) =>
+            format!("{} is your {} sign-in code. It expires in {} minutes.", code, product, valid_minutes),
+        (SmsTemplate::Otp{code, valid_minutes}, Locale::Es) =>
+            format!("{} es tu código para iniciar sesión en {}. Caduca en {} minutos.", code, product, valid_minutes),
+        (SmsTemplate::VerifyNewNumber{code, valid_minutes}, Locale::En) =>
+            format!("{} is your {} code to add this number. It expires in {} minutes.", code, product, valid_minutes),
+        (SmsTemplate::VerifyNewNumber{code, valid_minutes}, Locale::Es) =>
+            format!("{} es tu código de {} para añadir este número. Caduca en {} minutos.", code, product, valid_minutes),
+        (SmsTemplate::IdentifierChanged{new_address}, Locale::En) =>
+            format!("Your {} account no longer signs in with this number, it now uses {}. If this wasn't you, sign in and check your account.", product, new_address),
+        (SmsTemplate::IdentifierChanged{new_address}, Locale::Es) =>
+            format!("Tu cuenta de {} ya no inicia sesión con este número, ahora usa {}. Si no fuiste tú, inicia sesión y revisa tu cuenta.", product, new_address),
+    }
+}
+
+
+/// Renders templates and hands them to the sender; `Mailer` for phones.
+pub struct Texter{
+    sender: Arc<dyn SmsSender>,
+    product: String,
+}
+
+impl Texter{
+    pub fn new(sender: Arc<dyn SmsSender>, product: String)->Self{
+        Self{sender, product}
+    }
+
+    pub async fn send(&self, to: &str, template: &SmsTemplate, locale: Locale)->Result<(), String>{
+        self.sender.send(to, &render(template, locale, &self.product)).await
+    }
+}
+
+
+#[cfg(test)]
+pub(crate) mod fakes{
+    use super::*;
+    use std::sync::Mutex;
+
+    /// Keeps every text it is given, for tests.
+    #[derive(Default)]
+    pub struct CapturingSms{
+        sent: Mutex<Vec<(String, String)>>
+    }
+
+    impl CapturingSms{
+        pub fn sent(&self)->Vec<(String, String)>{
+            self.sent.lock().unwrap().clone()
+        }
+    }
+
+    #[tonic::async_trait]
+    impl SmsSender for CapturingSms{
+        async fn send(&self, to: &str, text: &str)->Result<(), String>{
+            self.sent.lock().unwrap().push((to.to_owned(), text.to_owned()));
+            Ok(())
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    #[tokio::test]
+    async fn texts_are_rendered_in_the_callers_language(){
+        let sms = Arc::new(fakes::CapturingSms::default());
+        let texter = Texter::new(sms.clone(), "ChatApp".to_owned());
+        let verify = SmsTemplate::VerifyNewNumber{code: "A1B2C3".to_owned(), valid_minutes: 10};
+
+        texter.send("+15550001", &verify, Locale::parse("es-MX")).await.unwrap();
+
+        let sent = sms.sent();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].0, "+15550001");
+        assert!(sent[0].1.starts_with("A1B2C3 es tu código de ChatApp"));
+        let otp = SmsTemplate::Otp{code: "D4E5F6".to_owned(), valid_minutes: 2};
+        assert_eq!(render(&otp, Locale::En, "ChatApp"), "D4E5F6 is your ChatApp sign-in code. It expires in 2 minutes.");
+        assert!(Texter::new(Arc::new(NoSms), "ChatApp".to_owned()).send("+15550001", &verify, Locale::En).await.is_err());
+    }
+}
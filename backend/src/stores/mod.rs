@@ -0,0 +1,346 @@
+use crate::utils;
+
+pub mod sqlite;
+
+pub type StoreResult<T> = Result<T, String>;
+
+
+/// A verified email address or phone number an account can log in with.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Identifier{
+    Email(String),
+    Phone(String),
+}
+
+impl Identifier{
+    /// Same rule the frontend uses: anything containing '@' is an email.
+    pub fn parse(raw: &str)->Self{
+        let raw = raw.trim();
+        if raw.contains('@'){
+            Identifier::Email(raw.to_lowercase())
+        }else{
+            Identifier::Phone(raw.chars().filter(|c| c.is_ascii_digit() || *c=='+').collect())
+        }
+    }
+
+    pub fn as_str(&self)->&str{
+        match self{
+            Identifier::Email(email) => email,
+            Identifier::Phone(phone) => phone,
+        }
+    }
+
+    /// What clients send to contact sync instead of the raw address.
+    pub fn hash(&self)->String{
+        utils::sha256_hex(self.as_str())
+    }
+}
+
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UserStatus{
+    Active,
+    Suspended,
+    Deleted,
+}
+
+impl UserStatus{
+    pub fn as_str(&self)->&'static str{
+        match self{
+            UserStatus::Active => "active",
+            UserStatus::Suspended => "suspended",
+            UserStatus::Deleted => "deleted",
+        }
+    }
+
+    pub fn parse(raw: &str)->Self{
+        match raw{
+            "suspended" => UserStatus::Suspended,
+            "deleted" => UserStatus::Deleted,
+            _ => UserStatus::Active,
+        }
+    }
+}
+
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct UserRecord{
+    pub id: String,
+    pub email: Option<String>,
+    pub phone: Option<String>,
+    pub display_name: String,
+    pub created_at: i64,
+    pub status: UserStatus,
+}
+
+impl UserRecord{
+    /// A fresh active account for someone who just verified `identifier`.
+    pub fn new(identifier: &Identifier)->Self{
+        let (email, phone) = match identifier{
+            Identifier::Email(email) => (Some(email.clone()), None),
+            Identifier::Phone(phone) => (None, Some(phone.clone())),
+        };
+        let display_name = match identifier{
+            Identifier::Email(email) => email.split('@').next().unwrap_or_default().to_owned(),
+            Identifier::Phone(phone) => phone.clone(),
+        };
+        Self{
+            id: utils::random_hex(16),
+            email,
+            phone,
+            display_name,
+            created_at: utils::now_secs(),
+            status: UserStatus::Active,
+        }
+    }
+}
+
+
+/// The public face of an account, as shown to other users.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Profile{
+    pub user_id: String,
+    pub display_name: String,
+    pub about: String,
+    pub avatar: Vec<u8>,
+    pub avatar_mime: String,
+}
+
+/// Fields left as `None` keep their current value.
+#[derive(Debug, Clone, Default)]
+pub struct ProfileUpdate{
+    pub display_name: Option<String>,
+    pub about: Option<String>,
+    pub avatar: Option<(Vec<u8>, String)>,
+}
+
+
+/// Persistent account storage. `UserService` only talks to this trait so the
+/// backing database can be swapped without touching the RPC handlers.
+#[tonic::async_trait]
+pub trait UserStore: Send + Sync{
+    async fn get_user(&self, user_id: &str)->StoreResult<Option<UserRecord>>;
+
+    async fn find_by_identifier(&self, identifier: &Identifier)->StoreResult<Option<UserRecord>>;
+
+    async fn insert_user(&self, user: &UserRecord)->StoreResult<()>;
+
+    /// Profiles of the active accounts among `user_ids`; unknown ids are skipped.
+    async fn get_profiles(&self, user_ids: &[String])->StoreResult<Vec<Profile>>;
+
+    /// Pairs each matching hash with the profile of the active account it belongs to.
+    async fn find_by_identifier_hashes(&self, hashes: &[String])->StoreResult<Vec<(String, Profile)>>;
+
+    /// Returns the updated profile, or `None` if there is no such account.
+    async fn update_profile(&self, user_id: &str, update: &ProfileUpdate)->StoreResult<Option<Profile>>;
+
+    /// Points the account's email (or phone) at `identifier`. Without `replace`
+    /// the account must not have one of that kind yet. Fails if another account
+    /// already uses it.
+    async fn set_identifier(&self, user_id: &str, identifier: &Identifier, replace: bool)->StoreResult<()>;
+
+    /// Erases everything the account holds (identifiers, profile, contacts,
+    /// blocks, 2FA, reports it filed, its reactions and read markers) and leaves a
+    /// `Deleted` tombstone so the id is never reused. Messages it sent are wiped to
+    /// deleted placeholders; peers keep their own messages and read state. Reports
+    /// filed against it are kept for moderation.
+    async fn delete_user(&self, user_id: &str)->StoreResult<()>;
+
+    /// Returns the account owning `identifier`, creating it on first login.
+    async fn find_or_create(&self, identifier: &Identifier)->StoreResult<UserRecord>{
+        if let Some(user) = self.find_by_identifier(identifier).await?{
+            return Ok(user);
+        }
+        let user = UserRecord::new(identifier);
+        self.insert_user(&user).await?;
+        Ok(user)
+    }
+}
+
+
+/// An abuse report waiting for a moderator.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Report{
+    pub reporter_id: String,
+    pub reported_id: String,
+    pub reason: String,
+    pub details: String,
+    pub created_at: i64,
+}
+
+
+/// Per-user contact lists, block lists and abuse reports.
+#[tonic::async_trait]
+pub trait ContactStore: Send + Sync{
+    async fn add_contact(&self, owner_id: &str, contact_id: &str)->StoreResult<()>;
+
+    async fn remove_contact(&self, owner_id: &str, contact_id: &str)->StoreResult<()>;
+
+    async fn list_contacts(&self, owner_id: &str)->StoreResult<Vec<Profile>>;
+
+    async fn block_user(&self, blocker_id: &str, blocked_id: &str)->StoreResult<()>;
+
+    async fn unblock_user(&self, blocker_id: &str, blocked_id: &str)->StoreResult<()>;
+
+    async fn list_blocked(&self, blocker_id: &str)->StoreResult<Vec<Profile>>;
+
+    async fn is_blocked(&self, blocker_id: &str, blocked_id: &str)->StoreResult<bool>;
+
+    /// Returns the id of the stored report.
+    async fn insert_report(&self, report: &Report)->StoreResult<i64>;
+
+    async fn list_reports_by(&self, reporter_id: &str)->StoreResult<Vec<Report>>;
+}
+
+
+/// A user's TOTP enrollment; `secret_encrypted` is opaque to the store.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TotpRecord{
+    pub secret_encrypted: Vec<u8>,
+    pub confirmed: bool,
+    // the last 30s window a code was accepted for, so a code can't be replayed
+    pub last_used_step: i64,
+}
+
+
+/// TOTP secrets and recovery codes for accounts with two-step verification.
+#[tonic::async_trait]
+pub trait TwoFactorStore: Send + Sync{
+    async fn get_totp(&self, user_id: &str)->StoreResult<Option<TotpRecord>>;
+
+    /// Starts (or restarts) an unconfirmed enrollment.
+    async fn put_pending_totp(&self, user_id: &str, secret_encrypted: &[u8])->StoreResult<()>;
+
+    /// Turns the pending enrollment on and replaces the recovery codes.
+    async fn confirm_totp(&self, user_id: &str, step: i64, recovery_code_hashes: &[String])->StoreResult<()>;
+
+    /// Records `step` as used; false if it (or a later one) already was.
+    async fn use_totp_step(&self, user_id: &str, step: i64)->StoreResult<bool>;
+
+    /// Burns a recovery code; false if it doesn't exist or was already used.
+    async fn use_recovery_code(&self, user_id: &str, code_hash: &str)->StoreResult<bool>;
+
+    async fn unused_recovery_codes(&self, user_id: &str)->StoreResult<i64>;
+
+    /// Removes the secret and every recovery code.
+    async fn delete_totp(&self, user_id: &str)->StoreResult<()>;
+}
+
+
+/// A message as kept on the server. `body` is empty once it was deleted for everyone.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StoredMessage{
+    pub id: i64,
+    pub sender_id: String,
+    pub recipient_id: String,
+    pub body: Vec<u8>,
+    pub sent_at: i64,
+    pub edited_at: Option<i64>,
+    pub deleted: bool,
+    pub reply_to: Option<i64>,
+}
+
+
+/// One person's emoji on a message.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StoredReaction{
+    pub message_id: i64,
+    pub user_id: String,
+    pub emoji: String,
+}
+
+
+/// A search result: the message plus its body split into plain and matching runs.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MessageMatch{
+    pub message: StoredMessage,
+    pub spans: Vec<(String, bool)>,
+}
+
+
+/// What to look for in a user's message history.
+#[derive(Debug, Clone, Default)]
+pub struct MessageQuery{
+    /// Words as the user typed them; every word has to appear, the last one as a prefix.
+    pub text: String,
+    /// Only the conversation with this user.
+    pub peer_id: Option<String>,
+    /// Only messages older than this id, for paging.
+    pub before_id: Option<i64>,
+    pub limit: i64,
+}
+
+
+/// One of a user's conversations and how much of it they have read.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConversationSummary{
+    pub peer_id: String,
+    pub last_read_id: i64,
+    /// Messages from the peer after `last_read_id`, not counting deleted or hidden ones.
+    pub unread_count: i64,
+    /// The newest message either side sent that `user_id` can still see.
+    pub last_message: StoredMessage,
+    /// How far the peer has received and read, for the ticks on our own messages.
+    pub peer_delivered_id: i64,
+    pub peer_read_id: i64,
+}
+
+
+/// Message history, so messages can be edited and deleted after they were sent.
+#[tonic::async_trait]
+pub trait MessageStore: Send + Sync{
+    async fn insert_message(&self, sender_id: &str, recipient_id: &str, body: &[u8], sent_at: i64, reply_to: Option<i64>)->StoreResult<StoredMessage>;
+
+    async fn get_message(&self, message_id: i64)->StoreResult<Option<StoredMessage>>;
+
+    /// Replaces the body; `None` if there is no such message or it was deleted.
+    async fn edit_message(&self, message_id: i64, body: &[u8], edited_at: i64)->StoreResult<Option<StoredMessage>>;
+
+    /// Wipes the body and reactions and marks the message deleted for both sides.
+    async fn delete_message(&self, message_id: i64)->StoreResult<()>;
+
+    /// Hides a message from one participant only.
+    async fn hide_message(&self, user_id: &str, message_id: i64)->StoreResult<()>;
+
+    async fn is_hidden(&self, user_id: &str, message_id: i64)->StoreResult<bool>;
+
+    /// Every message `user_id` sent or received and hasn't hidden, oldest first.
+    async fn list_user_messages(&self, user_id: &str)->StoreResult<Vec<StoredMessage>>;
+
+    /// False if `user_id` had already reacted with `emoji`.
+    async fn add_reaction(&self, message_id: i64, user_id: &str, emoji: &str)->StoreResult<bool>;
+
+    /// False if there was no such reaction.
+    async fn remove_reaction(&self, message_id: i64, user_id: &str, emoji: &str)->StoreResult<bool>;
+
+    /// Reactions on any of `message_ids`, oldest first.
+    async fn list_reactions(&self, message_ids: &[i64])->StoreResult<Vec<StoredReaction>>;
+
+    /// Newest first. Only messages `user_id` can still see, never deleted ones.
+    async fn search_messages(&self, user_id: &str, query: &MessageQuery)->StoreResult<Vec<MessageMatch>>;
+
+    /// Up to `count` messages either side of `message` in its conversation, as
+    /// `user_id` sees it, each side oldest first.
+    async fn message_context(&self, user_id: &str, message: &StoredMessage, count: i64)->StoreResult<(Vec<StoredMessage>, Vec<StoredMessage>)>;
+
+    /// A page of the conversation with `peer_id` as `user_id` sees it, oldest
+    /// first: the `limit` newest messages before `before_id`, or if `after_id`
+    /// is given, the `limit` oldest after it. Deleted messages are included.
+    async fn conversation_history(&self, user_id: &str, peer_id: &str, before_id: Option<i64>, after_id: Option<i64>, limit: i64)->StoreResult<Vec<StoredMessage>>;
+
+    /// Moves the read marker forward to `message_id` and returns where it ends up,
+    /// which is further along if another device already read past it.
+    async fn mark_read(&self, user_id: &str, peer_id: &str, message_id: i64)->StoreResult<i64>;
+
+    /// Like `mark_read`, for messages that reached one of `user_id`'s devices.
+    async fn mark_delivered(&self, user_id: &str, peer_id: &str, message_id: i64)->StoreResult<i64>;
+
+    /// Everyone `user_id` has visible messages with, most recently active first.
+    async fn list_conversations(&self, user_id: &str)->StoreResult<Vec<ConversationSummary>>;
+}
+
+
+/// Everything the services need from persistence, behind one handle.
+pub trait Store: UserStore + ContactStore + TwoFactorStore + MessageStore{}
+
+impl<T: UserStore + ContactStore + TwoFactorStore + MessageStore> Store for T{}
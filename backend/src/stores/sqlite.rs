@@ -0,0 +1,1288 @@
+use std::str::FromStr;
+
+use sqlx::{QueryBuilder, Row, Sqlite};
+use sqlx::sqlite::{SqliteConnectOptions, SqlitePool, SqlitePoolOptions, SqliteRow};
+
+use super::{ContactStore, ConversationSummary, Identifier, MessageMatch, MessageQuery, MessageStore, Profile, ProfileUpdate, Report, StoreResult, StoredMessage, StoredReaction, TotpRecord, TwoFactorStore, UserRecord, UserStatus, UserStore};
+use crate::utils;
+
+
+pub struct SqliteStore{
+    pool: SqlitePool
+}
+
+impl SqliteStore{
+    /// Opens (creating if needed) the database at `url` and runs pending migrations.
+    pub async fn connect(url: &str)->StoreResult<Self>{
+        let options = SqliteConnectOptions::from_str(url)
+            .map_err(|e| format!("invalid database url {}: {}", url, e))?
+            .create_if_missing(true)
+            .foreign_keys(true);
+
+        // every connection to ":memory:" is its own database, so keep exactly one alive
+        let in_memory = url.contains(":memory:");
+        let pool = SqlitePoolOptions::new()
+            .max_connections(if in_memory { 1 } else { 5 })
+            .idle_timeout(if in_memory { None } else { Some(std::time::Duration::from_secs(600)) })
+            .max_lifetime(if in_memory { None } else { Some(std::time::Duration::from_secs(1800)) })
+            .connect_with(options)
+            .await
+            .map_err(|e| format!("could not open database {}: {}", url, e))?;
+
+        sqlx::migrate!("./migrations")
+            .run(&pool)
+            .await
+            .map_err(|e| format!("migration failed: {}", e))?;
+
+        let store = Self{pool};
+        store.backfill_identifier_hashes().await?;
+        Ok(store)
+    }
+
+    /// Rows created before contact discovery existed have no hashes yet.
+    async fn backfill_identifier_hashes(&self)->StoreResult<()>{
+        let rows = sqlx::query(
+            "SELECT id, email, phone FROM users
+             WHERE (email IS NOT NULL AND email_hash IS NULL)
+                OR (phone IS NOT NULL AND phone_hash IS NULL)"
+        )
+            .fetch_all(&self.pool)
+            .await
+            .map_err(|e| e.to_string())?;
+
+        for row in rows{
+            let id: String = row.try_get("id").map_err(|e| e.to_string())?;
+            let email: Option<String> = row.try_get("email").map_err(|e| e.to_string())?;
+            let phone: Option<String> = row.try_get("phone").map_err(|e| e.to_string())?;
+
+            sqlx::query("UPDATE users SET email_hash = ?, phone_hash = ? WHERE id = ?")
+                .bind(email.map(|e| Identifier::Email(e).hash()))
+                .bind(phone.map(|p| Identifier::Phone(p).hash()))
+                .bind(&id)
+                .execute(&self.pool)
+                .await
+                .map_err(|e| e.to_string())?;
+        }
+        Ok(())
+    }
+}
+
+
+fn user_from_row(row: &SqliteRow)->Result<UserRecord, sqlx::Error>{
+    Ok(UserRecord{
+        id: row.try_get("id")?,
+        email: row.try_get("email")?,
+        phone: row.try_get("phone")?,
+        display_name: row.try_get("display_name")?,
+        created_at: row.try_get("created_at")?,
+        status: UserStatus::parse(row.try_get::<&str, _>("status")?),
+    })
+}
+
+fn profile_from_row(row: &SqliteRow)->Result<Profile, sqlx::Error>{
+    Ok(Profile{
+        user_id: row.try_get("id")?,
+        display_name: row.try_get("display_name")?,
+        about: row.try_get("about")?,
+        avatar: row.try_get::<Option<Vec<u8>>, _>("avatar")?.unwrap_or_default(),
+        avatar_mime: row.try_get("avatar_mime")?,
+    })
+}
+
+// highlight() wraps matching words in these; control characters never come from typed text
+const MATCH_START: char = '\u{2}';
+const MATCH_END: char = '\u{3}';
+
+/// Turns free text into an FTS5 query that can't be a syntax error: every word
+/// quoted, the last one as a prefix so results show up while still typing.
+fn fts_query(text: &str)->Option<String>{
+    let words: Vec<String> = text.split_whitespace()
+        .map(|word| word.replace('"', ""))
+        .filter(|word| !word.is_empty())
+        .map(|word| format!("\"{}\"", word))
+        .collect();
+    if words.is_empty(){
+        return None;
+    }
+    Some(format!("{}*", words.join(" ")))
+}
+
+/// Splits highlight() output into (text, is_match) runs.
+fn split_highlight(marked: &str)->Vec<(String, bool)>{
+    let mut spans = Vec::new();
+    let mut current = String::new();
+    let mut in_match = false;
+    for c in marked.chars(){
+        if c == MATCH_START || c == MATCH_END{
+            if !current.is_empty(){
+                spans.push((std::mem::take(&mut current), in_match));
+            }
+            in_match = c == MATCH_START;
+        }else{
+            current.push(c);
+        }
+    }
+    if !current.is_empty(){
+        spans.push((current, in_match));
+    }
+    spans
+}
+
+fn message_from_row(row: &SqliteRow)->Result<StoredMessage, sqlx::Error>{
+    Ok(StoredMessage{
+        id: row.try_get("id")?,
+        sender_id: row.try_get("sender_id")?,
+        recipient_id: row.try_get("recipient_id")?,
+        body: row.try_get("body")?,
+        sent_at: row.try_get("sent_at")?,
+        edited_at: row.try_get("edited_at")?,
+        deleted: row.try_get("deleted")?,
+        reply_to: row.try_get("reply_to")?,
+    })
+}
+
+
+#[tonic::async_trait]
+impl UserStore for SqliteStore{
+    async fn get_user(&self, user_id: &str)->StoreResult<Option<UserRecord>>{
+        let row = sqlx::query("SELECT * FROM users WHERE id = ?")
+            .bind(user_id)
+            .fetch_optional(&self.pool)
+            .await
+            .map_err(|e| e.to_string())?;
+
+        row.as_ref().map(user_from_row).transpose().map_err(|e| e.to_string())
+    }
+
+    async fn find_by_identifier(&self, identifier: &Identifier)->StoreResult<Option<UserRecord>>{
+        let sql = match identifier{
+            Identifier::Email(_) => "SELECT * FROM users WHERE email = ?",
+            Identifier::Phone(_) => "SELECT * FROM users WHERE phone = ?",
+        };
+        let row = sqlx::query(sql)
+            .bind(identifier.as_str())
+            .fetch_optional(&self.pool)
+            .await
+            .map_err(|e| e.to_string())?;
+
+        row.as_ref().map(user_from_row).transpose().map_err(|e| e.to_string())
+    }
+
+    async fn insert_user(&self, user: &UserRecord)->StoreResult<()>{
+        sqlx::query(
+            "INSERT INTO users (id, email, phone, email_hash, phone_hash, display_name, created_at, status)
+             VALUES (?, ?, ?, ?, ?, ?, ?, ?)"
+        )
+            .bind(&user.id)
+            .bind(&user.email)
+            .bind(&user.phone)
+            .bind(user.email.clone().map(|e| Identifier::Email(e).hash()))
+            .bind(user.phone.clone().map(|p| Identifier::Phone(p).hash()))
+            .bind(&user.display_name)
+            .bind(user.created_at)
+            .bind(user.status.as_str())
+            .execute(&self.pool)
+            .await
+            .map_err(|e| e.to_string())?;
+        Ok(())
+    }
+
+    async fn get_profiles(&self, user_ids: &[String])->StoreResult<Vec<Profile>>{
+        if user_ids.is_empty(){
+            return Ok(Vec::new());
+        }
+
+        let mut query = QueryBuilder::<Sqlite>::new(
+            "SELECT id, display_name, about, avatar, avatar_mime FROM users
+             WHERE status = 'active' AND id IN ("
+        );
+        let mut ids = query.separated(", ");
+        for user_id in user_ids{
+            ids.push_bind(user_id);
+        }
+        ids.push_unseparated(")");
+
+        let rows = query.build()
+            .fetch_all(&self.pool)
+            .await
+            .map_err(|e| e.to_string())?;
+
+        rows.iter().map(profile_from_row).collect::<Result<_, _>>().map_err(|e| e.to_string())
+    }
+
+    async fn find_by_identifier_hashes(&self, hashes: &[String])->StoreResult<Vec<(String, Profile)>>{
+        if hashes.is_empty(){
+            return Ok(Vec::new());
+        }
+
+        let mut query = QueryBuilder::<Sqlite>::new(
+            "SELECT id, display_name, about, avatar, avatar_mime, email_hash, phone_hash FROM users
+             WHERE status = 'active' AND (email_hash IN ("
+        );
+        let mut in_email = query.separated(", ");
+        for hash in hashes{
+            in_email.push_bind(hash);
+        }
+        query.push(") OR phone_hash IN (");
+        let mut in_phone = query.separated(", ");
+        for hash in hashes{
+            in_phone.push_bind(hash);
+        }
+        query.push("))");
+
+        let rows = query.build()
+            .fetch_all(&self.pool)
+            .await
+            .map_err(|e| e.to_string())?;
+
+        let mut found = Vec::new();
+        for row in rows.iter(){
+            let profile = profile_from_row(row).map_err(|e| e.to_string())?;
+            for column in ["email_hash", "phone_hash"]{
+                let hash: Option<String> = row.try_get(column).map_err(|e| e.to_string())?;
+                if let Some(hash) = hash.filter(|h| hashes.contains(h)){
+                    found.push((hash, profile.clone()));
+                }
+            }
+        }
+        Ok(found)
+    }
+
+    async fn update_profile(&self, user_id: &str, update: &ProfileUpdate)->StoreResult<Option<Profile>>{
+        let (avatar, avatar_mime) = match &update.avatar{
+            Some((bytes, mime)) => (Some(bytes.as_slice()), Some(mime.as_str())),
+            None => (None, None),
+        };
+
+        let result = sqlx::query(
+            "UPDATE users SET
+                display_name = COALESCE(?, display_name),
+                about = COALESCE(?, about),
+                avatar = COALESCE(?, avatar),
+                avatar_mime = COALESCE(?, avatar_mime)
+             WHERE id = ? AND status = 'active'"
+        )
+            .bind(&update.display_name)
+            .bind(&update.about)
+            .bind(avatar)
+            .bind(avatar_mime)
+            .bind(user_id)
+            .execute(&self.pool)
+            .await
+            .map_err(|e| e.to_string())?;
+
+        if result.rows_affected() == 0{
+            return Ok(None);
+        }
+        Ok(self.get_profiles(&[user_id.to_owned()]).await?.pop())
+    }
+
+    async fn set_identifier(&self, user_id: &str, identifier: &Identifier, replace: bool)->StoreResult<()>{
+        let (sql, kind) = match identifier{
+            Identifier::Email(_) => ("UPDATE users SET email = ?, email_hash = ? WHERE id = ? AND (? OR email IS NULL)", "an email"),
+            Identifier::Phone(_) => ("UPDATE users SET phone = ?, phone_hash = ? WHERE id = ? AND (? OR phone IS NULL)", "a phone"),
+        };
+        let result = sqlx::query(sql)
+            .bind(identifier.as_str())
+            .bind(identifier.hash())
+            .bind(user_id)
+            .bind(replace)
+            .execute(&self.pool)
+            .await
+            .map_err(|e| match e.as_database_error(){
+                Some(db) if db.is_unique_violation() => format!("{} is used by another account", identifier.as_str()),
+                _ => e.to_string()
+            })?;
+        if result.rows_affected() == 0{
+            return Err(format!("the account already has {} or does not exist", kind));
+        }
+        Ok(())
+    }
+
+    async fn delete_user(&self, user_id: &str)->StoreResult<()>{
+        let mut tx = self.pool.begin().await.map_err(|e| e.to_string())?;
+
+        sqlx::query(
+            "UPDATE users SET
+                email = NULL, phone = NULL, email_hash = NULL, phone_hash = NULL,
+                display_name = '', about = '', avatar = NULL, avatar_mime = '',
+                status = 'deleted'
+             WHERE id = ?"
+        )
+            .bind(user_id)
+            .execute(&mut *tx)
+            .await
+            .map_err(|e| e.to_string())?;
+
+        let cleanups = [
+            "DELETE FROM contacts WHERE owner_id = ?1 OR contact_id = ?1",
+            "DELETE FROM blocks WHERE blocker_id = ?1 OR blocked_id = ?1",
+            "DELETE FROM user_totp WHERE user_id = ?1",
+            "DELETE FROM recovery_codes WHERE user_id = ?1",
+            "DELETE FROM reports WHERE reporter_id = ?1",
+            "DELETE FROM message_hidden WHERE user_id = ?1",
+            "DELETE FROM message_reactions WHERE user_id = ?1",
+            "DELETE FROM conversation_reads WHERE user_id = ?1",
+            // the peer keeps their side of each conversation: what the account
+            // sent becomes a deleted-for-everyone placeholder, what it received stays
+            "UPDATE messages SET body = x'', deleted = 1 WHERE sender_id = ?1",
+        ];
+        for sql in cleanups{
+            sqlx::query(sql)
+                .bind(user_id)
+                .execute(&mut *tx)
+                .await
+                .map_err(|e| e.to_string())?;
+        }
+
+        tx.commit().await.map_err(|e| e.to_string())
+    }
+}
+
+
+#[tonic::async_trait]
+impl ContactStore for SqliteStore{
+    async fn add_contact(&self, owner_id: &str, contact_id: &str)->StoreResult<()>{
+        sqlx::query("INSERT OR IGNORE INTO contacts (owner_id, contact_id, added_at) VALUES (?, ?, ?)")
+            .bind(owner_id)
+            .bind(contact_id)
+            .bind(utils::now_secs())
+            .execute(&self.pool)
+            .await
+            .map_err(|e| e.to_string())?;
+        Ok(())
+    }
+
+    async fn remove_contact(&self, owner_id: &str, contact_id: &str)->StoreResult<()>{
+        sqlx::query("DELETE FROM contacts WHERE owner_id = ? AND contact_id = ?")
+            .bind(owner_id)
+            .bind(contact_id)
+            .execute(&self.pool)
+            .await
+            .map_err(|e| e.to_string())?;
+        Ok(())
+    }
+
+    async fn list_contacts(&self, owner_id: &str)->StoreResult<Vec<Profile>>{
+        let rows = sqlx::query(
+            "SELECT u.id, u.display_name, u.about, u.avatar, u.avatar_mime
+             FROM contacts c JOIN users u ON u.id = c.contact_id
+             WHERE c.owner_id = ? AND u.status = 'active'
+             ORDER BY u.display_name COLLATE NOCASE"
+        )
+            .bind(owner_id)
+            .fetch_all(&self.pool)
+            .await
+            .map_err(|e| e.to_string())?;
+
+        rows.iter().map(profile_from_row).collect::<Result<_, _>>().map_err(|e| e.to_string())
+    }
+
+    async fn block_user(&self, blocker_id: &str, blocked_id: &str)->StoreResult<()>{
+        sqlx::query("INSERT OR IGNORE INTO blocks (blocker_id, blocked_id, blocked_at) VALUES (?, ?, ?)")
+            .bind(blocker_id)
+            .bind(blocked_id)
+            .bind(utils::now_secs())
+            .execute(&self.pool)
+            .await
+            .map_err(|e| e.to_string())?;
+        Ok(())
+    }
+
+    async fn unblock_user(&self, blocker_id: &str, blocked_id: &str)->StoreResult<()>{
+        sqlx::query("DELETE FROM blocks WHERE blocker_id = ? AND blocked_id = ?")
+            .bind(blocker_id)
+            .bind(blocked_id)
+            .execute(&self.pool)
+            .await
+            .map_err(|e| e.to_string())?;
+        Ok(())
+    }
+
+    async fn list_blocked(&self, blocker_id: &str)->StoreResult<Vec<Profile>>{
+        let rows = sqlx::query(
+            "SELECT u.id, u.display_name, u.about, u.avatar, u.avatar_mime
+             FROM blocks b JOIN users u ON u.id = b.blocked_id
+             WHERE b.blocker_id = ?
+             ORDER BY b.blocked_at DESC"
+        )
+            .bind(blocker_id)
+            .fetch_all(&self.pool)
+            .await
+            .map_err(|e| e.to_string())?;
+
+        rows.iter().map(profile_from_row).collect::<Result<_, _>>().map_err(|e| e.to_string())
+    }
+
+    async fn is_blocked(&self, blocker_id: &str, blocked_id: &str)->StoreResult<bool>{
+        let row = sqlx::query("SELECT 1 FROM blocks WHERE blocker_id = ? AND blocked_id = ?")
+            .bind(blocker_id)
+            .bind(blocked_id)
+            .fetch_optional(&self.pool)
+            .await
+            .map_err(|e| e.to_string())?;
+        Ok(row.is_some())
+    }
+
+    async fn insert_report(&self, report: &Report)->StoreResult<i64>{
+        let result = sqlx::query(
+            "INSERT INTO reports (reporter_id, reported_id, reason, details, created_at)
+             VALUES (?, ?, ?, ?, ?)"
+        )
+            .bind(&report.reporter_id)
+            .bind(&report.reported_id)
+            .bind(&report.reason)
+            .bind(&report.details)
+            .bind(report.created_at)
+            .execute(&self.pool)
+            .await
+            .map_err(|e| e.to_string())?;
+        Ok(result.last_insert_rowid())
+    }
+
+    async fn list_reports_by(&self, reporter_id: &str)->StoreResult<Vec<Report>>{
+        let rows = sqlx::query(
+            "SELECT reporter_id, reported_id, reason, details, created_at
+             FROM reports WHERE reporter_id = ? ORDER BY created_at"
+        )
+            .bind(reporter_id)
+            .fetch_all(&self.pool)
+            .await
+            .map_err(|e| e.to_string())?;
+
+        rows.iter()
+            .map(|row| -> Result<Report, sqlx::Error>{
+                Ok(Report{
+                    reporter_id: row.try_get("reporter_id")?,
+                    reported_id: row.try_get("reported_id")?,
+                    reason: row.try_get("reason")?,
+                    details: row.try_get("details")?,
+                    created_at: row.try_get("created_at")?,
+                })
+            })
+            .collect::<Result<_, _>>()
+            .map_err(|e| e.to_string())
+    }
+}
+
+
+#[tonic::async_trait]
+impl TwoFactorStore for SqliteStore{
+    async fn get_totp(&self, user_id: &str)->StoreResult<Option<TotpRecord>>{
+        let row = sqlx::query("SELECT secret_encrypted, confirmed, last_used_step FROM user_totp WHERE user_id = ?")
+            .bind(user_id)
+            .fetch_optional(&self.pool)
+            .await
+            .map_err(|e| e.to_string())?;
+
+        row.map(|row| -> Result<TotpRecord, sqlx::Error>{
+            Ok(TotpRecord{
+                secret_encrypted: row.try_get("secret_encrypted")?,
+                confirmed: row.try_get("confirmed")?,
+                last_used_step: row.try_get("last_used_step")?,
+            })
+        }).transpose().map_err(|e| e.to_string())
+    }
+
+    async fn put_pending_totp(&self, user_id: &str, secret_encrypted: &[u8])->StoreResult<()>{
+        // never overwrites a confirmed secret
+        sqlx::query(
+            "INSERT INTO user_totp (user_id, secret_encrypted, confirmed, last_used_step, created_at)
+             VALUES (?, ?, 0, 0, ?)
+             ON CONFLICT (user_id) DO UPDATE SET
+                secret_encrypted = excluded.secret_encrypted,
+                created_at = excluded.created_at
+             WHERE user_totp.confirmed = 0"
+        )
+            .bind(user_id)
+            .bind(secret_encrypted)
+            .bind(utils::now_secs())
+            .execute(&self.pool)
+            .await
+            .map_err(|e| e.to_string())?;
+        Ok(())
+    }
+
+    async fn confirm_totp(&self, user_id: &str, step: i64, recovery_code_hashes: &[String])->StoreResult<()>{
+        let mut tx = self.pool.begin().await.map_err(|e| e.to_string())?;
+
+        sqlx::query("UPDATE user_totp SET confirmed = 1, last_used_step = ? WHERE user_id = ?")
+            .bind(step)
+            .bind(user_id)
+            .execute(&mut *tx)
+            .await
+            .map_err(|e| e.to_string())?;
+        sqlx::query("DELETE FROM recovery_codes WHERE user_id = ?")
+            .bind(user_id)
+            .execute(&mut *tx)
+            .await
+            .map_err(|e| e.to_string())?;
+        for code_hash in recovery_code_hashes{
+            sqlx::query("INSERT INTO recovery_codes (user_id, code_hash) VALUES (?, ?)")
+                .bind(user_id)
+                .bind(code_hash)
+                .execute(&mut *tx)
+                .await
+                .map_err(|e| e.to_string())?;
+        }
+
+        tx.commit().await.map_err(|e| e.to_string())
+    }
+
+    async fn use_totp_step(&self, user_id: &str, step: i64)->StoreResult<bool>{
+        let result = sqlx::query("UPDATE user_totp SET last_used_step = ? WHERE user_id = ? AND last_used_step < ?")
+            .bind(step)
+            .bind(user_id)
+            .bind(step)
+            .execute(&self.pool)
+            .await
+            .map_err(|e| e.to_string())?;
+        Ok(result.rows_affected() == 1)
+    }
+
+    async fn use_recovery_code(&self, user_id: &str, code_hash: &str)->StoreResult<bool>{
+        let result = sqlx::query("UPDATE recovery_codes SET used_at = ? WHERE user_id = ? AND code_hash = ? AND used_at IS NULL")
+            .bind(utils::now_secs())
+            .bind(user_id)
+            .bind(code_hash)
+            .execute(&self.pool)
+            .await
+            .map_err(|e| e.to_string())?;
+        Ok(result.rows_affected() == 1)
+    }
+
+    async fn unused_recovery_codes(&self, user_id: &str)->StoreResult<i64>{
+        sqlx::query_scalar("SELECT COUNT(*) FROM recovery_codes WHERE user_id = ? AND used_at IS NULL")
+            .bind(user_id)
+            .fetch_one(&self.pool)
+            .await
+            .map_err(|e| e.to_string())
+    }
+
+    async fn delete_totp(&self, user_id: &str)->StoreResult<()>{
+        let mut tx = self.pool.begin().await.map_err(|e| e.to_string())?;
+        for table in ["user_totp", "recovery_codes"]{
+            sqlx::query(&format!("DELETE FROM {} WHERE user_id = ?", table))
+                .bind(user_id)
+                .execute(&mut *tx)
+                .await
+                .map_err(|e| e.to_string())?;
+        }
+        tx.commit().await.map_err(|e| e.to_string())
+    }
+}
+
+
+#[tonic::async_trait]
+impl MessageStore for SqliteStore{
+    async fn insert_message(&self, sender_id: &str, recipient_id: &str, body: &[u8], sent_at: i64, reply_to: Option<i64>)->StoreResult<StoredMessage>{
+        let result = sqlx::query("INSERT INTO messages (sender_id, recipient_id, body, sent_at, reply_to) VALUES (?, ?, ?, ?, ?)")
+            .bind(sender_id)
+            .bind(recipient_id)
+            .bind(body)
+            .bind(sent_at)
+            .bind(reply_to)
+            .execute(&self.pool)
+            .await
+            .map_err(|e| e.to_string())?;
+
+        Ok(StoredMessage{
+            id: result.last_insert_rowid(),
+            sender_id: sender_id.to_owned(),
+            recipient_id: recipient_id.to_owned(),
+            body: body.to_vec(),
+            sent_at,
+            edited_at: None,
+            deleted: false,
+            reply_to,
+        })
+    }
+
+    async fn get_message(&self, message_id: i64)->StoreResult<Option<StoredMessage>>{
+        let row = sqlx::query("SELECT * FROM messages WHERE id = ?")
+            .bind(message_id)
+            .fetch_optional(&self.pool)
+            .await
+            .map_err(|e| e.to_string())?;
+
+        row.as_ref().map(message_from_row).transpose().map_err(|e| e.to_string())
+    }
+
+    async fn edit_message(&self, message_id: i64, body: &[u8], edited_at: i64)->StoreResult<Option<StoredMessage>>{
+        let result = sqlx::query("UPDATE messages SET body = ?, edited_at = ? WHERE id = ? AND deleted = 0")
+            .bind(body)
+            .bind(edited_at)
+            .bind(message_id)
+            .execute(&self.pool)
+            .await
+            .map_err(|e| e.to_string())?;
+
+        if result.rows_affected() == 0{
+            return Ok(None);
+        }
+        self.get_message(message_id).await
+    }
+
+    async fn delete_message(&self, message_id: i64)->StoreResult<()>{
+        let mut tx = self.pool.begin().await.map_err(|e| e.to_string())?;
+        for sql in ["UPDATE messages SET body = x'', deleted = 1 WHERE id = ?", "DELETE FROM message_reactions WHERE message_id = ?"]{
+            sqlx::query(sql)
+                .bind(message_id)
+                .execute(&mut *tx)
+                .await
+                .map_err(|e| e.to_string())?;
+        }
+        tx.commit().await.map_err(|e| e.to_string())
+    }
+
+    async fn hide_message(&self, user_id: &str, message_id: i64)->StoreResult<()>{
+        sqlx::query("INSERT OR IGNORE INTO message_hidden (user_id, message_id) VALUES (?, ?)")
+            .bind(user_id)
+            .bind(message_id)
+            .execute(&self.pool)
+            .await
+            .map_err(|e| e.to_string())?;
+        Ok(())
+    }
+
+    async fn is_hidden(&self, user_id: &str, message_id: i64)->StoreResult<bool>{
+        let row = sqlx::query("SELECT 1 FROM message_hidden WHERE user_id = ? AND message_id = ?")
+            .bind(user_id)
+            .bind(message_id)
+            .fetch_optional(&self.pool)
+            .await
+            .map_err(|e| e.to_string())?;
+        Ok(row.is_some())
+    }
+
+    async fn list_user_messages(&self, user_id: &str)->StoreResult<Vec<StoredMessage>>{
+        let rows = sqlx::query(
+            "SELECT * FROM messages m
+             WHERE (m.sender_id = ?1 OR m.recipient_id = ?1)
+               AND NOT EXISTS (SELECT 1 FROM message_hidden h WHERE h.user_id = ?1 AND h.message_id = m.id)
+             ORDER BY m.id"
+        )
+            .bind(user_id)
+            .fetch_all(&self.pool)
+            .await
+            .map_err(|e| e.to_string())?;
+
+        rows.iter().map(message_from_row).collect::<Result<_, _>>().map_err(|e| e.to_string())
+    }
+
+    async fn add_reaction(&self, message_id: i64, user_id: &str, emoji: &str)->StoreResult<bool>{
+        let result = sqlx::query("INSERT OR IGNORE INTO message_reactions (message_id, user_id, emoji, reacted_at) VALUES (?, ?, ?, ?)")
+            .bind(message_id)
+            .bind(user_id)
+            .bind(emoji)
+            .bind(utils::now_secs())
+            .execute(&self.pool)
+            .await
+            .map_err(|e| e.to_string())?;
+        Ok(result.rows_affected() == 1)
+    }
+
+    async fn remove_reaction(&self, message_id: i64, user_id: &str, emoji: &str)->StoreResult<bool>{
+        let result = sqlx::query("DELETE FROM message_reactions WHERE message_id = ? AND user_id = ? AND emoji = ?")
+            .bind(message_id)
+            .bind(user_id)
+            .bind(emoji)
+            .execute(&self.pool)
+            .await
+            .map_err(|e| e.to_string())?;
+        Ok(result.rows_affected() == 1)
+    }
+
+    async fn list_reactions(&self, message_ids: &[i64])->StoreResult<Vec<StoredReaction>>{
+        if message_ids.is_empty(){
+            return Ok(Vec::new());
+        }
+
+        let mut query = QueryBuilder::<Sqlite>::new("SELECT message_id, user_id, emoji FROM message_reactions WHERE message_id IN (");
+        let mut ids = query.separated(", ");
+        for message_id in message_ids{
+            ids.push_bind(message_id);
+        }
+        ids.push_unseparated(") ORDER BY reacted_at, rowid");
+
+        let rows = query.build()
+            .fetch_all(&self.pool)
+            .await
+            .map_err(|e| e.to_string())?;
+
+        rows.iter()
+            .map(|row| -> Result<StoredReaction, sqlx::Error>{
+                Ok(StoredReaction{
+                    message_id: row.try_get("message_id")?,
+                    user_id: row.try_get("user_id")?,
+                    emoji: row.try_get("emoji")?,
+                })
+            })
+            .collect::<Result<_, _>>()
+            .map_err(|e| e.to_string())
+    }
+
+    async fn search_messages(&self, user_id: &str, query: &MessageQuery)->StoreResult<Vec<MessageMatch>>{
+        let Some(fts) = fts_query(&query.text) else {
+            return Ok(Vec::new());
+        };
+
+        let mut builder = QueryBuilder::<Sqlite>::new("SELECT m.*, highlight(message_search, 0, ");
+        builder.push_bind(MATCH_START.to_string())
+            .push(", ")
+            .push_bind(MATCH_END.to_string())
+            .push(") AS marked FROM message_search JOIN messages m ON m.id = message_search.rowid WHERE message_search MATCH ")
+            .push_bind(fts)
+            .push(" AND m.deleted = 0 AND (m.sender_id = ")
+            .push_bind(user_id)
+            .push(" OR m.recipient_id = ")
+            .push_bind(user_id)
+            .push(") AND NOT EXISTS (SELECT 1 FROM message_hidden h WHERE h.message_id = m.id AND h.user_id = ")
+            .push_bind(user_id)
+            .push(")");
+        if let Some(peer_id) = &query.peer_id{
+            builder.push(" AND (m.sender_id = ")
+                .push_bind(peer_id)
+                .push(" OR m.recipient_id = ")
+                .push_bind(peer_id)
+                .push(")");
+        }
+        if let Some(before_id) = query.before_id{
+            builder.push(" AND m.id < ").push_bind(before_id);
+        }
+        builder.push(" ORDER BY m.id DESC LIMIT ").push_bind(query.limit);
+
+        let rows = builder.build()
+            .fetch_all(&self.pool)
+            .await
+            .map_err(|e| e.to_string())?;
+
+        rows.iter()
+            .map(|row| -> Result<MessageMatch, sqlx::Error>{
+                Ok(MessageMatch{
+                    message: message_from_row(row)?,
+                    spans: split_highlight(row.try_get("marked")?),
+                })
+            })
+            .collect::<Result<_, _>>()
+            .map_err(|e| e.to_string())
+    }
+
+    async fn message_context(&self, user_id: &str, message: &StoredMessage, count: i64)->StoreResult<(Vec<StoredMessage>, Vec<StoredMessage>)>{
+        let peer_id = if message.sender_id == user_id { &message.recipient_id } else { &message.sender_id };
+        let conversation =
+            "((m.sender_id = ?1 AND m.recipient_id = ?2) OR (m.sender_id = ?2 AND m.recipient_id = ?1))
+             AND NOT EXISTS (SELECT 1 FROM message_hidden h WHERE h.message_id = m.id AND h.user_id = ?1)";
+
+        let mut sides = Vec::new();
+        for (comparison, order) in [("<", "DESC"), (">", "ASC")]{
+            let sql = format!("SELECT * FROM messages m WHERE {} AND m.id {} ?3 ORDER BY m.id {} LIMIT ?4", conversation, comparison, order);
+            let rows = sqlx::query(&sql)
+                .bind(user_id)
+                .bind(peer_id)
+                .bind(message.id)
+                .bind(count)
+                .fetch_all(&self.pool)
+                .await
+                .map_err(|e| e.to_string())?;
+            let messages: Vec<StoredMessage> = rows.iter().map(message_from_row).collect::<Result<_, _>>().map_err(|e| e.to_string())?;
+            sides.push(messages);
+        }
+
+        let after = sides.pop().unwrap_or_default();
+        let mut before = sides.pop().unwrap_or_default();
+        before.reverse();
+        Ok((before, after))
+    }
+
+    async fn conversation_history(&self, user_id: &str, peer_id: &str, before_id: Option<i64>, after_id: Option<i64>, limit: i64)->StoreResult<Vec<StoredMessage>>{
+        let (bound, order) = match after_id{
+            Some(_) => ("m.id > ?3", "ASC"),
+            None => ("m.id < ?3", "DESC"),
+        };
+        let sql = format!(
+            "SELECT * FROM messages m
+             WHERE ((m.sender_id = ?1 AND m.recipient_id = ?2) OR (m.sender_id = ?2 AND m.recipient_id = ?1))
+               AND NOT EXISTS (SELECT 1 FROM message_hidden h WHERE h.message_id = m.id AND h.user_id = ?1)
+               AND {}
+             ORDER BY m.id {} LIMIT ?4",
+            bound, order
+        );
+        let rows = sqlx::query(&sql)
+            .bind(user_id)
+            .bind(peer_id)
+            .bind(after_id.or(before_id).unwrap_or(i64::MAX))
+            .bind(limit)
+            .fetch_all(&self.pool)
+            .await
+            .map_err(|e| e.to_string())?;
+
+        let mut messages: Vec<StoredMessage> = rows.iter().map(message_from_row).collect::<Result<_, _>>().map_err(|e| e.to_string())?;
+        if after_id.is_none(){
+            messages.reverse();
+        }
+        Ok(messages)
+    }
+
+    async fn mark_read(&self, user_id: &str, peer_id: &str, message_id: i64)->StoreResult<i64>{
+        sqlx::query(
+            "INSERT INTO conversation_reads (user_id, peer_id, last_read_id, last_delivered_id, updated_at)
+             VALUES (?1, ?2, ?3, ?3, ?4)
+             ON CONFLICT (user_id, peer_id) DO UPDATE SET
+                last_read_id = excluded.last_read_id,
+                last_delivered_id = MAX(conversation_reads.last_delivered_id, excluded.last_read_id),
+                updated_at = excluded.updated_at
+             WHERE excluded.last_read_id > conversation_reads.last_read_id"
+        )
+            .bind(user_id)
+            .bind(peer_id)
+            .bind(message_id)
+            .bind(utils::now_secs())
+            .execute(&self.pool)
+            .await
+            .map_err(|e| e.to_string())?;
+
+        sqlx::query_scalar("SELECT last_read_id FROM conversation_reads WHERE user_id = ? AND peer_id = ?")
+            .bind(user_id)
+            .bind(peer_id)
+            .fetch_one(&self.pool)
+            .await
+            .map_err(|e| e.to_string())
+    }
+
+    async fn mark_delivered(&self, user_id: &str, peer_id: &str, message_id: i64)->StoreResult<i64>{
+        sqlx::query(
+            "INSERT INTO conversation_reads (user_id, peer_id, last_read_id, last_delivered_id, updated_at)
+             VALUES (?, ?, 0, ?, ?)
+             ON CONFLICT (user_id, peer_id) DO UPDATE SET
+                last_delivered_id = excluded.last_delivered_id,
+                updated_at = excluded.updated_at
+             WHERE excluded.last_delivered_id > conversation_reads.last_delivered_id"
+        )
+            .bind(user_id)
+            .bind(peer_id)
+            .bind(message_id)
+            .bind(utils::now_secs())
+            .execute(&self.pool)
+            .await
+            .map_err(|e| e.to_string())?;
+
+        sqlx::query_scalar("SELECT last_delivered_id FROM conversation_reads WHERE user_id = ? AND peer_id = ?")
+            .bind(user_id)
+            .bind(peer_id)
+            .fetch_one(&self.pool)
+            .await
+            .map_err(|e| e.to_string())
+    }
+
+    async fn list_conversations(&self, user_id: &str)->StoreResult<Vec<ConversationSummary>>{
+        let rows = sqlx::query(
+            "WITH visible AS (
+                SELECT m.*, CASE WHEN m.sender_id = ?1 THEN m.recipient_id ELSE m.sender_id END AS peer_id
+                FROM messages m
+                WHERE (m.sender_id = ?1 OR m.recipient_id = ?1)
+                  AND NOT EXISTS (SELECT 1 FROM message_hidden h WHERE h.message_id = m.id AND h.user_id = ?1)
+             ),
+             summary AS (
+                SELECT v.peer_id,
+                       COALESCE(r.last_read_id, 0) AS last_read_id,
+                       SUM(CASE WHEN v.sender_id = v.peer_id AND v.deleted = 0 AND v.id > COALESCE(r.last_read_id, 0)
+                                THEN 1 ELSE 0 END) AS unread_count,
+                       MAX(v.id) AS last_id
+                FROM visible v
+                LEFT JOIN conversation_reads r ON r.user_id = ?1 AND r.peer_id = v.peer_id
+                GROUP BY v.peer_id
+             )
+             SELECT s.peer_id, s.last_read_id, s.unread_count,
+                    COALESCE(p.last_delivered_id, 0) AS peer_delivered_id,
+                    COALESCE(p.last_read_id, 0) AS peer_read_id,
+                    m.*
+             FROM summary s
+             JOIN messages m ON m.id = s.last_id
+             LEFT JOIN conversation_reads p ON p.user_id = s.peer_id AND p.peer_id = ?1
+             ORDER BY m.id DESC"
+        )
+            .bind(user_id)
+            .fetch_all(&self.pool)
+            .await
+            .map_err(|e| e.to_string())?;
+
+        rows.iter()
+            .map(|row| -> Result<ConversationSummary, sqlx::Error>{
+                Ok(ConversationSummary{
+                    peer_id: row.try_get("peer_id")?,
+                    last_read_id: row.try_get("last_read_id")?,
+                    unread_count: row.try_get("unread_count")?,
+                    last_message: message_from_row(row)?,
+                    peer_delivered_id: row.try_get("peer_delivered_id")?,
+                    peer_read_id: row.try_get("peer_read_id")?,
+                })
+            })
+            .collect::<Result<_, _>>()
+            .map_err(|e| e.to_string())
+    }
+}
+
+
+/// An empty in-memory store, for tests anywhere in the crate.
+#[cfg(test)]
+pub(crate) async fn test_store()->std::sync::Arc<SqliteStore>{
+    std::sync::Arc::new(SqliteStore::connect("sqlite::memory:").await.unwrap())
+}
+
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    #[tokio::test]
+    async fn find_or_create_is_idempotent(){
+        let store = SqliteStore::connect("sqlite::memory:").await.unwrap();
+        let identifier = Identifier::parse("Alice@Example.com");
+
+        let created = store.find_or_create(&identifier).await.unwrap();
+        let found = store.find_or_create(&identifier).await.unwrap();
+
+        assert_eq!(created, found);
+        assert_eq!(created.email.as_deref(), Some("alice@example.com"));
+        assert_eq!(created.status, UserStatus::Active);
+        assert_eq!(store.get_user(&created.id).await.unwrap(), Some(created));
+    }
+
+    #[tokio::test]
+    async fn old_rows_get_hashes_and_are_found_by_them(){
+        let store = SqliteStore::connect("sqlite::memory:").await.unwrap();
+        // rows written before 0003 carry no hashes
+        for (id, email, phone, status) in [
+            ("both", Some("frank@example.com"), Some("+15550001"), "active"),
+            ("phone-only", None, Some("+15550002"), "active"),
+            ("gone", Some("gone@example.com"), None, "deleted"),
+        ]{
+            sqlx::query("INSERT INTO users (id, email, phone, created_at, status) VALUES (?, ?, ?, 0, ?)")
+                .bind(id).bind(email).bind(phone).bind(status)
+                .execute(&store.pool).await.unwrap();
+        }
+
+        store.backfill_identifier_hashes().await.unwrap();
+
+        let email = Identifier::parse("frank@example.com").hash();
+        let phone = Identifier::parse("+1 555 0001").hash();
+        let other = Identifier::parse("+15550002").hash();
+        let gone = Identifier::parse("gone@example.com").hash();
+        let unknown = Identifier::parse("nobody@example.com").hash();
+
+        let mut found: Vec<(String, String)> = store.find_by_identifier_hashes(&[email.clone(), phone.clone(), other.clone(), gone, unknown]).await.unwrap()
+            .into_iter().map(|(hash, profile)| (hash, profile.user_id)).collect();
+        found.sort();
+        let mut expected = vec![(email, "both".to_owned()), (phone, "both".to_owned()), (other, "phone-only".to_owned())];
+        expected.sort();
+        assert_eq!(found, expected);
+        assert!(store.find_by_identifier_hashes(&[]).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn identifiers_are_attached_or_replaced_but_never_shared(){
+        let store = SqliteStore::connect("sqlite::memory:").await.unwrap();
+        let gina = store.find_or_create(&Identifier::parse("gina@example.com")).await.unwrap();
+        let hank = store.find_or_create(&Identifier::parse("hank@example.com")).await.unwrap();
+        let phone = Identifier::parse("+1 555 0003");
+
+        store.set_identifier(&gina.id, &phone, false).await.unwrap();
+        // attaching never overwrites
+        let err = store.set_identifier(&gina.id, &Identifier::parse("gina@new.example"), false).await.unwrap_err();
+        assert_eq!(err, "the account already has an email or does not exist");
+        store.set_identifier(&gina.id, &Identifier::parse("Gina@New.example"), true).await.unwrap();
+
+        let user = store.get_user(&gina.id).await.unwrap().unwrap();
+        assert_eq!(user.email.as_deref(), Some("gina@new.example"));
+        assert_eq!(user.phone.as_deref(), Some("+15550003"));
+        assert_eq!(store.find_by_identifier(&phone).await.unwrap().map(|u| u.id), Some(gina.id.clone()));
+        assert_eq!(store.find_by_identifier(&Identifier::parse("gina@example.com")).await.unwrap(), None);
+        let found = store.find_by_identifier_hashes(&[phone.hash()]).await.unwrap();
+        assert_eq!(found.len(), 1);
+
+        for replace in [false, true]{
+            let err = store.set_identifier(&hank.id, &phone, replace).await.unwrap_err();
+            assert_eq!(err, "+15550003 is used by another account");
+        }
+        assert_eq!(store.get_user(&hank.id).await.unwrap().unwrap().phone, None);
+    }
+
+    #[tokio::test]
+    async fn recovery_codes_and_totp_steps_are_single_use(){
+        let store = SqliteStore::connect("sqlite::memory:").await.unwrap();
+        let user = store.find_or_create(&Identifier::parse("bob@example.com")).await.unwrap();
+
+        store.put_pending_totp(&user.id, b"sealed").await.unwrap();
+        store.confirm_totp(&user.id, 100, &["h1".to_owned(), "h2".to_owned()]).await.unwrap();
+        // a confirmed secret can't be swapped by a new enrollment
+        store.put_pending_totp(&user.id, b"other").await.unwrap();
+
+        let record = store.get_totp(&user.id).await.unwrap().unwrap();
+        assert!(record.confirmed);
+        assert_eq!(record.secret_encrypted, b"sealed");
+
+        assert!(!store.use_totp_step(&user.id, 100).await.unwrap());
+        assert!(store.use_totp_step(&user.id, 101).await.unwrap());
+        assert!(store.use_recovery_code(&user.id, "h1").await.unwrap());
+        assert!(!store.use_recovery_code(&user.id, "h1").await.unwrap());
+        assert_eq!(store.unused_recovery_codes(&user.id).await.unwrap(), 1);
+
+        store.delete_totp(&user.id).await.unwrap();
+        assert_eq!(store.get_totp(&user.id).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn profile_updates_keep_unset_fields(){
+        let store = SqliteStore::connect("sqlite::memory:").await.unwrap();
+        let user = store.find_or_create(&Identifier::parse("erin@example.com")).await.unwrap();
+
+        let avatar = ProfileUpdate{avatar: Some((vec![1, 2, 3], "image/png".to_owned())), ..Default::default()};
+        store.update_profile(&user.id, &avatar).await.unwrap().unwrap();
+        let name = ProfileUpdate{display_name: Some("Erin".to_owned()), about: Some("hi".to_owned()), ..Default::default()};
+        let profile = store.update_profile(&user.id, &name).await.unwrap().unwrap();
+
+        assert_eq!(profile.display_name, "Erin");
+        assert_eq!(profile.about, "hi");
+        assert_eq!(profile.avatar, vec![1, 2, 3]);
+        assert_eq!(profile.avatar_mime, "image/png");
+
+        let unknown = "no-such-user".to_owned();
+        assert_eq!(store.update_profile(&unknown, &name).await.unwrap(), None);
+        let profiles = store.get_profiles(&[user.id.clone(), unknown]).await.unwrap();
+        assert_eq!(profiles, vec![profile]);
+        assert!(store.get_profiles(&[]).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn deleted_users_are_scrubbed_and_can_sign_up_again(){
+        let store = SqliteStore::connect("sqlite::memory:").await.unwrap();
+        let carol = Identifier::parse("carol@example.com");
+        let user = store.find_or_create(&carol).await.unwrap();
+        let friend = store.find_or_create(&Identifier::parse("dave@example.com")).await.unwrap();
+        store.add_contact(&friend.id, &user.id).await.unwrap();
+
+        store.delete_user(&user.id).await.unwrap();
+
+        let tombstone = store.get_user(&user.id).await.unwrap().unwrap();
+        assert_eq!(tombstone.status, UserStatus::Deleted);
+        assert_eq!(tombstone.email, None);
+        assert!(store.get_profiles(&[user.id.clone()]).await.unwrap().is_empty());
+        assert!(store.list_contacts(&friend.id).await.unwrap().is_empty());
+
+        let again = store.find_or_create(&carol).await.unwrap();
+        assert_ne!(again.id, user.id);
+    }
+
+    #[tokio::test]
+    async fn deleting_an_account_leaves_the_peer_a_placeholder(){
+        let store = SqliteStore::connect("sqlite::memory:").await.unwrap();
+        let alice = store.find_or_create(&Identifier::parse("alice@example.com")).await.unwrap().id;
+        let bob = store.find_or_create(&Identifier::parse("bob@example.com")).await.unwrap().id;
+        let from_alice = store.insert_message(&alice, &bob, b"secret", 1, None).await.unwrap();
+        let from_bob = store.insert_message(&bob, &alice, b"reply", 2, None).await.unwrap();
+        store.add_reaction(from_bob.id, &alice, "👍").await.unwrap();
+        store.add_reaction(from_alice.id, &bob, "❤️").await.unwrap();
+        store.mark_read(&bob, &alice, from_alice.id).await.unwrap();
+        store.mark_read(&alice, &bob, from_bob.id).await.unwrap();
+
+        store.delete_user(&alice).await.unwrap();
+
+        let history = store.conversation_history(&bob, &alice, None, None, 10).await.unwrap();
+        assert_eq!(history.len(), 2);
+        assert_eq!(history[0].id, from_alice.id);
+        assert!(history[0].deleted);
+        assert!(history[0].body.is_empty());
+        assert_eq!(history[1].body, b"reply");
+        assert!(!history[1].deleted);
+
+        let reactions = store.list_reactions(&[from_alice.id, from_bob.id]).await.unwrap();
+        assert_eq!(reactions.len(), 1);
+        assert_eq!(reactions[0].user_id, bob);
+        let search = MessageQuery{text: "secret".to_owned(), limit: 10, ..Default::default()};
+        assert!(store.search_messages(&bob, &search).await.unwrap().is_empty());
+
+        // bob's read state is his; alice's is gone
+        let conversations = store.list_conversations(&bob).await.unwrap();
+        assert_eq!(conversations.len(), 1);
+        assert_eq!(conversations[0].last_read_id, from_alice.id);
+        assert_eq!(conversations[0].peer_read_id, 0);
+    }
+
+    #[tokio::test]
+    async fn contacts_blocks_and_reports_are_per_owner(){
+        let store = SqliteStore::connect("sqlite::memory:").await.unwrap();
+        let erin = store.find_or_create(&Identifier::parse("erin@example.com")).await.unwrap();
+        let frank = store.find_or_create(&Identifier::parse("frank@example.com")).await.unwrap();
+        let grace = store.find_or_create(&Identifier::parse("grace@example.com")).await.unwrap();
+
+        store.add_contact(&erin.id, &grace.id).await.unwrap();
+        store.add_contact(&erin.id, &frank.id).await.unwrap();
+        store.add_contact(&erin.id, &frank.id).await.unwrap();
+        let names: Vec<String> = store.list_contacts(&erin.id).await.unwrap().into_iter().map(|p| p.display_name).collect();
+        assert_eq!(names, ["frank", "grace"]);
+        assert!(store.list_contacts(&frank.id).await.unwrap().is_empty());
+        store.remove_contact(&erin.id, &grace.id).await.unwrap();
+        assert_eq!(store.list_contacts(&erin.id).await.unwrap().len(), 1);
+
+        // blocking is one-way
+        store.block_user(&frank.id, &erin.id).await.unwrap();
+        store.block_user(&frank.id, &erin.id).await.unwrap();
+        assert!(store.is_blocked(&frank.id, &erin.id).await.unwrap());
+        assert!(!store.is_blocked(&erin.id, &frank.id).await.unwrap());
+        let blocked = store.list_blocked(&frank.id).await.unwrap();
+        assert_eq!(blocked.iter().map(|p| p.user_id.as_str()).collect::<Vec<_>>(), [erin.id.as_str()]);
+        store.unblock_user(&frank.id, &erin.id).await.unwrap();
+        assert!(!store.is_blocked(&frank.id, &erin.id).await.unwrap());
+        assert!(store.list_blocked(&frank.id).await.unwrap().is_empty());
+
+        let report = Report{
+            reporter_id: grace.id.clone(),
+            reported_id: frank.id.clone(),
+            reason: "spam".to_owned(),
+            details: "ads".to_owned(),
+            created_at: 42,
+        };
+        let first = store.insert_report(&report).await.unwrap();
+        let second = store.insert_report(&Report{reason: "other".to_owned(), ..report.clone()}).await.unwrap();
+        assert_ne!(first, second);
+        let filed = store.list_reports_by(&grace.id).await.unwrap();
+        assert_eq!(filed.len(), 2);
+        assert!(filed.contains(&report));
+        assert!(store.list_reports_by(&frank.id).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn edits_and_deletes_follow_the_message(){
+        let store = SqliteStore::connect("sqlite::memory:").await.unwrap();
+
+        let first = store.insert_message("alice", "bob", b"helo", 10, None).await.unwrap();
+        let second = store.insert_message("bob", "alice", b"hi", 11, Some(first.id)).await.unwrap();
+
+        let edited = store.edit_message(first.id, b"hello", 12).await.unwrap().unwrap();
+        assert_eq!(edited.body, b"hello");
+        assert_eq!(edited.edited_at, Some(12));
+
+        assert_eq!(second.reply_to, Some(first.id));
+        assert!(store.add_reaction(first.id, "bob", "👍").await.unwrap());
+        assert!(!store.add_reaction(first.id, "bob", "👍").await.unwrap());
+        assert_eq!(store.list_reactions(&[first.id, second.id]).await.unwrap().len(), 1);
+
+        store.hide_message("alice", second.id).await.unwrap();
+        assert!(store.is_hidden("alice", second.id).await.unwrap());
+        assert_eq!(store.list_user_messages("alice").await.unwrap().len(), 1);
+        assert_eq!(store.list_user_messages("bob").await.unwrap().len(), 2);
+
+        store.delete_message(first.id).await.unwrap();
+        let deleted = store.get_message(first.id).await.unwrap().unwrap();
+        assert!(deleted.deleted);
+        assert!(deleted.body.is_empty());
+        assert!(store.list_reactions(&[first.id]).await.unwrap().is_empty());
+        // a deleted message can't be edited back
+        assert_eq!(store.edit_message(first.id, b"again", 13).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn search_finds_visible_messages_with_context(){
+        let store = SqliteStore::connect("sqlite::memory:").await.unwrap();
+
+        store.insert_message("alice", "bob", b"are we still on for lunch?", 1, None).await.unwrap();
+        let hit = store.insert_message("bob", "alice", b"Yes, lunch at noon", 2, None).await.unwrap();
+        let gone = store.insert_message("alice", "bob", b"lunch is cancelled", 3, None).await.unwrap();
+        let hidden = store.insert_message("alice", "bob", b"lunchtime then", 4, None).await.unwrap();
+        store.insert_message("carol", "dave", b"lunch?", 5, None).await.unwrap();
+        store.delete_message(gone.id).await.unwrap();
+        store.hide_message("alice", hidden.id).await.unwrap();
+
+        let query = MessageQuery{text: "LUNCH no".to_owned(), limit: 10, ..Default::default()};
+        let found = store.search_messages("alice", &query).await.unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].message.id, hit.id);
+        assert_eq!(found[0].spans, vec![
+            ("Yes, ".to_owned(), false),
+            ("lunch".to_owned(), true),
+            (" at ".to_owned(), false),
+            ("noon".to_owned(), true),
+        ]);
+
+        // "lunch" prefix-matches "lunchtime" for bob, who didn't hide it
+        let query = MessageQuery{text: "lunch".to_owned(), peer_id: Some("alice".to_owned()), limit: 10, ..Default::default()};
+        assert_eq!(store.search_messages("bob", &query).await.unwrap().len(), 3);
+
+        let (before, after) = store.message_context("alice", &hit, 2).await.unwrap();
+        assert_eq!(before.len(), 1);
+        assert_eq!(after.iter().map(|m| m.id).collect::<Vec<_>>(), vec![gone.id]);
+    }
+
+    #[test]
+    fn typed_text_becomes_a_safe_fts_query(){
+        assert_eq!(fts_query("  hello \"wor"), Some("\"hello\" \"wor\"*".to_owned()));
+        assert_eq!(fts_query(" \" "), None);
+    }
+
+    #[tokio::test]
+    async fn read_markers_only_move_forward_and_drive_unread_counts(){
+        let store = SqliteStore::connect("sqlite::memory:").await.unwrap();
+
+        let first = store.insert_message("bob", "alice", b"one", 1, None).await.unwrap();
+        let second = store.insert_message("bob", "alice", b"two", 2, None).await.unwrap();
+        store.insert_message("alice", "bob", b"mine", 3, None).await.unwrap();
+        let deleted = store.insert_message("bob", "alice", b"oops", 4, None).await.unwrap();
+        store.delete_message(deleted.id).await.unwrap();
+        store.insert_message("carol", "alice", b"hey", 5, None).await.unwrap();
+
+        let conversations = store.list_conversations("alice").await.unwrap();
+        assert_eq!(conversations.iter().map(|c| c.peer_id.as_str()).collect::<Vec<_>>(), vec!["carol", "bob"]);
+        assert_eq!(conversations[1].unread_count, 2);
+        assert_eq!(conversations[1].last_message.id, deleted.id);
+        assert!(conversations[1].last_message.deleted);
+
+        assert_eq!(store.mark_read("alice", "bob", second.id).await.unwrap(), second.id);
+        // a device that is behind can't move the marker back
+        assert_eq!(store.mark_read("alice", "bob", first.id).await.unwrap(), second.id);
+
+        let bob = store.list_conversations("alice").await.unwrap().into_iter().find(|c| c.peer_id == "bob").unwrap();
+        assert_eq!(bob.last_read_id, second.id);
+        assert_eq!(bob.unread_count, 0);
+    }
+
+    #[tokio::test]
+    async fn delivery_markers_trail_reads_and_reach_the_sender(){
+        let store = SqliteStore::connect("sqlite::memory:").await.unwrap();
+        let first = store.insert_message("alice", "bob", b"one", 1, None).await.unwrap();
+        let second = store.insert_message("alice", "bob", b"two", 2, None).await.unwrap();
+
+        assert_eq!(store.mark_delivered("bob", "alice", second.id).await.unwrap(), second.id);
+        assert_eq!(store.mark_delivered("bob", "alice", first.id).await.unwrap(), second.id);
+        assert_eq!(store.mark_read("bob", "alice", first.id).await.unwrap(), first.id);
+
+        let with_bob = &store.list_conversations("alice").await.unwrap()[0];
+        assert_eq!((with_bob.peer_delivered_id, with_bob.peer_read_id), (second.id, first.id));
+
+        // reading further drags delivery along
+        let third = store.insert_message("alice", "bob", b"three", 3, None).await.unwrap();
+        store.mark_read("bob", "alice", third.id).await.unwrap();
+        let with_bob = &store.list_conversations("alice").await.unwrap()[0];
+        assert_eq!((with_bob.peer_delivered_id, with_bob.peer_read_id), (third.id, third.id));
+    }
+
+    #[tokio::test]
+    async fn history_pages_in_both_directions(){
+        let store = SqliteStore::connect("sqlite::memory:").await.unwrap();
+        let mut ids = Vec::new();
+        for i in 0..5{
+            let (from, to) = if i % 2 == 0 { ("alice", "bob") } else { ("bob", "alice") };
+            ids.push(store.insert_message(from, to, b"hi", i, None).await.unwrap().id);
+        }
+        store.insert_message("alice", "carol", b"elsewhere", 9, None).await.unwrap();
+        store.hide_message("alice", ids[3]).await.unwrap();
+
+        let page = |before, after| store.conversation_history("alice", "bob", before, after, 2);
+        let newest: Vec<i64> = page(None, None).await.unwrap().iter().map(|m| m.id).collect();
+        assert_eq!(newest, vec![ids[2], ids[4]]);
+        let older: Vec<i64> = page(Some(ids[2]), None).await.unwrap().iter().map(|m| m.id).collect();
+        assert_eq!(older, vec![ids[0], ids[1]]);
+        let newer: Vec<i64> = page(None, Some(ids[0])).await.unwrap().iter().map(|m| m.id).collect();
+        assert_eq!(newer, vec![ids[1], ids[2]]);
+    }
+}
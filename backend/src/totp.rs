@@ -0,0 +1,180 @@
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng};
+use aes_gcm::{Aes256Gcm, Nonce};
+use hmac::{Hmac, Mac};
+use sha1::Sha1;
+
+use crate::utils;
+
+/// Standard authenticator-app parameters (RFC 6238 defaults).
+pub const STEP_SECS: i64 = 30;
+pub const DIGITS: u32 = 6;
+/// Codes from one step either side are accepted to allow for clock drift.
+const ALLOWED_DRIFT_STEPS: i64 = 1;
+
+pub const RECOVERY_CODE_COUNT: usize = 10;
+
+
+pub fn generate_secret()->Vec<u8>{
+    utils::random_bytes(20)
+}
+
+pub fn step_at(unix_secs: i64)->i64{
+    unix_secs.div_euclid(STEP_SECS)
+}
+
+/// The code an authenticator shows for `step`.
+pub fn code_at(secret: &[u8], step: i64)->String{
+    let mut mac = <Hmac<Sha1> as Mac>::new_from_slice(secret).expect("HMAC can take key of any size");
+    mac.update(&step.to_be_bytes());
+    let digest = mac.finalize().into_bytes();
+
+    // dynamic truncation, RFC 4226 section 5.3
+    let offset = (digest[digest.len() - 1] & 0x0f) as usize;
+    let value = u32::from_be_bytes([digest[offset] & 0x7f, digest[offset + 1], digest[offset + 2], digest[offset + 3]]);
+    format!("{:0width$}", value % 10u32.pow(DIGITS), width = DIGITS as usize)
+}
+
+/// Returns the step `code` matches around `unix_secs`, if any.
+pub fn matching_step(secret: &[u8], code: &str, unix_secs: i64)->Option<i64>{
+    let code: String = code.chars().filter(|c| !c.is_whitespace()).collect();
+    if code.len() != DIGITS as usize{
+        return None;
+    }
+    let now = step_at(unix_secs);
+    (now - ALLOWED_DRIFT_STEPS..=now + ALLOWED_DRIFT_STEPS).find(|step| code_at(secret, *step) == code)
+}
+
+/// What authenticator apps scan from the enrollment QR code.
+pub fn otpauth_uri(issuer: &str, account: &str, secret: &[u8])->String{
+    format!(
+        "otpauth://totp/{}:{}?secret={}&issuer={}&algorithm=SHA1&digits={}&period={}",
+        percent_encode(issuer), percent_encode(account), base32(secret), percent_encode(issuer), DIGITS, STEP_SECS
+    )
+}
+
+/// RFC 4648 base32 without padding, the form authenticators expect.
+pub fn base32(bytes: &[u8])->String{
+    const ALPHABET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+    let mut out = String::new();
+    let (mut buffer, mut bits) = (0u32, 0u32);
+    for byte in bytes{
+        buffer = (buffer << 8) | *byte as u32;
+        bits += 8;
+        while bits >= 5{
+            bits -= 5;
+            out.push(ALPHABET[((buffer >> bits) & 31) as usize] as char);
+        }
+    }
+    if bits > 0{
+        out.push(ALPHABET[((buffer << (5 - bits)) & 31) as usize] as char);
+    }
+    out
+}
+
+fn percent_encode(raw: &str)->String{
+    raw.bytes()
+        .map(|b| match b{
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => (b as char).to_string(),
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+/// `xxxx-xxxx` codes, shown once; only their hashes are stored.
+pub fn generate_recovery_codes()->Vec<String>{
+    (0..RECOVERY_CODE_COUNT)
+        .map(|_| {
+            // 40 random bits, exactly eight base32 characters
+            let raw = base32(&utils::random_bytes(5)).to_lowercase();
+            format!("{}-{}", &raw[..4], &raw[4..])
+        })
+        .collect()
+}
+
+/// Recovery codes are compared case- and dash-insensitively.
+pub fn normalize_recovery_code(code: &str)->String{
+    code.chars().filter(|c| c.is_ascii_alphanumeric()).collect::<String>().to_lowercase()
+}
+
+
+/// Seals TOTP secrets before they reach the database.
+pub struct SecretCipher{
+    cipher: Aes256Gcm
+}
+
+impl SecretCipher{
+    /// `key` must be 32 bytes.
+    pub fn new(key: &[u8])->Result<Self, String>{
+        Aes256Gcm::new_from_slice(key)
+            .map(|cipher| Self{cipher})
+            .map_err(|_| "totp encryption key must be 32 bytes".to_owned())
+    }
+
+    /// Returns `nonce || ciphertext`.
+    pub fn seal(&self, plaintext: &[u8])->Result<Vec<u8>, String>{
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let ciphertext = self.cipher.encrypt(&nonce, plaintext)
+            .map_err(|_| "could not encrypt secret".to_owned())?;
+        Ok([nonce.as_slice(), &ciphertext].concat())
+    }
+
+    pub fn open(&self, sealed: &[u8])->Result<Vec<u8>, String>{
+        if sealed.len() < 12{
+            return Err("sealed secret is too short".to_owned());
+        }
+        let (nonce, ciphertext) = sealed.split_at(12);
+        self.cipher.decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map_err(|_| "could not decrypt secret, was the key changed?".to_owned())
+    }
+}
+
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    #[test]
+    fn matches_rfc_6238_vectors(){
+        // the SHA1 vectors from RFC 6238 appendix B, last six digits
+        let secret = b"12345678901234567890";
+        assert_eq!(code_at(secret, step_at(59)), "287082");
+        assert_eq!(code_at(secret, step_at(1111111109)), "081804");
+        assert_eq!(code_at(secret, step_at(2000000000)), "279037");
+
+        assert_eq!(matching_step(secret, "287 082", 59 + STEP_SECS), Some(1));
+        assert_eq!(matching_step(secret, "287082", 59 + 3 * STEP_SECS), None);
+    }
+
+    #[test]
+    fn base32_and_uri(){
+        assert_eq!(base32(b"foobar"), "MZXW6YTBOI");
+        assert_eq!(
+            otpauth_uri("Dioxus Chat", "a@b.c", b"foobar"),
+            "otpauth://totp/Dioxus%20Chat:a%40b.c?secret=MZXW6YTBOI&issuer=Dioxus%20Chat&algorithm=SHA1&digits=6&period=30"
+        );
+    }
+
+    #[test]
+    fn recovery_codes_are_distinct_and_normalize(){
+        let codes = generate_recovery_codes();
+        assert_eq!(codes.len(), RECOVERY_CODE_COUNT);
+        for code in &codes{
+            assert_eq!(code.len(), 9);
+            assert_eq!(&code[4..5], "-");
+            assert_eq!(normalize_recovery_code(&code.to_uppercase()), code.replace('-', ""));
+        }
+        let unique: std::collections::HashSet<_> = codes.iter().collect();
+        assert_eq!(unique.len(), codes.len());
+        assert_eq!(generate_secret().len(), 20);
+    }
+
+    #[test]
+    fn sealed_secrets_round_trip(){
+        let cipher = SecretCipher::new(&[7u8; 32]).unwrap();
+        let sealed = cipher.seal(b"secret").unwrap();
+
+        assert_ne!(&sealed[12..], b"secret");
+        assert_eq!(cipher.open(&sealed).unwrap(), b"secret");
+        assert!(SecretCipher::new(&[8u8; 32]).unwrap().open(&sealed).is_err());
+    }
+}
@@ -0,0 +1,187 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use deadpool_redis::redis::{cmd, pipe};
+use tonic::Status;
+
+use crate::stores::Store;
+use crate::totp::{self, SecretCipher};
+use crate::utils;
+
+/// How long the second step of a login may take.
+const CHALLENGE_TTL_SECS: i64 = 5 * 60;
+/// Wrong codes allowed per challenge before the whole login has to restart.
+const MAX_CHALLENGE_ATTEMPTS: i64 = 5;
+
+
+/// A login that passed the first factor and is waiting for a TOTP or recovery code.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PendingLogin{
+    pub user_id: String,
+    pub device_name: String,
+    pub locale: String,
+}
+
+
+/// Optional TOTP second factor: enrollment, checking codes and recovery codes.
+///
+/// Secrets are sealed with `SecretCipher` before they reach the store. Half-done
+/// logins wait in Redis as `two_factor:<token>` until a code arrives.
+pub struct TwoFactor{
+    store: Arc<dyn Store>,
+    redis_pool: deadpool_redis::Pool,
+    cipher: SecretCipher,
+    issuer: String
+}
+
+impl TwoFactor{
+    pub fn new(store: Arc<dyn Store>, redis_pool: deadpool_redis::Pool, cipher: SecretCipher, issuer: String)->Self{
+        Self{store, redis_pool, cipher, issuer}
+    }
+
+    async fn redis(&self)->Result<deadpool_redis::Connection, Status>{
+        self.redis_pool.get().await.map_err(|e| Status::unavailable(format!("redis unavailable: {}", e)))
+    }
+
+    pub async fn is_enabled(&self, user_id: &str)->Result<bool, Status>{
+        let record = self.store.get_totp(user_id).await.map_err(Status::internal)?;
+        Ok(record.is_some_and(|record| record.confirmed))
+    }
+
+    pub async fn recovery_codes_left(&self, user_id: &str)->Result<i64, Status>{
+        self.store.unused_recovery_codes(user_id).await.map_err(Status::internal)
+    }
+
+    /// Starts enrollment and returns the base32 secret and its otpauth URI.
+    pub async fn enroll(&self, user_id: &str, account_label: &str)->Result<(String, String), Status>{
+        if self.is_enabled(user_id).await?{
+            return Err(Status::failed_precondition("two-step verification is already on"));
+        }
+        let secret = totp::generate_secret();
+        let sealed = self.cipher.seal(&secret).map_err(Status::internal)?;
+        self.store.put_pending_totp(user_id, &sealed).await.map_err(Status::internal)?;
+
+        Ok((totp::base32(&secret), totp::otpauth_uri(&self.issuer, account_label, &secret)))
+    }
+
+    /// Finishes enrollment with a first code from the app; returns fresh recovery codes.
+    pub async fn confirm(&self, user_id: &str, code: &str)->Result<Vec<String>, Status>{
+        let record = self.store.get_totp(user_id).await.map_err(Status::internal)?
+            .ok_or_else(|| Status::failed_precondition("start enrollment first"))?;
+        if record.confirmed{
+            return Err(Status::failed_precondition("two-step verification is already on"));
+        }
+        let secret = self.cipher.open(&record.secret_encrypted).map_err(Status::internal)?;
+        let step = totp::matching_step(&secret, code, utils::now_secs())
+            .ok_or_else(|| Status::invalid_argument("that code is not right, check the time on your device"))?;
+
+        self.replace_recovery_codes(user_id, Some(step)).await
+    }
+
+    /// New recovery codes for an enabled account; the old ones stop working.
+    pub async fn regenerate_recovery_codes(&self, user_id: &str, code: &str)->Result<Vec<String>, Status>{
+        self.check_code(user_id, code).await?;
+        self.replace_recovery_codes(user_id, None).await
+    }
+
+    async fn replace_recovery_codes(&self, user_id: &str, confirm_step: Option<i64>)->Result<Vec<String>, Status>{
+        let codes = totp::generate_recovery_codes();
+        let hashes: Vec<String> = codes.iter()
+            .map(|code| utils::sha256_hex(&totp::normalize_recovery_code(code)))
+            .collect();
+        let step = match confirm_step{
+            Some(step) => step,
+            None => self.store.get_totp(user_id).await.map_err(Status::internal)?
+                .map(|record| record.last_used_step)
+                .unwrap_or_default()
+        };
+        self.store.confirm_totp(user_id, step, &hashes).await.map_err(Status::internal)?;
+        Ok(codes)
+    }
+
+    /// Turns 2FA off; needs a current code or a recovery code.
+    pub async fn disable(&self, user_id: &str, code: &str)->Result<(), Status>{
+        self.check_code(user_id, code).await?;
+        self.store.delete_totp(user_id).await.map_err(Status::internal)
+    }
+
+    /// Accepts a TOTP code (each at most once) or an unused recovery code.
+    pub async fn check_code(&self, user_id: &str, code: &str)->Result<(), Status>{
+        let record = self.store.get_totp(user_id).await.map_err(Status::internal)?
+            .filter(|record| record.confirmed)
+            .ok_or_else(|| Status::failed_precondition("two-step verification is not on"))?;
+
+        let secret = self.cipher.open(&record.secret_encrypted).map_err(Status::internal)?;
+        if let Some(step) = totp::matching_step(&secret, code, utils::now_secs()){
+            if self.store.use_totp_step(user_id, step).await.map_err(Status::internal)?{
+                return Ok(());
+            }
+            return Err(Status::unauthenticated("that code was already used, wait for the next one"));
+        }
+
+        let hash = utils::sha256_hex(&totp::normalize_recovery_code(code));
+        if self.store.use_recovery_code(user_id, &hash).await.map_err(Status::internal)?{
+            return Ok(());
+        }
+        Err(Status::unauthenticated("invalid code"))
+    }
+
+    /// Parks a login until the second factor arrives; the token goes back to the client.
+    pub async fn begin_login(&self, login: &PendingLogin)->Result<String, Status>{
+        let token = utils::random_hex(24);
+        let key = format!("two_factor:{}", token);
+        let mut conn = self.redis().await?;
+
+        pipe().atomic()
+            .cmd("HSET").arg(&key)
+                .arg("user_id").arg(&login.user_id)
+                .arg("device_name").arg(&login.device_name)
+                .arg("locale").arg(&login.locale)
+                .arg("attempts").arg(0)
+                .ignore()
+            .cmd("EXPIRE").arg(&key).arg(CHALLENGE_TTL_SECS).ignore()
+            .query_async::<()>(&mut conn)
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?;
+        Ok(token)
+    }
+
+    /// Completes a parked login if `code` is good. The token works once.
+    pub async fn finish_login(&self, token: &str, code: &str)->Result<PendingLogin, Status>{
+        let key = format!("two_factor:{}", token);
+        let mut conn = self.redis().await?;
+
+        let login: HashMap<String, String> = cmd("HGETALL").arg(&key)
+            .query_async(&mut conn)
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?;
+        let Some(user_id) = login.get("user_id") else {
+            return Err(Status::unauthenticated("this login has expired, start again"));
+        };
+
+        let attempts: i64 = cmd("HINCRBY").arg(&key).arg("attempts").arg(1)
+            .query_async(&mut conn)
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?;
+        if attempts > MAX_CHALLENGE_ATTEMPTS{
+            let _ = cmd("DEL").arg(&key).query_async::<()>(&mut conn).await;
+            return Err(Status::unauthenticated("too many wrong codes, start again"));
+        }
+
+        self.check_code(user_id, code).await?;
+
+        let removed: i64 = cmd("DEL").arg(&key)
+            .query_async(&mut conn)
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?;
+        if removed == 0{
+            return Err(Status::unauthenticated("this login has expired, start again"));
+        }
+
+        Ok(PendingLogin{
+            user_id: user_id.clone(),
+            device_name: login.get("device_name").cloned().unwrap_or_default(),
+            locale: login.get("locale").cloned().unwrap_or_default(),
+        })
+    }
+}
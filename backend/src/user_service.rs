@@ -2,12 +2,7 @@
 
 use sha2::Sha256;
 use sha2::Digest;
-use fastrand;
 
-use lettre::message::{Mailbox, header::ContentType};
-use lettre::transport::smtp::authentication::Credentials;
-use lettre::{Message, SmtpTransport, AsyncSmtpTransport, Transport};
-use lettre::{AsyncTransport, Tokio1Executor};
 use tonic::{
     Request, Response,
     Status, 
@@ -25,6 +20,36 @@ pub use users::{
 
     OtpVerifyRequest,
     OtpVerifyResponse,
+    VerifyMagicLinkRequest,
+    VerifyTwoFactorRequest,
+    TwoFactorStatus,
+    TotpEnrollment,
+    TotpCodeRequest,
+    RecoveryCodes,
+    AccountDeletionRequest,
+    DeleteAccountRequest,
+    DataExportChunk,
+    AccountIdentifiers,
+    IdentifierChangeRequest,
+    ConfirmIdentifierChangeRequest,
+    Profile,
+    GetProfileRequest,
+    GetProfilesRequest,
+    ProfileList,
+    UpdateProfileRequest,
+    LookupUserRequest,
+    SyncContactsRequest,
+    SyncContactsResponse,
+    RegisteredContact,
+    PeerRequest,
+    ReportReason,
+    ReportUserRequest,
+    ReportUserResponse,
+    SessionTokens,
+    RefreshTokenRequest,
+    SessionInfo,
+    SessionList,
+    RevokeSessionRequest,
     user_server::{
         UserServer, User
     },
@@ -33,23 +58,54 @@ pub use users::{
 };
 
 pub use users::otp_request::Id;
+pub use users::lookup_user_request::Id as LookupId;
+pub use users::identifier_change_request::NewId;
 pub use users::otp_request_error::Err as OtpError;
 
 use deadpool_redis::{redis::{cmd, FromRedisValue}, Config, Runtime};
 
+use std::sync::Arc;
+use crate::utils;
+use crate::magic_links::{MagicLinks, MAGIC_LINK_TTL_SECS};
+use crate::mailer::{Locale, Mailer, MailTemplate};
+use crate::sms::{SmsTemplate, Texter};
+use crate::two_factor::{PendingLogin, TwoFactor};
+use crate::sessions::{IssuedTokens, SessionManager, ACCESS_TTL_SECS};
+use crate::stores::{self, ContactStore, Identifier, MessageStore, ProfileUpdate, Report, Store, UserStatus, UserStore};
+
+const MAX_DISPLAY_NAME_CHARS: usize = 64;
+const MAX_ABOUT_CHARS: usize = 140;
+const MAX_AVATAR_BYTES: usize = 256 * 1024;
+const MAX_PROFILES_PER_REQUEST: usize = 100;
+const MAX_CONTACT_HASHES: usize = 1000;
+const MAX_REPORT_DETAILS_CHARS: usize = 2000;
+const OTP_VALID_MINUTES: u32 = 2;
+const ACCOUNT_DELETION_VALID_MINUTES: u32 = 10;
+const MAX_ACCOUNT_DELETION_ATTEMPTS: i64 = 5;
+const EXPORT_CHUNK_BYTES: usize = 64 * 1024;
+const IDENTIFIER_CHANGE_VALID_MINUTES: u32 = 10;
+const MAX_IDENTIFIER_CHANGE_ATTEMPTS: i64 = 5;
+
 pub mod users{
     tonic::include_proto!("users");
 }
 
 
 pub struct UserService{
-    mailing_cred: Credentials,
-    redis_pool: deadpool_redis::Pool
+    mailer: Arc<Mailer>,
+    texter: Texter,
+    magic_links: MagicLinks,
+    two_factor: TwoFactor,
+    redis_pool: deadpool_redis::Pool,
+    store: Arc<dyn Store>,
+    sessions: Arc<SessionManager>
 }
 
 
 #[tonic::async_trait]
 impl User for UserService{
+    type ExportMyDataStream = ReceiverStream<Result<DataExportChunk, Status>>;
+
     async fn request_otp(
         &self,
         request: Request<OtpRequest>
@@ -61,32 +117,54 @@ impl User for UserService{
         println!("DDDDD");
         let otp_request = request.into_inner();
         let mut redis_conn = self.redis_pool.get().await.unwrap();
-        let otp: String = std::iter::repeat_with(fastrand::alphanumeric).take(6).collect();
+        let otp = utils::random_code(6);
 
         match otp_request.id{
+            Some(Id::Email(email)) if otp_request.magic_link =>{
+                let url = self.magic_links.issue(&email).await?;
+                let template = MailTemplate::MagicLink{url, valid_minutes: (MAGIC_LINK_TTL_SECS / 60) as u32};
+                match self.mailer.enqueue(&email, &template, Locale::parse(&otp_request.locale)){
+                    Ok(())=>return Ok(Response::new(OtpRequestError{err: None})),
+                    Err(st)=>return Ok(Response::new(OtpRequestError{err: Some(OtpError::Email(st))}))
+                }
+            },
             Some(Id::Email(email)) =>{
 
                 let x:() = cmd("SET").arg(&email)
                     .arg( &otp)
                     .arg("EX")
-                    .arg(2*60)
+                    .arg(OTP_VALID_MINUTES*60)
                     .query_async::<()>(&mut redis_conn)
                     .await.unwrap();
 
-                match self.send_mail(
-                    &"Nam".to_owned(),
-                    &email,
-                    &"Subject".to_owned(),
-                    &format!("here is your verification code: {}", otp)
-                ).await{
+                let template = MailTemplate::Otp{code: otp, valid_minutes: OTP_VALID_MINUTES};
+                // only waits for the mail to be queued; the queue retries delivery
+                match self.mailer.enqueue(&email, &template, Locale::parse(&otp_request.locale)){
                     Ok(())=>return Ok(Response::new(OtpRequestError{err: None})),
                     Err(st)=>return Ok(Response::new(OtpRequestError{err: Some(OtpError::Email(st))}))  
                 }
             },
-            Some(Id::Phone(phone)) =>todo!(),
-            None => todo!()
+            Some(Id::Phone(_)) if otp_request.magic_link =>{
+                return Err(Status::invalid_argument("sign-in links can only be sent by email"));
+            },
+            Some(Id::Phone(phone)) =>{
+                cmd("SET").arg(&phone)
+                    .arg(&otp)
+                    .arg("EX")
+                    .arg(OTP_VALID_MINUTES*60)
+                    .query_async::<()>(&mut redis_conn)
+                    .await
+                    .map_err(|e| Status::internal(e.to_string()))?;
+
+                let template = SmsTemplate::Otp{code: otp, valid_minutes: OTP_VALID_MINUTES};
+                let number = Identifier::parse(&phone);
+                match self.texter.send(number.as_str(), &template, Locale::parse(&otp_request.locale)).await{
+                    Ok(())=>return Ok(Response::new(OtpRequestError{err: None})),
+                    Err(st)=>return Ok(Response::new(OtpRequestError{err: Some(OtpError::Phone(st))}))
+                }
+            },
+            None => return Err(Status::invalid_argument("give an email or phone number to sign in with"))
         }
-        Ok(Response::new(OtpRequestError{err: None}))
     }
 
     async fn verify_otp(
@@ -103,71 +181,980 @@ impl User for UserService{
         println!("EMAIL: {}", &email_or_phone);
         let otp:String = match cmd("GET").arg(&email_or_phone).query_async(&mut redis_conn).await{
             Ok(otp)=>otp,
-            Err(st)=>return Ok(Response::new(OtpVerifyResponse{res: Some(Res::ErrMsg(st.to_string()))}))
+            Err(st)=>return Ok(Response::new(OtpVerifyResponse{res: Some(Res::ErrMsg(st.to_string())), ..Default::default()}))
         };
         println!("{:?}", "kkkk");
 
         let otp_verify_msg = if otp==verify_request.otp{
 
-            let mut hasher = Sha256::new();
-
-            hasher.update(email_or_phone.as_bytes());
-
-            let result = hasher.finalize();
-            let unique_id = hex::encode(result);
+            // a code logs in once
+            let _ = cmd("DEL").arg(&email_or_phone).query_async::<()>(&mut redis_conn).await;
 
-            // OtpVerifyResponse{res: Some(Res::Uuid(unique_id))}
-            OtpVerifyResponse{res: Some(Res::Uuid(email_or_phone.to_owned()))}
+            self.complete_login(email_or_phone, &verify_request.device_name, &verify_request.locale).await?
         }else{
-            OtpVerifyResponse{res: Some(Res::ErrMsg("Invalid Otp.".to_string()))}
+            OtpVerifyResponse{res: Some(Res::ErrMsg("Invalid Otp.".to_string())), ..Default::default()}
         };
 
         return Ok(Response::new(otp_verify_msg));
 
     }
+
+    async fn verify_magic_link(
+        &self,
+        request: Request<VerifyMagicLinkRequest>
+    )
+    ->Result<Response<OtpVerifyResponse>, Status>
+    {
+        let request = request.into_inner();
+        let identifier = self.magic_links.redeem(&request.link).await?;
+        let response = self.complete_login(&identifier, &request.device_name, &request.locale).await?;
+        Ok(Response::new(response))
+    }
+
+    async fn verify_two_factor(
+        &self,
+        request: Request<VerifyTwoFactorRequest>
+    )
+    ->Result<Response<OtpVerifyResponse>, Status>
+    {
+        let request = request.into_inner();
+        let login = self.two_factor.finish_login(&request.two_factor_token, &request.code).await?;
+        let user = self.store.get_user(&login.user_id).await
+            .map_err(Status::internal)?
+            .ok_or_else(|| Status::not_found("no such user"))?;
+
+        let response = self.open_session(&user, &login.device_name, &login.locale).await?;
+        Ok(Response::new(response))
+    }
+
+    async fn refresh_token(
+        &self,
+        request: Request<RefreshTokenRequest>
+    )
+    ->Result<Response<SessionTokens>, Status>
+    {
+        let tokens = self.sessions.refresh(&request.into_inner().refresh_token).await?;
+        Ok(Response::new(tokens.into()))
+    }
+
+    async fn logout(
+        &self,
+        request: Request<Empty>
+    )
+    ->Result<Response<Empty>, Status>
+    {
+        let claims = self.sessions.authenticate(&request).await?;
+        self.sessions.revoke(&claims.user_id, &claims.session_id).await?;
+        Ok(Response::new(Empty{}))
+    }
+
+    async fn list_sessions(
+        &self,
+        request: Request<Empty>
+    )
+    ->Result<Response<SessionList>, Status>
+    {
+        let claims = self.sessions.authenticate(&request).await?;
+        let sessions = self.sessions.list(&claims.user_id).await?;
+
+        Ok(Response::new(SessionList{
+            sessions: sessions.into_iter()
+                .map(|session| SessionInfo{
+                    current: session.session_id == claims.session_id,
+                    session_id: session.session_id,
+                    device_name: session.device_name,
+                    created_at: session.created_at,
+                    last_seen: session.last_seen,
+                })
+                .collect()
+        }))
+    }
+
+    async fn revoke_session(
+        &self,
+        request: Request<RevokeSessionRequest>
+    )
+    ->Result<Response<Empty>, Status>
+    {
+        let claims = self.sessions.authenticate(&request).await?;
+        self.sessions.revoke(&claims.user_id, &request.into_inner().session_id).await?;
+        Ok(Response::new(Empty{}))
+    }
+
+    async fn get_profile(
+        &self,
+        request: Request<GetProfileRequest>
+    )
+    ->Result<Response<Profile>, Status>
+    {
+        self.sessions.authenticate(&request).await?;
+        let user_id = request.into_inner().user_id;
+        let mut profiles = self.store.get_profiles(&[user_id])
+            .await
+            .map_err(Status::internal)?;
+
+        match profiles.pop(){
+            Some(profile) => Ok(Response::new(profile.into())),
+            None => Err(Status::not_found("no such user"))
+        }
+    }
+
+    async fn get_profiles(
+        &self,
+        request: Request<GetProfilesRequest>
+    )
+    ->Result<Response<ProfileList>, Status>
+    {
+        self.sessions.authenticate(&request).await?;
+        let user_ids = request.into_inner().user_ids;
+        if user_ids.len() > MAX_PROFILES_PER_REQUEST{
+            return Err(Status::invalid_argument(format!("at most {} profiles per request", MAX_PROFILES_PER_REQUEST)));
+        }
+
+        let profiles = self.store.get_profiles(&user_ids)
+            .await
+            .map_err(Status::internal)?;
+
+        Ok(Response::new(ProfileList{
+            profiles: profiles.into_iter().map(Into::into).collect()
+        }))
+    }
+
+    async fn lookup_user(
+        &self,
+        request: Request<LookupUserRequest>
+    )
+    ->Result<Response<Profile>, Status>
+    {
+        self.sessions.authenticate(&request).await?;
+        let identifier = match request.into_inner().id{
+            Some(LookupId::Email(email)) => Identifier::parse(&email),
+            Some(LookupId::Phone(phone)) => Identifier::parse(&phone),
+            None => return Err(Status::invalid_argument("email or phone is required"))
+        };
+
+        let user = self.store.find_by_identifier(&identifier)
+            .await
+            .map_err(Status::internal)?
+            .filter(|user| user.status == UserStatus::Active)
+            .ok_or_else(|| Status::not_found("no account for that email or phone"))?;
+
+        let mut profiles = self.store.get_profiles(&[user.id])
+            .await
+            .map_err(Status::internal)?;
+
+        match profiles.pop(){
+            Some(profile) => Ok(Response::new(profile.into())),
+            None => Err(Status::not_found("no account for that email or phone"))
+        }
+    }
+
+    async fn sync_contacts(
+        &self,
+        request: Request<SyncContactsRequest>
+    )
+    ->Result<Response<SyncContactsResponse>, Status>
+    {
+        self.sessions.authenticate(&request).await?;
+        let hashes = request.into_inner().identifier_hashes;
+        if hashes.len() > MAX_CONTACT_HASHES{
+            return Err(Status::invalid_argument(format!("at most {} hashes per request", MAX_CONTACT_HASHES)));
+        }
+
+        let found = self.store.find_by_identifier_hashes(&hashes)
+            .await
+            .map_err(Status::internal)?;
+
+        Ok(Response::new(SyncContactsResponse{
+            contacts: found.into_iter()
+                .map(|(identifier_hash, profile)| RegisteredContact{
+                    identifier_hash,
+                    profile: Some(profile.into())
+                })
+                .collect()
+        }))
+    }
+
+    async fn add_contact(
+        &self,
+        request: Request<PeerRequest>
+    )
+    ->Result<Response<Empty>, Status>
+    {
+        let claims = self.sessions.authenticate(&request).await?;
+        let request = request.into_inner();
+        self.ensure_peer(&claims.user_id, &request.peer_id).await?;
+        self.store.add_contact(&claims.user_id, &request.peer_id)
+            .await
+            .map_err(Status::internal)?;
+        Ok(Response::new(Empty{}))
+    }
+
+    async fn remove_contact(
+        &self,
+        request: Request<PeerRequest>
+    )
+    ->Result<Response<Empty>, Status>
+    {
+        let claims = self.sessions.authenticate(&request).await?;
+        let request = request.into_inner();
+        self.store.remove_contact(&claims.user_id, &request.peer_id)
+            .await
+            .map_err(Status::internal)?;
+        Ok(Response::new(Empty{}))
+    }
+
+    async fn list_contacts(
+        &self,
+        request: Request<Empty>
+    )
+    ->Result<Response<ProfileList>, Status>
+    {
+        let claims = self.sessions.authenticate(&request).await?;
+        let profiles = self.store.list_contacts(&claims.user_id)
+            .await
+            .map_err(Status::internal)?;
+        Ok(Response::new(ProfileList{profiles: profiles.into_iter().map(Into::into).collect()}))
+    }
+
+    async fn block_user(
+        &self,
+        request: Request<PeerRequest>
+    )
+    ->Result<Response<Empty>, Status>
+    {
+        let claims = self.sessions.authenticate(&request).await?;
+        let request = request.into_inner();
+        self.ensure_peer(&claims.user_id, &request.peer_id).await?;
+        self.store.block_user(&claims.user_id, &request.peer_id)
+            .await
+            .map_err(Status::internal)?;
+        Ok(Response::new(Empty{}))
+    }
+
+    async fn unblock_user(
+        &self,
+        request: Request<PeerRequest>
+    )
+    ->Result<Response<Empty>, Status>
+    {
+        let claims = self.sessions.authenticate(&request).await?;
+        let request = request.into_inner();
+        self.store.unblock_user(&claims.user_id, &request.peer_id)
+            .await
+            .map_err(Status::internal)?;
+        Ok(Response::new(Empty{}))
+    }
+
+    async fn list_blocked(
+        &self,
+        request: Request<Empty>
+    )
+    ->Result<Response<ProfileList>, Status>
+    {
+        let claims = self.sessions.authenticate(&request).await?;
+        let profiles = self.store.list_blocked(&claims.user_id)
+            .await
+            .map_err(Status::internal)?;
+        Ok(Response::new(ProfileList{profiles: profiles.into_iter().map(Into::into).collect()}))
+    }
+
+    async fn report_user(
+        &self,
+        request: Request<ReportUserRequest>
+    )
+    ->Result<Response<ReportUserResponse>, Status>
+    {
+        let claims = self.sessions.authenticate(&request).await?;
+        let request = request.into_inner();
+        if request.details.chars().count() > MAX_REPORT_DETAILS_CHARS{
+            return Err(Status::invalid_argument(format!("details must be at most {} characters", MAX_REPORT_DETAILS_CHARS)));
+        }
+        self.ensure_peer(&claims.user_id, &request.reported_id).await?;
+
+        let reason = ReportReason::try_from(request.reason).unwrap_or(ReportReason::Other);
+        let report = Report{
+            reporter_id: claims.user_id,
+            reported_id: request.reported_id,
+            reason: reason.as_str_name().to_lowercase(),
+            details: request.details,
+            created_at: utils::now_secs(),
+        };
+        let report_id = self.store.insert_report(&report)
+            .await
+            .map_err(Status::internal)?;
+
+        if request.also_block{
+            self.store.block_user(&report.reporter_id, &report.reported_id)
+                .await
+                .map_err(Status::internal)?;
+        }
+
+        Ok(Response::new(ReportUserResponse{report_id}))
+    }
+
+    async fn update_profile(
+        &self,
+        request: Request<UpdateProfileRequest>
+    )
+    ->Result<Response<Profile>, Status>
+    {
+        let claims = self.sessions.authenticate(&request).await?;
+        let update = profile_update(request.into_inner())?;
+        match self.store.update_profile(&claims.user_id, &update).await{
+            Ok(Some(profile)) => Ok(Response::new(profile.into())),
+            Ok(None) => Err(Status::not_found("no such user")),
+            Err(err) => Err(Status::internal(err))
+        }
+    }
+
+    async fn get_two_factor_status(
+        &self,
+        request: Request<Empty>
+    )
+    ->Result<Response<TwoFactorStatus>, Status>
+    {
+        let claims = self.sessions.authenticate(&request).await?;
+        let enabled = self.two_factor.is_enabled(&claims.user_id).await?;
+        let recovery_codes_left = if enabled{ self.two_factor.recovery_codes_left(&claims.user_id).await? }else{ 0 };
+        Ok(Response::new(TwoFactorStatus{enabled, recovery_codes_left}))
+    }
+
+    async fn enroll_totp(
+        &self,
+        request: Request<Empty>
+    )
+    ->Result<Response<TotpEnrollment>, Status>
+    {
+        let claims = self.sessions.authenticate(&request).await?;
+        let user = self.store.get_user(&claims.user_id).await
+            .map_err(Status::internal)?
+            .ok_or_else(|| Status::not_found("no such user"))?;
+
+        // what the authenticator app lists the entry as
+        let label = user.email.or(user.phone).unwrap_or(user.id);
+        let (secret, otpauth_uri) = self.two_factor.enroll(&claims.user_id, &label).await?;
+        Ok(Response::new(TotpEnrollment{secret, otpauth_uri}))
+    }
+
+    async fn confirm_totp(
+        &self,
+        request: Request<TotpCodeRequest>
+    )
+    ->Result<Response<RecoveryCodes>, Status>
+    {
+        let claims = self.sessions.authenticate(&request).await?;
+        let codes = self.two_factor.confirm(&claims.user_id, &request.into_inner().code).await?;
+        Ok(Response::new(RecoveryCodes{codes}))
+    }
+
+    async fn regenerate_recovery_codes(
+        &self,
+        request: Request<TotpCodeRequest>
+    )
+    ->Result<Response<RecoveryCodes>, Status>
+    {
+        let claims = self.sessions.authenticate(&request).await?;
+        let codes = self.two_factor.regenerate_recovery_codes(&claims.user_id, &request.into_inner().code).await?;
+        Ok(Response::new(RecoveryCodes{codes}))
+    }
+
+    async fn disable_totp(
+        &self,
+        request: Request<TotpCodeRequest>
+    )
+    ->Result<Response<Empty>, Status>
+    {
+        let claims = self.sessions.authenticate(&request).await?;
+        self.two_factor.disable(&claims.user_id, &request.into_inner().code).await?;
+        Ok(Response::new(Empty{}))
+    }
+
+    async fn request_account_deletion(
+        &self,
+        request: Request<AccountDeletionRequest>
+    )
+    ->Result<Response<Empty>, Status>
+    {
+        let claims = self.sessions.authenticate(&request).await?;
+        let user = self.store.get_user(&claims.user_id).await
+            .map_err(Status::internal)?
+            .ok_or_else(|| Status::not_found("no such user"))?;
+        let Some(email) = user.email else {
+            return Err(Status::failed_precondition("deleting needs an email on the account to confirm with"));
+        };
+
+        let code = utils::random_code(6);
+        let key = format!("account_deletion:{}", user.id);
+        let mut redis_conn = self.redis_pool.get().await.map_err(|e| Status::unavailable(e.to_string()))?;
+        deadpool_redis::redis::pipe().atomic()
+            .cmd("DEL").arg(&key).ignore()
+            .cmd("HSET").arg(&key).arg("code").arg(&code).arg("attempts").arg(0).ignore()
+            .cmd("EXPIRE").arg(&key).arg(ACCOUNT_DELETION_VALID_MINUTES * 60).ignore()
+            .query_async::<()>(&mut redis_conn)
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?;
+
+        let template = MailTemplate::AccountDeletion{code, valid_minutes: ACCOUNT_DELETION_VALID_MINUTES};
+        self.mailer.enqueue(&email, &template, Locale::parse(&request.get_ref().locale)).map_err(Status::internal)?;
+        Ok(Response::new(Empty{}))
+    }
+
+    async fn delete_account(
+        &self,
+        request: Request<DeleteAccountRequest>
+    )
+    ->Result<Response<Empty>, Status>
+    {
+        let claims = self.sessions.authenticate(&request).await?;
+        let request = request.into_inner();
+        let user = self.store.get_user(&claims.user_id).await
+            .map_err(Status::internal)?
+            .ok_or_else(|| Status::not_found("no such user"))?;
+
+        let key = format!("account_deletion:{}", user.id);
+        let mut redis_conn = self.redis_pool.get().await.map_err(|e| Status::unavailable(e.to_string()))?;
+        let expected: Option<String> = cmd("HGET").arg(&key).arg("code")
+            .query_async(&mut redis_conn)
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?;
+        let Some(expected) = expected else {
+            return Err(Status::permission_denied("invalid or expired confirmation code"));
+        };
+
+        let attempts: i64 = cmd("HINCRBY").arg(&key).arg("attempts").arg(1)
+            .query_async(&mut redis_conn)
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?;
+        if attempts > MAX_ACCOUNT_DELETION_ATTEMPTS{
+            let _ = cmd("DEL").arg(&key).query_async::<()>(&mut redis_conn).await;
+            return Err(Status::permission_denied("too many wrong codes, ask for a new one"));
+        }
+        if expected != request.code.trim(){
+            return Err(Status::permission_denied("invalid or expired confirmation code"));
+        }
+        if self.two_factor.is_enabled(&user.id).await?{
+            self.two_factor.check_code(&user.id, &request.two_factor_code).await?;
+        }
+
+        self.store.delete_user(&user.id).await.map_err(Status::internal)?;
+
+        // drop leftover codes so the freed address starts clean
+        let mut keys = vec![key];
+        keys.extend(user.email.iter().chain(user.phone.iter()).cloned());
+        let _ = cmd("DEL").arg(&keys).query_async::<()>(&mut redis_conn).await;
+
+        // revoking closes each device's stream, discarding anything still queued for it
+        for session in self.sessions.list(&user.id).await?{
+            self.sessions.revoke(&user.id, &session.session_id).await?;
+        }
+        Ok(Response::new(Empty{}))
+    }
+
+    async fn export_my_data(
+        &self,
+        request: Request<Empty>
+    )
+    ->Result<Response<Self::ExportMyDataStream>, Status>
+    {
+        let claims = self.sessions.authenticate(&request).await?;
+        let document = self.export_document(&claims.user_id).await?;
+        let bytes = serde_json::to_vec_pretty(&document).map_err(|e| Status::internal(e.to_string()))?;
+
+        let (sender, receiver) = tokio::sync::mpsc::channel(4);
+        tokio::spawn(async move {
+            for chunk in bytes.chunks(EXPORT_CHUNK_BYTES){
+                if sender.send(Ok(DataExportChunk{data: chunk.to_vec()})).await.is_err(){
+                    break;
+                }
+            }
+        });
+        Ok(Response::new(ReceiverStream::new(receiver)))
+    }
+
+    async fn get_account_identifiers(
+        &self,
+        request: Request<Empty>
+    )
+    ->Result<Response<AccountIdentifiers>, Status>
+    {
+        let claims = self.sessions.authenticate(&request).await?;
+        let user = self.store.get_user(&claims.user_id).await
+            .map_err(Status::internal)?
+            .ok_or_else(|| Status::not_found("no such user"))?;
+        Ok(Response::new(user.into()))
+    }
+
+    async fn request_identifier_change(
+        &self,
+        request: Request<IdentifierChangeRequest>
+    )
+    ->Result<Response<Empty>, Status>
+    {
+        let claims = self.sessions.authenticate(&request).await?;
+        let request = request.into_inner();
+
+        let identifier = new_identifier(request.new_id)?;
+        self.check_identifier_change(&claims.user_id, &identifier, request.replace).await?;
+
+        let code = utils::random_code(6);
+        let key = format!("identifier_change:{}", claims.user_id);
+        let mut redis_conn = self.redis_pool.get().await.map_err(|e| Status::unavailable(e.to_string()))?;
+        deadpool_redis::redis::pipe().atomic()
+            .cmd("DEL").arg(&key).ignore()
+            .cmd("HSET").arg(&key)
+                .arg("identifier").arg(identifier.as_str())
+                .arg("code").arg(&code)
+                .arg("attempts").arg(0)
+                .arg("locale").arg(&request.locale)
+                .arg("replace").arg(request.replace as i64)
+                .ignore()
+            .cmd("EXPIRE").arg(&key).arg(IDENTIFIER_CHANGE_VALID_MINUTES * 60).ignore()
+            .query_async::<()>(&mut redis_conn)
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?;
+
+        let locale = Locale::parse(&request.locale);
+        match &identifier{
+            Identifier::Email(email) => {
+                let template = MailTemplate::VerifyNewAddress{code, valid_minutes: IDENTIFIER_CHANGE_VALID_MINUTES};
+                self.mailer.enqueue(email, &template, locale).map_err(Status::internal)?;
+            }
+            Identifier::Phone(phone) => {
+                let template = SmsTemplate::VerifyNewNumber{code, valid_minutes: IDENTIFIER_CHANGE_VALID_MINUTES};
+                self.texter.send(phone, &template, locale).await.map_err(Status::unavailable)?;
+            }
+        }
+        Ok(Response::new(Empty{}))
+    }
+
+    async fn confirm_identifier_change(
+        &self,
+        request: Request<ConfirmIdentifierChangeRequest>
+    )
+    ->Result<Response<AccountIdentifiers>, Status>
+    {
+        let claims = self.sessions.authenticate(&request).await?;
+        let request = request.into_inner();
+        let key = format!("identifier_change:{}", claims.user_id);
+        let mut redis_conn = self.redis_pool.get().await.map_err(|e| Status::unavailable(e.to_string()))?;
+
+        let pending: std::collections::HashMap<String, String> = cmd("HGETALL").arg(&key)
+            .query_async(&mut redis_conn)
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?;
+        let (Some(new_address), Some(code)) = (pending.get("identifier"), pending.get("code")) else {
+            return Err(Status::failed_precondition("no change pending, or it expired"));
+        };
+
+        let attempts: i64 = cmd("HINCRBY").arg(&key).arg("attempts").arg(1)
+            .query_async(&mut redis_conn)
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?;
+        if attempts > MAX_IDENTIFIER_CHANGE_ATTEMPTS{
+            let _ = cmd("DEL").arg(&key).query_async::<()>(&mut redis_conn).await;
+            return Err(Status::permission_denied("too many wrong codes, start again"));
+        }
+        if *code != request.code.trim(){
+            return Err(Status::permission_denied("invalid code"));
+        }
+        if self.two_factor.is_enabled(&claims.user_id).await?{
+            self.two_factor.check_code(&claims.user_id, &request.two_factor_code).await?;
+        }
+
+        let identifier = Identifier::parse(new_address);
+        let replace = pending.get("replace").is_some_and(|r| r == "1");
+        let locale = Locale::parse(pending.get("locale").map(String::as_str).unwrap_or_default());
+        let after = self.apply_identifier_change(&claims.user_id, &identifier, replace, locale).await?;
+        let _ = cmd("DEL").arg(&key).query_async::<()>(&mut redis_conn).await;
+        Ok(Response::new(after.into()))
+    }
 }
 
 
+impl From<stores::UserRecord> for AccountIdentifiers{
+    fn from(user: stores::UserRecord)->Self{
+        AccountIdentifiers{
+            email: user.email.unwrap_or_default(),
+            phone: user.phone.unwrap_or_default(),
+        }
+    }
+}
+
+impl From<IssuedTokens> for SessionTokens{
+    fn from(tokens: IssuedTokens)->Self{
+        SessionTokens{
+            access_token: tokens.access_token,
+            access_ttl_seconds: ACCESS_TTL_SECS as u32,
+            refresh_token: tokens.refresh_token,
+            session_id: tokens.session_id,
+            cache_key: tokens.cache_key,
+        }
+    }
+}
 
+impl From<stores::Profile> for Profile{
+    fn from(profile: stores::Profile)->Self{
+        Profile{
+            user_id: profile.user_id,
+            display_name: profile.display_name,
+            about: profile.about,
+            avatar: profile.avatar,
+            avatar_mime: profile.avatar_mime,
+        }
+    }
+}
+
+
+
+
+/// Checks an UpdateProfile request against the size and type limits.
+fn profile_update(request: UpdateProfileRequest)->Result<ProfileUpdate, Status>{
+    let display_name = request.display_name.map(|name| name.trim().to_owned());
+    if let Some(name) = &display_name{
+        if name.is_empty() || name.chars().count() > MAX_DISPLAY_NAME_CHARS{
+            return Err(Status::invalid_argument(format!("display name must be 1-{} characters", MAX_DISPLAY_NAME_CHARS)));
+        }
+    }
+
+    if let Some(about) = &request.about{
+        if about.chars().count() > MAX_ABOUT_CHARS{
+            return Err(Status::invalid_argument(format!("about text must be at most {} characters", MAX_ABOUT_CHARS)));
+        }
+    }
+
+    let avatar = match request.avatar{
+        Some(bytes) => {
+            let mime = request.avatar_mime.unwrap_or_default();
+            if !mime.starts_with("image/"){
+                return Err(Status::invalid_argument("avatar_mime must be an image type"));
+            }
+            if bytes.len() > MAX_AVATAR_BYTES{
+                return Err(Status::invalid_argument(format!("avatar must be at most {} bytes", MAX_AVATAR_BYTES)));
+            }
+            Some((bytes, mime))
+        },
+        None => None
+    };
+
+    Ok(ProfileUpdate{display_name, about: request.about, avatar})
+}
+
+/// The address a change asks for, normalized the way logins are.
+fn new_identifier(new_id: Option<NewId>)->Result<Identifier, Status>{
+    match new_id{
+        Some(NewId::Email(email)) if email.contains('@') => Ok(Identifier::parse(&email)),
+        Some(NewId::Email(_)) => Err(Status::invalid_argument("that is not an email address")),
+        Some(NewId::Phone(phone)) => match Identifier::parse(&phone){
+            // international format: a '+' and 7 to 15 digits
+            Identifier::Phone(phone) if phone.starts_with('+') && (8..=16).contains(&phone.len()) && !phone[1..].contains('+') => Ok(Identifier::Phone(phone)),
+            _ => Err(Status::invalid_argument("give the phone number with its country code, e.g. +34 600 000 000")),
+        },
+        None => Err(Status::invalid_argument("give the new email or phone")),
+    }
+}
 
 impl UserService{
-    pub fn new()->Self{
-        let mut cfg = Config::from_url("redis://127.0.0.1:6379");
-        let pool = cfg.create_pool(Some(Runtime::Tokio1)).unwrap();
+    pub fn new(store: Arc<dyn Store>, redis_pool: deadpool_redis::Pool, sessions: Arc<SessionManager>, mailer: Arc<Mailer>, texter: Texter, magic_links: MagicLinks, two_factor: TwoFactor)->Self{
         Self{
-            mailing_cred: Credentials::new("abdulkuddusa4@gmail.com".to_owned(), "lypo whlp okjv ygii".to_owned()),
-            redis_pool: pool
+            mailer,
+            texter,
+            magic_links,
+            two_factor,
+            redis_pool,
+            store,
+            sessions
         }
     }
 
-    pub async fn send_mail(
-        &self,
-        name: &String, email: &String,
-        subject: &String, body: &String
-    )->Result<(), String>{
-        let email = Message::builder()
-            .from(Mailbox::new(Some("NoBody".to_owned()), "nobody@domain.tld".parse().unwrap()))
-            .to(Mailbox::new(
-                Some(name.to_owned()), 
-                match email.parse(){
-                    Ok(x)=>x,
-                    Err(err)=>return Err(format!("error parsing email: {:?}", err))
+    /// Whether the account may take `identifier`: nobody may have it yet, and
+    /// attaching needs a free slot of that kind. Returns the account as it is.
+    async fn check_identifier_change(&self, user_id: &str, identifier: &Identifier, replace: bool)->Result<stores::UserRecord, Status>{
+        match self.store.find_by_identifier(identifier).await.map_err(Status::internal)?{
+            Some(owner) if owner.id == user_id => return Err(Status::invalid_argument("that is already on your account")),
+            Some(_) => return Err(Status::already_exists("that address belongs to another account")),
+            None => {}
+        }
+
+        let user = self.store.get_user(user_id).await
+            .map_err(Status::internal)?
+            .ok_or_else(|| Status::not_found("no such user"))?;
+        let (current, kind) = match identifier{
+            Identifier::Email(_) => (&user.email, "an email"),
+            Identifier::Phone(_) => (&user.phone, "a phone number"),
+        };
+        if current.is_some() && !replace{
+            return Err(Status::failed_precondition(format!("your account already has {}, replace it instead", kind)));
+        }
+        Ok(user)
+    }
+
+    /// Saves a confirmed change and tells the old addresses about it.
+    async fn apply_identifier_change(&self, user_id: &str, identifier: &Identifier, replace: bool, locale: Locale)->Result<stores::UserRecord, Status>{
+        let before = self.check_identifier_change(user_id, identifier, replace).await?;
+        self.store.set_identifier(user_id, identifier, replace).await.map_err(Status::already_exists)?;
+
+        let new_address = identifier.as_str().to_owned();
+        if let Some(old_email) = &before.email{
+            let template = MailTemplate::IdentifierChanged{new_address: new_address.clone()};
+            if let Err(err) = self.mailer.enqueue(old_email, &template, locale){
+                println!("could not notify {} of the change: {}", old_email, err);
+            }
+        }
+        // a number that stays on the account isn't texted, only one that was replaced
+        if let (Identifier::Phone(_), Some(old_phone)) = (identifier, &before.phone){
+            let template = SmsTemplate::IdentifierChanged{new_address};
+            if let Err(err) = self.texter.send(old_phone, &template, locale).await{
+                println!("could not notify {} of the change: {}", old_phone, err);
+            }
+        }
+
+        self.store.get_user(user_id).await
+            .map_err(Status::internal)?
+            .ok_or_else(|| Status::not_found("no such user"))
+    }
+
+    /// Shared tail of every first-factor login: finds or creates the account for
+    /// `email_or_phone`, then either opens a session or, with 2FA on, asks for a code.
+    async fn complete_login(&self, email_or_phone: &str, device_name: &str, locale: &str)->Result<OtpVerifyResponse, Status>{
+        // first successful login creates the account
+        let identifier = Identifier::parse(email_or_phone);
+        let user = match self.store.find_or_create(&identifier).await{
+            Ok(user) => user,
+            Err(err) => return Err(Status::internal(format!("could not load account: {}", err)))
+        };
+
+        let device_name = match device_name.trim(){
+            "" => "Unknown device".to_owned(),
+            name => name.chars().take(64).collect()
+        };
+
+        if self.two_factor.is_enabled(&user.id).await?{
+            let login = PendingLogin{user_id: user.id, device_name, locale: locale.to_owned()};
+            return Ok(OtpVerifyResponse{
+                two_factor_token: self.two_factor.begin_login(&login).await?,
+                ..Default::default()
+            });
+        }
+        self.open_session(&user, &device_name, locale).await
+    }
+
+    async fn open_session(&self, user: &stores::UserRecord, device_name: &str, locale: &str)->Result<OtpVerifyResponse, Status>{
+        let tokens = self.sessions.create(&user.id, device_name).await?;
+
+        // a heads-up when this is not the account's only device
+        if let Some(email) = &user.email{
+            let other_devices = self.sessions.list(&user.id).await.map(|s| s.len() > 1).unwrap_or(false);
+            if other_devices{
+                let template = MailTemplate::NewDeviceLogin{device_name: device_name.to_owned()};
+                if let Err(err) = self.mailer.enqueue(email, &template, Locale::parse(locale)){
+                    println!("could not send new sign-in notice: {}", err);
                 }
-            ))
-            .subject(subject)
-            .header(ContentType::TEXT_PLAIN)
-            .body(body.to_owned())
-            .unwrap();
+            }
+        }
 
-        let mailer = AsyncSmtpTransport::<Tokio1Executor>::relay("smtp.gmail.com")
-            .unwrap()
-            .credentials(self.mailing_cred.clone())
-            .build();
+        // the account id is what chat routing is keyed by
+        Ok(OtpVerifyResponse{
+            res: Some(Res::Uuid(user.id.clone())),
+            user_id: user.id.clone(),
+            tokens: Some(tokens.into()),
+            ..Default::default()
+        })
+    }
+
+    /// Everything held about `user_id`, as one JSON document.
+    async fn export_document(&self, user_id: &str)->Result<serde_json::Value, Status>{
+        use base64::Engine;
+        use serde_json::json;
+
+        let user = self.store.get_user(user_id).await
+            .map_err(Status::internal)?
+            .ok_or_else(|| Status::not_found("no such user"))?;
+        let profile = self.store.get_profiles(&[user.id.clone()]).await
+            .map_err(Status::internal)?
+            .pop()
+            .unwrap_or_default();
+        let people = |profiles: Vec<stores::Profile>| profiles.into_iter()
+            .map(|p| json!({"user_id": p.user_id, "display_name": p.display_name}))
+            .collect::<Vec<_>>();
 
-        match mailer.send(email).await {
-            Ok(_) => println!("Email sent successfully!"),
-            Err(e) => panic!("Could not send email: {e:?}"),
+        let contacts = people(self.store.list_contacts(user_id).await.map_err(Status::internal)?);
+        let blocked = people(self.store.list_blocked(user_id).await.map_err(Status::internal)?);
+        let reports: Vec<_> = self.store.list_reports_by(user_id).await
+            .map_err(Status::internal)?
+            .into_iter()
+            .map(|r| json!({"reported_id": r.reported_id, "reason": r.reason, "details": r.details, "created_at": r.created_at}))
+            .collect();
+        let sessions: Vec<_> = self.sessions.list(user_id).await?
+            .into_iter()
+            .map(|s| json!({"session_id": s.session_id, "device_name": s.device_name, "created_at": s.created_at, "last_seen": s.last_seen}))
+            .collect();
+        let two_factor = self.two_factor.is_enabled(user_id).await?;
+        // deleted messages are listed without their text
+        let messages: Vec<_> = self.store.list_user_messages(user_id).await
+            .map_err(Status::internal)?
+            .into_iter()
+            .map(|m| json!({
+                "id": m.id,
+                "from": m.sender_id,
+                "to": m.recipient_id,
+                "text": String::from_utf8_lossy(&m.body),
+                "sent_at": m.sent_at,
+                "edited_at": m.edited_at,
+                "deleted": m.deleted,
+                "reply_to": m.reply_to,
+            }))
+            .collect();
+
+        Ok(json!({
+            "exported_at": utils::now_secs(),
+            "account": {
+                "id": user.id,
+                "email": user.email,
+                "phone": user.phone,
+                "created_at": user.created_at,
+                "status": user.status.as_str(),
+            },
+            "profile": {
+                "display_name": profile.display_name,
+                "about": profile.about,
+                "avatar_mime": profile.avatar_mime,
+                "avatar_base64": base64::engine::general_purpose::STANDARD.encode(&profile.avatar),
+            },
+            "contacts": contacts,
+            "blocked": blocked,
+            "reports_filed": reports,
+            "sessions": sessions,
+            "two_factor_enabled": two_factor,
+            "messages": messages,
+        }))
+    }
+
+    /// Both ends of a contact/block/report must be real accounts, and not the same one.
+    async fn ensure_peer(&self, user_id: &str, peer_id: &str)->Result<(), Status>{
+        if user_id == peer_id{
+            return Err(Status::invalid_argument("cannot do that to yourself"));
+        }
+        for user_id in [user_id, peer_id]{
+            if self.store.get_user(user_id).await.map_err(Status::internal)?.is_none(){
+                return Err(Status::not_found(format!("no such user: {}", user_id)));
+            }
         }
         Ok(())
     }
-}
\ No newline at end of file
+}
+
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use crate::mailer::{MailQueue, RetryPolicy, SenderIdentity};
+    use crate::mailer::fakes::{CapturingTransport, MemoryDeadLetters};
+    use crate::sms::fakes::CapturingSms;
+
+    fn request()->UpdateProfileRequest{
+        UpdateProfileRequest{display_name: Some("  Ada  ".to_owned()), ..Default::default()}
+    }
+
+    #[test]
+    fn profile_updates_are_trimmed_and_limited(){
+        let update = profile_update(request()).unwrap();
+        assert_eq!(update.display_name.as_deref(), Some("Ada"));
+        assert_eq!(update.about, None);
+        assert_eq!(update.avatar, None);
+
+        for display_name in ["   ".to_owned(), "x".repeat(MAX_DISPLAY_NAME_CHARS + 1)]{
+            let status = profile_update(UpdateProfileRequest{display_name: Some(display_name), ..request()}).unwrap_err();
+            assert_eq!(status.code(), tonic::Code::InvalidArgument);
+        }
+        let about = "é".repeat(MAX_ABOUT_CHARS);
+        assert!(profile_update(UpdateProfileRequest{about: Some(about.clone()), ..request()}).is_ok());
+        assert!(profile_update(UpdateProfileRequest{about: Some(about + "é"), ..request()}).is_err());
+    }
+
+    #[test]
+    fn avatars_must_be_small_images(){
+        let with_avatar = |bytes: usize, mime: Option<&str>| UpdateProfileRequest{
+            avatar: Some(vec![0; bytes]),
+            avatar_mime: mime.map(str::to_owned),
+            ..request()
+        };
+
+        let update = profile_update(with_avatar(MAX_AVATAR_BYTES, Some("image/png"))).unwrap();
+        assert_eq!(update.avatar, Some((vec![0; MAX_AVATAR_BYTES], "image/png".to_owned())));
+
+        for bad in [with_avatar(MAX_AVATAR_BYTES + 1, Some("image/png")), with_avatar(10, Some("text/html")), with_avatar(10, None)]{
+            assert_eq!(profile_update(bad).unwrap_err().code(), tonic::Code::InvalidArgument);
+        }
+    }
+
+    async fn service()->(UserService, Arc<crate::stores::sqlite::SqliteStore>, Arc<CapturingTransport>, Arc<CapturingSms>){
+        let store = crate::stores::sqlite::test_store().await;
+        // these tests skip the code checks that live in Redis
+        let pool = crate::sessions::fakes::dead_redis_pool();
+        let sessions = Arc::new(crate::sessions::fakes::sessions(crate::chat_service::ChatService::start_channel_layer()));
+        let transport = Arc::new(CapturingTransport::default());
+        let queue = MailQueue::start(transport.clone(), Arc::new(MemoryDeadLetters::default()), RetryPolicy::default(), 8);
+        let sender = SenderIdentity{name: "Chat Team".to_owned(), address: "hello@chat.example".to_owned(), product: "ChatApp".to_owned()};
+        let sms = Arc::new(CapturingSms::default());
+        let two_factor = TwoFactor::new(store.clone(), pool.clone(), crate::totp::SecretCipher::new(&[7; 32]).unwrap(), "ChatApp".to_owned());
+        let service = UserService::new(
+            store.clone(),
+            pool.clone(),
+            sessions,
+            Arc::new(Mailer::new(queue, sender)),
+            Texter::new(sms.clone(), "ChatApp".to_owned()),
+            MagicLinks::new(pool, "test-key".to_owned(), "dioxuschat://login".to_owned()),
+            two_factor,
+        );
+        (service, store, transport, sms)
+    }
+
+    #[test]
+    fn new_identifiers_are_normalized(){
+        let email = new_identifier(Some(NewId::Email(" Ivy@Example.com ".to_owned()))).unwrap();
+        assert_eq!(email, Identifier::Email("ivy@example.com".to_owned()));
+        let phone = new_identifier(Some(NewId::Phone("+34 600-000-000".to_owned()))).unwrap();
+        assert_eq!(phone, Identifier::Phone("+34600000000".to_owned()));
+
+        for bad in [NewId::Email("ivy".to_owned()), NewId::Phone("600 000 000".to_owned()), NewId::Phone("+1 23".to_owned()), NewId::Phone("+1 555+0004".to_owned())]{
+            assert_eq!(new_identifier(Some(bad)).unwrap_err().code(), tonic::Code::InvalidArgument);
+        }
+        assert!(new_identifier(None).is_err());
+    }
+
+    #[tokio::test]
+    async fn confirmed_changes_attach_or_replace_and_notify_the_old_addresses(){
+        let (service, store, mail, sms) = service().await;
+        let ivy = store.find_or_create(&Identifier::parse("ivy@example.com")).await.unwrap();
+        let jack = store.find_or_create(&Identifier::parse("jack@example.com")).await.unwrap();
+        let phone = Identifier::parse("+15550004");
+
+        let after = service.apply_identifier_change(&ivy.id, &phone, false, Locale::En).await.unwrap();
+        assert_eq!(after.email.as_deref(), Some("ivy@example.com"));
+        assert_eq!(after.phone.as_deref(), Some("+15550004"));
+        let sent = mail.wait_for(1).await;
+        let raw = String::from_utf8(sent[0].formatted()).unwrap();
+        assert!(raw.contains("To: ivy@example.com"));
+        assert!(raw.contains("+15550004"));
+        // nothing to replace, so no text
+        assert!(sms.sent().is_empty());
+
+        let other = Identifier::parse("+15550005");
+        let status = service.apply_identifier_change(&ivy.id, &other, false, Locale::En).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::FailedPrecondition);
+        let after = service.apply_identifier_change(&ivy.id, &other, true, Locale::En).await.unwrap();
+        assert_eq!(after.phone.as_deref(), Some("+15550005"));
+        let texts = sms.sent();
+        assert_eq!(texts.len(), 1);
+        assert_eq!(texts[0].0, "+15550004");
+        assert!(texts[0].1.contains("+15550005"));
+        assert_eq!(store.find_by_identifier(&phone).await.unwrap(), None);
+
+        let mine = service.check_identifier_change(&ivy.id, &other, true).await.unwrap_err();
+        assert_eq!(mine.code(), tonic::Code::InvalidArgument);
+        let taken = service.apply_identifier_change(&ivy.id, &Identifier::parse("jack@example.com"), true, Locale::En).await.unwrap_err();
+        assert_eq!(taken.code(), tonic::Code::AlreadyExists);
+        assert_eq!(store.get_user(&jack.id).await.unwrap().unwrap().email.as_deref(), Some("jack@example.com"));
+    }
+}
@@ -1,5 +1,7 @@
+use aes_gcm::aead::OsRng;
+use aes_gcm::aead::rand_core::RngCore;
 use hmac::{Hmac, Mac};
-use sha2::Sha256;
+use sha2::{Digest, Sha256};
 use hex;
 
 type HmacSha256 = Hmac<Sha256>;
@@ -29,3 +31,67 @@ pub fn verify_uuid(payload: &String, encoded_msg: &String, key: &String) -> bool
     mac.verify_slice(&existing_code).is_ok()
 }
 
+
+/// Bytes from the operating system's CSPRNG, fit for secrets and nonces.
+pub fn random_bytes(n_bytes: usize) -> Vec<u8> {
+    let mut bytes = vec![0u8; n_bytes];
+    OsRng.fill_bytes(&mut bytes);
+    bytes
+}
+
+/// A short code for people to type, e.g. one mailed to confirm an action:
+/// `len` letters and digits from the OS CSPRNG, without modulo bias.
+pub fn random_code(len: usize) -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789";
+    // 248 is the largest multiple of 62 that fits a byte; anything above is redrawn
+    let limit = (256 / ALPHABET.len() * ALPHABET.len()) as u8;
+    let mut code = String::with_capacity(len);
+    while code.len() < len {
+        for byte in random_bytes(len) {
+            if byte < limit && code.len() < len {
+                code.push(ALPHABET[byte as usize % ALPHABET.len()] as char);
+            }
+        }
+    }
+    code
+}
+
+pub fn random_hex(n_bytes: usize) -> String {
+    hex::encode(random_bytes(n_bytes))
+}
+
+pub fn now_secs() -> i64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or_default()
+}
+
+pub fn sha256_hex(payload: &str) -> String {
+    hex::encode(Sha256::digest(payload.as_bytes()))
+}
+
+/// `payload.signature` — for tokens that carry their own data, like login links.
+pub fn sign_token(payload: &str, key: &str) -> String {
+    format!("{}.{}", payload, create_uuid(&payload.to_owned(), &key.to_owned()))
+}
+
+/// The payload of a `sign_token` token, if its signature checks out.
+pub fn open_token<'a>(token: &'a str, key: &str) -> Option<&'a str> {
+    let (payload, signature) = token.rsplit_once('.')?;
+    verify_uuid(&payload.to_owned(), &signature.to_owned(), &key.to_owned()).then_some(payload)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn random_codes_are_alphanumeric_and_vary() {
+        let code = random_code(6);
+        assert_eq!(code.len(), 6);
+        assert!(code.chars().all(|c| c.is_ascii_alphanumeric()));
+        assert_ne!(random_code(32), random_code(32));
+    }
+}
@@ -1,10 +1,11 @@
 fn main(){
-	tonic_prost_build::compile_protos(
-		"../proto_library/users.proto"
-	).unwrap();
-
-	tonic_prost_build::compile_protos(
-		"../proto_library/chat.proto"
-	).unwrap();
-
-}
\ No newline at end of file
+	// clients only, and without tonic's transport so the same code builds for
+	// wasm32, where calls go through gRPC-Web instead
+	for proto in ["../proto_library/users.proto", "../proto_library/chat.proto"]{
+		tonic_prost_build::configure()
+			.build_server(false)
+			.build_transport(false)
+			.compile_protos(&[proto], &["../proto_library"])
+			.unwrap();
+	}
+}
@@ -0,0 +1,160 @@
+//! Encrypted on-disk copy of the sidebar and message history, so the app opens
+//! with everything in place and only asks the server for what changed.
+//!
+//! Each chat is one sealed JSON blob (AES-256-GCM, `nonce || ciphertext`) under
+//! the session's `cache_key`. The server hands that key out with the session
+//! tokens and it is never written to disk, so the file is unreadable without a
+//! live session. Peers are indexed by a keyed hash rather than their id.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::path::PathBuf;
+
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng};
+use aes_gcm::{Aes256Gcm, Nonce};
+use rusqlite::OptionalExtension;
+use sha2::{Digest, Sha256};
+
+use crate::Chat;
+
+/// Messages kept per chat; older ones can be fetched from the server again.
+const MAX_CACHED_MESSAGES: usize = 500;
+/// Sealed into the file to tell whether it was written under the current key.
+const KEY_CHECK: &[u8] = b"dioxuschat-cache-v1";
+
+pub struct ChatCache {
+    conn: rusqlite::Connection,
+    cipher: Aes256Gcm,
+    key: Vec<u8>,
+    // peer tag -> hash of what was last written, to skip unchanged chats
+    written: RefCell<HashMap<String, u64>>,
+}
+
+impl ChatCache {
+    /// Opens `user_id`'s cache. Contents written under another session's key
+    /// can't be read anyway, so they are dropped.
+    pub fn open(user_id: &str, cache_key_hex: &str) -> Result<Self, String> {
+        let key = hex::decode(cache_key_hex).map_err(|_| "malformed cache key".to_string())?;
+        let cipher = Aes256Gcm::new_from_slice(&key).map_err(|_| "cache key must be 32 bytes".to_string())?;
+
+        let path = cache_path(user_id);
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir).map_err(|e| format!("could not create {}: {e}", dir.display()))?;
+        }
+        let conn = rusqlite::Connection::open(&path).map_err(|e| e.to_string())?;
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS meta (name TEXT PRIMARY KEY, value BLOB NOT NULL);
+             CREATE TABLE IF NOT EXISTS chats (peer_tag TEXT PRIMARY KEY, sealed BLOB NOT NULL);",
+        )
+        .map_err(|e| e.to_string())?;
+
+        let cache = Self { conn, cipher, key, written: RefCell::default() };
+        cache.check_key()?;
+        Ok(cache)
+    }
+
+    fn check_key(&self) -> Result<(), String> {
+        let stored: Option<Vec<u8>> = self.conn
+            .query_row("SELECT value FROM meta WHERE name = 'key_check'", [], |row| row.get(0))
+            .optional()
+            .map_err(|e| e.to_string())?;
+        if stored.and_then(|sealed| self.unseal(&sealed).ok()).as_deref() == Some(KEY_CHECK) {
+            return Ok(());
+        }
+
+        self.conn.execute_batch("DELETE FROM chats; DELETE FROM meta;").map_err(|e| e.to_string())?;
+        self.conn
+            .execute("INSERT INTO meta (name, value) VALUES ('key_check', ?1)", [self.seal(KEY_CHECK)?])
+            .map_err(|e| e.to_string())?;
+        Ok(())
+    }
+
+    /// Every cached chat. Local ids are left at 0 for the caller to assign.
+    pub fn load_chats(&self) -> Vec<Chat> {
+        let Ok(mut statement) = self.conn.prepare("SELECT peer_tag, sealed FROM chats") else {
+            return Vec::new();
+        };
+        let rows = statement.query_map([], |row| Ok((row.get::<_, String>(0)?, row.get::<_, Vec<u8>>(1)?)));
+        let Ok(rows) = rows else { return Vec::new() };
+
+        let mut chats = Vec::new();
+        for (tag, sealed) in rows.flatten() {
+            let Ok(json) = self.unseal(&sealed) else { continue };
+            let Ok(chat) = serde_json::from_slice::<Chat>(&json) else { continue };
+            self.written.borrow_mut().insert(tag, fingerprint(&json));
+            chats.push(chat);
+        }
+        chats
+    }
+
+    /// Writes `chat` unless it is unchanged since the last write.
+    pub fn save_chat(&self, chat: &Chat) -> Result<(), String> {
+        let mut chat = chat.clone();
+        let stored = chat.messages.iter().filter(|m| m.server_id != 0).count();
+        let mut excess = stored.saturating_sub(MAX_CACHED_MESSAGES);
+        chat.messages.retain(|m| {
+            let drop = excess > 0 && m.server_id != 0;
+            if drop { excess -= 1; }
+            !drop
+        });
+
+        let json = serde_json::to_vec(&chat).map_err(|e| e.to_string())?;
+        let tag = self.peer_tag(&chat.peer_id);
+        let print = fingerprint(&json);
+        if self.written.borrow().get(&tag) == Some(&print) {
+            return Ok(());
+        }
+
+        self.conn
+            .execute(
+                "INSERT INTO chats (peer_tag, sealed) VALUES (?1, ?2)
+                 ON CONFLICT (peer_tag) DO UPDATE SET sealed = excluded.sealed",
+                rusqlite::params![tag, self.seal(&json)?],
+            )
+            .map_err(|e| e.to_string())?;
+        self.written.borrow_mut().insert(tag, print);
+        Ok(())
+    }
+
+    fn peer_tag(&self, peer_id: &str) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(&self.key);
+        hasher.update(peer_id.as_bytes());
+        hex::encode(hasher.finalize())
+    }
+
+    fn seal(&self, plaintext: &[u8]) -> Result<Vec<u8>, String> {
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let ciphertext = self.cipher
+            .encrypt(&nonce, plaintext)
+            .map_err(|_| "could not encrypt cache entry".to_string())?;
+        Ok([nonce.as_slice(), &ciphertext].concat())
+    }
+
+    fn unseal(&self, sealed: &[u8]) -> Result<Vec<u8>, String> {
+        if sealed.len() < 12 {
+            return Err("cache entry is too short".to_string());
+        }
+        let (nonce, ciphertext) = sealed.split_at(12);
+        self.cipher
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map_err(|_| "could not decrypt cache entry".to_string())
+    }
+}
+
+fn fingerprint(bytes: &[u8]) -> u64 {
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    bytes.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Deletes `user_id`'s cache, e.g. when the account is deleted or on logout.
+pub fn wipe(user_id: &str) {
+    let _ = std::fs::remove_file(cache_path(user_id));
+}
+
+fn cache_path(user_id: &str) -> PathBuf {
+    let name = hex::encode(Sha256::digest(user_id.as_bytes()));
+    crate::platform::data_dir().join(format!("cache-{}.sqlite3", &name[..16]))
+}
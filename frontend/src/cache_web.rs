@@ -0,0 +1,23 @@
+//! Browser stand-in for the message cache. There is no SQLite in the page and
+//! the sealed history is too big for localStorage, so the web build always
+//! starts from the server and keeps nothing between visits.
+
+use crate::Chat;
+
+pub struct ChatCache;
+
+impl ChatCache {
+    pub fn open(_user_id: &str, _cache_key_hex: &str) -> Result<Self, String> {
+        Err("no message cache in the browser".to_string())
+    }
+
+    pub fn load_chats(&self) -> Vec<Chat> {
+        Vec::new()
+    }
+
+    pub fn save_chat(&self, _chat: &Chat) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+pub fn wipe(_user_id: &str) {}
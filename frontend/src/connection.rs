@@ -0,0 +1,96 @@
+//! Keeping the link to the server up: how long to wait between reconnects,
+//! and what the UI says about it meanwhile.
+
+use std::time::Duration;
+
+const FIRST_RETRY: Duration = Duration::from_millis(500);
+const MAX_RETRY: Duration = Duration::from_secs(30);
+
+/// State of the message stream, shown as a banner when it isn't `Online`.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Connection {
+    Connecting,
+    Online,
+    /// Next attempt in this many seconds.
+    Offline(u64),
+}
+
+/// Exponential backoff with jitter, so a server restart isn't met by every
+/// client reconnecting in the same instant.
+#[derive(Default)]
+pub struct Backoff {
+    attempt: u32,
+}
+
+impl Backoff {
+    /// How long to wait before the next attempt: doubling from half a second up
+    /// to 30s, each wait randomly cut to somewhere between half and all of it.
+    pub fn next_delay(&mut self) -> Duration {
+        let ceiling = self.ceiling();
+        self.attempt += 1;
+        let half = ceiling / 2;
+        half + half.mul_f64(jitter())
+    }
+
+    pub fn reset(&mut self) {
+        self.attempt = 0;
+    }
+
+    /// The longest the next wait can be.
+    fn ceiling(&self) -> Duration {
+        FIRST_RETRY.saturating_mul(1 << self.attempt.min(16)).min(MAX_RETRY)
+    }
+}
+
+/// A number in [0, 1). Only spreads clients apart, so the clock is random enough.
+fn jitter() -> f64 {
+    let nanos = crate::platform::since_epoch().subsec_nanos();
+    // scramble the low bits a little; consecutive calls are close in time
+    let mixed = nanos.wrapping_mul(2_654_435_761) >> 8;
+    mixed as f64 / (1u32 << 24) as f64
+}
+
+/// Errors worth retrying once the connection is back, as opposed to the
+/// server turning the request down.
+pub fn is_transient(status: &tonic::Status) -> bool {
+    matches!(
+        status.code(),
+        tonic::Code::Unavailable | tonic::Code::DeadlineExceeded | tonic::Code::Cancelled | tonic::Code::Unknown
+    )
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_doubles_up_to_the_cap_and_resets() {
+        let mut backoff = Backoff::default();
+        let ceilings: Vec<u64> = (0..10)
+            .map(|_| {
+                let ceiling = backoff.ceiling();
+                let delay = backoff.next_delay();
+                assert!(delay >= ceiling / 2 && delay <= ceiling, "{delay:?} outside {ceiling:?}");
+                ceiling.as_millis() as u64
+            })
+            .collect();
+        assert_eq!(ceilings, [500, 1_000, 2_000, 4_000, 8_000, 16_000, 30_000, 30_000, 30_000, 30_000]);
+
+        // far past the cap the shift must not overflow
+        backoff.attempt = 1_000;
+        assert_eq!(backoff.ceiling(), MAX_RETRY);
+
+        backoff.reset();
+        assert_eq!(backoff.ceiling(), FIRST_RETRY);
+        assert!(backoff.next_delay() <= FIRST_RETRY);
+    }
+
+    #[test]
+    fn jitter_stays_in_the_unit_interval() {
+        for _ in 0..1_000 {
+            let j = jitter();
+            assert!((0.0..1.0).contains(&j), "{j}");
+        }
+    }
+}
@@ -0,0 +1,30 @@
+//! The signed-in account, remembered between runs so a restart doesn't mean
+//! another OTP. Only the refresh token is kept: the access token and cache key
+//! come back from RefreshToken on the next launch, and the refresh token
+//! rotates on every renewal, so a copied file stops working once the app runs.
+
+use serde::{Deserialize, Serialize};
+
+use crate::platform;
+
+const FILE: &str = "login.json";
+
+#[derive(Serialize, Deserialize)]
+pub struct SavedLogin {
+    pub user_id: String,
+    pub refresh_token: String,
+}
+
+pub fn load() -> Option<SavedLogin> {
+    serde_json::from_str(&platform::read_text(FILE)?).ok()
+}
+
+/// Replaces the saved login, readable only by the current user.
+pub fn save(login: &SavedLogin) -> Result<(), String> {
+    let json = serde_json::to_string(login).map_err(|e| e.to_string())?;
+    platform::write_text(FILE, &json, true)
+}
+
+pub fn clear() {
+    platform::remove(FILE);
+}
@@ -1,74 +1,401 @@
+//! Typed access to the backend. Components take the shared [`Api`] handle from
+//! `GlobalState` and call its methods, or those of the signed-in [`Account`];
+//! every call carries the current access token, so call sites pass plain values.
+
+use dioxus::prelude::*;
+use tonic::service::interceptor::InterceptedService;
+
 // ── Generated protobuf types ──────────────────────────────────────────────────
-pub mod proto {
+pub mod users {
     tonic::include_proto!("users");
 }
+pub mod chat {
+    tonic::include_proto!("chat");
+}
 
-use proto::auth_service_client::AuthServiceClient;
-use proto::{OtpRequest, VerifyRequest};
-
-/// Address of the gRPC auth server.
-/// Change this to match your deployment (e.g. "https://api.example.com:443").
-pub const SERVER_ADDR: &str = "http://[::1]:50051";
-
-// ── Public async helpers ──────────────────────────────────────────────────────
-
-/// Send a one-time-password to `identifier` (email or phone).
-///
-/// Returns the opaque `request_id` that must be passed to `verify_otp`, or an
-/// error string to display to the user.
-pub async fn request_otp(identifier: String) -> Result<String, String> {
-    let mut client = AuthServiceClient::connect(SERVER_ADDR)
-        .await
-        .map_err(|e| format!("Cannot reach server: {e}"))?;
-
-    let resp = client
-        .request_otp(OtpRequest {
-            identifier: identifier.clone(),
-        })
-        .await
-        .map_err(|s| format!("Server error: {}", s.message()))?
-        .into_inner();
-
-    if resp.success {
-        Ok(resp.request_id)
-    } else {
-        Err(if resp.message.is_empty() {
-            "Failed to send OTP. Please try again.".into()
-        } else {
-            resp.message
-        })
+use chat::chat_client::ChatClient;
+use chat::{ChatEvent, Conversation, DeleteMessageRequest, EditMessageRequest, GetHistoryRequest, History, IncomingMessage};
+use chat::{ListConversationsRequest, MarkReadRequest, ReactRequest, ReactionChanged, ReceiveMessageRequest};
+use chat::{SearchHit, SearchMessagesRequest};
+use users::user_client::UserClient;
+use users::{identifier_change_request, lookup_user_request, otp_request, otp_verify_response};
+use users::{AccountDeletionRequest, AccountIdentifiers, ConfirmIdentifierChangeRequest, DeleteAccountRequest, Empty};
+use users::{GetProfileRequest, IdentifierChangeRequest, LookupUserRequest, OtpRequest, OtpVerifyRequest, OtpVerifyResponse};
+use users::{PeerRequest, Profile, RefreshTokenRequest, ReportReason, ReportUserRequest, SessionTokens, TotpCodeRequest};
+use users::{TotpEnrollment, TwoFactorStatus, UpdateProfileRequest, VerifyMagicLinkRequest, VerifyTwoFactorRequest};
+
+/// What calls travel over: an HTTP/2 channel on desktop, gRPC-Web requests
+/// through the browser's fetch on the web.
+#[cfg(not(target_arch = "wasm32"))]
+pub type Transport = tonic::transport::Channel;
+#[cfg(target_arch = "wasm32")]
+pub type Transport = tonic_web_wasm_client::Client;
+
+pub type UserApi = UserClient<InterceptedService<Transport, BearerToken>>;
+pub type ChatApi = ChatClient<InterceptedService<Transport, BearerToken>>;
+
+/// Opens the transport to `addr`. The desktop app dials the server here; the
+/// browser only finds out whether it's there with the first call.
+pub async fn connect(addr: &str) -> Result<Transport, String> {
+    #[cfg(not(target_arch = "wasm32"))]
+    {
+        let endpoint = tonic::transport::Endpoint::from_shared(addr.to_string())
+            .map_err(|e| format!("That is not a valid address: {e}"))?
+            .connect_timeout(std::time::Duration::from_secs(5));
+        endpoint.connect().await.map_err(|e| e.to_string())
+    }
+    #[cfg(target_arch = "wasm32")]
+    {
+        Ok(tonic_web_wasm_client::Client::new(addr.to_string()))
+    }
+}
+
+/// Whether a chat server answers at `addr`, before there is a session to call
+/// it with. The call is a refresh with no token, so any answer but
+/// "unavailable" means one is listening.
+pub async fn probe(addr: &str) -> Result<(), String> {
+    let transport = connect(addr).await?;
+    match UserClient::new(transport).refresh_token(RefreshTokenRequest::default()).await {
+        Err(e) if matches!(e.code(), tonic::Code::Unavailable | tonic::Code::Unknown) => Err(e.message().to_string()),
+        _ => Ok(()),
     }
 }
 
-/// Verify the OTP the user entered.
-///
-/// Returns the session token on success, or an error string.
-pub async fn verify_otp(
-    request_id: String,
-    identifier: String,
-    otp: String,
-) -> Result<String, String> {
-    let mut client = AuthServiceClient::connect(SERVER_ADDR)
-        .await
-        .map_err(|e| format!("Cannot reach server: {e}"))?;
-
-    let resp = client
-        .verify_otp(VerifyRequest {
-            request_id,
-            identifier,
-            otp,
-        })
-        .await
-        .map_err(|s| format!("Server error: {}", s.message()))?
-        .into_inner();
-
-    if resp.success {
-        Ok(resp.token)
-    } else {
-        Err(if resp.message.is_empty() {
-            "Invalid OTP. Please check and try again.".into()
+/// Adds `authorization: Bearer <access token>` to requests while signed in.
+#[derive(Clone, Copy)]
+pub struct BearerToken(Signal<Option<SessionTokens>>);
+
+impl tonic::service::Interceptor for BearerToken {
+    fn call(&mut self, mut request: tonic::Request<()>) -> Result<tonic::Request<()>, tonic::Status> {
+        if let Some(tokens) = self.0.peek().as_ref() {
+            if let Ok(value) = format!("Bearer {}", tokens.access_token).parse() {
+                request.metadata_mut().insert("authorization", value);
+            }
+        }
+        Ok(request)
+    }
+}
+
+/// The shared transport plus the session whose token goes with each call.
+/// Cheap to clone: the channel is a handle and the session a signal.
+#[derive(Clone)]
+pub struct Api {
+    channel: Transport,
+    session: Signal<Option<SessionTokens>>,
+}
+
+/// Where a first-factor login left us.
+#[derive(Clone, PartialEq)]
+pub enum SignIn {
+    /// Account id (the "uuid") and the new session's tokens.
+    Done(String, SessionTokens),
+    /// Two-step verification is on; the token goes to VerifyTwoFactor with a code.
+    NeedsCode(String),
+}
+
+/// Reads a VerifyOtp / VerifyMagicLink / VerifyTwoFactor reply.
+fn login_result(resp: OtpVerifyResponse) -> Result<SignIn, String> {
+    if !resp.two_factor_token.is_empty() {
+        return Ok(SignIn::NeedsCode(resp.two_factor_token));
+    }
+    match (resp.res, resp.tokens) {
+        (Some(otp_verify_response::Res::Uuid(uuid)), Some(tokens)) => Ok(SignIn::Done(uuid, tokens)),
+        (Some(otp_verify_response::Res::ErrMsg(msg)), _) => Err(msg),
+        _ => Err("Unexpected response from server.".to_string()),
+    }
+}
+
+impl Api {
+    pub fn new(channel: Transport, session: Signal<Option<SessionTokens>>) -> Self {
+        Self { channel, session }
+    }
+
+    pub fn users(&self) -> UserApi {
+        UserClient::with_interceptor(self.channel.clone(), BearerToken(self.session))
+    }
+
+    pub fn chat(&self) -> ChatApi {
+        ChatClient::with_interceptor(self.channel.clone(), BearerToken(self.session))
+    }
+
+    /// The chat calls made as `user_id`, the account this session belongs to.
+    pub fn account(&self, user_id: String) -> Account {
+        Account { api: self.clone(), user_id }
+    }
+
+    // ── Auth ──────────────────────────────────────────────────────────────────
+
+    /// Sends a sign-in code, or with `magic_link` a sign-in link, to an email
+    /// address or phone number.
+    pub async fn request_otp(&self, address: String, magic_link: bool, locale: String) -> Result<(), tonic::Status> {
+        let id = if address.contains('@') { otp_request::Id::Email(address) } else { otp_request::Id::Phone(address) };
+        self.users().request_otp(OtpRequest { id: Some(id), locale, magic_link }).await?;
+        Ok(())
+    }
+
+    /// Signs in with the code sent to `address`.
+    pub async fn verify_otp(&self, address: String, otp: String, device_name: String, locale: String) -> Result<SignIn, String> {
+        let resp = self.users()
+            .verify_otp(OtpVerifyRequest { email_or_phone: address, otp, device_name, locale })
+            .await
+            .map_err(|e| format!("Verification failed: {}", e.message()))?;
+        login_result(resp.into_inner())
+    }
+
+    /// Signs in with an emailed link, pasted whole or received as a deep link.
+    pub async fn verify_magic_link(&self, link: String, device_name: String, locale: String) -> Result<SignIn, String> {
+        let resp = self.users()
+            .verify_magic_link(VerifyMagicLinkRequest { link, device_name, locale })
+            .await
+            .map_err(|e| format!("Sign-in failed: {}", e.message()))?;
+        login_result(resp.into_inner())
+    }
+
+    /// Finishes a sign-in that stopped at [`SignIn::NeedsCode`].
+    pub async fn verify_two_factor(&self, two_factor_token: String, code: String) -> Result<SignIn, String> {
+        let resp = self.users()
+            .verify_two_factor(VerifyTwoFactorRequest { two_factor_token, code })
+            .await
+            .map_err(|e| e.message().to_string())?;
+        login_result(resp.into_inner())
+    }
+
+    /// Trades a refresh token for fresh tokens. The old refresh token stops working.
+    pub async fn refresh(&self, refresh_token: String) -> Result<SessionTokens, tonic::Status> {
+        let resp = self.users().refresh_token(RefreshTokenRequest { refresh_token }).await?;
+        Ok(resp.into_inner())
+    }
+
+    /// Ends this session on the server.
+    pub async fn logout(&self) -> Result<(), tonic::Status> {
+        self.users().logout(Empty {}).await?;
+        Ok(())
+    }
+
+    // ── Profiles and contacts ─────────────────────────────────────────────────
+
+    pub async fn profile(&self, user_id: String) -> Result<Profile, tonic::Status> {
+        let resp = self.users().get_profile(GetProfileRequest { user_id }).await?;
+        Ok(resp.into_inner())
+    }
+
+    /// Saves our own profile; `avatar` is the image and its mime type, if a new
+    /// one was picked. Returns the profile as stored.
+    pub async fn update_profile(
+        &self,
+        display_name: String,
+        about: String,
+        avatar: Option<(Vec<u8>, String)>,
+    ) -> Result<Profile, tonic::Status> {
+        let (avatar, avatar_mime) = avatar.unzip();
+        let request = UpdateProfileRequest { display_name: Some(display_name), about: Some(about), avatar, avatar_mime };
+        let resp = self.users().update_profile(request).await?;
+        Ok(resp.into_inner())
+    }
+
+    /// Finds the account behind an email address or phone number; NotFound if
+    /// nobody signs in with it.
+    pub async fn lookup(&self, address: String) -> Result<Profile, tonic::Status> {
+        let id = if address.contains('@') {
+            lookup_user_request::Id::Email(address)
+        } else {
+            lookup_user_request::Id::Phone(address)
+        };
+        let resp = self.users().lookup_user(LookupUserRequest { id: Some(id) }).await?;
+        Ok(resp.into_inner())
+    }
+
+    /// People we saved as contacts.
+    pub async fn contacts(&self) -> Result<Vec<Profile>, tonic::Status> {
+        Ok(self.users().list_contacts(Empty {}).await?.into_inner().profiles)
+    }
+
+    /// People we blocked.
+    pub async fn blocked(&self) -> Result<Vec<Profile>, tonic::Status> {
+        Ok(self.users().list_blocked(Empty {}).await?.into_inner().profiles)
+    }
+
+    /// Saves `peer_id` as a contact, or with `saved` false removes them.
+    pub async fn set_contact(&self, peer_id: String, saved: bool) -> Result<(), tonic::Status> {
+        let mut users = self.users();
+        let request = PeerRequest { peer_id };
+        if saved { users.add_contact(request).await?; } else { users.remove_contact(request).await?; }
+        Ok(())
+    }
+
+    /// Blocks `peer_id`, or with `blocked` false unblocks them.
+    pub async fn set_blocked(&self, peer_id: String, blocked: bool) -> Result<(), tonic::Status> {
+        let mut users = self.users();
+        let request = PeerRequest { peer_id };
+        if blocked { users.block_user(request).await?; } else { users.unblock_user(request).await?; }
+        Ok(())
+    }
+
+    pub async fn report(
+        &self,
+        peer_id: String,
+        reason: ReportReason,
+        details: String,
+        also_block: bool,
+    ) -> Result<(), tonic::Status> {
+        let request = ReportUserRequest { reported_id: peer_id, reason: reason as i32, details, also_block };
+        self.users().report_user(request).await?;
+        Ok(())
+    }
+
+    // ── Two-step verification ─────────────────────────────────────────────────
+
+    pub async fn two_factor_status(&self) -> Result<TwoFactorStatus, tonic::Status> {
+        Ok(self.users().get_two_factor_status(Empty {}).await?.into_inner())
+    }
+
+    /// Starts setting up an authenticator; nothing changes until it is confirmed.
+    pub async fn enroll_totp(&self) -> Result<TotpEnrollment, tonic::Status> {
+        Ok(self.users().enroll_totp(Empty {}).await?.into_inner())
+    }
+
+    /// Turns two-step verification on; returns the recovery codes.
+    pub async fn confirm_totp(&self, code: String) -> Result<Vec<String>, tonic::Status> {
+        Ok(self.users().confirm_totp(TotpCodeRequest { code }).await?.into_inner().codes)
+    }
+
+    /// Replaces the recovery codes; the old ones stop working.
+    pub async fn regenerate_recovery_codes(&self, code: String) -> Result<Vec<String>, tonic::Status> {
+        Ok(self.users().regenerate_recovery_codes(TotpCodeRequest { code }).await?.into_inner().codes)
+    }
+
+    pub async fn disable_totp(&self, code: String) -> Result<(), tonic::Status> {
+        self.users().disable_totp(TotpCodeRequest { code }).await?;
+        Ok(())
+    }
+
+    // ── Account ───────────────────────────────────────────────────────────────
+
+    /// The email address and phone number the account signs in with.
+    pub async fn identifiers(&self) -> Result<AccountIdentifiers, tonic::Status> {
+        Ok(self.users().get_account_identifiers(Empty {}).await?.into_inner())
+    }
+
+    /// Sends a code to `address` to prove it is ours. With `replace` it takes
+    /// the place of the one of its kind the account has, otherwise it is added.
+    pub async fn request_identifier_change(&self, address: String, replace: bool, locale: String) -> Result<(), tonic::Status> {
+        let new_id = if address.contains('@') {
+            identifier_change_request::NewId::Email(address)
         } else {
-            resp.message
-        })
+            identifier_change_request::NewId::Phone(address)
+        };
+        let request = IdentifierChangeRequest { new_id: Some(new_id), locale, replace };
+        self.users().request_identifier_change(request).await?;
+        Ok(())
+    }
+
+    /// Completes the change with the code sent to the new address; returns
+    /// the identifiers as they are now.
+    pub async fn confirm_identifier_change(&self, code: String, two_factor_code: String) -> Result<AccountIdentifiers, tonic::Status> {
+        let request = ConfirmIdentifierChangeRequest { code, two_factor_code };
+        Ok(self.users().confirm_identifier_change(request).await?.into_inner())
+    }
+
+    /// Everything the server keeps about the account, as one JSON document.
+    pub async fn export_my_data(&self) -> Result<Vec<u8>, tonic::Status> {
+        let mut stream = self.users().export_my_data(Empty {}).await?.into_inner();
+        let mut bytes = Vec::new();
+        while let Some(chunk) = stream.message().await? {
+            bytes.extend_from_slice(&chunk.data);
+        }
+        Ok(bytes)
+    }
+
+    /// Mails the code that [`Api::delete_account`] asks for.
+    pub async fn request_account_deletion(&self, locale: String) -> Result<(), tonic::Status> {
+        self.users().request_account_deletion(AccountDeletionRequest { locale }).await?;
+        Ok(())
+    }
+
+    pub async fn delete_account(&self, code: String, two_factor_code: String) -> Result<(), tonic::Status> {
+        self.users().delete_account(DeleteAccountRequest { code, two_factor_code }).await?;
+        Ok(())
+    }
+}
+
+/// The signed-in account's side of the [`Api`]. Chat calls name the caller,
+/// which the server checks against the token, so this fills it in for them.
+#[derive(Clone)]
+pub struct Account {
+    api: Api,
+    user_id: String,
+}
+
+impl Account {
+    /// Opens the account's event stream; it ends when the server drops it or
+    /// the session is revoked.
+    pub async fn subscribe(&self) -> Result<tonic::Streaming<ChatEvent>, tonic::Status> {
+        let request = ReceiveMessageRequest { id: self.user_id.clone() };
+        Ok(self.api.chat().receive_incoming_messages(request).await?.into_inner())
+    }
+
+    /// Sends a message from this account. Returns it as stored, with its
+    /// server id and timestamp.
+    pub async fn send(&self, message: IncomingMessage) -> Result<IncomingMessage, tonic::Status> {
+        let message = IncomingMessage { from_addr: self.user_id.clone(), ..message };
+        Ok(self.api.chat().send_message(message).await?.into_inner())
+    }
+
+    /// Replaces the text of one of our messages; returns it as edited.
+    pub async fn edit(&self, message_id: u64, text: String) -> Result<IncomingMessage, tonic::Status> {
+        let request = EditMessageRequest { user_id: self.user_id.clone(), message_id, msg: text.into_bytes() };
+        Ok(self.api.chat().edit_message(request).await?.into_inner())
+    }
+
+    pub async fn delete(&self, message_id: u64, for_everyone: bool) -> Result<(), tonic::Status> {
+        let request = DeleteMessageRequest { user_id: self.user_id.clone(), message_id, for_everyone };
+        self.api.chat().delete_message(request).await?;
+        Ok(())
     }
-}
\ No newline at end of file
+
+    /// Adds, or with `remove` takes back, our `emoji` on a message. Returns
+    /// the change, to apply the same way as one arriving on the stream.
+    pub async fn react(&self, message_id: u64, emoji: String, remove: bool) -> Result<ReactionChanged, tonic::Status> {
+        let request = ReactRequest { user_id: self.user_id.clone(), message_id, emoji: emoji.clone(), remove };
+        self.api.chat().react_to_message(request).await?;
+        Ok(ReactionChanged { message_id, user_id: self.user_id.clone(), emoji, removed: remove })
+    }
+
+    /// Searches every conversation; newest hits first.
+    pub async fn search(&self, query: String) -> Result<Vec<SearchHit>, tonic::Status> {
+        let request = SearchMessagesRequest { user_id: self.user_id.clone(), query, ..Default::default() };
+        Ok(self.api.chat().search_messages(request).await?.into_inner().hits)
+    }
+
+    /// Marks `peer_id`'s messages up to `message_id` as read, on all our devices.
+    pub async fn mark_read(&self, peer_id: String, message_id: u64) -> Result<(), tonic::Status> {
+        let request = MarkReadRequest { user_id: self.user_id.clone(), peer_id, message_id };
+        self.api.chat().mark_read(request).await?;
+        Ok(())
+    }
+
+    /// Tells `peer_id` their messages up to `message_id` reached us.
+    pub async fn mark_delivered(&self, peer_id: String, message_id: u64) -> Result<(), tonic::Status> {
+        let request = MarkReadRequest { user_id: self.user_id.clone(), peer_id, message_id };
+        self.api.chat().mark_delivered(request).await?;
+        Ok(())
+    }
+
+    /// Every conversation, most recently active first.
+    pub async fn conversations(&self) -> Result<Vec<Conversation>, tonic::Status> {
+        let request = ListConversationsRequest { user_id: self.user_id.clone() };
+        Ok(self.api.chat().list_conversations(request).await?.into_inner().conversations)
+    }
+
+    /// A page of the conversation with `peer_id`, oldest first: the newest
+    /// messages, or with `before` the ones older than that message.
+    pub async fn history(&self, peer_id: String, before: Option<u64>) -> Result<History, tonic::Status> {
+        let request = GetHistoryRequest {
+            user_id: self.user_id.clone(),
+            peer_id,
+            before_id: before.unwrap_or_default(),
+            ..Default::default()
+        };
+        Ok(self.api.chat().get_history(request).await?.into_inner())
+    }
+}
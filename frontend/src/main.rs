@@ -1,66 +1,301 @@
+use base64::Engine;
 use dioxus::prelude::*;
-use tonic::transport::Channel;
+use serde::{Deserialize, Serialize};
 
-// ┌─────────────────────────────────────────────────────────────────────────┐
-// │  SERVER ADDRESS — edit this one line to point at your backend           │
-// └─────────────────────────────────────────────────────────────────────────┘
-const SERVER_ADDR: &str = "https://poodle-flexible-carefully.ngrok-free.app";
+// the browser has no SQLite, so the web build runs without a local copy
+#[cfg_attr(target_arch = "wasm32", path = "cache_web.rs")]
+mod cache;
+mod connection;
+mod credentials;
+mod notifications;
+mod platform;
+mod settings;
 
-// ── Proto modules ─────────────────────────────────────────────────────────────
-mod users {
-    tonic::include_proto!("users");
-}
-mod chat {
-    tonic::include_proto!("chat");
-}
+use connection::{is_transient, Backoff, Connection};
+
+/// Scheme the OS hands sign-in links to; the link arrives as a launch argument.
+const MAGIC_LINK_PREFIX: &str = "dioxuschat://login";
+
+// ── Backend client ────────────────────────────────────────────────────────────
+mod grpc;
 
-use users::user_client::UserClient;
-use users::{otp_request, OtpRequest, OtpVerifyRequest, otp_verify_response};
+use grpc::{chat, users, Api, SignIn};
+use users::{AccountIdentifiers, ReportReason, SessionTokens};
 
-use chat::chat_client::ChatClient;
-use chat::{IncomingMessage, ReceiveMessageRequest};
+use chat::{chat_event, IncomingMessage, ReactionChanged, SearchHit};
 
 // ── App-level screen state ────────────────────────────────────────────────────
 #[derive(Clone, PartialEq)]
 enum AppScreen {
+    Resuming,   // a saved login is being renewed
     Identifier,
     Otp,
+    TwoFactor,
     Chat,
 }
 
 // ── Global RPC state (single channel, shared everywhere) ─────────────────────
 #[derive(Clone)]
 struct GlobalState {
-    rpc_channel: Channel,
+    api: Api,
+    session: Signal<Option<SessionTokens>>,   // set once VerifyOtp succeeds
+}
+
+/// Signs in with an emailed link, pasted whole or received as a deep link.
+async fn verify_magic_link(api: Api, link: String) -> Result<SignIn, String> {
+    api.verify_magic_link(link, platform::device_name(), platform::locale()).await
+}
+
+/// Tags an outgoing message so the server's echo can be matched to it.
+fn new_client_id(local_id: usize) -> String {
+    let nanos = platform::since_epoch().as_nanos();
+    format!("{nanos:x}-{local_id}")
+}
+
+fn now_secs() -> i64 {
+    platform::since_epoch().as_secs() as i64
+}
+
+fn local_time(unix_secs: i64) -> chrono::DateTime<chrono::Local> {
+    chrono::DateTime::from_timestamp(unix_secs, 0).unwrap_or_default().with_timezone(&chrono::Local)
+}
+
+/// Compact age for the sidebar: "now", "5m", "3h", "2d", then the date.
+fn short_time(unix_secs: i64, now: i64) -> String {
+    let age = now - unix_secs;
+    match age {
+        _ if unix_secs == 0 => String::new(),
+        ..=59 => "now".to_string(),
+        60..=3599 => format!("{}m", age / 60),
+        3600..=86_399 => format!("{}h", age / 3600),
+        86_400..=604_799 => format!("{}d", age / 86_400),
+        _ => local_time(unix_secs).format("%d/%m/%y").to_string(),
+    }
+}
+
+/// Time of day on a message bubble, e.g. "14:05".
+fn clock_time(unix_secs: i64) -> String {
+    if unix_secs == 0 {
+        return String::new();
+    }
+    local_time(unix_secs).format("%H:%M").to_string()
+}
+
+/// Full date and time, for hovering over a message's time.
+fn full_time(unix_secs: i64) -> String {
+    local_time(unix_secs).format("%A %-d %B %Y, %H:%M").to_string()
+}
+
+fn same_day(a: i64, b: i64) -> bool {
+    local_time(a).date_naive() == local_time(b).date_naive()
+}
+
+/// Heading above each day's messages: "Today", "Yesterday", the weekday for
+/// the past week, then the date.
+fn day_label(unix_secs: i64, now: i64) -> String {
+    use chrono::Datelike;
+
+    let day = local_time(unix_secs).date_naive();
+    let today = local_time(now).date_naive();
+    match (today - day).num_days() {
+        0 => "Today".to_string(),
+        1 => "Yesterday".to_string(),
+        2..=6 => day.format("%A").to_string(),
+        _ if day.year() == today.year() => day.format("%-d %B").to_string(),
+        _ => day.format("%-d %B %Y").to_string(),
+    }
+}
+
+/// Messages from the same side this close together share one group of bubbles.
+const GROUP_GAP_SECS: i64 = 5 * 60;
+
+/// Whether `message` continues the group `previous` belongs to.
+fn continues_group(previous: &Message, message: &Message) -> bool {
+    previous.side == message.side
+        && previous.sent_at != 0
+        && message.sent_at - previous.sent_at < GROUP_GAP_SECS
+        && same_day(previous.sent_at, message.sent_at)
 }
 
 // ── Data model ───────────────────────────────────────────────────────────────
-#[derive(Clone, PartialEq)]
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize)]
 enum Side {
     Me,
     Them,
 }
 
-#[derive(Clone, PartialEq)]
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
 struct Message {
+    #[serde(skip)]
     id: usize,
+    server_id: u64,      // 0 until the server has stored it
+    client_id: String,   // ours for messages we sent, so the server's echo can be matched
     text: String,
     side: Side,
+    edited: bool,
+    deleted: bool,       // deleted for everyone; only a placeholder is shown
+    quote: Option<Quote>,
+    reactions: Vec<Reaction>,
+    #[serde(default)]
+    state: MessageState,
+    #[serde(default)]
+    sent_at: i64,        // unix seconds the server stored it at; our clock until then
 }
 
-#[derive(Clone, PartialEq)]
+/// How far one of our messages has got. Received messages just stay `Sent`.
+#[derive(Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+enum MessageState {
+    Pending,     // queued, or on its way to the server
+    #[default]
+    Sent,        // stored by the server
+    Delivered,   // reached one of the peer's devices
+    Read,
+    Failed,      // turned down by the server, or the app closed before it went out
+}
+
+/// The message a reply points at, as shown above the reply.
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+struct Quote {
+    server_id: u64,
+    from_addr: String,
+    snippet: String,
+    deleted: bool,
+}
+
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+struct Reaction {
+    emoji: String,
+    user_ids: Vec<String>,
+}
+
+impl Message {
+    fn received(id: usize, incoming: &IncomingMessage, side: Side) -> Self {
+        Self {
+            id,
+            server_id: incoming.id,
+            client_id: incoming.client_id.clone(),
+            text: String::from_utf8_lossy(&incoming.msg).to_string(),
+            side,
+            edited: incoming.edited_at != 0,
+            deleted: incoming.deleted,
+            quote: incoming.quote.as_ref().map(|q| Quote {
+                server_id: q.id,
+                from_addr: q.from_addr.clone(),
+                snippet: q.snippet.clone(),
+                deleted: q.deleted,
+            }),
+            reactions: incoming.reactions.iter()
+                .map(|r| Reaction { emoji: r.emoji.clone(), user_ids: r.user_ids.clone() })
+                .collect(),
+            state: MessageState::Sent,
+            sent_at: incoming.sent_at,
+        }
+    }
+
+    /// How a reply to this message quotes it until the server says otherwise.
+    fn quote(&self, my_id: &str, peer_id: &str) -> Quote {
+        let from_addr = match self.side {
+            Side::Me   => my_id,
+            Side::Them => peer_id,
+        };
+        Quote {
+            server_id: self.server_id,
+            from_addr: from_addr.to_string(),
+            snippet: self.text.chars().take(120).collect(),
+            deleted: self.deleted,
+        }
+    }
+}
+
+/// Offered in a message's menu; any single emoji is accepted by the server.
+const QUICK_REACTIONS: [&str; 6] = ["👍", "❤️", "😂", "😮", "😢", "🙏"];
+
+#[derive(Clone, PartialEq, Default, Serialize, Deserialize)]
+struct Profile {
+    display_name: String,
+    about: String,
+    avatar_url: Option<String>,   // data: URL, ready for an <img src>
+}
+
+impl From<users::Profile> for Profile {
+    fn from(p: users::Profile) -> Self {
+        let avatar_url = if p.avatar.is_empty() {
+            None
+        } else {
+            let encoded = base64::engine::general_purpose::STANDARD.encode(&p.avatar);
+            Some(format!("data:{};base64,{}", p.avatar_mime, encoded))
+        };
+        Self { display_name: p.display_name, about: p.about, avatar_url }
+    }
+}
+
+impl From<chat::PeerProfile> for Profile {
+    fn from(p: chat::PeerProfile) -> Self {
+        users::Profile {
+            display_name: p.display_name,
+            about: p.about,
+            avatar: p.avatar,
+            avatar_mime: p.avatar_mime,
+            ..Default::default()
+        }.into()
+    }
+}
+
+/// Persisted as-is by the local cache, except for the local ids and sync flags.
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
 struct Chat {
+    #[serde(skip)]
     id: usize,
-    contact: String,
+    peer_id: String,   // the peer's account id — what messages are addressed to
+    contact: String,   // email / phone we looked them up by; empty if they wrote first
+    profile: Option<Profile>,
+    saved: bool,       // in our server-side contact list
+    blocked: bool,     // we blocked them
     messages: Vec<Message>,
+    last_read: u64,    // server id of the newest message we have read, on any device
+    unread: u64,
+    last_message: Option<Message>,   // from ListConversations, until the history is loaded
+    last_activity: i64,              // unix seconds of the newest message; orders the sidebar
+    has_older: bool,                 // the server holds messages older than the first one here
+    #[serde(default)]
+    peer_delivered: u64,             // server id of the newest of our messages the peer has received
+    #[serde(default)]
+    peer_read: u64,                  // … and read
+    #[serde(skip)]
+    history_synced: bool,            // reconciled with GetHistory since the app started
+    #[serde(skip)]
+    delivered_acked: u64,            // newest of their messages we told the server we received
 }
 
 impl Chat {
-    fn new(id: usize, contact: impl Into<String>) -> Self {
-        Self { id, contact: contact.into(), messages: Vec::new() }
+    fn new(id: usize, peer_id: impl Into<String>, contact: impl Into<String>) -> Self {
+        Self {
+            id,
+            peer_id: peer_id.into(),
+            contact: contact.into(),
+            profile: None,
+            saved: false,
+            blocked: false,
+            messages: Vec::new(),
+            last_read: 0,
+            unread: 0,
+            last_message: None,
+            last_activity: 0,
+            has_older: false,
+            peer_delivered: 0,
+            peer_read: 0,
+            history_synced: false,
+            delivered_acked: 0,
+        }
     }
 
     fn display_name(&self) -> &str {
+        if let Some(p) = self.profile.as_ref().filter(|p| !p.display_name.is_empty()) {
+            return &p.display_name;
+        }
+        if self.contact.is_empty() {
+            return &self.peer_id[..self.peer_id.len().min(8)];
+        }
         if let Some(pos) = self.contact.find('@') {
             &self.contact[..pos]
         } else {
@@ -68,6 +303,18 @@ impl Chat {
         }
     }
 
+    fn subtitle(&self) -> &str {
+        if !self.contact.is_empty() {
+            &self.contact
+        } else {
+            self.profile.as_ref().map(|p| p.about.as_str()).unwrap_or("")
+        }
+    }
+
+    fn avatar_url(&self) -> Option<String> {
+        self.profile.as_ref().and_then(|p| p.avatar_url.clone())
+    }
+
     fn avatar_char(&self) -> char {
         self.display_name()
             .chars()
@@ -78,10 +325,93 @@ impl Chat {
             .unwrap_or('?')
     }
 
+    /// Moves the read marker forward and counts what is left unread.
+    fn read_up_to(&mut self, server_id: u64) {
+        self.last_read = self.last_read.max(server_id);
+        self.unread = self.messages.iter()
+            .filter(|m| m.side == Side::Them && !m.deleted && m.server_id > self.last_read)
+            .count() as u64;
+    }
+
+    /// Moves the peer's receipts forward and updates the ticks on our messages.
+    /// Call with zeros to refresh the ticks after messages got their server ids.
+    fn peer_reached(&mut self, delivered: u64, read: u64) {
+        self.peer_read = self.peer_read.max(read);
+        self.peer_delivered = self.peer_delivered.max(delivered).max(self.peer_read);
+        let (delivered, read) = (self.peer_delivered, self.peer_read);
+        for m in self.messages.iter_mut().filter(|m| m.side == Side::Me && m.server_id != 0) {
+            m.state = match m.server_id {
+                id if id <= read      => MessageState::Read,
+                id if id <= delivered => MessageState::Delivered,
+                _                     => MessageState::Sent,
+            };
+        }
+    }
+
+    /// The message we sent as `client_id`, once the server has stored it.
+    fn confirm_sent(&mut self, client_id: &str, server_id: u64, sent_at: i64) {
+        if let Some(m) = self.messages.iter_mut().find(|m| m.client_id == client_id && !client_id.is_empty()) {
+            m.server_id = server_id;
+            m.sent_at = sent_at;
+            m.state = MessageState::Sent;
+        }
+        self.peer_reached(0, 0);
+    }
+
+    fn message_mut(&mut self, server_id: u64) -> Option<&mut Message> {
+        self.messages.iter_mut().find(|m| m.server_id == server_id && server_id != 0)
+    }
+
+    /// Folds the newest page of server history into what we have cached. The
+    /// page wins for the ids it covers; cached messages older than it are kept
+    /// only if nothing can be missing in between.
+    fn merge_latest(&mut self, mut page: Vec<Message>, has_more: bool) {
+        self.keep_local_ids(&mut page);
+        let first = page.first().map(|m| m.server_id).unwrap_or(u64::MAX);
+        let last = page.last().map(|m| m.server_id).unwrap_or(0);
+        let newest_cached = self.messages.iter().map(|m| m.server_id).max().unwrap_or(0);
+        let contiguous = !has_more || newest_cached >= first;
+
+        let mut merged: Vec<Message> = if contiguous && has_more {
+            self.messages.iter().filter(|m| m.server_id != 0 && m.server_id < first).cloned().collect()
+        } else {
+            Vec::new()
+        };
+        self.has_older = has_more && (merged.is_empty() || self.has_older);
+        merged.extend(page);
+        // arrived on the stream while the page was on its way, or not sent yet
+        merged.extend(self.messages.iter().filter(|m| m.server_id > last || m.server_id == 0).cloned());
+        self.messages = merged;
+        self.history_synced = true;
+        self.peer_reached(0, 0);
+    }
+
+    /// Puts a page from before the first message we hold in front of it.
+    fn prepend_older(&mut self, mut page: Vec<Message>, has_more: bool) {
+        let first = self.messages.iter().map(|m| m.server_id).filter(|&id| id != 0).min().unwrap_or(u64::MAX);
+        page.retain(|m| m.server_id < first);
+        page.append(&mut self.messages);
+        self.messages = page;
+        self.has_older = has_more;
+        self.peer_reached(0, 0);
+    }
+
+    fn keep_local_ids(&self, page: &mut [Message]) {
+        for message in page {
+            if let Some(cached) = self.messages.iter().find(|m| m.server_id == message.server_id) {
+                message.id = cached.id;
+            }
+        }
+    }
+
     fn preview(&self) -> String {
         self.messages
             .last()
+            .or(self.last_message.as_ref())
             .map(|m| {
+                if m.deleted {
+                    return "This message was deleted".to_string();
+                }
                 let prefix = match m.side {
                     Side::Me   => "You: ",
                     Side::Them => "",
@@ -109,6 +439,13 @@ fn validate_identifier(s: &str) -> Result<(), &'static str> {
     }
 }
 
+fn initial_of(name: &str) -> char {
+    name.chars()
+        .next()
+        .and_then(|c| c.to_uppercase().next())
+        .unwrap_or('?')
+}
+
 // ── Entry point ───────────────────────────────────────────────────────────────
 fn main() {
     dioxus::launch(App);
@@ -117,41 +454,160 @@ fn main() {
 // ── Root component ────────────────────────────────────────────────────────────
 #[component]
 fn App() -> Element {
-    let mut screen     = use_signal(|| AppScreen::Identifier);
+    let saved_login    = use_hook(|| std::rc::Rc::new(credentials::load()));
+    let mut screen     = use_signal(|| {
+        if saved_login.is_some() { AppScreen::Resuming } else { AppScreen::Identifier }
+    });
     let mut email      = use_signal(String::new);   // the address the OTP was sent to
     let mut uuid       = use_signal(String::new);   // returned by VerifyOtp
+    let mut session    = use_signal(|| None::<SessionTokens>);
+    let mut magic_link = use_signal(|| false);          // waiting on an emailed link rather than a code
+    let mut notice     = use_signal(String::new);       // shown on the identifier screen
+    let mut two_factor = use_signal(String::new);       // pending login waiting on an authenticator code
+
+    let signed_in = use_callback(move |result: SignIn| match result {
+        SignIn::Done(user_uuid, tokens) => {
+            uuid.set(user_uuid);
+            session.set(Some(tokens));
+            screen.set(AppScreen::Chat);
+        }
+        SignIn::NeedsCode(token) => {
+            two_factor.set(token);
+            screen.set(AppScreen::TwoFactor);
+        }
+    });
+
+    // Build the shared gRPC channel on startup and whenever the server address
+    // changes, retrying until the server answers. Once connected, the channel
+    // re-establishes dropped connections by itself.
+    let mut server_addr   = use_signal(settings::server_addr);
+    let mut settings_open = use_signal(|| false);
+    let mut connect_note  = use_signal(String::new);
+    let clients = use_resource(move || async move {
+        let addr = server_addr.read().clone();
+        connect_note.set(String::new());
+        if let Err(e) = settings::check_addr(&addr) {
+            connect_note.set(e);
+            return std::future::pending().await;
+        }
+        let mut backoff = Backoff::default();
+        loop {
+            match grpc::connect(addr.trim()).await {
+                Ok(channel) => return channel,
+                Err(e) => {
+                    let delay = backoff.next_delay();
+                    connect_note.set(format!("Can't reach the server ({e}). Retrying in {}s…", delay.as_secs().max(1)));
+                    platform::sleep(delay).await;
+                }
+            }
+        }
+    });
+
+    // Opening a sign-in link launches the app with the link as an argument.
+    let mut launch_link = use_signal(|| platform::launch_link(MAGIC_LINK_PREFIX));
+    use_effect(move || {
+        let Some(channel) = clients.read().as_ref().cloned() else { return };
+        // used once, even if the channel is rebuilt for another server later
+        let Some(link) = launch_link.write().take() else { return };
+        spawn(async move {
+            match verify_magic_link(Api::new(channel, session), link).await {
+                Ok(result) => signed_in.call(result),
+                Err(e) => notice.set(e),
+            }
+        });
+    });
+
+    // Remember the session whenever it changes; refresh tokens rotate on renewal.
+    use_effect(move || {
+        let user_id = uuid.read().clone();
+        let Some(tokens) = session.read().clone() else { return };
+        let login = credentials::SavedLogin { user_id, refresh_token: tokens.refresh_token };
+        if let Err(e) = credentials::save(&login) {
+            warn!("could not remember the login: {e}");
+        }
+    });
 
-    // Build the shared gRPC channel once on startup.
-    let clients = use_resource(|| async {
-        let rpc_channel = Channel::from_shared(SERVER_ADDR)
-            .expect("invalid SERVER_ADDR")
-            .connect()
-            .await
-            .expect("failed to connect to gRPC server");
-        GlobalState { rpc_channel }
+    // Renew a saved login instead of asking for a new code.
+    let resume_login = saved_login.clone();
+    use_effect(move || {
+        let Some(channel) = clients.read().as_ref().cloned() else { return };
+        let Some(saved) = resume_login.as_ref() else { return };
+        if *screen.peek() != AppScreen::Resuming {
+            return;
+        }
+        let (user_id, refresh_token) = (saved.user_id.clone(), saved.refresh_token.clone());
+        spawn(async move {
+            match Api::new(channel, session).refresh(refresh_token).await {
+                Ok(tokens) => signed_in.call(SignIn::Done(user_id, tokens)),
+                Err(e) => {
+                    if e.code() == tonic::Code::Unauthenticated {
+                        credentials::clear();
+                        cache::wipe(&user_id);
+                        notice.set("Your session has ended. Please sign in again.".to_string());
+                    } else {
+                        notice.set(format!("Could not resume your session: {}", e.message()));
+                    }
+                    screen.set(AppScreen::Identifier);
+                }
+            }
+        });
     });
 
-    let Some(clients) = clients.read().as_ref().cloned() else {
+    if *settings_open.read() {
+        return rsx! {
+            style { {STYLES} }
+            ServerSettings {
+                current: server_addr.read().clone(),
+                on_saved: move |addr: String| {
+                    settings_open.set(false);
+                    if addr != *server_addr.peek() {
+                        server_addr.set(addr);
+                    }
+                },
+                on_close: move |_| settings_open.set(false),
+            }
+        };
+    }
+
+    let Some(rpc_channel) = clients.read().as_ref().cloned() else {
         return rsx! {
             div { class: "auth-bg",
                 div { class: "auth-card",
                     div { class: "auth-logo", "⏳" }
-                    p { class: "auth-subtitle", "Connecting to server…" }
+                    p { class: "auth-subtitle", "Connecting to {server_addr}…" }
+                    if !connect_note.read().is_empty() {
+                        p { class: "auth-hint", "{connect_note}" }
+                    }
+                    button { class: "auth-link", onclick: move |_| settings_open.set(true), "Server settings" }
                 }
             }
             style { {STYLES} }
         };
     };
 
-    use_context_provider(|| GlobalState { rpc_channel: clients.rpc_channel.clone() });
+    // Provided on every render rather than once, so screens mounted after the
+    // server address changed get the new channel.
+    provide_context(GlobalState { api: Api::new(rpc_channel, session), session });
 
     rsx! {
         style { {STYLES} }
         match *screen.read() {
+            AppScreen::Resuming => rsx! {
+                div { class: "auth-bg",
+                    div { class: "auth-card",
+                        div { class: "auth-logo", "⏳" }
+                        p { class: "auth-subtitle", "Signing you back in…" }
+                    }
+                }
+            },
             AppScreen::Identifier => rsx! {
                 IdentifierScreen {
-                    on_success: move |ident: String| {
+                    notice: notice.read().clone(),
+                    on_settings: move |_| settings_open.set(true),
+                    on_success: move |(ident, by_link): (String, bool)| {
                         email.set(ident);
+                        magic_link.set(by_link);
+                        notice.set(String::new());
                         screen.set(AppScreen::Otp);
                     }
                 }
@@ -159,23 +615,39 @@ fn App() -> Element {
             AppScreen::Otp => rsx! {
                 OtpScreen {
                     email: email.read().clone(),
-                    on_success: move |user_uuid: String| {
-                        uuid.set(user_uuid);
-                        screen.set(AppScreen::Chat);
-                    },
+                    magic_link: *magic_link.read(),
+                    on_success: move |result: SignIn| signed_in.call(result),
+                    on_back: move |_| screen.set(AppScreen::Identifier),
+                }
+            },
+            AppScreen::TwoFactor => rsx! {
+                TwoFactorScreen {
+                    token: two_factor.read().clone(),
+                    on_success: move |result: SignIn| signed_in.call(result),
                     on_back: move |_| screen.set(AppScreen::Identifier),
                 }
             },
             AppScreen::Chat => rsx! {
-                ChatApp { my_id: uuid.read().clone() }
+                ChatApp {
+                    my_id: uuid.read().clone(),
+                    on_signed_out: move |_| {
+                        // the cache key died with the session, so its data goes too
+                        credentials::clear();
+                        cache::wipe(&uuid.peek());
+                        session.set(None);
+                        uuid.set(String::new());
+                        screen.set(AppScreen::Identifier);
+                    },
+                }
             },
         }
     }
 }
 
 // ── Screen 1 – Identifier ─────────────────────────────────────────────────────
+// on_success carries the identifier and whether a sign-in link was asked for.
 #[component]
-fn IdentifierScreen(on_success: EventHandler<String>) -> Element {
+fn IdentifierScreen(notice: String, on_settings: EventHandler<()>, on_success: EventHandler<(String, bool)>) -> Element {
     let mut input   = use_signal(String::new);
     let mut error   = use_signal(String::new);
     let mut loading = use_signal(|| false);
@@ -183,35 +655,28 @@ fn IdentifierScreen(on_success: EventHandler<String>) -> Element {
     let global = use_context::<GlobalState>();
 
     // use_callback returns a Copy handle — safe to move into multiple event handlers.
-    let submit = use_callback(move |_: ()| {
+    let submit = use_callback(move |by_link: bool| {
         let ident = input.read().trim().to_string();
 
         if let Err(e) = validate_identifier(&ident) {
             error.set(e.to_owned());
             return;
         }
+        if by_link && !ident.contains('@') {
+            error.set("Sign-in links can only be sent to an email address.".to_owned());
+            return;
+        }
 
         loading.set(true);
         error.set(String::new());
 
-        let channel = global.rpc_channel.clone();
+        let api = global.api.clone();
         let ident_clone = ident.clone();
 
         spawn(async move {
-            let mut user_client = UserClient::new(channel);
-
-            let id = if ident_clone.contains('@') {
-                otp_request::Id::Email(ident_clone.clone())
-            } else {
-                otp_request::Id::Phone(ident_clone.clone())
-            };
-
-            match user_client
-                .request_otp(tonic::Request::new(OtpRequest { id: Some(id) }))
-                .await
-            {
+            match api.request_otp(ident_clone.clone(), by_link, platform::locale()).await {
                 Ok(_) => {
-                    on_success.call(ident_clone);
+                    on_success.call((ident_clone, by_link));
                 }
                 Err(e) => {
                     error.set(format!("Failed to send OTP: {}", e.message()));
@@ -242,31 +707,155 @@ fn IdentifierScreen(on_success: EventHandler<String>) -> Element {
                         error.set(String::new());
                     },
                     onkeydown: move |e: Event<KeyboardData>| {
-                        if e.key() == Key::Enter && !*loading.read() { submit.call(()); }
+                        if e.key() == Key::Enter && !*loading.read() { submit.call(false); }
                     },
                 }
 
                 if !error.read().is_empty() {
                     div { class: "auth-error", "⚠  {error}" }
+                } else if !notice.is_empty() {
+                    div { class: "auth-error", "⚠  {notice}" }
                 }
 
                 button {
                     class: "auth-btn",
                     disabled: *loading.read(),
-                    onclick: move |_| submit.call(()),
+                    onclick: move |_| submit.call(false),
                     if *loading.read() { "Sending…" } else { "Send OTP  →" }
                 }
+
+                div { class: "auth-links",
+                    button {
+                        class: "auth-link",
+                        disabled: *loading.read(),
+                        onclick: move |_| submit.call(true),
+                        "Email me a sign-in link instead"
+                    }
+                    button {
+                        class: "auth-link",
+                        disabled: *loading.read(),
+                        onclick: move |_| on_settings.call(()),
+                        "Server settings"
+                    }
+                }
+            }
+        }
+    }
+}
+
+// ── Server settings ───────────────────────────────────────────────────────────
+#[derive(Clone, PartialEq)]
+enum ConnectionTest {
+    Idle,
+    Running,
+    Passed(std::time::Duration),
+    Failed(String),
+}
+
+#[component]
+fn ServerSettings(current: String, on_saved: EventHandler<String>, on_close: EventHandler<()>) -> Element {
+    let mut draft = use_signal(|| current.clone());
+    let mut test  = use_signal(|| ConnectionTest::Idle);
+    let mut error = use_signal(String::new);
+    let from_env  = use_hook(settings::env_server_addr);
+
+    let run_test = move |_| {
+        test.set(ConnectionTest::Running);
+        let addr = draft.read().trim().to_string();
+        spawn(async move {
+            match settings::test_connection(&addr).await {
+                Ok(took) => test.set(ConnectionTest::Passed(took)),
+                Err(e)   => test.set(ConnectionTest::Failed(e)),
+            }
+        });
+    };
+
+    let save = move |_| {
+        let addr = draft.read().trim().to_string();
+        if let Err(e) = settings::check_addr(&addr) {
+            error.set(e);
+            return;
+        }
+        // the default isn't written down, so a later release can move it
+        let saved = (addr != settings::DEFAULT_SERVER_ADDR).then_some(addr.as_str());
+        match settings::save_server_addr(saved) {
+            Ok(()) => on_saved.call(addr),
+            Err(e) => error.set(e),
+        }
+    };
+
+    rsx! {
+        div { class: "auth-bg",
+            div { class: "auth-card",
+                div { class: "auth-logo", "🛰️" }
+                h1 { class: "auth-title", "Server" }
+                p  { class: "auth-subtitle", "The address of the DioxusChat server to connect to" }
+
+                input {
+                    class: "auth-input",
+                    r#type: "url",
+                    placeholder: "https://chat.example.com",
+                    value: "{draft}",
+                    disabled: from_env.is_some(),
+                    oninput: move |e| {
+                        draft.set(e.value());
+                        test.set(ConnectionTest::Idle);
+                        error.set(String::new());
+                    },
+                }
+
+                if from_env.is_some() {
+                    p { class: "auth-hint", "Set by the {settings::SERVER_ENV} environment variable, which takes precedence." }
+                }
+                match test.read().clone() {
+                    ConnectionTest::Idle => rsx! {},
+                    ConnectionTest::Running => rsx! { p { class: "auth-hint", "Testing…" } },
+                    ConnectionTest::Passed(took) => rsx! {
+                        p { class: "settings-ok", "✓ Connected in {took.as_millis()} ms" }
+                    },
+                    ConnectionTest::Failed(e) => rsx! { div { class: "auth-error", "⚠  {e}" } },
+                }
+                if !error.read().is_empty() {
+                    div { class: "auth-error", "⚠  {error}" }
+                }
+
+                button {
+                    class: "auth-btn",
+                    disabled: from_env.is_some(),
+                    onclick: save,
+                    "Save"
+                }
+
+                div { class: "auth-links",
+                    button { class: "auth-link", onclick: move |_| on_close.call(()), "← Back" }
+                    button {
+                        class: "auth-link",
+                        disabled: *test.read() == ConnectionTest::Running,
+                        onclick: run_test,
+                        "Test connection"
+                    }
+                    button {
+                        class: "auth-link",
+                        disabled: from_env.is_some(),
+                        onclick: move |_| {
+                            draft.set(settings::DEFAULT_SERVER_ADDR.to_string());
+                            test.set(ConnectionTest::Idle);
+                        },
+                        "Use default"
+                    }
+                }
             }
         }
     }
 }
 
 // ── Screen 2 – OTP Verification ───────────────────────────────────────────────
-// on_success now carries the UUID string returned by the server.
+// With `magic_link` the user was emailed a link instead of a code and can paste it here.
 #[component]
 fn OtpScreen(
     email: String,
-    on_success: EventHandler<String>,
+    magic_link: bool,
+    on_success: EventHandler<SignIn>,
     on_back: EventHandler<()>,
 ) -> Element {
     let mut otp_val = use_signal(String::new);
@@ -277,8 +866,12 @@ fn OtpScreen(
     let email_clone = email.clone();
 
     let verify = use_callback(move |_: ()| {
-        let otp = otp_val.read().trim().to_string();
-        if otp.len() < 4 {
+        let entered = otp_val.read().trim().to_string();
+        if magic_link && !entered.contains("token=") {
+            error.set("Paste the whole link from the email.".to_string());
+            return;
+        }
+        if !magic_link && entered.len() < 4 {
             error.set("Please enter the complete OTP code.".to_string());
             return;
         }
@@ -286,91 +879,93 @@ fn OtpScreen(
         loading.set(true);
         error.set(String::new());
 
-        let channel   = global.rpc_channel.clone();
+        let api       = global.api.clone();
         let addr      = email_clone.clone();
 
         spawn(async move {
-            let mut user_client = UserClient::new(channel);
+            let result = if magic_link {
+                verify_magic_link(api, entered).await
+            } else {
+                api.verify_otp(addr, entered, platform::device_name(), platform::locale()).await
+            };
 
-            match user_client
-                .verify_otp(tonic::Request::new(OtpVerifyRequest {
-                    email_or_phone: addr,
-                    otp,
-                }))
-                .await
-            {
-                Ok(resp) => {
-                    match resp.into_inner().res {
-                        Some(otp_verify_response::Res::Uuid(uuid)) => {
-                            on_success.call(uuid);
-                        }
-                        Some(otp_verify_response::Res::ErrMsg(msg)) => {
-                            error.set(msg);
-                            loading.set(false);
-                        }
-                        None => {
-                            error.set("Unexpected response from server.".to_string());
-                            loading.set(false);
-                        }
-                    }
-                }
-                Err(e) => {
-                    error.set(format!("Verification failed: {}", e.message()));
+            match result {
+                Ok(login) => on_success.call(login),
+                Err(msg) => {
+                    error.set(msg);
                     loading.set(false);
                 }
             }
         });
     });
 
-    // Resend OTP
+    // Resend OTP (or link)
     let global2     = use_context::<GlobalState>();
     let email_resend = email.clone();
     let resend = move |_| {
         otp_val.set(String::new());
         error.set(String::new());
 
-        let channel = global2.rpc_channel.clone();
+        let api = global2.api.clone();
         let addr    = email_resend.clone();
 
         spawn(async move {
-            let mut user_client = UserClient::new(channel);
-            let id = if addr.contains('@') {
-                otp_request::Id::Email(addr)
-            } else {
-                otp_request::Id::Phone(addr)
-            };
-            let _ = user_client
-                .request_otp(tonic::Request::new(OtpRequest { id: Some(id) }))
-                .await;
+            let _ = api.request_otp(addr, magic_link, platform::locale()).await;
         });
     };
 
     rsx! {
         div { class: "auth-bg",
             div { class: "auth-card",
-                div { class: "auth-logo", "🔑" }
-                h1 { class: "auth-title", "Enter OTP" }
-                p  { class: "auth-subtitle",
-                    "A code was sent to "
-                    strong { "{email}" }
+                div { class: "auth-logo", if magic_link { "✉️" } else { "🔑" } }
+                h1 { class: "auth-title", if magic_link { "Check your email" } else { "Enter OTP" } }
+                if magic_link {
+                    p  { class: "auth-subtitle",
+                        "We sent a sign-in link to "
+                        strong { "{email}" }
+                        ". Open it on this computer, or paste it below."
+                    }
+                } else {
+                    p  { class: "auth-subtitle",
+                        "A code was sent to "
+                        strong { "{email}" }
+                    }
                 }
 
-                input {
-                    class: "auth-input otp-input",
-                    r#type: "text",
-                    placeholder: "6-digit code",
-                    maxlength: "6",
-                    value: "{otp_val}",
-                    autofocus: true,
-                    disabled: *loading.read(),
-                    oninput: move |e| {
-                        let val: String = e.value().chars().take(6).collect();
-                        otp_val.set(val);
-                        error.set(String::new());
-                    },
-                    onkeydown: move |e: Event<KeyboardData>| {
-                        if e.key() == Key::Enter && !*loading.read() { verify.call(()); }
-                    },
+                if magic_link {
+                    input {
+                        class: "auth-input",
+                        r#type: "text",
+                        placeholder: "{MAGIC_LINK_PREFIX}?token=…",
+                        value: "{otp_val}",
+                        autofocus: true,
+                        disabled: *loading.read(),
+                        oninput: move |e| {
+                            otp_val.set(e.value());
+                            error.set(String::new());
+                        },
+                        onkeydown: move |e: Event<KeyboardData>| {
+                            if e.key() == Key::Enter && !*loading.read() { verify.call(()); }
+                        },
+                    }
+                } else {
+                    input {
+                        class: "auth-input otp-input",
+                        r#type: "text",
+                        placeholder: "6-digit code",
+                        maxlength: "6",
+                        value: "{otp_val}",
+                        autofocus: true,
+                        disabled: *loading.read(),
+                        oninput: move |e| {
+                            let val: String = e.value().chars().take(6).collect();
+                            otp_val.set(val);
+                            error.set(String::new());
+                        },
+                        onkeydown: move |e: Event<KeyboardData>| {
+                            if e.key() == Key::Enter && !*loading.read() { verify.call(()); }
+                        },
+                    }
                 }
 
                 if !error.read().is_empty() {
@@ -395,7 +990,7 @@ fn OtpScreen(
                         class: "auth-link",
                         disabled: *loading.read(),
                         onclick: resend,
-                        "Resend OTP"
+                        if magic_link { "Resend link" } else { "Resend OTP" }
                     }
                 }
             }
@@ -403,278 +998,2146 @@ fn OtpScreen(
     }
 }
 
-// ── Screen 3 – Chat app ───────────────────────────────────────────────────────
+// ── Screen 2b – Two-step verification ──────────────────────────────────────────
 #[component]
-fn ChatApp(my_id: String) -> Element {
-    let mut chats:     Signal<Vec<Chat>>        = use_signal(Vec::new);
-    let mut active_id: Signal<Option<usize>>    = use_signal(|| None);
-    let mut next_id:   Signal<usize>            = use_signal(|| 1usize);
-    let mut modal_open:  Signal<bool>           = use_signal(|| false);
-    let mut modal_input: Signal<String>         = use_signal(String::new);
-    let mut modal_error: Signal<String>         = use_signal(String::new);
-    let mut msg_input:   Signal<String>         = use_signal(String::new);
+fn TwoFactorScreen(token: String, on_success: EventHandler<SignIn>, on_back: EventHandler<()>) -> Element {
+    let mut code    = use_signal(String::new);
+    let mut error   = use_signal(String::new);
+    let mut loading = use_signal(|| false);
 
     let global = use_context::<GlobalState>();
 
-    // ── Start the incoming-message stream exactly once on mount ──────────────
-    // use_hook runs only on the first render — no reactive re-fires.
-    use_hook(|| {
-        let channel   = global.rpc_channel.clone();
-        let id        = my_id.clone();
+    let verify = use_callback(move |_: ()| {
+        let entered = code.read().trim().to_string();
+        if entered.is_empty() {
+            error.set("Enter the code from your authenticator app.".to_string());
+            return;
+        }
 
-        spawn(async move {
-            // Retry loop: if the stream drops, wait 2s and reconnect.
-            loop {
-                let mut chat_client = ChatClient::new(channel.clone());
+        loading.set(true);
+        error.set(String::new());
 
-                let stream_result = chat_client
-                    .receive_incoming_messages(tonic::Request::new(
-                        ReceiveMessageRequest { id: id.clone() },
-                    ))
-                    .await;
+        let api = global.api.clone();
+        let token   = token.clone();
 
-                let Ok(response) = stream_result else {
-                    // Failed to open stream — wait then retry.
-                    continue;
-                };
+        spawn(async move {
+            let result = api.verify_two_factor(token, entered).await;
 
-                let mut stream = response.into_inner();
+            match result {
+                Ok(result) => on_success.call(result),
+                Err(msg) => {
+                    error.set(msg);
+                    code.set(String::new());
+                    loading.set(false);
+                }
+            }
+        });
+    });
+
+    rsx! {
+        div { class: "auth-bg",
+            div { class: "auth-card",
+                div { class: "auth-logo", "🛡️" }
+                h1 { class: "auth-title", "Two-step verification" }
+                p  { class: "auth-subtitle",
+                    "Enter the 6-digit code from your authenticator app, or one of your recovery codes."
+                }
+
+                input {
+                    class: "auth-input otp-input",
+                    r#type: "text",
+                    placeholder: "123456",
+                    maxlength: "12",
+                    value: "{code}",
+                    autofocus: true,
+                    disabled: *loading.read(),
+                    oninput: move |e| {
+                        code.set(e.value());
+                        error.set(String::new());
+                    },
+                    onkeydown: move |e: Event<KeyboardData>| {
+                        if e.key() == Key::Enter && !*loading.read() { verify.call(()); }
+                    },
+                }
+
+                if !error.read().is_empty() {
+                    div { class: "auth-error", "⚠  {error}" }
+                }
+
+                button {
+                    class: "auth-btn",
+                    disabled: *loading.read(),
+                    onclick: move |_| verify.call(()),
+                    if *loading.read() { "Verifying…" } else { "Verify & Continue  →" }
+                }
+
+                div { class: "auth-links",
+                    button {
+                        class: "auth-link",
+                        disabled: *loading.read(),
+                        onclick: move |_| on_back.call(()),
+                        "← Start over"
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Shows the new text of an edited message, wherever it is.
+fn apply_edit(chats: &mut [Chat], edited: &IncomingMessage) {
+    for chat in chats.iter_mut() {
+        if let Some(m) = chat.message_mut(edited.id) {
+            m.text = String::from_utf8_lossy(&edited.msg).to_string();
+            m.edited = true;
+        }
+    }
+}
+
+/// Adds or takes back one person's reaction; applying the same change twice is harmless.
+fn apply_reaction(chats: &mut [Chat], change: &ReactionChanged) {
+    for chat in chats.iter_mut() {
+        let Some(m) = chat.message_mut(change.message_id) else { continue };
+        match m.reactions.iter().position(|r| r.emoji == change.emoji) {
+            Some(i) if change.removed => {
+                m.reactions[i].user_ids.retain(|u| *u != change.user_id);
+                if m.reactions[i].user_ids.is_empty() {
+                    m.reactions.remove(i);
+                }
+            }
+            Some(i) => {
+                if !m.reactions[i].user_ids.contains(&change.user_id) {
+                    m.reactions[i].user_ids.push(change.user_id.clone());
+                }
+            }
+            None if change.removed => {}
+            None => m.reactions.push(Reaction {
+                emoji: change.emoji.clone(),
+                user_ids: vec![change.user_id.clone()],
+            }),
+        }
+    }
+}
+
+/// Deleted for everyone leaves a placeholder; deleted for me drops the bubble.
+fn apply_delete(chats: &mut [Chat], server_id: u64, for_everyone: bool) {
+    for chat in chats.iter_mut() {
+        if for_everyone {
+            if let Some(m) = chat.message_mut(server_id) {
+                m.text.clear();
+                m.deleted = true;
+                m.reactions.clear();
+            }
+            // replies keep pointing at it, now as a deleted message
+            for m in chat.messages.iter_mut() {
+                if let Some(q) = m.quote.as_mut().filter(|q| q.server_id == server_id) {
+                    q.snippet.clear();
+                    q.deleted = true;
+                }
+            }
+        } else {
+            chat.messages.retain(|m| m.server_id != server_id);
+        }
+    }
+}
+
+// ── Screen 3 – Chat app ───────────────────────────────────────────────────────
+#[component]
+fn ChatApp(my_id: String, on_signed_out: EventHandler<()>) -> Element {
+    let mut chats:     Signal<Vec<Chat>>        = use_signal(Vec::new);
+    let mut active_id: Signal<Option<usize>>    = use_signal(|| None);
+    let mut next_id:   Signal<usize>            = use_signal(|| 1usize);
+    let mut modal_open:  Signal<bool>           = use_signal(|| false);
+    let mut modal_input: Signal<String>         = use_signal(String::new);
+    let mut modal_error: Signal<String>         = use_signal(String::new);
+    let mut modal_busy:  Signal<bool>           = use_signal(|| false);
+    let mut msg_input:   Signal<String>         = use_signal(String::new);
+    let mut my_profile:   Signal<Profile>       = use_signal(Profile::default);
+    let mut profile_open: Signal<bool>          = use_signal(|| false);
+    let mut security_open: Signal<bool>         = use_signal(|| false);
+    let mut account_open: Signal<bool>          = use_signal(|| false);
+    let mut report_for:   Signal<Option<usize>> = use_signal(|| None);
+    let mut replying_to:  Signal<Option<(usize, Quote)>> = use_signal(|| None);   // (chat id, quoted message)
+    let mut search_query: Signal<String>        = use_signal(String::new);
+    let mut search_hits:  Signal<Vec<SearchHit>> = use_signal(Vec::new);
+    let mut search_note:  Signal<String>        = use_signal(String::new);   // "Searching…", errors, no results
+    let mut search_gen:   Signal<u64>           = use_signal(|| 0u64);       // bumps per keystroke; stale searches give up
+    let mut connection:   Signal<Connection>    = use_signal(|| Connection::Connecting);
+    let mut outbox:       Signal<Vec<IncomingMessage>> = use_signal(Vec::new);   // waiting to be sent, oldest first
+    let mut muted:        Signal<std::collections::BTreeSet<String>> = use_signal(|| settings::muted_chats(&my_id));   // peer ids
+    let window_focused = notifications::use_window_focus();
+    let outbox_wake   = use_hook(|| std::rc::Rc::new(tokio::sync::Notify::new()));
+    let reconnect_now = use_hook(|| std::rc::Rc::new(tokio::sync::Notify::new()));
+
+    let global = use_context::<GlobalState>();
+    let account = global.api.account(my_id.clone());
+    let mut session = global.session;
+
+    // ── Local cache: show what we had last time before the server answers ────
+    let cache: std::rc::Rc<Option<cache::ChatCache>> = use_hook(|| {
+        let key = session.peek().as_ref().map(|t| t.cache_key.clone()).unwrap_or_default();
+        let opened = cache::ChatCache::open(&my_id, &key);
+        if let Err(e) = &opened {
+            warn!("message cache unavailable: {e}");
+        }
+        let opened = opened.ok();
+
+        if let Some(store) = &opened {
+            let mut loaded = store.load_chats();
+            for chat in loaded.iter_mut() {
+                chat.id = *next_id.peek();
+                for message in chat.messages.iter_mut() {
+                    *next_id.write() += 1;
+                    message.id = *next_id.peek();
+                    // the outbox didn't survive the restart; these can be retried by hand
+                    if message.state == MessageState::Pending {
+                        message.state = MessageState::Failed;
+                    }
+                }
+                *next_id.write() += 1;
+            }
+            chats.set(loaded);
+        }
+        std::rc::Rc::new(opened)
+    });
+
+    // Write back whichever chats changed; unchanged ones are skipped by the cache.
+    let cache_w = cache.clone();
+    use_effect(move || {
+        let Some(store) = cache_w.as_ref() else { return };
+        for chat in chats.read().iter() {
+            if let Err(e) = store.save_chat(chat) {
+                warn!("could not cache chat: {e}");
+            }
+        }
+    });
+
+    // ── Keep the access token fresh for as long as we are signed in ──────────
+    use_hook(|| {
+        let api = global.api.clone();
+
+        spawn(async move {
+            // Renew a minute before expiry; retry soon after transient errors.
+            let renew_after = |t: &SessionTokens| t.access_ttl_seconds.saturating_sub(60).max(30);
+            let mut wait = session.peek().as_ref().map(renew_after).unwrap_or(30);
+            loop {
+                platform::sleep(std::time::Duration::from_secs(wait as u64)).await;
+                let Some(tokens) = session.peek().clone() else { return };
+
+                match api.refresh(tokens.refresh_token.clone()).await {
+                    Ok(fresh) => {
+                        wait = renew_after(&fresh);
+                        session.set(Some(fresh));
+                    }
+                    Err(e) if e.code() == tonic::Code::Unauthenticated => {
+                        on_signed_out.call(());
+                        return;
+                    }
+                    Err(_) => wait = 15,
+                }
+            }
+        });
+    });
+
+    // ── Load our own profile once on mount ────────────────────────────────────
+    use_hook(|| {
+        let api = global.api.clone();
+        let user_id = my_id.clone();
+
+        spawn(async move {
+            if let Ok(profile) = api.profile(user_id).await {
+                my_profile.set(profile.into());
+            }
+        });
+    });
+
+    // ── Seed the sidebar with saved contacts and people we blocked ───────────
+    use_hook(|| {
+        let api = global.api.clone();
+
+        spawn(async move {
+            let contacts = api.contacts().await;
+            let blocked = api.blocked().await;
+
+            let lists = [(contacts, false), (blocked, true)];
+            for (result, is_block_list) in lists {
+                let Ok(profiles) = result else { continue };
+                for profile in profiles {
+                    let existing = chats.read().iter().find(|c| c.peer_id == profile.user_id).map(|c| c.id);
+                    let cid = match existing {
+                        Some(cid) => cid,
+                        None => {
+                            let cid = *next_id.read();
+                            *next_id.write() += 1;
+                            chats.write().push(Chat::new(cid, profile.user_id.clone(), ""));
+                            cid
+                        }
+                    };
+                    if let Some(chat) = chats.write().iter_mut().find(|c| c.id == cid) {
+                        if is_block_list { chat.blocked = true } else { chat.saved = true }
+                        chat.profile = Some(profile.into());
+                    }
+                }
+            }
+        });
+    });
+
+    // ── Log out: end the session on the server, then forget it here ─────────
+    let api_logout = global.api.clone();
+
+    let logout = use_callback(move |_: ()| {
+        let api = api_logout.clone();
+        spawn(async move {
+            // Signing out locally matters more than the server hearing about it.
+            let _ = api.logout().await;
+            on_signed_out.call(());
+        });
+    });
+
+    // ── Read markers ──────────────────────────────────────────────────────────
+    // Everything currently in the chat counts as read once it is open.
+    let account_read = account.clone();
+
+    let mark_read = use_callback(move |cid: usize| {
+        let target = {
+            let mut chats_w = chats.write();
+            let Some(chat) = chats_w.iter_mut().find(|c| c.id == cid) else { return };
+            let newest = chat.messages.iter().map(|m| m.server_id).max().unwrap_or_default();
+            if newest <= chat.last_read {
+                chat.unread = 0;
+                return;
+            }
+            chat.read_up_to(newest);
+            chat.unread = 0;
+            (chat.peer_id.clone(), newest)
+        };
+
+        let account = account_read.clone();
+        spawn(async move {
+            let (peer_id, message_id) = target;
+            let _ = account.mark_read(peer_id, message_id).await;
+        });
+    });
+
+    // Tells the sender their messages reached us, for chats we aren't looking at.
+    let account_ack = account.clone();
+
+    let ack_delivered = use_callback(move |cid: usize| {
+        let target = {
+            let mut chats_w = chats.write();
+            let Some(chat) = chats_w.iter_mut().find(|c| c.id == cid) else { return };
+            let newest = chat.messages.iter()
+                .chain(chat.last_message.iter())
+                .filter(|m| m.side == Side::Them)
+                .map(|m| m.server_id)
+                .max()
+                .unwrap_or_default();
+            if newest <= chat.delivered_acked.max(chat.last_read) {
+                return;
+            }
+            chat.delivered_acked = newest;
+            (chat.peer_id.clone(), newest)
+        };
+
+        let account = account_ack.clone();
+        spawn(async move {
+            let (peer_id, message_id) = target;
+            let _ = account.mark_delivered(peer_id, message_id).await;
+        });
+    });
+
+    // ── History: reconcile the cache with the server, page further back ──────
+    // `before` is None for the newest page, which is merged into what we have.
+    let account_hist = account.clone();
+    let my_id_hist   = my_id.clone();
+
+    let load_history = use_callback(move |(cid, before): (usize, Option<u64>)| {
+        let Some(peer_id) = chats.peek().iter().find(|c| c.id == cid).map(|c| c.peer_id.clone()) else { return };
+        let account = account_hist.clone();
+        let user_id = my_id_hist.clone();
+
+        spawn(async move {
+            let Ok(history) = account.history(peer_id, before).await else { return };
+
+            let page: Vec<Message> = history.messages.iter()
+                .map(|m| {
+                    let mid = *next_id.peek();
+                    *next_id.write() += 1;
+                    let side = if m.from_addr == user_id { Side::Me } else { Side::Them };
+                    Message::received(mid, m, side)
+                })
+                .collect();
+
+            let mut chats_w = chats.write();
+            let Some(chat) = chats_w.iter_mut().find(|c| c.id == cid) else { return };
+            match before {
+                None    => chat.merge_latest(page, history.has_more),
+                Some(_) => chat.prepend_older(page, history.has_more),
+            }
+            if let Some(newest) = history.messages.last() {
+                chat.last_activity = chat.last_activity.max(newest.sent_at);
+            }
+        });
+    });
+
+    // Opening a chat brings it up to date once per run.
+    use_effect(move || {
+        let Some(cid) = *active_id.read() else { return };
+        let synced = chats.peek().iter().find(|c| c.id == cid).map(|c| c.history_synced);
+        if synced == Some(false) {
+            if let Some(chat) = chats.write().iter_mut().find(|c| c.id == cid) {
+                chat.history_synced = true;
+            }
+            load_history.call((cid, None));
+        }
+    });
+
+    // ── Rebuild the sidebar from the server's list of conversations ──────────
+    use_hook(|| {
+        let account = account.clone();
+        let user_id = my_id.clone();
+
+        spawn(async move {
+            let Ok(conversations) = account.conversations().await else { return };
+
+            for conversation in conversations {
+                let existing = chats.read().iter().find(|c| c.peer_id == conversation.peer_id).map(|c| c.id);
+                let cid = existing.unwrap_or_else(|| {
+                    let cid = *next_id.read();
+                    *next_id.write() += 1;
+                    chats.write().push(Chat::new(cid, conversation.peer_id.clone(), ""));
+                    cid
+                });
+                let last = conversation.last_message.map(|m| {
+                    let mid = *next_id.read();
+                    *next_id.write() += 1;
+                    let side = if m.from_addr == user_id { Side::Me } else { Side::Them };
+                    (m.sent_at, Message::received(mid, &m, side))
+                });
+
+                if let Some(chat) = chats.write().iter_mut().find(|c| c.id == cid) {
+                    if let Some(peer) = conversation.peer {
+                        chat.profile = Some(peer.into());
+                    }
+                    chat.last_read = chat.last_read.max(conversation.last_read_id);
+                    chat.unread = conversation.unread_count;
+                    chat.peer_reached(conversation.peer_delivered_id, conversation.peer_read_id);
+                    if let Some((sent_at, message)) = last {
+                        chat.last_activity = chat.last_activity.max(sent_at);
+                        chat.last_message = Some(message);
+                    }
+                }
+                if conversation.unread_count > 0 {
+                    ack_delivered.call(cid);
+                }
+            }
+        });
+    });
+
+    // ── Notifications for messages that arrive while we're in the background ─
+    // Clicking one opens its chat and brings the window back.
+    let notifier = use_hook(|| {
+        let (clicks, mut clicked) = tokio::sync::mpsc::unbounded_channel::<String>();
+        spawn(async move {
+            while let Some(peer_id) = clicked.recv().await {
+                notifications::bring_to_front();
+                let Some(cid) = chats.peek().iter().find(|c| c.peer_id == peer_id).map(|c| c.id) else { continue };
+                active_id.set(Some(cid));
+                msg_input.set(String::new());
+                mark_read.call(cid);
+            }
+        });
+        std::rc::Rc::new(notifications::Notifier::new(clicks))
+    });
+
+    // Whatever came into the open chat while we were away is read on return.
+    use_effect(move || {
+        if !*window_focused.read() { return; }
+        if let Some(cid) = *active_id.peek() {
+            mark_read.call(cid);
+        }
+    });
+
+    // ── Start the incoming-message stream exactly once on mount ──────────────
+    // use_hook runs only on the first render — no reactive re-fires.
+    use_hook(|| {
+        let api       = global.api.clone();
+        let account   = account.clone();
+        let id        = my_id.clone();
+        let wake      = outbox_wake.clone();
+        let retry_now = reconnect_now.clone();
+        let notifier  = notifier.clone();
+
+        spawn(async move {
+            // Retry loop: if the stream drops, back off and reconnect.
+            let mut backoff = Backoff::default();
+            let mut attempted = false;
+            let mut was_online = false;
+            loop {
+                if attempted {
+                    let delay = backoff.next_delay();
+                    connection.set(Connection::Offline(delay.as_secs().max(1)));
+                    tokio::select! {
+                        _ = platform::sleep(delay) => {}
+                        _ = retry_now.notified() => {}
+                    }
+                    connection.set(Connection::Connecting);
+                }
+                attempted = true;
+
+                let mut stream = match account.subscribe().await {
+                    Ok(stream) => stream,
+                    Err(e) if e.code() == tonic::Code::Unauthenticated => {
+                        on_signed_out.call(());
+                        return;
+                    }
+                    // Failed to open stream — back off, then retry.
+                    Err(_) => continue,
+                };
+
+                connection.set(Connection::Online);
+                backoff.reset();
+                wake.notify_one();
+                // Whatever arrived while we were away only shows up in history.
+                if was_online {
+                    for chat in chats.write().iter_mut() {
+                        chat.history_synced = false;
+                    }
+                    if let Some(aid) = *active_id.peek() {
+                        load_history.call((aid, None));
+                    }
+                }
+                was_online = true;
 
                 loop {
                     match stream.message().await {
-                        Ok(Some(incoming)) => {
-                            let from = incoming.from_addr.clone();
-                            let text = String::from_utf8_lossy(&incoming.msg).to_string();
+                        Ok(Some(event)) => match event.event {
+                            Some(chat_event::Event::Message(incoming)) => {
+                                // Our own sends are echoed to every device we're signed in on.
+                                let (peer, side) = if incoming.from_addr == id {
+                                    (incoming.to_addr.clone(), Side::Me)
+                                } else {
+                                    (incoming.from_addr.clone(), Side::Them)
+                                };
+
+                                // ── Step 1: find existing chat id (read lock released before any write) ──
+                                let existing_id: Option<usize> = {
+                                    chats.read().iter().find(|c| c.peer_id == peer).map(|c| c.id)
+                                };
+
+                                // ── Step 2: create chat if needed (no read lock held) ──
+                                let chat_id = if let Some(cid) = existing_id {
+                                    cid
+                                } else {
+                                    // Allocate a new id, then push the chat.
+                                    let cid = { *next_id.read() };
+                                    *next_id.write() += 1;
+                                    chats.write().push(Chat::new(cid, peer.clone(), ""));
+
+                                    // We only know their account id — fetch who they are.
+                                    let api = api.clone();
+                                    let peer_id = peer.clone();
+                                    spawn(async move {
+                                        if let Ok(profile) = api.profile(peer_id).await {
+                                            if let Some(chat) = chats.write().iter_mut().find(|c| c.id == cid) {
+                                                chat.profile = Some(profile.into());
+                                            }
+                                        }
+                                    });
+                                    cid
+                                };
+
+                                // ── Step 3: skip messages we already show (e.g. the echo of our own send) ──
+                                {
+                                    let mut chats_w = chats.write();
+                                    let Some(chat) = chats_w.iter_mut().find(|c| c.id == chat_id) else { continue };
+                                    if chat.message_mut(incoming.id).is_some() {
+                                        continue;
+                                    }
+                                    if !incoming.client_id.is_empty() && chat.messages.iter().any(|m| m.client_id == incoming.client_id) {
+                                        chat.confirm_sent(&incoming.client_id, incoming.id, incoming.sent_at);
+                                        continue;
+                                    }
+                                }
+
+                                // ── Step 4: allocate message id ──
+                                let mid = { *next_id.read() };
+                                *next_id.write() += 1;
+
+                                // ── Step 5: append message (single write lock, held only for this block) ──
+                                {
+                                    let mut chats_w = chats.write();
+                                    if let Some(chat) = chats_w.iter_mut().find(|c| c.id == chat_id) {
+                                        if side == Side::Them && incoming.id > chat.last_read {
+                                            chat.unread += 1;
+                                        }
+                                        chat.last_activity = chat.last_activity.max(incoming.sent_at);
+                                        chat.messages.push(Message::received(mid, &incoming, side));
+                                    }
+                                }
+
+                                // Messages arriving in the open chat are read straight away,
+                                // unless the window is in the background.
+                                let focused = *window_focused.peek();
+                                if focused && *active_id.peek() == Some(chat_id) {
+                                    mark_read.call(chat_id);
+                                } else if side == Side::Them {
+                                    ack_delivered.call(chat_id);
+                                }
+
+                                let text = String::from_utf8_lossy(&incoming.msg);
+                                let body = notifications::preview(&text, incoming.deleted);
+                                if let Some(body) = body.filter(|_| !focused && side == Side::Them && !muted.peek().contains(&peer)) {
+                                    let title = chats.peek().iter()
+                                        .find(|c| c.id == chat_id)
+                                        .map(|c| c.display_name().to_string())
+                                        .unwrap_or_default();
+                                    notifier.show(&title, &body, peer.clone());
+                                }
+                            }
+                            Some(chat_event::Event::Edited(edited)) => {
+                                apply_edit(&mut chats.write(), &edited);
+                            }
+                            Some(chat_event::Event::Deleted(deleted)) => {
+                                apply_delete(&mut chats.write(), deleted.id, deleted.for_everyone);
+                            }
+                            Some(chat_event::Event::Reaction(change)) => {
+                                apply_reaction(&mut chats.write(), &change);
+                            }
+                            // Another of our devices read further.
+                            Some(chat_event::Event::Read(marker)) => {
+                                if let Some(chat) = chats.write().iter_mut().find(|c| c.peer_id == marker.peer_id) {
+                                    chat.read_up_to(marker.last_read_id);
+                                }
+                            }
+                            // Receipts for what we sent.
+                            Some(chat_event::Event::PeerDelivered(marker)) => {
+                                if let Some(chat) = chats.write().iter_mut().find(|c| c.peer_id == marker.peer_id) {
+                                    chat.peer_reached(marker.last_read_id, 0);
+                                }
+                            }
+                            Some(chat_event::Event::PeerRead(marker)) => {
+                                if let Some(chat) = chats.write().iter_mut().find(|c| c.peer_id == marker.peer_id) {
+                                    chat.peer_reached(0, marker.last_read_id);
+                                }
+                            }
+                            None => {}
+                        },
+                        // The server closed this session (revoked elsewhere or logged out).
+                        Err(e) if e.code() == tonic::Code::Unauthenticated => {
+                            on_signed_out.call(());
+                            return;
+                        }
+                        // Stream ended cleanly or errored — break inner loop, then reconnect.
+                        Ok(None) | Err(_) => break,
+                    }
+                }
+
+            }
+        });
+    });
+
+    // ── Modal helpers ─────────────────────────────────────────────────────────
+    let open_modal  = move |_| { modal_input.set(String::new()); modal_error.set(String::new()); modal_busy.set(false); modal_open.set(true); };
+    let close_modal = move |_| modal_open.set(false);
+
+    let api_lookup = global.api.clone();
+    let confirm_new_chat = use_callback(move |_: ()| {
+        if *modal_busy.read() { return; }
+        let contact = modal_input.read().trim().to_string();
+        if let Err(e) = validate_identifier(&contact) {
+            modal_error.set(e.to_string());
+            return;
+        }
+
+        // Resolve the address to an account before opening a chat with it.
+        modal_busy.set(true);
+        let api = api_lookup.clone();
+        spawn(async move {
+            let result = api.lookup(contact.clone()).await;
+            modal_busy.set(false);
+
+            let profile = match result {
+                Ok(profile) => profile,
+                Err(e) if e.code() == tonic::Code::NotFound => {
+                    modal_error.set(format!("{contact} isn't on DioxusChat yet."));
+                    return;
+                }
+                Err(e) => {
+                    modal_error.set(format!("Lookup failed: {}", e.message()));
+                    return;
+                }
+            };
+
+            let existing = chats.read().iter().find(|c| c.peer_id == profile.user_id).map(|c| c.id);
+            if let Some(cid) = existing {
+                active_id.set(Some(cid));
+            } else {
+                let id = *next_id.read();
+                *next_id.write() += 1;
+                let mut chat = Chat::new(id, profile.user_id.clone(), contact);
+                chat.profile = Some(profile.into());
+                chats.write().push(chat);
+                active_id.set(Some(id));
+            }
+            modal_open.set(false);
+            msg_input.set(String::new());
+        });
+    });
+
+    let confirm_click = move |_| confirm_new_chat.call(());
+    let confirm_key   = move |e: Event<KeyboardData>| { if e.key() == Key::Enter { confirm_new_chat.call(()); } };
+
+    // ── Send message ──────────────────────────────────────────────────────────
+    let outbox_send  = outbox_wake.clone();
+
+    let send = use_callback(move |_: ()| {
+        let text = msg_input.read().trim().to_string();
+        if text.is_empty() { return; }
+        let Some(aid) = *active_id.read() else { return };
+
+        let peer = chats.read().iter().find(|c| c.id == aid).map(|c| c.peer_id.clone());
+        let Some(to_addr) = peer else { return };
+
+        let quote = replying_to.read().clone().filter(|(cid, _)| *cid == aid).map(|(_, q)| q);
+        let reply_to = quote.as_ref().map(|q| q.server_id).unwrap_or_default();
+
+        // Optimistically add the message to the UI.
+        let mid = *next_id.read();
+        *next_id.write() += 1;
+        let client_id = new_client_id(mid);
+        if let Some(chat) = chats.write().iter_mut().find(|c| c.id == aid) {
+            chat.messages.push(Message {
+                id: mid,
+                server_id: 0,
+                client_id: client_id.clone(),
+                text: text.clone(),
+                side: Side::Me,
+                edited: false,
+                deleted: false,
+                quote,
+                reactions: Vec::new(),
+                state: MessageState::Pending,
+                sent_at: now_secs(),
+            });
+            chat.last_activity = now_secs();
+        }
+        msg_input.set(String::new());
+        replying_to.set(None);
+
+        outbox.write().push(IncomingMessage {
+            to_addr,
+            msg: text.into_bytes(),
+            client_id,
+            reply_to,
+            ..Default::default()
+        });
+        outbox_send.notify_one();
+    });
+
+    // Puts a failed message back in the outbox, under the same client id so a
+    // late echo of an earlier attempt still matches it.
+    let outbox_retry = outbox_wake.clone();
+
+    let retry_send = use_callback(move |client_id: String| {
+        let request = {
+            let mut chats_w = chats.write();
+            let found = chats_w.iter_mut().find_map(|c| {
+                let peer_id = c.peer_id.clone();
+                c.messages.iter_mut()
+                    .find(|m| m.client_id == client_id && m.state == MessageState::Failed)
+                    .map(|m| (peer_id, m))
+            });
+            let Some((to_addr, message)) = found else { return };
+            message.state = MessageState::Pending;
+            message.sent_at = now_secs();
+            IncomingMessage {
+                to_addr,
+                msg: message.text.clone().into_bytes(),
+                client_id: client_id.clone(),
+                reply_to: message.quote.as_ref().map(|q| q.server_id).unwrap_or_default(),
+                ..Default::default()
+            }
+        };
+        outbox.write().push(request);
+        outbox_retry.notify_one();
+    });
+
+    // ── Outbox: sends queued messages in order whenever we are online ───────
+    use_hook(|| {
+        let account = account.clone();
+        let wake    = outbox_wake.clone();
+
+        spawn(async move {
+            let mut backoff = Backoff::default();
+            loop {
+                wake.notified().await;
+                while *connection.peek() == Connection::Online {
+                    let Some(request) = outbox.peek().first().cloned() else { break };
+                    match account.send(request.clone()).await {
+                        Ok(stored) => {
+                            backoff.reset();
+                            outbox.write().retain(|m| m.client_id != request.client_id);
+                            // Remember the server id so the message can be edited or deleted later.
+                            if let Some(chat) = chats.write().iter_mut().find(|c| c.peer_id == request.to_addr) {
+                                chat.confirm_sent(&request.client_id, stored.id, stored.sent_at);
+                            }
+                        }
+                        // The stream going down stops this loop; reconnecting wakes it again.
+                        Err(e) if is_transient(&e) => platform::sleep(backoff.next_delay()).await,
+                        // Turned down by the server; left for the user to retry or delete.
+                        Err(_) => {
+                            outbox.write().retain(|m| m.client_id != request.client_id);
+                            let mut chats_w = chats.write();
+                            let failed = chats_w.iter_mut()
+                                .filter(|c| c.peer_id == request.to_addr)
+                                .flat_map(|c| c.messages.iter_mut())
+                                .find(|m| m.client_id == request.client_id);
+                            if let Some(m) = failed {
+                                m.state = MessageState::Failed;
+                            }
+                        }
+                    }
+                }
+            }
+        });
+    });
+
+    // ── Edit / delete our messages ────────────────────────────────────────────
+    let account_edit = account.clone();
+
+    let edit_message = use_callback(move |(server_id, text): (u64, String)| {
+        let account = account_edit.clone();
+        spawn(async move {
+            if let Ok(edited) = account.edit(server_id, text).await {
+                apply_edit(&mut chats.write(), &edited);
+            }
+        });
+    });
+
+    let account_delete = account.clone();
+
+    let delete_message = use_callback(move |(server_id, for_everyone): (u64, bool)| {
+        let account = account_delete.clone();
+        spawn(async move {
+            if account.delete(server_id, for_everyone).await.is_ok() {
+                apply_delete(&mut chats.write(), server_id, for_everyone);
+            }
+        });
+    });
+
+    let account_react = account.clone();
+
+    let react = use_callback(move |(server_id, emoji, remove): (u64, String, bool)| {
+        let account = account_react.clone();
+        spawn(async move {
+            if let Ok(change) = account.react(server_id, emoji, remove).await {
+                apply_reaction(&mut chats.write(), &change);
+            }
+        });
+    });
+
+    // ── Contact / block toggles from the chat header ──────────────────────────
+    let api_peer = global.api.clone();
+
+    // Muting only silences notifications; it is remembered on this device.
+    let my_id_mute = my_id.clone();
+    let toggle_mute = use_callback(move |peer_id: String| {
+        let now_muted = !muted.peek().contains(&peer_id);
+        if let Err(e) = settings::set_muted(&my_id_mute, &peer_id, now_muted) {
+            warn!("could not save mute setting: {e}");
+        }
+        if now_muted {
+            muted.write().insert(peer_id);
+        } else {
+            muted.write().remove(&peer_id);
+        }
+    });
+
+    let toggle_flag = use_callback(move |(cid, block): (usize, bool)| {
+        let Some((peer_id, on)) = chats.read().iter().find(|c| c.id == cid)
+            .map(|c| (c.peer_id.clone(), if block { c.blocked } else { c.saved }))
+        else { return };
+
+        let api = api_peer.clone();
+
+        spawn(async move {
+            let result = if block { api.set_blocked(peer_id, !on).await } else { api.set_contact(peer_id, !on).await };
+            if result.is_ok() {
+                if let Some(chat) = chats.write().iter_mut().find(|c| c.id == cid) {
+                    if block { chat.blocked = !on } else { chat.saved = !on }
+                }
+            }
+        });
+    });
+
+    // ── Message search ────────────────────────────────────────────────────────
+    let account_search = account.clone();
+
+    let search = use_callback(move |query: String| {
+        search_query.set(query.clone());
+        let generation = *search_gen.read() + 1;
+        search_gen.set(generation);
+
+        if query.trim().chars().count() < 2 {
+            search_hits.set(Vec::new());
+            search_note.set(String::new());
+            return;
+        }
+
+        let account = account_search.clone();
+        spawn(async move {
+            // Wait for a pause in typing before asking the server.
+            platform::sleep(std::time::Duration::from_millis(300)).await;
+            if *search_gen.peek() != generation { return; }
+            search_note.set("Searching…".to_string());
+
+            let result = account.search(query).await;
+            if *search_gen.peek() != generation { return; }
+
+            match result {
+                Ok(hits) => {
+                    search_note.set(if hits.is_empty() { "No messages found.".to_string() } else { String::new() });
+                    search_hits.set(hits);
+                }
+                Err(e) => search_note.set(format!("Search failed: {}", e.message())),
+            }
+        });
+    });
+
+    // Opens the conversation a hit belongs to and points at the message.
+    let api_hit = global.api.clone();
+    let my_id_hit   = my_id.clone();
+
+    let open_hit = use_callback(move |message: IncomingMessage| {
+        let peer_id = if message.from_addr == my_id_hit { message.to_addr.clone() } else { message.from_addr.clone() };
+        let existing = chats.read().iter().find(|c| c.peer_id == peer_id).map(|c| c.id);
+        let cid = match existing {
+            Some(cid) => cid,
+            None => {
+                let cid = *next_id.read();
+                *next_id.write() += 1;
+                chats.write().push(Chat::new(cid, peer_id.clone(), ""));
+
+                let api = api_hit.clone();
+                spawn(async move {
+                    if let Ok(profile) = api.profile(peer_id).await {
+                        if let Some(chat) = chats.write().iter_mut().find(|c| c.id == cid) {
+                            chat.profile = Some(profile.into());
+                        }
+                    }
+                });
+                cid
+            }
+        };
+        active_id.set(Some(cid));
+        msg_input.set(String::new());
+        mark_read.call(cid);
+
+        // Give the panel a moment to render, then scroll to and flash the message.
+        let target = message.id;
+        spawn(async move {
+            platform::sleep(std::time::Duration::from_millis(80)).await;
+            document::eval(&format!(
+                "const el = document.getElementById('msg-{target}');
+                 if (el) {{ el.scrollIntoView({{ block: 'center' }}); el.classList.add('flash');
+                            setTimeout(() => el.classList.remove('flash'), 1600); }}"
+            ));
+        });
+    });
+
+    let send_click = move |_| send.call(());
+    let send_key   = move |e: Event<KeyboardData>| { if e.key() == Key::Enter { send.call(()); } };
+
+    // ── Derived ───────────────────────────────────────────────────────────────
+    let connection_note: Option<String> = match *connection.read() {
+        Connection::Online => None,
+        Connection::Connecting => Some("Connecting…".to_string()),
+        Connection::Offline(secs) => Some(format!("Offline. Reconnecting in {secs}s…")),
+    }
+    .map(|note| match outbox.read().len() {
+        0 => note,
+        1 => format!("{note} 1 message waiting to send."),
+        n => format!("{note} {n} messages waiting to send."),
+    });
+    let active_chat: Option<Chat> = active_id
+        .read()
+        .and_then(|aid| chats.read().iter().find(|c| c.id == aid).cloned());
+    // Most recent conversation first; ones without messages keep their order at the end.
+    let mut sorted_chats: Vec<Chat> = chats.read().clone();
+    sorted_chats.sort_by_key(|c| std::cmp::Reverse(c.last_activity));
+    let now = now_secs();
+    let reply_quote: Option<Quote> = replying_to
+        .read()
+        .clone()
+        .filter(|(cid, _)| Some(*cid) == *active_id.read())
+        .map(|(_, q)| q);
+
+    rsx! {
+        div { class: "app",
+
+            // ════ SIDEBAR ════════════════════════════════════════════════════
+            div { class: "sidebar",
+                div { class: "sidebar-header",
+                    div { class: "sidebar-title",
+                        button {
+                            class: "me-btn",
+                            title: "Edit profile",
+                            onclick: move |_| profile_open.set(true),
+                            Avatar {
+                                class: "me-avatar",
+                                initial: initial_of(&my_profile.read().display_name),
+                                url: my_profile.read().avatar_url.clone(),
+                            }
+                        }
+                        "DioxusChat"
+                    }
+                    button { class: "add-btn", title: "New chat", onclick: open_modal, "＋" }
+                }
+
+                if let Some(note) = connection_note {
+                    div { class: "connection-banner",
+                        span { "{note}" }
+                        if matches!(*connection.read(), Connection::Offline(_)) {
+                            button {
+                                class: "connection-retry",
+                                onclick: {
+                                    let retry_now = reconnect_now.clone();
+                                    move |_| retry_now.notify_one()
+                                },
+                                "Retry now"
+                            }
+                        }
+                    }
+                }
+
+                div { class: "sidebar-search",
+                    input {
+                        class: "search-input",
+                        r#type: "search",
+                        placeholder: "🔍  Search messages",
+                        value: "{search_query}",
+                        oninput: move |e| search.call(e.value()),
+                        onkeydown: move |e: Event<KeyboardData>| { if e.key() == Key::Escape { search.call(String::new()); } },
+                    }
+                }
+
+                if !search_query.read().trim().is_empty() {
+                    div { class: "chat-list search-results",
+                        if !search_note.read().is_empty() {
+                            div { class: "empty-list", "{search_note}" }
+                        }
+                        for hit in search_hits.read().iter() {
+                            {
+                                let message = hit.message.clone().unwrap_or_default();
+                                let author_of = |m: &IncomingMessage| -> String {
+                                    if m.from_addr == my_id {
+                                        return "You".to_string();
+                                    }
+                                    chats.read().iter()
+                                        .find(|c| c.peer_id == m.from_addr)
+                                        .map(|c| c.display_name().to_string())
+                                        .unwrap_or_else(|| m.from_addr.chars().take(8).collect())
+                                };
+                                let peer_id = if message.from_addr == my_id { &message.to_addr } else { &message.from_addr };
+                                let title = chats.read().iter()
+                                    .find(|c| c.peer_id == *peer_id)
+                                    .map(|c| c.display_name().to_string())
+                                    .unwrap_or_else(|| peer_id.chars().take(8).collect());
+                                let context = |m: &IncomingMessage| -> String {
+                                    let text = if m.deleted { "This message was deleted".to_string() } else { String::from_utf8_lossy(&m.msg).chars().take(60).collect() };
+                                    format!("{}: {}", author_of(m), text)
+                                };
+                                let before: Vec<String> = hit.before.iter().map(&context).collect();
+                                let after: Vec<String> = hit.after.iter().map(&context).collect();
+                                let author = author_of(&message);
+                                let message_id = message.id;
+                                rsx! {
+                                    div {
+                                        key: "{message_id}",
+                                        class: "search-hit",
+                                        onclick: move |_| open_hit.call(message.clone()),
+                                        div { class: "search-hit-title", "{title}" }
+                                        for line in before {
+                                            div { class: "search-context", "{line}" }
+                                        }
+                                        div { class: "search-hit-text",
+                                            span { class: "search-hit-author", "{author}: " }
+                                            for run in hit.spans.iter() {
+                                                if run.highlighted {
+                                                    mark { "{run.text}" }
+                                                } else {
+                                                    span { "{run.text}" }
+                                                }
+                                            }
+                                        }
+                                        for line in after {
+                                            div { class: "search-context", "{line}" }
+                                        }
+                                    }
+                                }
+                            }
+                        }
+                    }
+                } else {
+                    div { class: "chat-list",
+                        if chats.read().is_empty() {
+                            div { class: "empty-list", "No chats yet." br {} "Tap ＋ to start one." }
+                        }
+                        for chat in sorted_chats.iter() {
+                            {
+                                let cid       = chat.id;
+                                let name      = chat.display_name().to_string();
+                                let avatar    = chat.avatar_char();
+                                let avatar_url = chat.avatar_url();
+                                let preview   = chat.preview();
+                                let unread    = chat.unread;
+                                let is_muted  = muted.read().contains(&chat.peer_id);
+                                let when      = short_time(chat.last_activity, now);
+                                let is_active = *active_id.read() == Some(cid);
+                                let cls = if is_active { "chat-item active" } else { "chat-item" };
+                                rsx! {
+                                    div {
+                                        class: "{cls}",
+                                        onclick: move |_| { active_id.set(Some(cid)); msg_input.set(String::new()); mark_read.call(cid); },
+                                        Avatar { class: "chat-avatar", initial: avatar, url: avatar_url }
+                                        div { class: "chat-item-body",
+                                            div { class: "chat-item-name",    "{name}" }
+                                            div { class: "chat-item-preview", "{preview}" }
+                                        }
+                                        div { class: "chat-item-meta",
+                                            span { class: if unread > 0 { "chat-item-time unread" } else { "chat-item-time" }, "{when}" }
+                                            div { class: "chat-item-badges",
+                                                if is_muted {
+                                                    span { class: "chat-item-muted", title: "Muted", "🔕" }
+                                                }
+                                                if unread > 0 {
+                                                    span { class: if is_muted { "unread-badge muted" } else { "unread-badge" }, if unread > 99 { "99+" } else { "{unread}" } }
+                                                }
+                                            }
+                                        }
+                                    }
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+
+            // ════ MAIN PANEL ═════════════════════════════════════════════════
+            div { class: "chat-panel",
+                match active_chat {
+                    None => rsx! {
+                        div { class: "empty-state",
+                            span { class: "empty-icon", "💬" }
+                            div { class: "empty-title", "Welcome to DioxusChat" }
+                            div { class: "empty-sub",
+                                "Select a conversation or tap " strong { "＋" } " to start a new one."
+                            }
+                        }
+                    },
+                    Some(chat) => rsx! {
+                        div { class: "chat-header",
+                            Avatar { class: "chat-header-avatar", initial: chat.avatar_char(), url: chat.avatar_url() }
+                            div {
+                                div { class: "chat-header-name",    "{chat.display_name()}" }
+                                div { class: "chat-header-contact", "{chat.subtitle()}" }
+                            }
+                            div { class: "chat-header-actions",
+                                {
+                                    let cid = chat.id;
+                                    let peer_id = chat.peer_id.clone();
+                                    let is_muted = muted.read().contains(&peer_id);
+                                    rsx! {
+                                        button {
+                                            class: "header-btn",
+                                            title: if is_muted { "Unmute notifications" } else { "Mute notifications" },
+                                            onclick: move |_| toggle_mute.call(peer_id.clone()),
+                                            if is_muted { "🔕" } else { "🔔" }
+                                        }
+                                        button {
+                                            class: "header-btn",
+                                            title: if chat.saved { "Remove from contacts" } else { "Save contact" },
+                                            onclick: move |_| toggle_flag.call((cid, false)),
+                                            if chat.saved { "★" } else { "☆" }
+                                        }
+                                        button {
+                                            class: "header-btn",
+                                            title: if chat.blocked { "Unblock" } else { "Block" },
+                                            onclick: move |_| toggle_flag.call((cid, true)),
+                                            if chat.blocked { "Unblock" } else { "Block" }
+                                        }
+                                        button {
+                                            class: "header-btn",
+                                            title: "Report",
+                                            onclick: move |_| report_for.set(Some(cid)),
+                                            "⚑"
+                                        }
+                                    }
+                                }
+                            }
+                        }
+
+                        div { class: "messages",
+                            if chat.has_older {
+                                button {
+                                    class: "load-older",
+                                    onclick: {
+                                        let cid = chat.id;
+                                        let oldest = chat.messages.iter().map(|m| m.server_id).filter(|&id| id != 0).min();
+                                        move |_| load_history.call((cid, oldest))
+                                    },
+                                    "Load earlier messages"
+                                }
+                            }
+                            if chat.messages.is_empty() {
+                                div { class: "no-messages", "Say hello to {chat.display_name()} 👋" }
+                            }
+                            for (i, msg) in chat.messages.iter().enumerate() {
+                                ChatBubble {
+                                    key: "{msg.id}",
+                                    message: msg.clone(),
+                                    day_break: {
+                                        let previous = i.checked_sub(1).map(|p| chat.messages[p].sent_at).unwrap_or_default();
+                                        (msg.sent_at != 0 && (previous == 0 || !same_day(previous, msg.sent_at)))
+                                            .then(|| day_label(msg.sent_at, now))
+                                    },
+                                    grouped: i.checked_sub(1).is_some_and(|p| continues_group(&chat.messages[p], msg)),
+                                    my_id: my_id.clone(),
+                                    peer_name: chat.display_name().to_string(),
+                                    on_edit: move |edit| edit_message.call(edit),
+                                    on_delete: move |delete| delete_message.call(delete),
+                                    on_react: move |reaction| react.call(reaction),
+                                    on_retry: move |client_id| retry_send.call(client_id),
+                                    on_reply: {
+                                        let (cid, my_id, peer_id) = (chat.id, my_id.clone(), chat.peer_id.clone());
+                                        move |m: Message| replying_to.set(Some((cid, m.quote(&my_id, &peer_id))))
+                                    },
+                                }
+                            }
+                        }
+
+                        if let Some(quote) = reply_quote.clone() {
+                            div { class: "reply-bar",
+                                QuoteBlock {
+                                    author: if quote.from_addr == my_id { "You".to_string() } else { chat.display_name().to_string() },
+                                    quote: quote.clone(),
+                                }
+                                button { class: "reply-cancel", title: "Cancel reply", onclick: move |_| replying_to.set(None), "✕" }
+                            }
+                        }
+
+                        if chat.blocked {
+                            div { class: "input-bar blocked-bar",
+                                "You blocked {chat.display_name()}. Unblock them to send messages."
+                            }
+                        } else {
+                            div { class: "input-bar",
+                                input {
+                                    class: "text-input",
+                                    r#type: "text",
+                                    placeholder: "Message {chat.display_name()}…",
+                                    value: "{msg_input}",
+                                    oninput:   move |e| msg_input.set(e.value()),
+                                    onkeydown: send_key,
+                                }
+                                button { class: "send-btn", onclick: send_click, "➤" }
+                            }
+                        }
+                    },
+                }
+            }
+
+            // ════ NEW-CHAT MODAL ══════════════════════════════════════════════
+            if *modal_open.read() {
+                div { class: "modal-backdrop", onclick: close_modal,
+                    div {
+                        class: "modal",
+                        onclick: move |e| e.stop_propagation(),
+
+                        div { class: "modal-header",
+                            span { class: "modal-icon", "✉️" }
+                            div {
+                                div { class: "modal-title", "New Chat" }
+                                div { class: "modal-sub",   "Enter the contact's email or phone number" }
+                            }
+                            button { class: "modal-close", onclick: close_modal, "✕" }
+                        }
+
+                        div { class: "modal-body",
+                            input {
+                                class: "modal-input",
+                                r#type: "text",
+                                placeholder: "e.g. alice@example.com or +1 555 000 0000",
+                                value: "{modal_input}",
+                                autofocus: true,
+                                oninput:   move |e| { modal_input.set(e.value()); modal_error.set(String::new()); },
+                                onkeydown: confirm_key,
+                            }
+                            if !modal_error.read().is_empty() {
+                                div { class: "modal-error", "⚠ {modal_error}" }
+                            }
+                        }
+
+                        div { class: "modal-footer",
+                            button { class: "modal-btn-cancel",  onclick: close_modal,    "Cancel" }
+                            button {
+                                class: "modal-btn-confirm",
+                                disabled: *modal_busy.read(),
+                                onclick: confirm_click,
+                                if *modal_busy.read() { "Looking up…" } else { "Start Chat" }
+                            }
+                        }
+                    }
+                }
+            }
+
+            // ════ REPORT MODAL ════════════════════════════════════════════════
+            if let Some(chat) = report_for.read().and_then(|cid| chats.read().iter().find(|c| c.id == cid).cloned()) {
+                ReportModal {
+                    peer_id: chat.peer_id.clone(),
+                    peer_name: chat.display_name().to_string(),
+                    on_close: move |_| report_for.set(None),
+                    on_reported: move |also_block: bool| {
+                        if also_block {
+                            if let Some(c) = chats.write().iter_mut().find(|c| c.id == chat.id) {
+                                c.blocked = true;
+                            }
+                        }
+                        report_for.set(None);
+                    },
+                }
+            }
+
+            // ════ PROFILE MODAL ═══════════════════════════════════════════════
+            if *profile_open.read() {
+                ProfileModal {
+                    profile: my_profile.read().clone(),
+                    on_close: move |_| profile_open.set(false),
+                    on_saved: move |p: Profile| { my_profile.set(p); profile_open.set(false); },
+                    on_security: move |_| { profile_open.set(false); security_open.set(true); },
+                    on_account: move |_| { profile_open.set(false); account_open.set(true); },
+                    on_logout: move |_| { profile_open.set(false); logout.call(()); },
+                }
+            }
+
+            // ════ ACCOUNT MODAL ═══════════════════════════════════════════════
+            if *account_open.read() {
+                AccountModal {
+                    on_close: move |_| account_open.set(false),
+                    on_deleted: move |_| on_signed_out.call(()),
+                }
+            }
+
+            // ════ SECURITY MODAL ══════════════════════════════════════════════
+            if *security_open.read() {
+                SecurityModal { on_close: move |_| security_open.set(false) }
+            }
+        }
+    }
+}
+
+// ── Report dialog ─────────────────────────────────────────────────────────────
+#[component]
+fn ReportModal(
+    peer_id: String,
+    peer_name: String,
+    on_close: EventHandler<()>,
+    on_reported: EventHandler<bool>,   // carries whether they were also blocked
+) -> Element {
+    let mut reason     = use_signal(|| ReportReason::Spam);
+    let mut details    = use_signal(String::new);
+    let mut also_block = use_signal(|| true);
+    let mut error      = use_signal(String::new);
+    let mut sending    = use_signal(|| false);
+
+    let global = use_context::<GlobalState>();
+
+    let submit = move |_| {
+        sending.set(true);
+        error.set(String::new());
+
+        let api = global.api.clone();
+        let block   = *also_block.read();
+        let peer    = peer_id.clone();
+        let reason  = *reason.read();
+        let details = details.read().trim().to_string();
+
+        spawn(async move {
+            match api.report(peer, reason, details, block).await {
+                Ok(_) => on_reported.call(block),
+                Err(e) => {
+                    error.set(e.message().to_string());
+                    sending.set(false);
+                }
+            }
+        });
+    };
+
+    let reasons = [
+        (ReportReason::Spam,                 "Spam"),
+        (ReportReason::Harassment,           "Harassment"),
+        (ReportReason::Impersonation,        "Impersonation"),
+        (ReportReason::InappropriateContent, "Inappropriate content"),
+        (ReportReason::Other,                "Something else"),
+    ];
+
+    rsx! {
+        div { class: "modal-backdrop", onclick: move |_| on_close.call(()),
+            div {
+                class: "modal",
+                onclick: move |e| e.stop_propagation(),
+
+                div { class: "modal-header",
+                    span { class: "modal-icon", "⚑" }
+                    div {
+                        div { class: "modal-title", "Report {peer_name}" }
+                        div { class: "modal-sub",   "Reports are reviewed by moderators" }
+                    }
+                    button { class: "modal-close", onclick: move |_| on_close.call(()), "✕" }
+                }
+
+                div { class: "modal-body report-body",
+                    for (value, label) in reasons {
+                        label { class: "report-reason",
+                            input {
+                                r#type: "radio",
+                                name: "reason",
+                                checked: *reason.read() == value,
+                                onchange: move |_| reason.set(value),
+                            }
+                            "{label}"
+                        }
+                    }
+                    textarea {
+                        class: "modal-input report-details",
+                        placeholder: "Anything else moderators should know? (optional)",
+                        maxlength: "2000",
+                        value: "{details}",
+                        oninput: move |e| details.set(e.value()),
+                    }
+                    label { class: "report-reason",
+                        input {
+                            r#type: "checkbox",
+                            checked: *also_block.read(),
+                            onchange: move |e| also_block.set(e.checked()),
+                        }
+                        "Also block {peer_name}"
+                    }
+                    if !error.read().is_empty() {
+                        div { class: "modal-error", "⚠ {error}" }
+                    }
+                }
+
+                div { class: "modal-footer",
+                    button { class: "modal-btn-cancel", onclick: move |_| on_close.call(()), "Cancel" }
+                    button {
+                        class: "modal-btn-confirm report-btn",
+                        disabled: *sending.read(),
+                        onclick: submit,
+                        if *sending.read() { "Sending…" } else { "Report" }
+                    }
+                }
+            }
+        }
+    }
+}
+
+// ── Profile editor ────────────────────────────────────────────────────────────
+#[component]
+fn ProfileModal(
+    profile: Profile,
+    on_close: EventHandler<()>,
+    on_saved: EventHandler<Profile>,
+    on_security: EventHandler<()>,
+    on_account: EventHandler<()>,
+    on_logout: EventHandler<()>,
+) -> Element {
+    let mut name    = use_signal(|| profile.display_name.clone());
+    let mut about   = use_signal(|| profile.about.clone());
+    let mut avatar  = use_signal(|| None::<(Vec<u8>, String)>);   // newly picked image, if any
+    let mut error   = use_signal(String::new);
+    let mut saving  = use_signal(|| false);
+
+    let global = use_context::<GlobalState>();
+
+    let pick_avatar = move |e: Event<FormData>| {
+        let Some(file) = e.files().into_iter().next() else { return };
+        spawn(async move {
+            let mime = file.content_type().unwrap_or_else(|| "image/png".to_string());
+            match file.read_bytes().await {
+                Ok(bytes) => { avatar.set(Some((bytes.to_vec(), mime))); error.set(String::new()); }
+                Err(e)    => error.set(format!("Could not read image: {e}")),
+            }
+        });
+    };
+
+    let save = move |_| {
+        saving.set(true);
+        error.set(String::new());
+
+        let api = global.api.clone();
+        let picked  = avatar.read().clone();
+        let name    = name.read().trim().to_string();
+        let about   = about.read().trim().to_string();
+
+        spawn(async move {
+            match api.update_profile(name, about, picked).await {
+                Ok(profile) => on_saved.call(profile.into()),
+                Err(e) => {
+                    error.set(e.message().to_string());
+                    saving.set(false);
+                }
+            }
+        });
+    };
+
+    // Preview the freshly picked image, otherwise whatever the server has.
+    let preview_url = match avatar.read().as_ref() {
+        Some((bytes, mime)) => Some(format!(
+            "data:{mime};base64,{}",
+            base64::engine::general_purpose::STANDARD.encode(bytes)
+        )),
+        None => profile.avatar_url.clone(),
+    };
+
+    rsx! {
+        div { class: "modal-backdrop", onclick: move |_| on_close.call(()),
+            div {
+                class: "modal",
+                onclick: move |e| e.stop_propagation(),
+
+                div { class: "modal-header",
+                    span { class: "modal-icon", "👤" }
+                    div {
+                        div { class: "modal-title", "Your Profile" }
+                        div { class: "modal-sub",   "This is what your contacts see" }
+                    }
+                    button { class: "modal-close", onclick: move |_| on_close.call(()), "✕" }
+                }
+
+                div { class: "modal-body profile-body",
+                    label { class: "profile-avatar-pick",
+                        Avatar { class: "profile-avatar", initial: initial_of(&name.read()), url: preview_url }
+                        input { r#type: "file", accept: "image/*", onchange: pick_avatar }
+                        span { class: "profile-avatar-hint", "Change photo" }
+                    }
+                    input {
+                        class: "modal-input",
+                        r#type: "text",
+                        placeholder: "Display name",
+                        maxlength: "64",
+                        value: "{name}",
+                        oninput: move |e| name.set(e.value()),
+                    }
+                    input {
+                        class: "modal-input",
+                        r#type: "text",
+                        placeholder: "About",
+                        maxlength: "140",
+                        value: "{about}",
+                        oninput: move |e| about.set(e.value()),
+                    }
+                    if !error.read().is_empty() {
+                        div { class: "modal-error", "⚠ {error}" }
+                    }
+                    button { class: "profile-security-link", onclick: move |_| on_security.call(()),
+                        "🛡️  Two-step verification"
+                    }
+                    button { class: "profile-security-link", onclick: move |_| on_account.call(()),
+                        "🗂️  Your data & account"
+                    }
+                    button { class: "profile-security-link profile-logout", onclick: move |_| on_logout.call(()),
+                        "🚪  Log out"
+                    }
+                }
+
+                div { class: "modal-footer",
+                    button { class: "modal-btn-cancel",  onclick: move |_| on_close.call(()), "Cancel" }
+                    button {
+                        class: "modal-btn-confirm",
+                        disabled: *saving.read(),
+                        onclick: save,
+                        if *saving.read() { "Saving…" } else { "Save" }
+                    }
+                }
+            }
+        }
+    }
+}
 
-                            // ── Step 1: find existing chat id (read lock released before any write) ──
-                            let existing_id: Option<usize> = {
-                                chats.read().iter().find(|c| c.contact == from).map(|c| c.id)
-                            };
+// ── Two-step verification settings ────────────────────────────────────────────
+#[derive(Clone, PartialEq)]
+enum SecurityStep {
+    Loading,
+    Off,
+    /// Enrollment started: base32 secret and otpauth URI for the authenticator app.
+    Enrolling(String, String),
+    /// Just turned on or regenerated; shown once.
+    RecoveryCodes(Vec<String>),
+    On(i64),
+}
 
-                            // ── Step 2: create chat if needed (no read lock held) ──
-                            let chat_id = if let Some(cid) = existing_id {
-                                cid
-                            } else {
-                                // Allocate a new id, then push the chat.
-                                let cid = { *next_id.read() };
-                                *next_id.write() += 1;
-                                chats.write().push(Chat::new(cid, from.clone()));
-                                cid
-                            };
+#[component]
+fn SecurityModal(on_close: EventHandler<()>) -> Element {
+    let mut step  = use_signal(|| SecurityStep::Loading);
+    let mut code  = use_signal(String::new);
+    let mut error = use_signal(String::new);
+    let mut busy  = use_signal(|| false);
 
-                            // ── Step 3: allocate message id ──
-                            let mid = { *next_id.read() };
-                            *next_id.write() += 1;
+    let global = use_context::<GlobalState>();
+    let api = global.api.clone();
 
-                            // ── Step 4: append message (single write lock, held only for this block) ──
-                            {
-                                let mut chats_w = chats.write();
-                                if let Some(chat) = chats_w.iter_mut().find(|c| c.id == chat_id) {
-                                    chat.messages.push(Message {
-                                        id:   mid,
-                                        text,
-                                        side: Side::Them,
-                                    });
-                                }
-                            }
-                        }
-                        // Stream ended cleanly or errored — break inner loop, then reconnect.
-                        Ok(None) | Err(_) => break,
-                    }
+    let refresh = use_callback(move |_: ()| {
+        let api = api.clone();
+        spawn(async move {
+            match api.two_factor_status().await {
+                Ok(status) => {
+                    step.set(if status.enabled { SecurityStep::On(status.recovery_codes_left) } else { SecurityStep::Off });
                 }
-
+                Err(e) => error.set(e.message().to_string()),
             }
         });
     });
+    use_hook(|| refresh.call(()));
+
+    // Runs one of the code-taking RPCs; `action` picks which.
+    let api = global.api.clone();
+    let submit = use_callback(move |action: &'static str| {
+        let entered = code.read().trim().to_string();
+        if action != "enroll" && entered.is_empty() {
+            error.set("Enter a code first.".to_string());
+            return;
+        }
+        busy.set(true);
+        error.set(String::new());
 
-    // ── Modal helpers ─────────────────────────────────────────────────────────
-    let open_modal  = move |_| { modal_input.set(String::new()); modal_error.set(String::new()); modal_open.set(true); };
-    let close_modal = move |_| modal_open.set(false);
-
-    let mut confirm_new_chat = move || {
-        let contact = modal_input.read().trim().to_string();
-        match validate_identifier(&contact) {
-            Err(e) => modal_error.set(e.to_string()),
-            Ok(()) => {
-                let existing = chats.read().iter().find(|c| c.contact == contact).map(|c| c.id);
-                if let Some(cid) = existing {
-                    active_id.set(Some(cid));
-                } else {
-                    let id = *next_id.read();
-                    *next_id.write() += 1;
-                    chats.write().push(Chat::new(id, contact));
-                    active_id.set(Some(id));
-                }
-                modal_open.set(false);
-                msg_input.set(String::new());
+        let api = api.clone();
+        spawn(async move {
+            let result = match action {
+                "enroll" => api.enroll_totp().await
+                    .map(|r| SecurityStep::Enrolling(r.secret, r.otpauth_uri)),
+                "confirm" => api.confirm_totp(entered).await
+                    .map(SecurityStep::RecoveryCodes),
+                "regenerate" => api.regenerate_recovery_codes(entered).await
+                    .map(SecurityStep::RecoveryCodes),
+                _ => api.disable_totp(entered).await
+                    .map(|_| SecurityStep::Off),
+            };
+            match result {
+                Ok(next) => { step.set(next); code.set(String::new()); }
+                Err(e) => error.set(e.message().to_string()),
             }
+            busy.set(false);
+        });
+    });
+
+    let code_input = rsx! {
+        input {
+            class: "modal-input",
+            r#type: "text",
+            placeholder: "Code from your authenticator",
+            maxlength: "12",
+            value: "{code}",
+            oninput: move |e| { code.set(e.value()); error.set(String::new()); },
         }
     };
 
-    let confirm_click = move |_| confirm_new_chat();
-    let confirm_key   = move |e: Event<KeyboardData>| { if e.key() == Key::Enter { confirm_new_chat(); } };
-
-    // ── Send message ──────────────────────────────────────────────────────────
-    let channel_send = global.rpc_channel.clone();
-    let my_id_send   = my_id.clone();
+    rsx! {
+        div { class: "modal-backdrop", onclick: move |_| on_close.call(()),
+            div {
+                class: "modal",
+                onclick: move |e| e.stop_propagation(),
 
-    let send = use_callback(move |_: ()| {
-        let text = msg_input.read().trim().to_string();
-        if text.is_empty() { return; }
-        let Some(aid) = *active_id.read() else { return };
+                div { class: "modal-header",
+                    span { class: "modal-icon", "🛡️" }
+                    div {
+                        div { class: "modal-title", "Two-step verification" }
+                        div { class: "modal-sub",   "Ask for an authenticator code after each sign-in" }
+                    }
+                    button { class: "modal-close", onclick: move |_| on_close.call(()), "✕" }
+                }
 
-        let contact = chats.read().iter().find(|c| c.id == aid).map(|c| c.contact.clone());
-        let Some(to_addr) = contact else { return };
+                div { class: "modal-body security-body",
+                    match step.read().clone() {
+                        SecurityStep::Loading => rsx! { p { class: "security-text", "Loading…" } },
+                        SecurityStep::Off => rsx! {
+                            p { class: "security-text",
+                                "Off. Turn it on to require a code from an authenticator app as well as your email."
+                            }
+                        },
+                        SecurityStep::Enrolling(secret, uri) => rsx! {
+                            p { class: "security-text", "Add this key to your authenticator app, then enter the code it shows." }
+                            div { class: "security-secret", "{secret}" }
+                            div { class: "security-uri", "{uri}" }
+                            {code_input}
+                        },
+                        SecurityStep::RecoveryCodes(codes) => rsx! {
+                            p { class: "security-text",
+                                "Save these recovery codes somewhere safe. Each works once if you lose your authenticator. They won't be shown again."
+                            }
+                            div { class: "security-codes",
+                                for c in codes { span { "{c}" } }
+                            }
+                        },
+                        SecurityStep::On(left) => rsx! {
+                            p { class: "security-text", "On · {left} recovery codes left." }
+                            {code_input}
+                        },
+                    }
+                    if !error.read().is_empty() {
+                        div { class: "modal-error", "⚠ {error}" }
+                    }
+                }
 
-        // Optimistically add the message to the UI.
-        let mid = *next_id.read();
-        *next_id.write() += 1;
-        if let Some(chat) = chats.write().iter_mut().find(|c| c.id == aid) {
-            chat.messages.push(Message { id: mid, text: text.clone(), side: Side::Me });
+                div { class: "modal-footer",
+                    match step.read().clone() {
+                        SecurityStep::Off => rsx! {
+                            button { class: "modal-btn-confirm", disabled: *busy.read(), onclick: move |_| submit.call("enroll"), "Turn on" }
+                        },
+                        SecurityStep::Enrolling(..) => rsx! {
+                            button { class: "modal-btn-confirm", disabled: *busy.read(), onclick: move |_| submit.call("confirm"), "Verify" }
+                        },
+                        SecurityStep::RecoveryCodes(_) => rsx! {
+                            button { class: "modal-btn-confirm", onclick: move |_| refresh.call(()), "I saved them" }
+                        },
+                        SecurityStep::On(_) => rsx! {
+                            button { class: "modal-btn-cancel", disabled: *busy.read(), onclick: move |_| submit.call("regenerate"), "New recovery codes" }
+                            button { class: "modal-btn-confirm", disabled: *busy.read(), onclick: move |_| submit.call("disable"), "Turn off" }
+                        },
+                        SecurityStep::Loading => rsx! {},
+                    }
+                }
+            }
         }
-        msg_input.set(String::new());
+    }
+}
 
-        // Fire-and-forget over gRPC.
-        let channel    = channel_send.clone();
-        let from_addr  = my_id_send.clone();
+// ── Data export & account deletion ────────────────────────────────────────────
+#[component]
+fn AccountModal(on_close: EventHandler<()>, on_deleted: EventHandler<()>) -> Element {
+    let mut status      = use_signal(String::new);
+    let mut error       = use_signal(String::new);
+    let mut busy        = use_signal(|| false);
+    let mut code_sent   = use_signal(|| false);
+    let mut code        = use_signal(String::new);
+    let mut two_factor  = use_signal(String::new);
+    let mut identifiers = use_signal(AccountIdentifiers::default);
+    let mut new_address = use_signal(String::new);
+    let mut change_sent = use_signal(|| false);
+    let mut change_code = use_signal(String::new);
 
+    let global = use_context::<GlobalState>();
+    let api = global.api.clone();
+    use_hook(move || {
         spawn(async move {
-            let mut chat_client = ChatClient::new(channel);
-            let _ = chat_client
-                .send_message(tonic::Request::new(IncomingMessage {
-                    from_addr,
-                    to_addr,
-                    msg: text.into_bytes(),
-                }))
-                .await;
+            if let Ok(ids) = api.identifiers().await {
+                identifiers.set(ids);
+            }
         });
     });
 
-    let send_click = move |_| send.call(());
-    let send_key   = move |e: Event<KeyboardData>| { if e.key() == Key::Enter { send.call(()); } };
+    let api = global.api.clone();
+    let send_change_code = move |_| {
+        let address = new_address.read().trim().to_string();
+        if let Err(e) = validate_identifier(&address) {
+            error.set(e.to_owned());
+            return;
+        }
+        busy.set(true);
+        error.set(String::new());
+        // a kind the account already has is swapped out, a missing one is added
+        let ids = identifiers.read().clone();
+        let replace = if address.contains('@') { !ids.email.is_empty() } else { !ids.phone.is_empty() };
+        let api = api.clone();
+        spawn(async move {
+            match api.request_identifier_change(address, replace, platform::locale()).await {
+                Ok(_) => change_sent.set(true),
+                Err(e) => error.set(e.message().to_string()),
+            }
+            busy.set(false);
+        });
+    };
 
-    // ── Derived ───────────────────────────────────────────────────────────────
-    let active_chat: Option<Chat> = active_id
-        .read()
-        .and_then(|aid| chats.read().iter().find(|c| c.id == aid).cloned());
+    let api = global.api.clone();
+    let confirm_change = move |_| {
+        busy.set(true);
+        error.set(String::new());
+        let code = change_code.read().trim().to_string();
+        let two_factor_code = two_factor.read().trim().to_string();
+        let api = api.clone();
+        spawn(async move {
+            match api.confirm_identifier_change(code, two_factor_code).await {
+                Ok(ids) => {
+                    identifiers.set(ids);
+                    change_sent.set(false);
+                    change_code.set(String::new());
+                    new_address.set(String::new());
+                    status.set("Sign-in details updated.".to_string());
+                }
+                Err(e) => error.set(e.message().to_string()),
+            }
+            busy.set(false);
+        });
+    };
+
+    let api = global.api.clone();
+    let export = move |_| {
+        busy.set(true);
+        error.set(String::new());
+        status.set("Preparing your archive…".to_string());
+        let api = api.clone();
+        spawn(async move {
+            let result = async {
+                let bytes = api.export_my_data().await.map_err(|e| e.message().to_string())?;
+                let name = format!("dioxuschat-export-{}.json", platform::since_epoch().as_secs());
+                platform::save_download(&name, &bytes)
+            }
+            .await;
+            match result {
+                Ok(saved) => status.set(saved),
+                Err(e) => { status.set(String::new()); error.set(e); }
+            }
+            busy.set(false);
+        });
+    };
+
+    let api = global.api.clone();
+    let request_code = move |_| {
+        busy.set(true);
+        error.set(String::new());
+        let api = api.clone();
+        spawn(async move {
+            match api.request_account_deletion(platform::locale()).await {
+                Ok(_) => code_sent.set(true),
+                Err(e) => error.set(e.message().to_string()),
+            }
+            busy.set(false);
+        });
+    };
+
+    let api = global.api.clone();
+    let delete = move |_| {
+        busy.set(true);
+        error.set(String::new());
+        let api = api.clone();
+        let code = code.read().trim().to_string();
+        let two_factor_code = two_factor.read().trim().to_string();
+        spawn(async move {
+            match api.delete_account(code, two_factor_code).await {
+                Ok(_) => on_deleted.call(()),
+                Err(e) => { error.set(e.message().to_string()); busy.set(false); }
+            }
+        });
+    };
 
     rsx! {
-        div { class: "app",
+        div { class: "modal-backdrop", onclick: move |_| on_close.call(()),
+            div {
+                class: "modal",
+                onclick: move |e| e.stop_propagation(),
 
-            // ════ SIDEBAR ════════════════════════════════════════════════════
-            div { class: "sidebar",
-                div { class: "sidebar-header",
-                    div { class: "sidebar-title", span { "💬" } "DioxusChat" }
-                    button { class: "add-btn", title: "New chat", onclick: open_modal, "＋" }
+                div { class: "modal-header",
+                    span { class: "modal-icon", "🗂️" }
+                    div {
+                        div { class: "modal-title", "Your data & account" }
+                        div { class: "modal-sub",   "Download or erase what the server keeps about you" }
+                    }
+                    button { class: "modal-close", onclick: move |_| on_close.call(()), "✕" }
                 }
 
-                div { class: "chat-list",
-                    if chats.read().is_empty() {
-                        div { class: "empty-list", "No chats yet." br {} "Tap ＋ to start one." }
+                div { class: "modal-body security-body",
+                    {
+                        let ids = identifiers.read().clone();
+                        let email = if ids.email.is_empty() { "—".to_string() } else { ids.email };
+                        let phone = if ids.phone.is_empty() { "—".to_string() } else { ids.phone };
+                        rsx! {
+                            p { class: "security-text", "Email: {email}" }
+                            p { class: "security-text", "Phone: {phone}" }
+                        }
                     }
-                    for chat in chats.read().iter() {
+                    if *change_sent.read() {
+                        p { class: "security-text", "We sent a code to {new_address}." }
+                        input {
+                            class: "modal-input",
+                            r#type: "text",
+                            placeholder: "Code sent to the new address",
+                            value: "{change_code}",
+                            oninput: move |e| change_code.set(e.value()),
+                        }
+                        input {
+                            class: "modal-input",
+                            r#type: "text",
+                            placeholder: "Authenticator code (if two-step verification is on)",
+                            value: "{two_factor}",
+                            oninput: move |e| two_factor.set(e.value()),
+                        }
+                        button { class: "modal-btn-confirm", disabled: *busy.read(), onclick: confirm_change, "Confirm change" }
+                    } else {
+                        input {
+                            class: "modal-input",
+                            r#type: "text",
+                            placeholder: "New email or phone (+34 600 000 000)",
+                            value: "{new_address}",
+                            oninput: move |e| new_address.set(e.value()),
+                        }
                         {
-                            let cid       = chat.id;
-                            let name      = chat.display_name().to_string();
-                            let avatar    = chat.avatar_char();
-                            let preview   = chat.preview();
-                            let is_active = *active_id.read() == Some(cid);
-                            let cls = if is_active { "chat-item active" } else { "chat-item" };
+                            let ids = identifiers.read();
+                            let label = match (new_address.read().contains('@'), ids.email.is_empty(), ids.phone.is_empty()) {
+                                (true, false, _)  => "Replace email",
+                                (true, true, _)   => "Add email",
+                                (false, _, false) => "Replace phone",
+                                (false, _, true)  => "Add phone",
+                            };
                             rsx! {
-                                div {
-                                    class: "{cls}",
-                                    onclick: move |_| { active_id.set(Some(cid)); msg_input.set(String::new()); },
-                                    div { class: "chat-avatar", "{avatar}" }
-                                    div { class: "chat-item-body",
-                                        div { class: "chat-item-name",    "{name}" }
-                                        div { class: "chat-item-preview", "{preview}" }
-                                    }
-                                }
+                                button { class: "modal-btn-cancel", disabled: *busy.read(), onclick: send_change_code, "{label}" }
                             }
                         }
                     }
-                }
-            }
 
-            // ════ MAIN PANEL ═════════════════════════════════════════════════
-            div { class: "chat-panel",
-                match active_chat {
-                    None => rsx! {
-                        div { class: "empty-state",
-                            span { class: "empty-icon", "💬" }
-                            div { class: "empty-title", "Welcome to DioxusChat" }
-                            div { class: "empty-sub",
-                                "Select a conversation or tap " strong { "＋" } " to start a new one."
-                            }
-                        }
-                    },
-                    Some(chat) => rsx! {
-                        div { class: "chat-header",
-                            div { class: "chat-header-avatar", "{chat.avatar_char()}" }
-                            div {
-                                div { class: "chat-header-name",    "{chat.display_name()}" }
-                                div { class: "chat-header-contact", "{chat.contact}" }
-                            }
-                        }
+                    p { class: "security-text",
+                        "Download a JSON archive of your account, profile, contacts, blocks, reports and sessions."
+                    }
+                    button { class: "modal-btn-cancel", disabled: *busy.read(), onclick: export, "Download my data" }
+                    if !status.read().is_empty() {
+                        p { class: "security-text", "{status}" }
+                    }
 
-                        div { class: "messages",
-                            if chat.messages.is_empty() {
-                                div { class: "no-messages", "Say hello to {chat.display_name()} 👋" }
-                            }
-                            for msg in chat.messages.iter() {
-                                ChatBubble { message: msg.clone() }
-                            }
+                    div { class: "danger-zone",
+                        p { class: "security-text",
+                            "Deleting your account erases your profile and contacts and signs out every device. This can't be undone."
                         }
-
-                        div { class: "input-bar",
+                        if *code_sent.read() {
+                            input {
+                                class: "modal-input",
+                                r#type: "text",
+                                placeholder: "Code from the confirmation email",
+                                value: "{code}",
+                                oninput: move |e| code.set(e.value()),
+                            }
                             input {
-                                class: "text-input",
+                                class: "modal-input",
                                 r#type: "text",
-                                placeholder: "Message {chat.display_name()}…",
-                                value: "{msg_input}",
-                                oninput:   move |e| msg_input.set(e.value()),
-                                onkeydown: send_key,
+                                placeholder: "Authenticator code (if two-step verification is on)",
+                                value: "{two_factor}",
+                                oninput: move |e| two_factor.set(e.value()),
                             }
-                            button { class: "send-btn", onclick: send_click, "➤" }
+                            button { class: "danger-btn", disabled: *busy.read(), onclick: delete, "Delete my account forever" }
+                        } else {
+                            button { class: "danger-btn", disabled: *busy.read(), onclick: request_code, "Delete account…" }
                         }
-                    },
+                    }
+
+                    if !error.read().is_empty() {
+                        div { class: "modal-error", "⚠ {error}" }
+                    }
                 }
             }
+        }
+    }
+}
+
+// ── Avatar ────────────────────────────────────────────────────────────────────
+// Profile picture when there is one, otherwise the initial on a coloured disc.
+#[component]
+fn Avatar(class: String, initial: char, url: Option<String>) -> Element {
+    rsx! {
+        div { class: "{class}",
+            if let Some(url) = url {
+                img { class: "avatar-img", src: "{url}" }
+            } else {
+                "{initial}"
+            }
+        }
+    }
+}
 
-            // ════ NEW-CHAT MODAL ══════════════════════════════════════════════
-            if *modal_open.read() {
-                div { class: "modal-backdrop", onclick: close_modal,
-                    div {
-                        class: "modal",
-                        onclick: move |e| e.stop_propagation(),
+// ── Chat bubble ───────────────────────────────────────────────────────────────
+#[component]
+fn ChatBubble(
+    message: Message,
+    day_break: Option<String>,   // first message of a day: the day's heading, shown above it
+    grouped: bool,               // continues the previous message's group
+    my_id: String,
+    peer_name: String,
+    on_edit: EventHandler<(u64, String)>,
+    on_delete: EventHandler<(u64, bool)>,
+    on_react: EventHandler<(u64, String, bool)>,
+    on_reply: EventHandler<Message>,
+    on_retry: EventHandler<String>,
+) -> Element {
+    let mut menu_open: Signal<bool>   = use_signal(|| false);
+    let mut editing:   Signal<bool>   = use_signal(|| false);
+    let mut draft:     Signal<String> = use_signal(String::new);
 
-                        div { class: "modal-header",
-                            span { class: "modal-icon", "✉️" }
-                            div {
-                                div { class: "modal-title", "New Chat" }
-                                div { class: "modal-sub",   "Enter the contact's email or phone number" }
+    let (row_cls, bubble_cls) = match message.side {
+        Side::Me   => ("row row-me",   "bubble bubble-me"),
+        Side::Them => ("row row-them", "bubble bubble-them"),
+    };
+    let row_cls = if grouped { format!("{row_cls} row-grouped") } else { row_cls.to_string() };
+    let time = clock_time(message.sent_at);
+    let time_title = if message.sent_at == 0 { String::new() } else { full_time(message.sent_at) };
+    let mine = message.side == Side::Me;
+    // Not stored yet (or already gone): nothing the server could act on.
+    let actionable = message.server_id != 0;
+    let server_id = message.server_id;
+    let quote_author = |from_addr: &str| if from_addr == my_id { "You".to_string() } else { peer_name.clone() };
+
+    let mut save = move || {
+        let text = draft.read().trim().to_string();
+        if !text.is_empty() {
+            on_edit.call((server_id, text));
+        }
+        editing.set(false);
+    };
+
+    rsx! {
+        if let Some(label) = day_break {
+            div { class: "day-separator", span { "{label}" } }
+        }
+        div { class: "{row_cls}", id: if actionable { "msg-{server_id}" },
+            div { class: "bubble-stack",
+                if *editing.read() {
+                    div { class: "{bubble_cls} bubble-editing",
+                        input {
+                            class: "bubble-edit-input",
+                            value: "{draft}",
+                            autofocus: true,
+                            oninput: move |e| draft.set(e.value()),
+                            onkeydown: move |e: Event<KeyboardData>| match e.key() {
+                                Key::Enter  => save(),
+                                Key::Escape => editing.set(false),
+                                _ => {}
+                            },
+                        }
+                        div { class: "bubble-edit-actions",
+                            button { class: "bubble-action", onclick: move |_| editing.set(false), "Cancel" }
+                            button { class: "bubble-action", onclick: move |_| save(), "Save" }
+                        }
+                    }
+                } else if message.deleted {
+                    div { class: "{bubble_cls} bubble-deleted",
+                        "🚫 This message was deleted"
+                        span { class: "bubble-time", title: "{time_title}", "{time}" }
+                    }
+                } else if message.state == MessageState::Failed {
+                    div {
+                        class: "{bubble_cls} bubble-failed",
+                        title: "Tap to send again",
+                        onclick: {
+                            let client_id = message.client_id.clone();
+                            move |_| on_retry.call(client_id.clone())
+                        },
+                        if let Some(quote) = message.quote.clone() {
+                            QuoteBlock { author: quote_author(&quote.from_addr), quote }
+                        }
+                        "{message.text}"
+                        div { class: "bubble-failed-note", "⚠ Not sent. Tap to retry." }
+                    }
+                } else {
+                    div { class: "{bubble_cls}",
+                        if let Some(quote) = message.quote.clone() {
+                            QuoteBlock { author: quote_author(&quote.from_addr), quote }
+                        }
+                        "{message.text}"
+                        if message.edited {
+                            span { class: "bubble-edited", "edited" }
+                        }
+                        span { class: "bubble-time", title: "{time_title}", "{time}" }
+                        if mine {
+                            match message.state {
+                                MessageState::Pending   => rsx! { span { class: "bubble-state", title: "Sending", "🕓" } },
+                                MessageState::Sent      => rsx! { span { class: "bubble-state", title: "Sent", "✓" } },
+                                MessageState::Delivered => rsx! { span { class: "bubble-state", title: "Delivered", "✓✓" } },
+                                MessageState::Read      => rsx! { span { class: "bubble-state state-read", title: "Read", "✓✓" } },
+                                MessageState::Failed    => rsx! {},
                             }
-                            button { class: "modal-close", onclick: close_modal, "✕" }
                         }
+                    }
+                }
 
-                        div { class: "modal-body",
-                            input {
-                                class: "modal-input",
-                                r#type: "text",
-                                placeholder: "e.g. alice@example.com or +1 555 000 0000",
-                                value: "{modal_input}",
-                                autofocus: true,
-                                oninput:   move |e| { modal_input.set(e.value()); modal_error.set(String::new()); },
-                                onkeydown: confirm_key,
-                            }
-                            if !modal_error.read().is_empty() {
-                                div { class: "modal-error", "⚠ {modal_error}" }
+                if !message.reactions.is_empty() {
+                    div { class: "reactions",
+                        for reaction in message.reactions.iter() {
+                            {
+                                let reacted = reaction.user_ids.contains(&my_id);
+                                let emoji = reaction.emoji.clone();
+                                rsx! {
+                                    button {
+                                        key: "{reaction.emoji}",
+                                        class: if reacted { "reaction reaction-mine" } else { "reaction" },
+                                        onclick: move |_| on_react.call((server_id, emoji.clone(), reacted)),
+                                        "{reaction.emoji} {reaction.user_ids.len()}"
+                                    }
+                                }
                             }
                         }
+                    }
+                }
+            }
 
-                        div { class: "modal-footer",
-                            button { class: "modal-btn-cancel",  onclick: close_modal,    "Cancel" }
-                            button { class: "modal-btn-confirm", onclick: confirm_click,  "Start Chat" }
+            if actionable && !*editing.read() {
+                div { class: "bubble-menu",
+                    button {
+                        class: "bubble-menu-btn",
+                        title: "More",
+                        onclick: move |_| { let open = *menu_open.read(); menu_open.set(!open); },
+                        "⋮"
+                    }
+                    if *menu_open.read() {
+                        div { class: "bubble-menu-list",
+                            if !message.deleted {
+                                div { class: "quick-reactions",
+                                    for emoji in QUICK_REACTIONS {
+                                        {
+                                            let reacted = message.reactions.iter()
+                                                .any(|r| r.emoji == emoji && r.user_ids.contains(&my_id));
+                                            rsx! {
+                                                button {
+                                                    key: "{emoji}",
+                                                    class: "quick-reaction",
+                                                    onclick: move |_| { menu_open.set(false); on_react.call((server_id, emoji.to_string(), reacted)); },
+                                                    "{emoji}"
+                                                }
+                                            }
+                                        }
+                                    }
+                                }
+                                button {
+                                    class: "bubble-action",
+                                    onclick: {
+                                        let message = message.clone();
+                                        move |_| { menu_open.set(false); on_reply.call(message.clone()); }
+                                    },
+                                    "Reply"
+                                }
+                            }
+                            if mine && !message.deleted {
+                                button {
+                                    class: "bubble-action",
+                                    onclick: {
+                                        let text = message.text.clone();
+                                        move |_| {
+                                            draft.set(text.clone());
+                                            editing.set(true);
+                                            menu_open.set(false);
+                                        }
+                                    },
+                                    "Edit"
+                                }
+                            }
+                            button {
+                                class: "bubble-action",
+                                onclick: move |_| { menu_open.set(false); on_delete.call((server_id, false)); },
+                                "Delete for me"
+                            }
+                            if mine && !message.deleted {
+                                button {
+                                    class: "bubble-action bubble-action-danger",
+                                    onclick: move |_| { menu_open.set(false); on_delete.call((server_id, true)); },
+                                    "Delete for everyone"
+                                }
+                            }
                         }
                     }
                 }
@@ -683,16 +3146,17 @@ fn ChatApp(my_id: String) -> Element {
     }
 }
 
-// ── Chat bubble ───────────────────────────────────────────────────────────────
+/// The quoted message shown inside a reply and above the input while replying.
 #[component]
-fn ChatBubble(message: Message) -> Element {
-    let (row_cls, bubble_cls) = match message.side {
-        Side::Me   => ("row row-me",   "bubble bubble-me"),
-        Side::Them => ("row row-them", "bubble bubble-them"),
-    };
+fn QuoteBlock(author: String, quote: Quote) -> Element {
     rsx! {
-        div { class: "{row_cls}",
-            div { class: "{bubble_cls}", "{message.text}" }
+        div { class: "quote",
+            div { class: "quote-author", "{author}" }
+            if quote.deleted {
+                div { class: "quote-text quote-deleted", "This message was deleted" }
+            } else {
+                div { class: "quote-text", "{quote.snippet}" }
+            }
         }
     }
 }
@@ -716,6 +3180,8 @@ body { font-family: -apple-system, BlinkMacSystemFont, "Segoe UI", sans-serif; }
 .auth-title { color: #e9edef; font-size: 26px; font-weight: 700; letter-spacing: -.5px; }
 .auth-subtitle { color: #8696a0; font-size: 14px; text-align: center; line-height: 1.5; max-width: 300px; }
 .auth-subtitle strong { color: #e9edef; }
+.auth-hint { color: #8696a0; font-size: 12px; text-align: center; max-width: 300px; }
+.settings-ok { color: #00a884; font-size: 13px; }
 .auth-input {
     width: 100%; padding: 14px 18px; border-radius: 12px;
     border: 1.5px solid #2a3942; background: #111b21; color: #e9edef;
@@ -749,6 +3215,16 @@ body { font-family: -apple-system, BlinkMacSystemFont, "Segoe UI", sans-serif; }
 .add-btn { width: 34px; height: 34px; border-radius: 50%; border: none; background: #00a884; color: #fff; font-size: 20px; cursor: pointer; display: flex; align-items: center; justify-content: center; transition: background .15s, transform .1s; flex-shrink: 0; }
 .add-btn:hover  { background: #06cf9c; }
 .add-btn:active { transform: scale(.9); }
+.sidebar-search { padding: 8px 12px; background: #111b21; border-bottom: 1px solid #1e2b33; }
+.search-input { width: 100%; padding: 8px 14px; border-radius: 8px; border: none; background: #202c33; color: #e9edef; font-size: 13px; outline: none; }
+.search-input::placeholder { color: #8696a0; }
+.search-hit { padding: 10px 16px; cursor: pointer; border-bottom: 1px solid #1e2b33; }
+.search-hit:hover { background: #1e2b33; }
+.search-hit-title { color: #e9edef; font-size: 14px; font-weight: 600; margin-bottom: 4px; }
+.search-hit-text { color: #d1d7db; font-size: 13px; line-height: 1.4; }
+.search-hit-text mark { background: #06cf9c33; color: #06cf9c; border-radius: 2px; }
+.search-hit-author { color: #8696a0; }
+.search-context { color: #667781; font-size: 12px; white-space: nowrap; overflow: hidden; text-overflow: ellipsis; }
 .chat-list { flex: 1; overflow-y: auto; }
 .chat-list::-webkit-scrollbar { width: 4px; }
 .chat-list::-webkit-scrollbar-thumb { background: #2a3942; border-radius: 4px; }
@@ -758,6 +3234,13 @@ body { font-family: -apple-system, BlinkMacSystemFont, "Segoe UI", sans-serif; }
 .chat-item.active { background: #2a3942; }
 .chat-avatar { width: 46px; height: 46px; border-radius: 50%; background: #00a884; color: #fff; font-size: 20px; font-weight: 700; display: flex; align-items: center; justify-content: center; flex-shrink: 0; }
 .chat-item-body { flex: 1; min-width: 0; }
+.chat-item-meta { display: flex; flex-direction: column; align-items: flex-end; gap: 4px; flex-shrink: 0; }
+.chat-item-time { color: #8696a0; font-size: 11px; }
+.chat-item-time.unread { color: #00a884; }
+.unread-badge { min-width: 20px; height: 20px; padding: 0 6px; border-radius: 10px; background: #00a884; color: #111b21; font-size: 11px; font-weight: 700; display: flex; align-items: center; justify-content: center; flex-shrink: 0; }
+.unread-badge.muted { background: #8696a0; }
+.chat-item-badges { display: flex; align-items: center; gap: 4px; }
+.chat-item-muted { font-size: 12px; opacity: 0.7; }
 .chat-item-name    { color: #e9edef; font-size: 15px; font-weight: 600; white-space: nowrap; overflow: hidden; text-overflow: ellipsis; }
 .chat-item-preview { color: #8696a0; font-size: 12px; margin-top: 2px; white-space: nowrap; overflow: hidden; text-overflow: ellipsis; }
 
@@ -774,12 +3257,65 @@ body { font-family: -apple-system, BlinkMacSystemFont, "Segoe UI", sans-serif; }
 .messages::-webkit-scrollbar { width: 4px; }
 .messages::-webkit-scrollbar-thumb { background: #2a3942; border-radius: 4px; }
 .no-messages { margin: auto; color: #8696a0; font-size: 14px; }
+.connection-banner {
+    display: flex; align-items: center; justify-content: space-between; gap: 8px;
+    padding: 8px 16px; background: #3b2f12; color: #f0c674; font-size: 12px;
+}
+.connection-retry { background: none; border: none; color: #e9edef; font-size: 12px; cursor: pointer; text-decoration: underline; }
+.load-older {
+    align-self: center; margin-bottom: 8px; padding: 4px 12px;
+    background: #202c33; color: #8696a0; border: none; border-radius: 12px;
+    font-size: 12px; cursor: pointer;
+}
+.load-older:hover { color: #e9edef; }
 .row       { display: flex; }
 .row-me    { justify-content: flex-end; }
 .row-them  { justify-content: flex-start; }
 .bubble { max-width: 68%; padding: 8px 12px; border-radius: 8px; font-size: 14px; line-height: 1.5; box-shadow: 0 1px 2px rgba(0,0,0,.3); word-break: break-word; }
 .bubble-me   { background: #005c4b; color: #e9edef; border-bottom-right-radius: 2px; }
 .bubble-them { background: #202c33; color: #e9edef; border-bottom-left-radius: 2px; }
+.bubble-edited  { margin-left: 8px; font-size: 11px; color: #8696a0; }
+.bubble-time    { margin-left: 8px; font-size: 11px; color: #8696a0; font-style: normal; white-space: nowrap; }
+.row-grouped { margin-top: -4px; }
+.row-grouped .bubble-me   { border-top-right-radius: 2px; }
+.row-grouped .bubble-them { border-top-left-radius: 2px; }
+.day-separator { align-self: center; margin: 10px 0 4px; }
+.day-separator span { display: inline-block; padding: 4px 12px; border-radius: 8px; background: #182229; color: #8696a0; font-size: 12px; box-shadow: 0 1px 2px rgba(0,0,0,.3); }
+.bubble-deleted { font-style: italic; color: #8696a0; }
+.bubble-state { margin-left: 6px; font-size: 11px; color: #8696a0; letter-spacing: -2px; }
+.bubble-state.state-read { color: #53bdeb; }
+.bubble-failed { cursor: pointer; opacity: .75; border: 1px solid #f15c6d; }
+.bubble-failed-note { margin-top: 2px; font-size: 11px; color: #f15c6d; }
+.bubble-editing { display: flex; flex-direction: column; gap: 6px; min-width: 240px; }
+.bubble-edit-input { padding: 6px 10px; border-radius: 6px; border: none; background: #2a3942; color: #e9edef; font-size: 14px; outline: none; }
+.bubble-edit-actions { display: flex; justify-content: flex-end; gap: 4px; }
+.row-me .bubble-menu { order: -1; }
+.bubble-menu { position: relative; align-self: center; margin: 0 4px; }
+.bubble-menu-btn { background: none; border: none; color: #8696a0; font-size: 16px; cursor: pointer; opacity: 0; padding: 2px 4px; }
+.row:hover .bubble-menu-btn { opacity: 1; }
+.bubble-menu-list { position: absolute; top: 100%; z-index: 5; display: flex; flex-direction: column; min-width: 170px; background: #233138; border-radius: 8px; padding: 4px 0; box-shadow: 0 8px 24px rgba(0,0,0,.4); }
+.row-me .bubble-menu-list { right: 0; }
+.bubble-action { background: none; border: none; color: #e9edef; font-size: 13px; text-align: left; padding: 8px 14px; cursor: pointer; }
+.bubble-action:hover { background: #182229; }
+.bubble-action-danger { color: #f15c6d; }
+.bubble-stack { display: flex; flex-direction: column; max-width: 68%; }
+.row-me .bubble-stack { align-items: flex-end; }
+.bubble-stack .bubble { max-width: 100%; }
+.quote { border-left: 3px solid #06cf9c; background: rgba(0,0,0,.18); border-radius: 4px; padding: 4px 8px; margin-bottom: 4px; font-size: 12px; }
+.quote-author { color: #06cf9c; font-weight: 600; }
+.quote-text { color: #aebac1; white-space: nowrap; overflow: hidden; text-overflow: ellipsis; max-width: 360px; }
+.quote-deleted { font-style: italic; }
+.reactions { display: flex; flex-wrap: wrap; gap: 4px; margin-top: -4px; padding: 0 6px; }
+.reaction { background: #202c33; border: 1px solid #2a3942; border-radius: 12px; color: #e9edef; font-size: 12px; padding: 1px 7px; cursor: pointer; }
+.reaction-mine { border-color: #00a884; background: #103529; }
+.quick-reactions { display: flex; gap: 2px; padding: 4px 8px; border-bottom: 1px solid #2a3942; }
+.quick-reaction { background: none; border: none; font-size: 18px; cursor: pointer; padding: 2px; border-radius: 6px; }
+.quick-reaction:hover { background: #182229; }
+.reply-bar { display: flex; align-items: center; gap: 10px; padding: 8px 16px 0; background: #202c33; }
+.reply-bar .quote { flex: 1; margin-bottom: 0; }
+.row.flash .bubble { animation: flash 1.6s ease-out; }
+@keyframes flash { 0%, 40% { box-shadow: 0 0 0 3px #06cf9c; } 100% { box-shadow: 0 1px 2px rgba(0,0,0,.3); } }
+.reply-cancel { background: none; border: none; color: #8696a0; font-size: 16px; cursor: pointer; }
 .input-bar { display: flex; align-items: center; gap: 10px; padding: 10px 16px; background: #202c33; border-top: 1px solid #1e2b33; }
 .text-input { flex: 1; padding: 10px 16px; border-radius: 24px; border: none; background: #2a3942; color: #e9edef; font-size: 14px; outline: none; }
 .text-input::placeholder { color: #8696a0; }
@@ -807,4 +3343,76 @@ body { font-family: -apple-system, BlinkMacSystemFont, "Segoe UI", sans-serif; }
 .modal-btn-confirm { padding: 9px 22px; border-radius: 8px; border: none; background: #00a884; color: #fff; font-size: 14px; font-weight: 600; cursor: pointer; transition: background .15s; }
 .modal-btn-confirm:hover  { background: #06cf9c; }
 .modal-btn-confirm:active { background: #008c70; }
-"#;
\ No newline at end of file
+.modal-btn-confirm:disabled { opacity: .5; cursor: not-allowed; }
+
+.chat-header-actions { margin-left: auto; display: flex; gap: 6px; }
+.header-btn { background: none; border: 1px solid #2a3942; color: #8696a0; font-size: 13px; padding: 6px 10px; border-radius: 8px; cursor: pointer; transition: background .12s, color .12s; }
+.header-btn:hover { background: #2a3942; color: #e9edef; }
+.blocked-bar { justify-content: center; color: #8696a0; font-size: 13px; padding: 16px; }
+.report-body { display: flex; flex-direction: column; gap: 10px; }
+.report-reason { display: flex; align-items: center; gap: 10px; color: #e9edef; font-size: 14px; cursor: pointer; }
+.report-details { min-height: 80px; resize: vertical; font-family: inherit; }
+.report-btn { background: #d9534f; }
+.report-btn:hover { background: #e5635f; }
+.me-btn { background: none; border: none; padding: 0; cursor: pointer; display: flex; }
+.me-avatar { width: 32px; height: 32px; border-radius: 50%; background: #00a884; color: #fff; font-size: 14px; font-weight: 700; display: flex; align-items: center; justify-content: center; overflow: hidden; }
+.avatar-img { width: 100%; height: 100%; object-fit: cover; border-radius: 50%; }
+.chat-avatar, .chat-header-avatar { overflow: hidden; }
+.profile-body { display: flex; flex-direction: column; align-items: center; gap: 12px; }
+.profile-avatar-pick { display: flex; flex-direction: column; align-items: center; gap: 6px; cursor: pointer; }
+.profile-avatar-pick input { display: none; }
+.profile-avatar { width: 96px; height: 96px; border-radius: 50%; background: #00a884; color: #fff; font-size: 40px; font-weight: 700; display: flex; align-items: center; justify-content: center; overflow: hidden; }
+.profile-avatar-hint { color: #00a884; font-size: 12px; }
+.profile-security-link { align-self: flex-start; background: none; border: none; color: #00a884; font-size: 13px; cursor: pointer; padding: 4px 0; }
+.profile-logout { color: #f15c6d; }
+.security-body { display: flex; flex-direction: column; gap: 10px; }
+.danger-zone { display: flex; flex-direction: column; gap: 10px; margin-top: 10px; padding-top: 14px; border-top: 1px solid #2a3942; }
+.danger-btn { background: #3b1f22; color: #ff6b6b; border: 1px solid #ff6b6b; border-radius: 8px; padding: 10px 14px; font-size: 14px; cursor: pointer; }
+.danger-btn:disabled { opacity: .5; cursor: default; }
+.security-text { color: #d1d7db; font-size: 14px; line-height: 1.5; margin: 0; }
+.security-secret { font-family: monospace; font-size: 16px; letter-spacing: 2px; color: #e9edef; background: #111b21; border-radius: 8px; padding: 10px; user-select: all; word-break: break-all; }
+.security-uri { font-family: monospace; font-size: 11px; color: #8696a0; user-select: all; word-break: break-all; }
+.security-codes { display: grid; grid-template-columns: 1fr 1fr; gap: 6px; font-family: monospace; font-size: 15px; color: #e9edef; background: #111b21; border-radius: 8px; padding: 12px; user-select: all; }
+"#;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    /// A moment on the local clock, the way the labels see it.
+    fn at(year: i32, month: u32, day: u32, hour: u32, min: u32, sec: u32) -> i64 {
+        chrono::Local.with_ymd_and_hms(year, month, day, hour, min, sec).earliest().unwrap().timestamp()
+    }
+
+    fn message(side: Side, sent_at: i64) -> Message {
+        Message::received(0, &IncomingMessage { sent_at, ..Default::default() }, side)
+    }
+
+    #[test]
+    fn day_labels_turn_over_at_local_midnight() {
+        let now = at(2026, 3, 10, 0, 0, 30);
+
+        assert_eq!(day_label(at(2026, 3, 10, 0, 0, 0), now), "Today");
+        assert_eq!(day_label(at(2026, 3, 9, 23, 59, 59), now), "Yesterday");
+        assert_eq!(day_label(at(2026, 3, 9, 0, 0, 0), now), "Yesterday");
+        assert_eq!(day_label(at(2026, 3, 8, 23, 59, 59), now), "Sunday");
+        assert_eq!(day_label(at(2026, 3, 4, 12, 0, 0), now), "Wednesday");
+        assert_eq!(day_label(at(2026, 3, 3, 12, 0, 0), now), "3 March");
+        assert_eq!(day_label(at(2025, 12, 31, 12, 0, 0), now), "31 December 2025");
+    }
+
+    #[test]
+    fn groups_break_on_sender_gap_and_day() {
+        let start = at(2026, 3, 9, 12, 0, 0);
+        let first = message(Side::Them, start);
+
+        assert!(continues_group(&first, &message(Side::Them, start + GROUP_GAP_SECS - 1)));
+        assert!(!continues_group(&first, &message(Side::Me, start + 1)));
+        assert!(!continues_group(&first, &message(Side::Them, start + GROUP_GAP_SECS)));
+
+        let late = message(Side::Them, at(2026, 3, 9, 23, 59, 0));
+        assert!(!continues_group(&late, &message(Side::Them, at(2026, 3, 10, 0, 1, 0))));
+        // a message without a time never anchors a group
+        assert!(!continues_group(&message(Side::Them, 0), &message(Side::Them, 1)));
+    }
+}
@@ -0,0 +1,167 @@
+//! Desktop notifications for messages that arrive while the window is in the
+//! background. Only the desktop build has a window to watch and a notification
+//! server to talk to; elsewhere the app counts as always focused and nothing is
+//! shown.
+
+use dioxus::prelude::*;
+use tokio::sync::mpsc::UnboundedSender;
+
+/// Whether the app window has focus, kept up to date as it changes.
+pub fn use_window_focus() -> Signal<bool> {
+    #[cfg(feature = "desktop")]
+    {
+        use dioxus::desktop::tao::event::{Event, WindowEvent};
+
+        let mut focused = use_signal(|| dioxus::desktop::window().is_focused());
+        dioxus::desktop::use_wry_event_handler(move |event, _| {
+            if let Event::WindowEvent { event: WindowEvent::Focused(now), .. } = event {
+                focused.set(*now);
+            }
+        });
+        focused
+    }
+    #[cfg(not(feature = "desktop"))]
+    {
+        use_signal(|| true)
+    }
+}
+
+/// Restores and raises the app window, e.g. after a notification was clicked.
+pub fn bring_to_front() {
+    #[cfg(feature = "desktop")]
+    {
+        let window = dioxus::desktop::window();
+        window.set_minimized(false);
+        window.set_visible(true);
+        window.set_focus();
+    }
+}
+
+/// Shows notifications and reports which were clicked. Each notification is
+/// tagged with the peer it is about; the tag comes back on `clicks`.
+///
+/// A single worker shows them in order, so a burst of messages never piles up
+/// threads or connections to the notification server.
+pub struct Notifier {
+    notes: UnboundedSender<Note>,
+}
+
+// only read where there is a notification server to show it on
+#[cfg_attr(not(feature = "desktop"), allow(dead_code))]
+struct Note {
+    title: String,
+    body: String,
+    tag: String,
+}
+
+impl Notifier {
+    pub fn new(clicks: UnboundedSender<String>) -> Self {
+        let (notes, queued) = tokio::sync::mpsc::unbounded_channel();
+        #[cfg(all(feature = "desktop", unix, not(target_os = "macos")))]
+        spawn(show_and_wait(queued, clicks));
+        // the other platforms don't report clicks back to us, and showing one
+        // blocks, so a plain thread works through the queue
+        #[cfg(all(feature = "desktop", not(all(unix, not(target_os = "macos")))))]
+        {
+            let _ = clicks;
+            let mut queued = queued;
+            std::thread::spawn(move || {
+                while let Some(note) = queued.blocking_recv() {
+                    if let Err(e) = note.notification().show() {
+                        warn!("could not show notification: {e}");
+                    }
+                }
+            });
+        }
+        #[cfg(not(feature = "desktop"))]
+        let _ = (queued, clicks);
+        Self { notes }
+    }
+
+    pub fn show(&self, title: &str, body: &str, tag: String) {
+        let _ = self.notes.send(Note { title: title.to_string(), body: body.to_string(), tag });
+    }
+}
+
+#[cfg(feature = "desktop")]
+impl Note {
+    fn notification(&self) -> notify_rust::Notification {
+        let mut notification = notify_rust::Notification::new();
+        notification.appname("DioxusChat").summary(&self.title).body(&self.body);
+        notification
+    }
+}
+
+/// Each notification takes the place of the one before it, so only the newest
+/// is on screen and its click is the only one worth waiting for.
+#[cfg(all(feature = "desktop", unix, not(target_os = "macos")))]
+async fn show_and_wait(mut queued: tokio::sync::mpsc::UnboundedReceiver<Note>, clicks: UnboundedSender<String>) {
+    let mut on_screen = None;
+    let mut next = queued.recv().await;
+    while let Some(note) = next.take() {
+        let mut notification = note.notification();
+        notification.action("default", "Open");
+        if let Some(id) = on_screen {
+            notification.id(id);
+        }
+        let handle = match notification.show_async().await {
+            Ok(handle) => handle,
+            Err(e) => {
+                warn!("could not show notification: {e}");
+                next = queued.recv().await;
+                continue;
+            }
+        };
+        on_screen = Some(handle.id());
+
+        let clicked = handle.wait_for_action_async(|response| {
+            if let notify_rust::NotificationResponse::Default = response {
+                let _ = clicks.send(note.tag.clone());
+            }
+        });
+        tokio::select! {
+            _ = clicked => next = queued.recv().await,
+            newer = queued.recv() => next = newer,
+        }
+    }
+}
+
+/// What a notification shows of a message: the first line, shortened. A
+/// deleted message has nothing to show and is not notified at all.
+pub fn preview(text: &str, deleted: bool) -> Option<String> {
+    const MAX_CHARS: usize = 100;
+    if deleted {
+        return None;
+    }
+    let line = text.lines().next().unwrap_or_default().trim();
+    if line.chars().count() > MAX_CHARS || line.len() < text.trim().len() {
+        let short: String = line.chars().take(MAX_CHARS).collect();
+        Some(format!("{}…", short.trim_end()))
+    } else {
+        Some(line.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn preview_keeps_the_first_line_and_marks_what_was_cut() {
+        let show = |text: &str| preview(text, false).unwrap();
+        assert_eq!(show("  hello there  "), "hello there");
+        assert_eq!(show("first\nsecond"), "first…");
+        assert_eq!(show("only line\n\n"), "only line");
+
+        let long = "é".repeat(100);
+        assert_eq!(show(&long), long);
+        assert_eq!(show(&format!("{long}é")), format!("{long}…"));
+        assert_eq!(show(&format!("{} end", "a".repeat(99))), format!("{}…", "a".repeat(99)));
+    }
+
+    #[test]
+    fn deleted_messages_have_no_preview() {
+        assert_eq!(preview("gone", true), None);
+        assert_eq!(preview("", true), None);
+    }
+}
@@ -0,0 +1,172 @@
+//! What differs between the desktop app and the browser build: timers, where
+//! the few small files we keep live, downloads, and facts about the machine.
+
+use std::time::Duration;
+
+pub use web_time::{Instant, SystemTime, UNIX_EPOCH};
+
+pub async fn sleep(duration: Duration) {
+    #[cfg(not(target_arch = "wasm32"))]
+    tokio::time::sleep(duration).await;
+    #[cfg(target_arch = "wasm32")]
+    gloo_timers::future::sleep(duration).await;
+}
+
+pub fn since_epoch() -> Duration {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default()
+}
+
+pub fn device_name() -> String {
+    if cfg!(target_arch = "wasm32") {
+        "DioxusChat in a browser".to_string()
+    } else {
+        format!("DioxusChat on {}", std::env::consts::OS)
+    }
+}
+
+/// Language for server-sent mail: the system locale (e.g. `es_ES.UTF-8`) on
+/// desktop, the browser's preferred language on the web.
+pub fn locale() -> String {
+    #[cfg(target_arch = "wasm32")]
+    {
+        web_sys::window()
+            .and_then(|w| w.navigator().language())
+            .unwrap_or_else(|| "en".to_owned())
+    }
+    #[cfg(not(target_arch = "wasm32"))]
+    {
+        std::env::var("LANG").unwrap_or_else(|_| "en".to_owned())
+    }
+}
+
+/// An environment variable; the browser has none.
+pub fn env_var(name: &str) -> Option<String> {
+    if cfg!(target_arch = "wasm32") {
+        return None;
+    }
+    std::env::var(name).ok()
+}
+
+/// A sign-in link the OS launched us with. Browsers can't be handed our
+/// scheme, so there the link is pasted on the code screen instead.
+pub fn launch_link(prefix: &str) -> Option<String> {
+    if cfg!(target_arch = "wasm32") {
+        return None;
+    }
+    std::env::args().skip(1).find(|a| a.starts_with(prefix))
+}
+
+// ── Small files ───────────────────────────────────────────────────────────────
+// Desktop keeps them in the data directory, the browser in localStorage.
+
+#[cfg(target_arch = "wasm32")]
+fn local_storage() -> Option<web_sys::Storage> {
+    web_sys::window()?.local_storage().ok()?
+}
+
+#[cfg(target_arch = "wasm32")]
+fn storage_key(name: &str) -> String {
+    format!("dioxuschat/{name}")
+}
+
+pub fn read_text(name: &str) -> Option<String> {
+    #[cfg(target_arch = "wasm32")]
+    {
+        local_storage()?.get_item(&storage_key(name)).ok()?
+    }
+    #[cfg(not(target_arch = "wasm32"))]
+    {
+        std::fs::read_to_string(data_dir().join(name)).ok()
+    }
+}
+
+/// Replaces `name`. A `private` file is readable by the current user only and
+/// is swapped in whole, so a crash never leaves half of it behind.
+pub fn write_text(name: &str, contents: &str, private: bool) -> Result<(), String> {
+    #[cfg(target_arch = "wasm32")]
+    {
+        let _ = private;
+        let storage = local_storage().ok_or("browser storage is unavailable")?;
+        storage.set_item(&storage_key(name), contents).map_err(|_| "browser storage is full".to_string())
+    }
+    #[cfg(not(target_arch = "wasm32"))]
+    {
+        let path = data_dir().join(name);
+        std::fs::create_dir_all(data_dir()).map_err(|e| e.to_string())?;
+        if !private {
+            return std::fs::write(&path, contents).map_err(|e| format!("could not save {}: {e}", path.display()));
+        }
+
+        let partial = path.with_extension("partial");
+        let mut options = std::fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        let mut file = options.open(&partial).map_err(|e| e.to_string())?;
+        std::io::Write::write_all(&mut file, contents.as_bytes()).map_err(|e| e.to_string())?;
+        file.sync_all().map_err(|e| e.to_string())?;
+        std::fs::rename(&partial, &path).map_err(|e| e.to_string())
+    }
+}
+
+pub fn remove(name: &str) {
+    #[cfg(target_arch = "wasm32")]
+    if let Some(storage) = local_storage() {
+        let _ = storage.remove_item(&storage_key(name));
+    }
+    #[cfg(not(target_arch = "wasm32"))]
+    {
+        let _ = std::fs::remove_file(data_dir().join(name));
+    }
+}
+
+/// Where the app keeps its files: XDG data dir on Linux, `%APPDATA%` on Windows,
+/// Application Support on macOS.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn data_dir() -> std::path::PathBuf {
+    use std::path::PathBuf;
+
+    if let Some(dir) = std::env::var_os("XDG_DATA_HOME") {
+        return PathBuf::from(dir).join("dioxuschat");
+    }
+    if let Some(dir) = std::env::var_os("APPDATA") {
+        return PathBuf::from(dir).join("DioxusChat");
+    }
+    let home = std::env::var_os("HOME").map(PathBuf::from).unwrap_or_default();
+    if cfg!(target_os = "macos") {
+        home.join("Library/Application Support/DioxusChat")
+    } else {
+        home.join(".local/share/dioxuschat")
+    }
+}
+
+/// Hands `bytes` to the user as a file and says where it went: ~/Downloads
+/// (or the working directory) on desktop, the browser's downloads on the web.
+pub fn save_download(file_name: &str, bytes: &[u8]) -> Result<String, String> {
+    #[cfg(target_arch = "wasm32")]
+    {
+        use base64::Engine;
+        let encoded = base64::engine::general_purpose::STANDARD.encode(bytes);
+        dioxus::prelude::document::eval(&format!(
+            "const a = document.createElement('a');
+             a.href = 'data:application/octet-stream;base64,{encoded}';
+             a.download = {name:?};
+             a.click();",
+            name = file_name,
+        ));
+        Ok(format!("Downloaded {file_name}"))
+    }
+    #[cfg(not(target_arch = "wasm32"))]
+    {
+        let downloads = std::env::var_os("HOME")
+            .or_else(|| std::env::var_os("USERPROFILE"))
+            .map(|home| std::path::PathBuf::from(home).join("Downloads"))
+            .filter(|dir| dir.is_dir());
+        let path = downloads.unwrap_or_default().join(file_name);
+        std::fs::write(&path, bytes).map_err(|e| format!("Could not save: {e}"))?;
+        Ok(format!("Saved to {}", path.display()))
+    }
+}
@@ -0,0 +1,98 @@
+//! Which server the app talks to. The `DIOXUSCHAT_SERVER` environment variable
+//! wins, then the address saved from the settings screen, then the default.
+//! Also which chats are muted, per account.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::platform::{self, Instant};
+
+pub const DEFAULT_SERVER_ADDR: &str = "https://poodle-flexible-carefully.ngrok-free.app";
+pub const SERVER_ENV: &str = "DIOXUSCHAT_SERVER";
+
+const FILE: &str = "settings.json";
+
+#[derive(Serialize, Deserialize, Default)]
+struct SettingsFile {
+    #[serde(default)]
+    server_addr: Option<String>,
+    #[serde(default)]
+    muted: BTreeMap<String, BTreeSet<String>>,   // account id -> peers we get no notifications from
+}
+
+fn load() -> SettingsFile {
+    platform::read_text(FILE)
+        .and_then(|json| serde_json::from_str(&json).ok())
+        .unwrap_or_default()
+}
+
+fn store(settings: &SettingsFile) -> Result<(), String> {
+    let json = serde_json::to_string_pretty(settings).map_err(|e| e.to_string())?;
+    platform::write_text(FILE, &json, false)
+}
+
+/// The address set in the environment, which the settings screen can't change.
+pub fn env_server_addr() -> Option<String> {
+    platform::env_var(SERVER_ENV).map(|a| a.trim().to_string()).filter(|a| !a.is_empty())
+}
+
+/// The address saved from the settings screen, if any.
+pub fn saved_server_addr() -> Option<String> {
+    load().server_addr.filter(|a| !a.is_empty())
+}
+
+pub fn server_addr() -> String {
+    env_server_addr()
+        .or_else(saved_server_addr)
+        .unwrap_or_else(|| DEFAULT_SERVER_ADDR.to_string())
+}
+
+/// Remembers `addr` for the next runs; `None` goes back to the default.
+pub fn save_server_addr(addr: Option<&str>) -> Result<(), String> {
+    let mut settings = load();
+    settings.server_addr = addr.map(str::to_string);
+    store(&settings)
+}
+
+/// Peers `user_id` muted; their messages still arrive, just without a notification.
+pub fn muted_chats(user_id: &str) -> BTreeSet<String> {
+    load().muted.remove(user_id).unwrap_or_default()
+}
+
+pub fn set_muted(user_id: &str, peer_id: &str, muted: bool) -> Result<(), String> {
+    let mut settings = load();
+    let peers = settings.muted.entry(user_id.to_string()).or_default();
+    if muted {
+        peers.insert(peer_id.to_string());
+    } else {
+        peers.remove(peer_id);
+    }
+    if peers.is_empty() {
+        settings.muted.remove(user_id);
+    }
+    store(&settings)
+}
+
+/// Checks that `addr` is something we can connect to, e.g. `https://chat.example.com`
+/// or `http://localhost:50051`.
+pub fn check_addr(addr: &str) -> Result<(), String> {
+    let addr = addr.trim();
+    if !(addr.starts_with("http://") || addr.starts_with("https://")) {
+        return Err("The address must start with http:// or https://".to_string());
+    }
+    if addr.len() <= "https://".len() || addr.contains(char::is_whitespace) {
+        return Err("That is not a valid address".to_string());
+    }
+    Ok(())
+}
+
+/// Makes one call to the server at `addr`, without keeping the connection,
+/// and says how long the round trip took.
+pub async fn test_connection(addr: &str) -> Result<Duration, String> {
+    check_addr(addr)?;
+    let started = Instant::now();
+    crate::grpc::probe(addr.trim()).await.map_err(|e| format!("Could not connect: {e}"))?;
+    Ok(started.elapsed())
+}